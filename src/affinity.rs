@@ -0,0 +1,104 @@
+use std::fs;
+use std::process::Output;
+
+use crate::config::CpuAffinity;
+use crate::qmp::QmpClient;
+use crate::utils::{find_in_path, run_shell_command};
+
+/// Where the kernel lists the host's online CPUs.
+const SYSFS_ONLINE_CPUS: &str = "/sys/devices/system/cpu/online";
+
+pub fn parse_cpu_list(list: &str) -> Result<Vec<usize>, String> {
+    //! Parses a list of CPUs in the form `taskset` and sysfs use, e.g.
+    //! `0-3,8`, returning the CPUs in the order given.
+    let invalid = || format!("Invalid CPU list '{list}'. Use the form '0-3,8'.");
+    let mut cpus: Vec<usize> = vec![];
+    for range in list.trim().split(',') {
+        let (first, last): (&str, &str) = range.split_once('-').unwrap_or((range, range));
+        let first: usize = first.trim().parse().map_err(|_| invalid())?;
+        let last: usize = last.trim().parse().map_err(|_| invalid())?;
+        if first > last {
+            return Err(invalid());
+        }
+        for cpu in first..=last {
+            if !cpus.contains(&cpu) {
+                cpus.push(cpu);
+            }
+        }
+    }
+    Ok(cpus)
+}
+
+fn check_cpus_online(cpus: &[usize]) -> Result<(), String> {
+    //! Checks that the host has each of the CPUs, and that they are online.
+    //! Hosts which don't list their online CPUs aren't checked.
+    let online: Vec<usize> = match fs::read_to_string(SYSFS_ONLINE_CPUS) {
+        Ok(online) => parse_cpu_list(&online)?,
+        Err(_) => return Ok(()),
+    };
+    match cpus.iter().find(|cpu| !online.contains(cpu)) {
+        Some(cpu) => Err(format!(
+            "Host CPU {cpu} is not online. The host's online CPUs are {}.",
+            fs::read_to_string(SYSFS_ONLINE_CPUS)
+                .unwrap_or_default()
+                .trim()
+        )),
+        None => Ok(()),
+    }
+}
+
+pub fn get_affinity_prefix(affinity: Option<&CpuAffinity>) -> Result<Vec<String>, String> {
+    //! Returns the command qemu must be run through to only run on the
+    //! host CPUs of `cpu_affinity`. Every thread qemu starts inherits this.
+    let affinity: &CpuAffinity = match affinity {
+        Some(affinity) => affinity,
+        None => return Ok(vec![]),
+    };
+    check_cpus_online(&parse_cpu_list(&affinity.cores)?)?;
+    if find_in_path("taskset").is_none() {
+        return Err(
+            "Unable to find 'taskset', which 'cpu_affinity' needs. Install 'util-linux'."
+                .to_owned(),
+        );
+    }
+    Ok(vec![
+        "taskset".to_owned(),
+        "-c".to_owned(),
+        affinity.cores.trim().to_owned(),
+    ])
+}
+
+pub fn pin_vcpus(image_name: &str, affinity: &CpuAffinity) -> Result<(), String> {
+    //! Pins each vCPU thread of a running VM to a single host CPU of
+    //! `cpu_affinity`, in order, wrapping around if the VM has more vCPUs
+    //! than there are CPUs. Waits for a VM which was just started to accept
+    //! QMP connections.
+    let cpus: Vec<usize> = parse_cpu_list(&affinity.cores)?;
+    let mut qmp: QmpClient = QmpClient::connect_to_started_vm(image_name)?;
+    for (index, thread_id) in qmp.query_vcpu_threads()? {
+        let cpu: String = cpus[index % cpus.len()].to_string();
+        let output: Output = run_shell_command(&["taskset", "-pc", &cpu, &thread_id.to_string()])?;
+        if !output.status.success() {
+            return Err(format!(
+                "Unable to pin vCPU {index} to host CPU {cpu}. {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_cpu_list;
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("0-3,8").unwrap(), vec![0, 1, 2, 3, 8]);
+        assert_eq!(parse_cpu_list("6,2,2-3").unwrap(), vec![6, 2, 3]);
+        assert_eq!(parse_cpu_list("0-7\n").unwrap().len(), 8);
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("").is_err());
+        assert!(parse_cpu_list("all").is_err());
+    }
+}
@@ -0,0 +1,562 @@
+use chrono::{Duration, Local, NaiveDateTime};
+use std::fs::{self, read_dir, File};
+use std::io::{IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+
+use crate::config::BackupPolicy;
+use crate::image::{get_image_info, ImageInfo};
+use crate::parse_args::Compression;
+use crate::qmp::QmpClient;
+use crate::utils::{get_partial_path, run_interactive_command, run_shell_command};
+
+/// Size of the chunks in which images are streamed through a compressor.
+const STREAM_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Format of the timestamp appended to the name of each backup, e.g.
+/// `deb12-20240131-154502.img`.
+pub const BACKUP_TIMESTAMP_FORMAT: &str = "%Y%m%d-%H%M%S";
+
+pub fn get_backup_file_name(image_path: &Path, timestamp: &NaiveDateTime) -> Option<String> {
+    //! Returns the file name of a backup of the given image taken at
+    //! `timestamp`, i.e. `<image name>-<timestamp>.<extension>`.
+    let stem: &str = image_path.file_stem()?.to_str()?;
+    let timestamp: String = timestamp.format(BACKUP_TIMESTAMP_FORMAT).to_string();
+    Some(
+        match image_path
+            .extension()
+            .and_then(|extension| extension.to_str())
+        {
+            Some(extension) => format!("{stem}-{timestamp}.{extension}"),
+            None => format!("{stem}-{timestamp}"),
+        },
+    )
+}
+
+/// A backup found in the backups directory.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Backup {
+    pub path: PathBuf,
+    /// When the backup was taken, as encoded in its file name.
+    pub timestamp: NaiveDateTime,
+}
+
+pub fn parse_backup_timestamp(file_name: &str, image_name: &str) -> Option<NaiveDateTime> {
+    //! Returns when a backup was taken if `file_name` is the name of a backup
+    //! of the image `image_name`, and `None` otherwise.
+    let stem: &str = file_name.split('.').next()?;
+    let timestamp: &str = stem.strip_prefix(image_name)?.strip_prefix('-')?;
+    NaiveDateTime::parse_from_str(timestamp, BACKUP_TIMESTAMP_FORMAT).ok()
+}
+
+pub fn list_backups(backup_directory: &Path, image_name: &str) -> Vec<Backup> {
+    //! Returns every backup of the given image, newest first.
+    let mut backups: Vec<Backup> = match read_dir(backup_directory) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file())
+            .filter_map(|path| {
+                let file_name: &str = path.file_name()?.to_str()?;
+                let timestamp: NaiveDateTime = parse_backup_timestamp(file_name, image_name)?;
+                Some(Backup { path, timestamp })
+            })
+            .collect(),
+        // no backups have been taken yet
+        Err(_) => vec![],
+    };
+    backups.sort_by_key(|backup| std::cmp::Reverse(backup.timestamp));
+    backups
+}
+
+pub fn get_backups_in_use(image_path: &Path, kept_backups: &[&Backup]) -> Vec<PathBuf> {
+    //! Returns every backup which the given image, or one of the backups
+    //! being kept, depends on as a backing file. Removing these would break
+    //! a chain of incremental backups.
+    let mut in_use: Vec<PathBuf> = get_backing_chain(image_path);
+    for backup in kept_backups {
+        in_use.extend(get_backing_chain(&backup.path));
+    }
+    in_use
+}
+
+pub fn select_backups_to_prune<'a>(
+    backups: &'a [Backup],
+    policy: &BackupPolicy,
+    now: &NaiveDateTime,
+) -> Vec<&'a Backup> {
+    //! Returns the backups which `policy` doesn't keep. `backups` must be
+    //! sorted newest first, as returned by `list_backups`.
+    if policy.keep_last.is_none() && policy.keep_days.is_none() {
+        return vec![];
+    }
+    backups
+        .iter()
+        .enumerate()
+        .filter(|(index, backup)| {
+            let kept_by_count: bool = policy.keep_last.is_some_and(|keep_last| *index < keep_last);
+            let kept_by_age: bool = policy.keep_days.is_some_and(|keep_days| {
+                backup.timestamp > *now - Duration::days(keep_days as i64)
+            });
+            !kept_by_count && !kept_by_age
+        })
+        .map(|(_, backup)| backup)
+        .collect()
+}
+
+pub fn get_backing_chain(image_path: &Path) -> Vec<PathBuf> {
+    //! Returns every backing file the given image depends on, nearest first.
+    let mut chain: Vec<PathBuf> = vec![];
+    let mut current: PathBuf = image_path.to_path_buf();
+    while let Some(backing_file) = get_image_info(&current)
+        .ok()
+        .and_then(|info| info.backing_file)
+    {
+        // guard against a (broken) circular chain
+        if chain.contains(&backing_file) {
+            break;
+        }
+        chain.push(backing_file.clone());
+        current = backing_file;
+    }
+    chain
+}
+
+fn new_backup_path(image_path: &Path, backup_directory: &Path) -> Result<PathBuf, String> {
+    //! Returns the path of a new, timestamped backup of the given image,
+    //! creating the backup directory if needed.
+    fs::create_dir_all(backup_directory).map_err(|e| {
+        format!(
+            "Unable to create backup directory '{}'. {e}",
+            backup_directory.display()
+        )
+    })?;
+
+    let file_name: String = get_backup_file_name(image_path, &Local::now().naive_local())
+        .ok_or(format!("Invalid image path '{}'.", image_path.display()))?;
+    let backup_path: PathBuf = backup_directory.join(file_name);
+    if backup_path.exists() {
+        return Err(format!(
+            "Backup '{}' already exists.",
+            backup_path.display()
+        ));
+    }
+    Ok(backup_path)
+}
+
+fn write_flattened_image(source: &Path, destination: &Path) -> Result<(), String> {
+    //! Writes a standalone copy of `source` to `destination`, merging in
+    //! every file of its backing chain. The copy keeps the format of
+    //! `source`.
+    let format: String = get_image_info(source)?.format;
+    let partial_path: PathBuf = get_partial_path(destination);
+    let source: String = source.display().to_string();
+    let partial: String = partial_path.display().to_string();
+    let output = run_shell_command(&[
+        "qemu-img",
+        "convert",
+        "--force-share",
+        "-O",
+        &format,
+        &source,
+        &partial,
+    ])?;
+    if !output.status.success() {
+        let _ = fs::remove_file(&partial_path);
+        return Err(format!(
+            "Unable to convert '{source}'. {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    fs::rename(&partial_path, destination).map_err(|e| {
+        let _ = fs::remove_file(&partial_path);
+        format!("Unable to move '{}' into place. {e}", destination.display())
+    })
+}
+
+pub fn create_backup(image_path: &Path, backup_directory: &Path) -> Result<PathBuf, String> {
+    //! Copies the given image into `backup_directory` under a timestamped
+    //! name, returning the path of the new backup. If the image is an
+    //! overlay left by an incremental backup, its backing chain is merged
+    //! into the copy, so that the backup stands on its own.
+    let backup_path: PathBuf = new_backup_path(image_path, backup_directory)?;
+
+    if get_image_info(image_path).is_ok_and(|info| info.backing_file.is_some()) {
+        write_flattened_image(image_path, &backup_path)?;
+        return Ok(backup_path);
+    }
+
+    let partial_path: PathBuf = get_partial_path(&backup_path);
+    let source: String = image_path.display().to_string();
+    let destination: String = partial_path.display().to_string();
+    // `cp` keeps sparse images sparse, which `fs::copy` does not.
+    let output = run_shell_command(&["cp", "--sparse=always", &source, &destination])?;
+    if !output.status.success() {
+        let _ = fs::remove_file(&partial_path);
+        return Err(format!(
+            "Unable to copy '{source}' to '{destination}'. {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    fs::rename(&partial_path, &backup_path).map_err(|e| {
+        let _ = fs::remove_file(&partial_path);
+        format!("Unable to move backup into place. {e}")
+    })?;
+    Ok(backup_path)
+}
+
+pub fn create_incremental_backup(
+    image_path: &Path,
+    backup_directory: &Path,
+    mut qmp_client: Option<&mut QmpClient>,
+) -> Result<PathBuf, String> {
+    //! Backs up the given image by freezing it: the image is moved into
+    //! `backup_directory`, and a new qcow2 overlay backed by it takes its
+    //! place. The backup therefore only stores what changed since the
+    //! previous backup, and the image only stores what changes from now on.
+    //!
+    //! If the image is in use by a running VM, `qmp_client` must be
+    //! connected to it, and the VM is switched over to the new overlay via
+    //! `blockdev-snapshot-sync`.
+    let info: ImageInfo = get_image_info(image_path)?;
+    let backup_path: PathBuf = new_backup_path(image_path, backup_directory)?;
+    let device: Option<String> = match qmp_client.as_deref_mut() {
+        Some(client) => Some(client.find_block_device(image_path)?),
+        None => None,
+    };
+
+    // a running VM keeps writing through its open file descriptor, so
+    // moving the image doesn't disturb it.
+    fs::rename(image_path, &backup_path).map_err(|e| {
+        format!(
+            "Unable to move '{}' to '{}'. {e}",
+            image_path.display(),
+            backup_path.display()
+        )
+    })?;
+    let restore_image = |e: String| -> String {
+        let _ = fs::remove_file(image_path);
+        match fs::rename(&backup_path, image_path) {
+            Ok(()) => e,
+            Err(rename_error) => format!(
+                "{e} Additionally, unable to move '{}' back to '{}'. {rename_error}",
+                backup_path.display(),
+                image_path.display()
+            ),
+        }
+    };
+
+    let output = run_shell_command(&[
+        "qemu-img",
+        "create",
+        "-f",
+        "qcow2",
+        "-F",
+        &info.format,
+        "-b",
+        &backup_path.display().to_string(),
+        &image_path.display().to_string(),
+    ])
+    .map_err(&restore_image)?;
+    if !output.status.success() {
+        return Err(restore_image(format!(
+            "Unable to create overlay '{}'. {}",
+            image_path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    if let (Some(client), Some(device)) = (qmp_client, device) {
+        client
+            .snapshot_onto_overlay(&device, image_path)
+            .map_err(restore_image)?;
+    }
+    Ok(backup_path)
+}
+
+fn get_compressed_extension(compression: Compression) -> Option<&'static str> {
+    //! Returns the extension appended to backups streamed through a
+    //! compressor, or `None` if the backup is still an image qemu can run.
+    match compression {
+        Compression::Zstd => Some("zst"),
+        Compression::Gzip => Some("gz"),
+        Compression::Qcow2 => None,
+    }
+}
+
+fn stream_through_command(
+    command: &[&str],
+    source: &Path,
+    destination: &Path,
+    action: &str,
+) -> Result<(), String> {
+    //! Feeds `source` to the standard input of `command`, writing its
+    //! standard output to `destination`. Progress is shown on standard error
+    //! if it is a terminal.
+    let mut input =
+        File::open(source).map_err(|e| format!("Unable to open '{}'. {e}", source.display()))?;
+    let output = File::create(destination)
+        .map_err(|e| format!("Unable to create '{}'. {e}", destination.display()))?;
+    let total_size: u64 = input.metadata().map_or(0, |metadata| metadata.len());
+    let show_progress: bool = std::io::stderr().is_terminal();
+
+    let mut child: Child = Command::new(command[0])
+        .args(&command[1..])
+        .stdin(Stdio::piped())
+        .stdout(output)
+        .spawn()
+        .map_err(|e| format!("Unable to run '{}'. {e}", command[0]))?;
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or("Unable to open compressor input.")?;
+
+    let mut buffer: Vec<u8> = vec![0; STREAM_CHUNK_SIZE];
+    let mut streamed: u64 = 0;
+    let mut last_percentage: Option<u64> = None;
+    loop {
+        let read: usize = input
+            .read(&mut buffer)
+            .map_err(|e| format!("Unable to read '{}'. {e}", source.display()))?;
+        if read == 0 {
+            break;
+        }
+        stdin
+            .write_all(&buffer[..read])
+            .map_err(|e| format!("Unable to write to '{}'. {e}", command[0]))?;
+        streamed += read as u64;
+
+        let percentage: u64 = (streamed * 100).checked_div(total_size).unwrap_or(100);
+        if show_progress && last_percentage != Some(percentage) {
+            eprint!("\r{action} '{}': {percentage}%", source.display());
+            last_percentage = Some(percentage);
+        }
+    }
+    if show_progress {
+        eprintln!();
+    }
+
+    // closing standard input lets the command finish.
+    drop(stdin);
+    match child.wait() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("'{}' exited with {status}.", command[0])),
+        Err(e) => Err(format!("Unable to wait for '{}'. {e}", command[0])),
+    }
+}
+
+pub fn create_compressed_backup(
+    image_path: &Path,
+    backup_directory: &Path,
+    compression: Compression,
+) -> Result<PathBuf, String> {
+    //! Like `create_backup`, but compresses the backup. `zstd` and `gzip`
+    //! backups get a `.zst` or `.gz` extension and must be restored before
+    //! they can be run, while `qcow2` backups are compressed qcow2 images.
+    let mut backup_path: PathBuf = new_backup_path(image_path, backup_directory)?;
+    if let Some(extension) = get_compressed_extension(compression) {
+        let file_name: String = backup_path
+            .file_name()
+            .map_or(String::new(), |name| name.to_string_lossy().to_string());
+        backup_path.set_file_name(format!("{file_name}.{extension}"));
+        if backup_path.exists() {
+            return Err(format!(
+                "Backup '{}' already exists.",
+                backup_path.display()
+            ));
+        }
+    }
+    let partial_path: PathBuf = get_partial_path(&backup_path);
+
+    let result: Result<(), String> = match compression {
+        Compression::Zstd => stream_through_command(
+            &["zstd", "-T0", "-q", "-c"],
+            image_path,
+            &partial_path,
+            "Compressing",
+        ),
+        Compression::Gzip => {
+            stream_through_command(&["gzip", "-c"], image_path, &partial_path, "Compressing")
+        }
+        // `-p` shows qemu-img's own progress.
+        Compression::Qcow2 => run_interactive_command(&[
+            "qemu-img",
+            "convert",
+            "--force-share",
+            "-p",
+            "-c",
+            "-O",
+            "qcow2",
+            &image_path.display().to_string(),
+            &partial_path.display().to_string(),
+        ]),
+    };
+    if let Err(e) = result {
+        let _ = fs::remove_file(&partial_path);
+        return Err(e);
+    }
+
+    fs::rename(&partial_path, &backup_path).map_err(|e| {
+        let _ = fs::remove_file(&partial_path);
+        format!("Unable to move backup into place. {e}")
+    })?;
+    Ok(backup_path)
+}
+
+pub fn restore_backup(backup_path: &Path, image_path: &Path) -> Result<(), String> {
+    //! Replaces the given image with a standalone copy of the backup at
+    //! `backup_path`, flattening any chain of incremental backups it sits on,
+    //! or decompressing a `zstd` or `gzip` backup. The backups themselves are
+    //! left untouched.
+    let decompressor: &[&str] = match backup_path
+        .extension()
+        .and_then(|extension| extension.to_str())
+    {
+        Some("zst") => &["zstd", "-d", "-q", "-c"],
+        Some("gz") => &["gzip", "-d", "-c"],
+        _ => return write_flattened_image(backup_path, image_path),
+    };
+
+    let partial_path: PathBuf = get_partial_path(image_path);
+    if let Err(e) =
+        stream_through_command(decompressor, backup_path, &partial_path, "Decompressing")
+    {
+        let _ = fs::remove_file(&partial_path);
+        return Err(e);
+    }
+    fs::rename(&partial_path, image_path).map_err(|e| {
+        let _ = fs::remove_file(&partial_path);
+        format!("Unable to move '{}' into place. {e}", image_path.display())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        create_backup, get_backup_file_name, parse_backup_timestamp, select_backups_to_prune,
+        stream_through_command, Backup,
+    };
+    use crate::config::BackupPolicy;
+    use chrono::{Datelike, NaiveDate, NaiveDateTime};
+    use std::path::{Path, PathBuf};
+
+    fn date(day: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 1, day)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_get_backup_file_name() {
+        let timestamp = NaiveDate::from_ymd_opt(2024, 1, 31)
+            .unwrap()
+            .and_hms_opt(15, 45, 2)
+            .unwrap();
+        assert_eq!(
+            get_backup_file_name(Path::new("/images/deb12.img"), &timestamp),
+            Some("deb12-20240131-154502.img".to_owned())
+        );
+        assert_eq!(
+            get_backup_file_name(Path::new("/images/deb12"), &timestamp),
+            Some("deb12-20240131-154502".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_parse_backup_timestamp() {
+        assert_eq!(
+            parse_backup_timestamp("deb12-20240131-154502.img", "deb12"),
+            Some(
+                NaiveDate::from_ymd_opt(2024, 1, 31)
+                    .unwrap()
+                    .and_hms_opt(15, 45, 2)
+                    .unwrap()
+            )
+        );
+        // a backup of another image whose name starts the same way
+        assert_eq!(
+            parse_backup_timestamp("deb12-test-20240131-154502.img", "deb12"),
+            None
+        );
+        assert_eq!(parse_backup_timestamp("deb12.img", "deb12"), None);
+    }
+
+    #[test]
+    fn test_select_backups_to_prune() {
+        // newest first, one per day from the 10th back to the 1st
+        let backups: Vec<Backup> = (1..=10)
+            .rev()
+            .map(|day| Backup {
+                path: PathBuf::from(format!("deb12-202401{day:02}-120000.img")),
+                timestamp: date(day),
+            })
+            .collect();
+        let now: NaiveDateTime = date(10);
+        let pruned_days = |policy: BackupPolicy| -> Vec<u32> {
+            select_backups_to_prune(&backups, &policy, &now)
+                .iter()
+                .map(|backup| backup.timestamp.day())
+                .collect()
+        };
+
+        assert_eq!(pruned_days(BackupPolicy::default()), Vec::<u32>::new());
+        assert_eq!(
+            pruned_days(BackupPolicy {
+                keep_last: Some(8),
+                keep_days: None,
+            }),
+            vec![2, 1]
+        );
+        assert_eq!(
+            pruned_days(BackupPolicy {
+                keep_last: None,
+                keep_days: Some(3),
+            }),
+            vec![7, 6, 5, 4, 3, 2, 1]
+        );
+        assert_eq!(
+            pruned_days(BackupPolicy {
+                keep_last: Some(5),
+                keep_days: Some(3),
+            }),
+            vec![5, 4, 3, 2, 1]
+        );
+    }
+
+    #[test]
+    fn test_stream_through_command() {
+        let directory =
+            std::env::temp_dir().join(format!("vm-manager-stream-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let source = directory.join("source");
+        let destination = directory.join("destination");
+        std::fs::write(&source, "disk contents").unwrap();
+
+        stream_through_command(&["tr", "a-z", "A-Z"], &source, &destination, "Testing").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&destination).unwrap(),
+            "DISK CONTENTS"
+        );
+        assert!(stream_through_command(&["false"], &source, &destination, "Testing").is_err());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_create_backup() {
+        let directory =
+            std::env::temp_dir().join(format!("vm-manager-backup-test-{}", std::process::id()));
+        let image = directory.join("deb12.img");
+        let backups = directory.join("backups");
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(&image, "disk contents").unwrap();
+
+        let backup = create_backup(&image, &backups).unwrap();
+        assert_eq!(std::fs::read_to_string(&backup).unwrap(), "disk contents");
+        assert_eq!(std::fs::read_dir(&backups).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,363 @@
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::config::{CatalogImage, Config};
+use crate::image::convert_image;
+use crate::parse_args::ImageFormat;
+use crate::utils::{get_partial_path, run_interactive_command, run_shell_command};
+
+/// The fingerprint of the key Ubuntu's cloud image checksums are signed with.
+const UBUNTU_CLOUD_IMAGE_KEY: &str = "D2EB44626FDDC30B513D5BB71A5D6C4C7DB87C81";
+
+/// The `(signature_url, signing_key)` of a signed checksum file.
+type Signature = (&'static str, &'static str);
+
+/// The cloud images `image pull` knows of without any config, by name, as
+/// `(name, url, checksums_url, signature)`.
+const BUILTIN_IMAGES: [(&str, &str, &str, Option<Signature>); 5] = [
+    (
+        "ubuntu-22.04",
+        "https://cloud-images.ubuntu.com/releases/22.04/release/ubuntu-22.04-server-cloudimg-amd64.img",
+        "https://cloud-images.ubuntu.com/releases/22.04/release/SHA256SUMS",
+        Some((
+            "https://cloud-images.ubuntu.com/releases/22.04/release/SHA256SUMS.gpg",
+            UBUNTU_CLOUD_IMAGE_KEY,
+        )),
+    ),
+    (
+        "ubuntu-24.04",
+        "https://cloud-images.ubuntu.com/releases/24.04/release/ubuntu-24.04-server-cloudimg-amd64.img",
+        "https://cloud-images.ubuntu.com/releases/24.04/release/SHA256SUMS",
+        Some((
+            "https://cloud-images.ubuntu.com/releases/24.04/release/SHA256SUMS.gpg",
+            UBUNTU_CLOUD_IMAGE_KEY,
+        )),
+    ),
+    (
+        "debian-12",
+        "https://cloud.debian.org/images/cloud/bookworm/latest/debian-12-genericcloud-amd64.qcow2",
+        "https://cloud.debian.org/images/cloud/bookworm/latest/SHA512SUMS",
+        None,
+    ),
+    (
+        "debian-13",
+        "https://cloud.debian.org/images/cloud/trixie/latest/debian-13-genericcloud-amd64.qcow2",
+        "https://cloud.debian.org/images/cloud/trixie/latest/SHA512SUMS",
+        None,
+    ),
+    (
+        "almalinux-9",
+        "https://repo.almalinux.org/almalinux/9/cloud/x86_64/images/AlmaLinux-9-GenericCloud-latest.x86_64.qcow2",
+        "https://repo.almalinux.org/almalinux/9/cloud/x86_64/images/CHECKSUM",
+        None,
+    ),
+];
+
+pub fn get_catalog(config: &Config) -> BTreeMap<String, CatalogImage> {
+    //! Returns every image `image pull` can download, by name: the built-in
+    //! ones, and those of the config's `image_catalog`, which take
+    //! precedence.
+    let mut catalog: BTreeMap<String, CatalogImage> = BUILTIN_IMAGES
+        .iter()
+        .map(|(name, url, checksums_url, signature)| {
+            (
+                name.to_string(),
+                CatalogImage {
+                    url: url.to_string(),
+                    checksums_url: checksums_url.to_string(),
+                    signature_url: signature.map(|(url, _)| url.to_owned()),
+                    signing_key: signature.map(|(_, key)| key.to_owned()),
+                },
+            )
+        })
+        .collect();
+    catalog.extend(
+        config
+            .get_image_catalog()
+            .iter()
+            .map(|(name, image)| (name.to_owned(), image.clone())),
+    );
+    catalog
+}
+
+fn get_file_name(url: &str) -> &str {
+    //! Returns the last segment of a URL's path, which checksum files name
+    //! their files by.
+    let path: &str = url.split(['?', '#']).next().unwrap_or(url);
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+fn find_checksum(checksums: &str, file_name: &str) -> Option<String> {
+    //! Finds the checksum of `file_name` in a checksum file, written either
+    //! as by `sha256sum` (`<hash>  <file>`, or `<hash> *<file>` for binary
+    //! mode) or in the BSD style (`SHA256 (<file>) = <hash>`).
+    checksums.lines().find_map(|line| {
+        let line: &str = line.trim();
+        if let Some((name, hash)) = line
+            .split_once(" (")
+            .and_then(|(_, rest)| rest.split_once(") = "))
+        {
+            return (name == file_name).then(|| hash.trim().to_lowercase());
+        }
+        let (hash, name) = line.split_once(char::is_whitespace)?;
+        let name: &str = name.trim_start();
+        (name.strip_prefix('*').unwrap_or(name) == file_name).then(|| hash.to_lowercase())
+    })
+}
+
+fn hash_file<D: Digest>(path: &Path) -> Result<String, String> {
+    let mut file: File =
+        File::open(path).map_err(|e| format!("Unable to read '{}'. {e}", path.display()))?;
+    let mut hasher: D = D::new();
+    let mut buffer: Vec<u8> = vec![0; 1 << 20];
+    loop {
+        let read: usize = file
+            .read(&mut buffer)
+            .map_err(|e| format!("Unable to read '{}'. {e}", path.display()))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+fn verify_checksum(path: &Path, expected: &str, url: &str) -> Result<(), String> {
+    //! Checks the file downloaded from `url` against its SHA-256 or SHA-512
+    //! checksum, told apart by their length.
+    let actual: String = match expected.len() {
+        64 => hash_file::<Sha256>(path)?,
+        128 => hash_file::<Sha512>(path)?,
+        _ => {
+            return Err(format!(
+                "'{expected}' is not a SHA-256 or SHA-512 checksum."
+            ))
+        }
+    };
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(format!(
+            "The checksum of '{url}' is {actual}, but should be {expected}. The download may be corrupt."
+        ))
+    }
+}
+
+fn fetch(url: &str) -> Result<String, String> {
+    //! Downloads a small text file, such as a checksum file.
+    let output = run_shell_command(&[
+        "curl",
+        "--fail",
+        "--silent",
+        "--show-error",
+        "--location",
+        url,
+    ])
+    .map_err(|e| format!("Unable to run 'curl'. Is it installed? {e}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Unable to download '{url}'. {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    //! Writes a key fingerprint the way `gpg` reports it: in uppercase hex,
+    //! without spaces or a `0x` prefix.
+    let fingerprint: String = fingerprint
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_uppercase();
+    fingerprint
+        .strip_prefix("0X")
+        .unwrap_or(&fingerprint)
+        .to_owned()
+}
+
+fn get_signing_keys(status: &str) -> Vec<String> {
+    //! Returns the fingerprints of the keys `gpg --status-fd` output reports
+    //! a good signature from: of each signing key, and of its primary key.
+    status
+        .lines()
+        .filter_map(|line| line.strip_prefix("[GNUPG:] VALIDSIG "))
+        .flat_map(|fields| {
+            let fields: Vec<&str> = fields.split_whitespace().collect();
+            [fields.first().copied(), fields.get(9).copied()]
+        })
+        .flatten()
+        .map(normalize_fingerprint)
+        .collect()
+}
+
+fn verify_signature(
+    checksums_path: &Path,
+    signature_url: &str,
+    signing_key: Option<&str>,
+    signature_path: &Path,
+) -> Result<(), String> {
+    //! Checks the detached signature of a checksum file with `gpg`, which
+    //! must already have the distribution's signing key. Only a signature by
+    //! `signing_key` is accepted, not one by any other key `gpg` trusts.
+    let signing_key: String = normalize_fingerprint(signing_key.ok_or(format!(
+        "'{signature_url}' has no 'signing_key' to check it with. Add the fingerprint of the distribution's signing key to the catalog entry, or pass --no-signature to rely on the checksums alone."
+    ))?);
+    download(signature_url, signature_path, false)?;
+    let output = run_shell_command(&[
+        "gpg",
+        "--status-fd",
+        "1",
+        "--verify",
+        &signature_path.display().to_string(),
+        &checksums_path.display().to_string(),
+    ])
+    .map_err(|e| format!("Unable to run 'gpg'. Is it installed? {e}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "The signature of '{signature_url}' couldn't be verified. Import the distribution's signing key with 'gpg --recv-keys {signing_key}', or pass --no-signature to rely on the checksums alone. {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    if get_signing_keys(&String::from_utf8_lossy(&output.stdout)).contains(&signing_key) {
+        Ok(())
+    } else {
+        Err(format!(
+            "'{signature_url}' isn't signed by the key '{signing_key}'. Pass --no-signature to rely on the checksums alone."
+        ))
+    }
+}
+
+fn download(url: &str, path: &Path, show_progress: bool) -> Result<(), String> {
+    //! Downloads `url` to `path`.
+    let path: String = path.display().to_string();
+    run_interactive_command(&[
+        "curl",
+        "--fail",
+        "--location",
+        if show_progress {
+            "--progress-bar"
+        } else {
+            "--silent"
+        },
+        "--output",
+        &path,
+        url,
+    ])
+    .map_err(|e| format!("Unable to download '{url}'. {e}"))
+}
+
+fn get_verified_checksum(
+    image: &CatalogImage,
+    path: &Path,
+    check_signature: bool,
+) -> Result<String, String> {
+    //! Returns the published checksum of the image to be pulled to `path`,
+    //! after checking the signature of the checksum file if it has one.
+    let checksums: String = fetch(&image.checksums_url)?;
+    if let Some(signature_url) = image.signature_url.as_ref().filter(|_| check_signature) {
+        let checksums_path: PathBuf = get_partial_path(&path.with_extension("checksums"));
+        let signature_path: PathBuf = get_partial_path(&path.with_extension("checksums.sig"));
+        let verified: Result<(), String> = fs::write(&checksums_path, &checksums)
+            .map_err(|e| format!("Unable to write '{}'. {e}", checksums_path.display()))
+            .and_then(|_| {
+                verify_signature(
+                    &checksums_path,
+                    signature_url,
+                    image.signing_key.as_deref(),
+                    &signature_path,
+                )
+            });
+        let _ = fs::remove_file(&checksums_path);
+        let _ = fs::remove_file(&signature_path);
+        verified?;
+    }
+    let file_name: &str = get_file_name(&image.url);
+    find_checksum(&checksums, file_name).ok_or(format!(
+        "'{}' has no checksum for '{file_name}'.",
+        image.checksums_url
+    ))
+}
+
+pub fn pull_image(image: &CatalogImage, path: &Path, check_signature: bool) -> Result<(), String> {
+    //! Downloads a cloud image, verifies it against the checksums published
+    //! with it, and converts it to a qcow2 image at `path`.
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory).map_err(|e| {
+            format!(
+                "Unable to create images directory '{}'. {e}",
+                directory.display()
+            )
+        })?;
+    }
+    let checksum: String = get_verified_checksum(image, path, check_signature)?;
+    let download_path: PathBuf = get_partial_path(&path.with_extension("download"));
+    let downloaded: Result<(), String> = download(&image.url, &download_path, true)
+        .and_then(|_| verify_checksum(&download_path, &checksum, &image.url))
+        .and_then(|_| convert_image(&download_path, path, ImageFormat::Qcow2));
+    let _ = fs::remove_file(&download_path);
+    downloaded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_checksum, get_file_name, get_signing_keys, normalize_fingerprint};
+
+    #[test]
+    fn test_find_checksum() {
+        let gnu: &str = "\
+0f1e  ubuntu-24.04-server-cloudimg-amd64.tar.gz
+ABCD *ubuntu-24.04-server-cloudimg-amd64.img
+";
+        assert_eq!(
+            find_checksum(gnu, "ubuntu-24.04-server-cloudimg-amd64.img"),
+            Some("abcd".to_owned())
+        );
+        let bsd: &str = "\
+# AlmaLinux-9-GenericCloud-latest.x86_64.qcow2: 512 bytes
+SHA256 (AlmaLinux-9-GenericCloud-latest.x86_64.qcow2) = 12ef
+";
+        assert_eq!(
+            find_checksum(bsd, "AlmaLinux-9-GenericCloud-latest.x86_64.qcow2"),
+            Some("12ef".to_owned())
+        );
+        assert_eq!(
+            find_checksum(gnu, "debian-12-genericcloud-amd64.qcow2"),
+            None
+        );
+        assert_eq!(
+            get_file_name("https://cloud.debian.org/images/cloud/bookworm/latest/debian-12-genericcloud-amd64.qcow2?mirror=1"),
+            "debian-12-genericcloud-amd64.qcow2"
+        );
+    }
+
+    #[test]
+    fn test_get_signing_keys() {
+        let status: &str = "\
+[GNUPG:] NEWSIG
+[GNUPG:] GOODSIG 1A5D6C4C7DB87C81 UEC Image Automatic Signing Key <cdimage@ubuntu.com>
+[GNUPG:] VALIDSIG 4A3CE3CD565D7EB5C810E2B97FF3F408476CF100 2024-09-11 1726053036 0 4 0 1 10 00 D2EB44626FDDC30B513D5BB71A5D6C4C7DB87C81
+[GNUPG:] TRUST_UNDEFINED 0 pgp
+";
+        assert_eq!(
+            get_signing_keys(status),
+            vec![
+                "4A3CE3CD565D7EB5C810E2B97FF3F408476CF100".to_owned(),
+                "D2EB44626FDDC30B513D5BB71A5D6C4C7DB87C81".to_owned(),
+            ]
+        );
+        assert!(get_signing_keys("[GNUPG:] BADSIG 1A5D6C4C7DB87C81 x\n").is_empty());
+        assert_eq!(
+            normalize_fingerprint("0xd2eb 4462 6fdd c30b 513d  5bb7 1a5d 6c4c 7db8 7c81"),
+            "D2EB44626FDDC30B513D5BB71A5D6C4C7DB87C81"
+        );
+    }
+}
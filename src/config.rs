@@ -1,21 +1,110 @@
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
 use std::fs::{self, metadata};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use crate::{utils::find_open_port, IMAGES_DIRECTORY};
+use crate::display::DisplayType;
+use crate::hotplug::DEFAULT_MEMORY_SLOTS;
+use crate::interpolate;
+use crate::{utils::find_open_port, xdg, DEFAULT_SHUTDOWN_TIMEOUT};
+
+/// The directory next to the config file whose config files are merged into
+/// it, after those it includes.
+const DROP_IN_DIRECTORY: &str = "conf.d";
+/// The directory next to the config file holding a file for each VM, named
+/// after its image, merged into the config after the drop-in directory.
+const VMS_DIRECTORY: &str = "vms.d";
+/// Extensions of the files taken from a drop-in directory.
+const CONFIG_EXTENSIONS: [&str; 3] = ["yml", "yaml", "toml"];
+/// Settings which included config files add to, rather than replace.
+const MERGED_LISTS: [&str; 4] = ["vms", "global_qemu_options", "networks", "webhooks"];
+/// Settings whose entries included config files add to, rather than replace
+/// the whole of.
+const MERGED_MAPPINGS: [&str; 4] = ["profiles", "variables", "groups", "image_catalog"];
+/// Settings whose values aren't interpolated with the config's variables
+/// when it's loaded. VMs, and the global options given to them, are
+/// interpolated with their own variables instead.
+const NOT_INTERPOLATED: [&str; 5] = [
+    "vms",
+    "global_qemu_options",
+    "profiles",
+    "variables",
+    "include",
+];
 
 #[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
 /// Used for storing a deserialized configuration.
 /// # Attributes:
 /// * base_images_directory - An `Option<String>` representing an image storage
-///     directory. If `None`, uses `~/.vm-manager/disk-images` instead.
+///   directory, searched before `image_directories`. It's the setting of
+///   configs from before several directories could be searched.
+/// * image_directories - A `Vec<String>` of the directories images are
+///   searched for in, in order. If neither it nor `base_images_directory` is
+///   set, `xdg::get_default_images_directory` is used instead.
 /// * global_qemu_options - A `Vec<QemuRunOption>` representing all qemu
-///     options put in the `global_qemu_options` section.
+///   options put in the `global_qemu_options` section.
 /// * vms - A `Vec<VMConfig>` which holds the configuration options for
-///     individual VMs.
+///   individual VMs.
+/// * shutdown_timeout - An `Option<u64>` representing how many seconds to
+///   wait for a guest to power down when stopping it before killing it. If
+///   `None`, uses `DEFAULT_SHUTDOWN_TIMEOUT` instead.
+/// * ssh - An `Option<SshCredentials>` used to log in to every VM which
+///   doesn't specify its own.
+/// * backups - An `Option<BackupPolicy>` deciding which backups
+///   `prune-backups` keeps, for every VM which doesn't specify its own.
+/// * networks - A `Vec<PrivateNetwork>` of the private networks VMs may
+///   join to talk to each other directly.
+/// * logs - An `Option<LogRotation>` deciding when the logs of VMs are
+///   rotated and pruned.
+/// * webhooks - A `Vec<Webhook>` of the URLs VM lifecycle events are posted
+///   to.
+/// * include - A `Vec<String>` of glob patterns matching further config
+///   files merged into this one, relative to the config file's directory.
+/// * variables - A `BTreeMap<String, String>` of the variables config values
+///   can refer to as `${NAME}`, besides the built-in ones.
+/// * groups - A `BTreeMap<String, Vec<GroupMember>>` of the VMs in each
+///   group, which `up` and `down` start and stop together.
+/// * ssh_config_file - An `Option<String>` naming a file kept up to date with
+///   an SSH client config entry for each running VM.
+/// * hostnames - An `Option<HostnamesConfig>` deciding how the names of
+///   running VMs are published.
+/// * image_catalog - A `BTreeMap<String, CatalogImage>` of cloud images
+///   `image pull` can download, besides the built-in ones.
 pub struct Config {
     base_images_directory: Option<String>,
+    #[serde(default)]
+    image_directories: Vec<String>,
     global_qemu_options: Vec<QemuRunOption>,
     vms: Vec<VMConfig>,
+    #[serde(default)]
+    shutdown_timeout: Option<u64>,
+    #[serde(default)]
+    ssh: Option<SshCredentials>,
+    #[serde(default)]
+    backups: Option<BackupPolicy>,
+    #[serde(default)]
+    networks: Vec<PrivateNetwork>,
+    #[serde(default)]
+    logs: Option<LogRotation>,
+    #[serde(default)]
+    webhooks: Vec<Webhook>,
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default, deserialize_with = "interpolate::deserialize_variables")]
+    variables: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    groups: BTreeMap<String, Vec<GroupMember>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ssh_config_file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hostnames: Option<HostnamesConfig>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    image_catalog: BTreeMap<String, CatalogImage>,
 }
 
 impl Config {
@@ -35,11 +124,66 @@ impl Config {
         let config_file_contents: String =
             fs::read_to_string(shellexpand::tilde(filename).to_string())?;
 
-        // deserialize file contents to structured data
-        let mut config: Self = match serde_yaml::from_str::<Self>(&config_file_contents) {
-            Ok(config) => config,
-            Err(e) => panic!("Unable to deserialize config file '{filename}'. {e}"),
-        };
+        // deserialize file contents to a document, to merge included files into
+        let mut document: serde_yaml::Value = ConfigFormat::from_path(filename)
+            .parse(&config_file_contents)
+            .map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Unable to deserialize config file '{filename}'. {e}"),
+                )
+            })?;
+        for path in get_included_files(filename, &document)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?
+        {
+            merge_included_file(&mut document, &path)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        }
+
+        Self::from_document(document)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    fn from_document(mut document: serde_yaml::Value) -> Result<Self, String> {
+        //! Builds the config from a config document with its included files
+        //! merged in, applying profiles, variables and global options.
+        apply_profiles(&mut document)?;
+
+        // interpolate the settings outside of VMs, which can't use the
+        // variables of a VM.
+        let mut variables: BTreeMap<String, String> = interpolate::get_builtin_variables();
+        if let Some(user_variables) = document.get("variables") {
+            variables.extend(
+                interpolate::deserialize_variables(user_variables.clone())
+                    .map_err(|e| format!("Unable to deserialize variables. {e}"))?,
+            );
+        }
+        if let serde_yaml::Value::Mapping(mapping) = &mut document {
+            for (key, value) in mapping.iter_mut() {
+                if !NOT_INTERPOLATED.contains(&key.as_str().unwrap_or_default()) {
+                    interpolate::interpolate_value(value, &variables)?;
+                }
+            }
+        }
+
+        // deserialize the merged document to structured data
+        let mut config: Self = serde_yaml::from_value(document)
+            .map_err(|e| format!("Unable to deserialize config. {e}"))?;
+
+        // refuse groups which can't be started in any order.
+        for group in config.groups.keys() {
+            config.get_group(group)?;
+        }
+
+        // VMs are told apart by their names.
+        for (index, vm) in config.vms.iter().enumerate() {
+            if config.vms[..index]
+                .iter()
+                .any(|other| other.name() == vm.name())
+            {
+                return Err(format!("More than one VM is called '{}'.", vm.name()));
+            }
+        }
 
         // apply all global configs to each VM
         for vm in &mut config.vms {
@@ -50,6 +194,28 @@ impl Config {
                 }
             }
 
+            // interpolate the VM's settings, including the global options,
+            // with its own variables.
+            let mut vm_variables: BTreeMap<String, String> = interpolate::get_builtin_variables();
+            vm_variables.insert(
+                interpolate::VM_NAME_VARIABLE.to_owned(),
+                vm.name().to_owned(),
+            );
+            vm_variables.extend(config.variables.clone());
+            vm_variables.extend(vm.variables.clone());
+            let mut value: serde_yaml::Value = serde_yaml::to_value(&*vm)
+                .map_err(|e| format!("Unable to serialize VM '{}'. {e}", vm.image_name))?;
+            interpolate::interpolate_value(&mut value, &vm_variables)
+                .map_err(|e| format!("Unable to interpolate VM '{}'. {e}", vm.image_name))?;
+            *vm = serde_yaml::from_value(value)
+                .map_err(|e| format!("Unable to deserialize VM '{}'. {e}", vm.image_name))?;
+            if vm.image_path().is_some_and(|path| !path.is_absolute()) {
+                return Err(format!(
+                    "The image_path of VM '{}' must be an absolute path.",
+                    vm.image_name
+                ));
+            }
+
             // check if `-nic` is present anywhere in options. If not, add it,
             // but only if there is at least one port mapping to add to it.
             if !vm.option_nic_present() && !vm.port_mappings.is_empty() {
@@ -78,61 +244,1215 @@ impl Config {
             }
         }
 
-        // if no base images directory was passed, use the program default.
-        if config.base_images_directory.is_none() {
-            config.base_images_directory = Some(IMAGES_DIRECTORY.to_owned());
+        // if no images directory was passed, use the program default.
+        if config.base_images_directory.is_none() && config.image_directories.is_empty() {
+            config.base_images_directory = Some(xdg::get_default_images_directory());
         }
 
         Ok(config)
     }
 
-    pub fn get_images_directory(&self) -> String {
-        //! Returns the specified images directory if
-        //! `self.base_images_directory` is not `None`. If it IS `None`, then
-        //! the directory `~/.vm-manager/disk-images` is used instead.
-        if let Some(directory) = &self.base_images_directory {
-            directory.to_owned()
-        } else {
-            IMAGES_DIRECTORY.to_owned()
+    pub fn get_image_directories(&self) -> Vec<String> {
+        //! Returns the directories images are searched for in, in order:
+        //! `self.base_images_directory`, if it's not `None`, followed by
+        //! `self.image_directories`. If there are none, then the default
+        //! images directory, `~/.vm-manager/disk-images` or
+        //! `$XDG_DATA_HOME/vm-manager/disk-images`, is used instead.
+        let mut directories: Vec<String> = self
+            .base_images_directory
+            .iter()
+            .chain(&self.image_directories)
+            .cloned()
+            .collect();
+        if directories.is_empty() {
+            directories.push(xdg::get_default_images_directory());
         }
+        directories
+    }
+
+    pub fn get_images_directory(&self) -> String {
+        //! Returns the primary images directory, the first one searched,
+        //! which new images and backups are written to.
+        self.get_image_directories().swap_remove(0)
     }
 
     pub fn get_backup_images_directory(&self) -> String {
-        //! Returns the specified backup images directory if
-        //! `self.base_images_directory` is not `None`. This is located at
-        //! `format!("{}/backup", self.get_images_directory())`. If it IS
-        //! `None`, then the directory `~/.vm-manager/disk-images/backups` is
-        //! used instead.
+        //! Returns the backup images directory, located at
+        //! `format!("{}/backups", self.get_images_directory())`, inside the
+        //! primary images directory.
         format!("{}/backups", self.get_images_directory())
     }
 
-    pub fn get_vm_config_with_image_name(&self, image_name: &str) -> Option<&VMConfig> {
-        //! Searches through the list of VMs in `self.vms`, and returns either
-        //! Some(vm) if the VM's image name contains the specified
-        //! `image_name`, and `None` otherwise.
+    pub fn get_shutdown_timeout(&self) -> Duration {
+        //! Returns how long to wait for a guest to power down before killing
+        //! it.
+        Duration::from_secs(self.shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT))
+    }
+
+    pub fn get_vm_config_with_name(&self, name: &str) -> Option<&VMConfig> {
+        //! Returns the VM called exactly `name`, if there is one. Callers
+        //! resolve partial names given on the command line first.
+        self.vms.iter().find(|vm| vm.name() == name)
+    }
+
+    pub fn get_vm_configs(&self) -> &[VMConfig] {
+        &self.vms
+    }
+
+    pub fn get_tags(&self, name: &str) -> Vec<String> {
+        //! Returns the tags of the VM called `name`, which has none if it
+        //! isn't configured.
         self.vms
             .iter()
-            .find(|vm| vm.image_name().contains(image_name))
+            .find(|vm| vm.name() == name)
+            .map_or(vec![], |vm| vm.tags.clone())
+    }
+
+    pub fn matches_filters(&self, name: &str, filters: &[VmFilter]) -> bool {
+        //! Returns `true` if the VM called `name` matches every one of
+        //! `filters`.
+        let tags: Vec<String> = self.get_tags(name);
+        filters.iter().all(|filter| filter.matches(&tags))
+    }
+
+    pub fn get_effective_vm_config(&self, vm: &VMConfig) -> VMConfig {
+        //! Returns the config a VM is run with. The global options and port
+        //! mappings are already merged into its options when the config is
+        //! loaded; this also fills in the `ssh` and `backups` settings it
+        //! inherits from the global sections, and the defaults of settings it
+        //! leaves unset.
+        let mut effective: VMConfig = vm.clone();
+        effective.ssh = Some(self.get_ssh_credentials(vm.name()));
+        effective.backups = Some(self.get_backup_policy(vm.name()));
+        effective.restart_policy = Some(vm.restart_policy());
+        effective.balloon = Some(vm.balloon());
+        effective.guest_agent = Some(vm.guest_agent());
+        if vm.max_memory().is_some() {
+            effective.memory_slots = Some(vm.memory_slots());
+        }
+        effective
+    }
+
+    pub fn get_ssh_credentials(&self, name: &str) -> SshCredentials {
+        //! Returns the SSH credentials to use for the VM with the given name.
+        //! Fields set in the VM's own `ssh` section take precedence over the
+        //! global `ssh` section.
+        let global: SshCredentials = self.ssh.clone().unwrap_or_default();
+        match self
+            .get_vm_config_with_name(name)
+            .and_then(|vm| vm.ssh.clone())
+        {
+            Some(vm) => SshCredentials {
+                user: vm.user.or(global.user),
+                identity_file: vm.identity_file.or(global.identity_file),
+            },
+            None => global,
+        }
+    }
+
+    pub fn get_private_network(&self, name: &str) -> Option<&PrivateNetwork> {
+        //! Returns the private network with the given name, if the config
+        //! defines one.
+        self.networks.iter().find(|network| network.name == name)
+    }
+
+    pub fn get_log_rotation(&self) -> LogRotation {
+        //! Returns how the logs of VMs are rotated and pruned.
+        self.logs.clone().unwrap_or_default()
+    }
+
+    pub fn get_webhooks(&self) -> &[Webhook] {
+        //! Returns the webhooks VM lifecycle events are posted to.
+        &self.webhooks
+    }
+
+    pub fn get_ssh_config_file(&self) -> Option<&str> {
+        //! Returns the file to keep up to date with an SSH client config
+        //! entry for each running VM, if any.
+        self.ssh_config_file.as_deref()
+    }
+
+    pub fn get_hostnames(&self) -> Option<&HostnamesConfig> {
+        //! Returns how the names of running VMs are published, if at all.
+        self.hostnames.as_ref()
+    }
+
+    pub fn get_image_catalog(&self) -> &BTreeMap<String, CatalogImage> {
+        //! Returns the cloud images `image pull` can download besides the
+        //! built-in ones.
+        &self.image_catalog
+    }
+
+    pub fn get_group(&self, name: &str) -> Result<Vec<&GroupMember>, String> {
+        //! Returns the VMs in the group called `name`, in the order they're
+        //! started: the order they're listed in, except that each VM comes
+        //! after the VMs it depends on.
+        let members: &Vec<GroupMember> = self.groups.get(name).ok_or_else(|| {
+            if self.groups.is_empty() {
+                format!(
+                    "No group is called '{name}'. Define groups in the config's 'groups' section."
+                )
+            } else {
+                format!(
+                    "No group is called '{name}'. The groups are: {}.",
+                    self.groups
+                        .keys()
+                        .cloned()
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                )
+            }
+        })?;
+        for (index, member) in members.iter().enumerate() {
+            if members[..index]
+                .iter()
+                .any(|other| other.name() == member.name())
+            {
+                return Err(format!(
+                    "Group '{name}' has VM '{}' more than once.",
+                    member.name()
+                ));
+            }
+            if let Some(dependency) = member
+                .depends_on()
+                .iter()
+                .find(|dependency| !members.iter().any(|other| other.name() == *dependency))
+            {
+                return Err(format!(
+                    "VM '{}' of group '{name}' depends on '{dependency}', which isn't in the group.",
+                    member.name()
+                ));
+            }
+        }
+        let mut ordered: Vec<&GroupMember> = Vec::with_capacity(members.len());
+        while ordered.len() < members.len() {
+            let next: &GroupMember = members
+                .iter()
+                .find(|member| {
+                    !ordered.contains(member)
+                        && member.depends_on().iter().all(|dependency| {
+                            ordered.iter().any(|other| other.name() == dependency)
+                        })
+                })
+                .ok_or(format!(
+                    "The VMs of group '{name}' depend on each other in a cycle."
+                ))?;
+            ordered.push(next);
+        }
+        Ok(ordered)
+    }
+
+    pub fn get_backup_policy(&self, name: &str) -> BackupPolicy {
+        //! Returns the retention policy for backups of the given VM's image.
+        //! Fields set in the VM's own `backups` section take precedence over
+        //! the global `backups` section.
+        let global: BackupPolicy = self.backups.clone().unwrap_or_default();
+        match self
+            .get_vm_config_with_name(name)
+            .and_then(|vm| vm.backups.clone())
+        {
+            Some(vm) => BackupPolicy {
+                keep_last: vm.keep_last.or(global.keep_last),
+                keep_days: vm.keep_days.or(global.keep_days),
+            },
+            None => global,
+        }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+/// Formats a config file can be written in, told apart by its extension.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+}
+
+impl ConfigFormat {
+    pub fn from_path(filename: &str) -> Self {
+        //! Returns the format of a config file: TOML for a `.toml` file, and
+        //! YAML otherwise.
+        match Path::new(filename).extension() {
+            Some(extension) if extension == "toml" => Self::Toml,
+            _ => Self::Yaml,
+        }
+    }
+
+    pub fn parse<T: DeserializeOwned>(&self, contents: &str) -> Result<T, String> {
+        match self {
+            Self::Yaml => serde_yaml::from_str(contents).map_err(|e| e.to_string()),
+            Self::Toml => toml::from_str(contents).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn serialize<T: Serialize>(&self, value: &T) -> Result<String, String> {
+        //! Serializes a config. As TOML has no null, settings left empty in
+        //! YAML can't be written to TOML, and are left out.
+        match self {
+            Self::Yaml => serde_yaml::to_string(value).map_err(|e| e.to_string()),
+            Self::Toml => {
+                let mut document: serde_yaml::Value =
+                    serde_yaml::to_value(value).map_err(|e| e.to_string())?;
+                remove_nulls(&mut document);
+                toml::to_string(&document).map_err(|e| e.to_string())
+            }
+        }
+    }
+}
+
+fn remove_nulls(value: &mut serde_yaml::Value) {
+    match value {
+        serde_yaml::Value::Mapping(mapping) => {
+            mapping.retain(|_, value| !value.is_null());
+            mapping.values_mut().for_each(remove_nulls);
+        }
+        serde_yaml::Value::Sequence(sequence) => {
+            sequence.retain(|value| !value.is_null());
+            sequence.iter_mut().for_each(remove_nulls);
+        }
+        _ => {}
+    }
+}
+
+pub fn load_config_document(filename: &str) -> Result<(String, serde_yaml::Value), String> {
+    //! Reads the config file, and parses it as a generic document, so that it
+    //! can be edited or converted without merging in global options the way
+    //! `load_from_file` does. TOML is parsed into the same document as YAML.
+    let path: String = shellexpand::tilde(filename).to_string();
+    let contents: String = fs::read_to_string(&path)
+        .map_err(|e| format!("Unable to read config file '{filename}'. {e}"))?;
+    let document: serde_yaml::Value = ConfigFormat::from_path(filename)
+        .parse(&contents)
+        .map_err(|e| format!("Unable to parse config file '{filename}'. {e}"))?;
+    Ok((contents, document))
+}
+
+fn get_config_directory(filename: &str) -> PathBuf {
+    Path::new(shellexpand::tilde(filename).as_ref())
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default()
+}
+
+fn is_vm_file(filename: &str) -> bool {
+    //! Returns whether a config file is one in a `vms.d` directory, which
+    //! holds the entry of a single VM rather than a config.
+    get_config_directory(filename)
+        .file_name()
+        .is_some_and(|name| name == VMS_DIRECTORY)
+}
+
+fn get_configured_vms(filename: &str, document: &serde_yaml::Value) -> Vec<serde_yaml::Value> {
+    //! Returns the VM entries in a config file. The entry in a VM's own file
+    //! uses the image it's named after, unless it names one itself.
+    if !is_vm_file(filename) {
+        return document["vms"].as_sequence().cloned().unwrap_or_default();
+    }
+    let mut vm: serde_yaml::Value = document.clone();
+    if let (serde_yaml::Value::Mapping(mapping), Some(image_name)) =
+        (&mut vm, Path::new(filename).file_stem())
+    {
+        if !mapping.contains_key("image_name") {
+            mapping.insert(
+                "image_name".into(),
+                image_name.to_string_lossy().as_ref().into(),
+            );
+        }
+    }
+    vec![vm]
+}
+
+fn get_drop_in_files(directory: &Path) -> Vec<PathBuf> {
+    //! Returns the config files in a drop-in directory, sorted by name.
+    let mut paths: Vec<PathBuf> = fs::read_dir(directory)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| {
+                    path.is_file()
+                        && path.extension().is_some_and(|extension| {
+                            CONFIG_EXTENSIONS.iter().any(|e| *e == extension)
+                        })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    paths.sort();
+    paths
+}
+
+pub fn get_included_files(
+    filename: &str,
+    document: &serde_yaml::Value,
+) -> Result<Vec<PathBuf>, String> {
+    //! Returns the files merged into the config file, in the order they're
+    //! merged: those matching its `include` patterns, each pattern's matches
+    //! sorted, followed by those in the `conf.d` directory next to it, and
+    //! then the VM files in the `vms.d` directory next to it.
+    let directory: PathBuf = get_config_directory(filename);
+    let mut paths: Vec<PathBuf> = vec![];
+    for pattern in document["include"].as_sequence().into_iter().flatten() {
+        let pattern: &str = pattern.as_str().ok_or(format!(
+            "'include' in config file '{filename}' must be a list of patterns."
+        ))?;
+        let pattern: String = directory
+            .join(shellexpand::tilde(pattern).as_ref())
+            .to_string_lossy()
+            .into_owned();
+        let mut matches: Vec<PathBuf> = glob::glob(&pattern)
+            .map_err(|e| format!("Invalid include pattern '{pattern}'. {e}"))?
+            .filter_map(Result::ok)
+            .filter(|path| path.is_file())
+            .collect();
+        matches.sort();
+        paths.extend(matches);
+    }
+    paths.extend(get_drop_in_files(&directory.join(DROP_IN_DIRECTORY)));
+    paths.extend(get_drop_in_files(&directory.join(VMS_DIRECTORY)));
+
+    let main_file: Option<PathBuf> = fs::canonicalize(shellexpand::tilde(filename).as_ref()).ok();
+    let mut included: Vec<PathBuf> = vec![];
+    for path in paths {
+        let canonical: Option<PathBuf> = fs::canonicalize(&path).ok();
+        if canonical != main_file
+            && !included
+                .iter()
+                .any(|other| fs::canonicalize(other).ok() == canonical)
+        {
+            included.push(path);
+        }
+    }
+    Ok(included)
+}
+
+fn merge_included_file(document: &mut serde_yaml::Value, path: &Path) -> Result<(), String> {
+    //! Merges an included config file into the config document. The lists
+    //! in `MERGED_LISTS` are appended to and the mappings in
+    //! `MERGED_MAPPINGS` are added to, while any other setting replaces the
+    //! one merged before it.
+    let filename: String = path.to_string_lossy().into_owned();
+    let contents: String = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read included config file '{filename}'. {e}"))?;
+    let included: serde_yaml::Value = ConfigFormat::from_path(&filename)
+        .parse(&contents)
+        .map_err(|e| format!("Unable to parse included config file '{filename}'. {e}"))?;
+    let included: serde_yaml::Value = if is_vm_file(&filename) {
+        let mut vms: serde_yaml::Mapping = serde_yaml::Mapping::new();
+        vms.insert(
+            "vms".into(),
+            serde_yaml::Value::Sequence(get_configured_vms(&filename, &included)),
+        );
+        serde_yaml::Value::Mapping(vms)
+    } else {
+        included
+    };
+    let included: serde_yaml::Mapping = match included {
+        serde_yaml::Value::Mapping(included) => included,
+        serde_yaml::Value::Null => return Ok(()),
+        _ => {
+            return Err(format!(
+                "Included config file '{filename}' is not a mapping."
+            ))
+        }
+    };
+    let mapping: &mut serde_yaml::Mapping = document
+        .as_mapping_mut()
+        .ok_or("The config file is not a mapping.".to_owned())?;
+    for (key, value) in included {
+        let name: String = key.as_str().unwrap_or_default().to_owned();
+        if name == "include" {
+            return Err(format!(
+                "Included config file '{filename}' can't include other files."
+            ));
+        }
+        if MERGED_MAPPINGS.contains(&name.as_str()) {
+            let serde_yaml::Value::Mapping(values) = value else {
+                return Err(format!(
+                    "'{name}' in included config file '{filename}' is not a mapping."
+                ));
+            };
+            match mapping.get_mut(&key) {
+                Some(serde_yaml::Value::Mapping(entries)) => entries.extend(values),
+                _ => {
+                    mapping.insert(key, serde_yaml::Value::Mapping(values));
+                }
+            }
+            continue;
+        }
+        if !MERGED_LISTS.contains(&name.as_str()) {
+            mapping.insert(key, value);
+            continue;
+        }
+        let serde_yaml::Value::Sequence(values) = value else {
+            return Err(format!(
+                "'{name}' in included config file '{filename}' is not a list."
+            ));
+        };
+        let list: &mut serde_yaml::Value = mapping
+            .entry(key)
+            .or_insert(serde_yaml::Value::Sequence(vec![]));
+        if list.is_null() {
+            *list = serde_yaml::Value::Sequence(vec![]);
+        }
+        let list: &mut Vec<serde_yaml::Value> = list
+            .as_sequence_mut()
+            .ok_or(format!("'{name}' in the config file is not a list."))?;
+        for value in values {
+            if let Some(vm_name) = get_vm_name(&value).filter(|_| name == "vms") {
+                if list.iter().any(|vm| get_vm_name(vm) == Some(vm_name)) {
+                    return Err(format!(
+                        "Included config file '{filename}' has a VM called '{vm_name}', which is already configured."
+                    ));
+                }
+            }
+            list.push(value);
+        }
+    }
+    Ok(())
+}
+
+fn merge_settings(base: &mut serde_yaml::Value, overrides: serde_yaml::Value) {
+    //! Merges VM settings into those they override: lists are appended to,
+    //! mappings are merged entry by entry, and anything else is replaced.
+    //! Settings left empty override nothing.
+    match (base, overrides) {
+        (_, serde_yaml::Value::Null) => {}
+        (serde_yaml::Value::Mapping(base), serde_yaml::Value::Mapping(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(base_value) => merge_settings(base_value, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (serde_yaml::Value::Sequence(base), serde_yaml::Value::Sequence(overrides)) => {
+            base.extend(overrides);
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+fn resolve_profile(
+    profiles: &serde_yaml::Mapping,
+    name: &str,
+    chain: &mut Vec<String>,
+) -> Result<serde_yaml::Value, String> {
+    //! Returns the settings of a profile, merged over those of the profile
+    //! it inherits from, if any. `chain` holds the profiles inheriting from
+    //! it, to catch a profile inheriting from itself.
+    if chain.iter().any(|profile| profile == name) {
+        chain.push(name.to_owned());
+        return Err(format!(
+            "Profile '{name}' inherits from itself: {}.",
+            chain.join(" -> ")
+        ));
+    }
+    let mut profile: serde_yaml::Value = profiles
+        .get(name)
+        .cloned()
+        .ok_or(format!("There is no profile named '{name}'."))?;
+    let profile_mapping: &mut serde_yaml::Mapping = profile
+        .as_mapping_mut()
+        .ok_or(format!("Profile '{name}' is not a mapping."))?;
+    let Some(parent) = profile_mapping.remove("profile") else {
+        return Ok(profile);
+    };
+    let parent: &str = parent.as_str().ok_or(format!(
+        "'profile' in profile '{name}' must be the name of a profile."
+    ))?;
+    chain.push(name.to_owned());
+    let mut settings: serde_yaml::Value = resolve_profile(profiles, parent, chain)?;
+    chain.pop();
+    merge_settings(&mut settings, profile);
+    Ok(settings)
+}
+
+fn apply_profiles(document: &mut serde_yaml::Value) -> Result<(), String> {
+    //! Merges each VM's settings over those of the profile it names, so that
+    //! it inherits every setting it doesn't set itself.
+    let profiles: serde_yaml::Mapping = match document.get("profiles") {
+        Some(serde_yaml::Value::Mapping(profiles)) => profiles.clone(),
+        None | Some(serde_yaml::Value::Null) => serde_yaml::Mapping::new(),
+        Some(_) => return Err("'profiles' in the config must be a mapping.".to_owned()),
+    };
+    for vm in document["vms"].as_sequence_mut().into_iter().flatten() {
+        let Some(profile) = vm.get("profile") else {
+            continue;
+        };
+        let image_name: String = vm["image_name"].as_str().unwrap_or_default().to_owned();
+        let profile: &str = profile.as_str().ok_or(format!(
+            "'profile' of VM '{image_name}' must be the name of a profile."
+        ))?;
+        let mut settings: serde_yaml::Value = resolve_profile(&profiles, profile, &mut vec![])
+            .map_err(|e| format!("Unable to apply the profile of VM '{image_name}'. {e}"))?;
+        merge_settings(&mut settings, vm.clone());
+        *vm = settings;
+    }
+    Ok(())
+}
+
+fn get_vm_name(vm: &serde_yaml::Value) -> Option<&str> {
+    //! Returns the name of a VM in a config document, which is its `name`,
+    //! or else its `image_name`.
+    vm.get("name")
+        .or_else(|| vm.get("image_name"))
+        .and_then(serde_yaml::Value::as_str)
+}
+
+pub fn find_config_file_with_vm(filename: &str, name: &str) -> String {
+    //! Returns the config file configuring the VM called `name`, which is
+    //! the given config file unless the VM is configured in a file included
+    //! by it.
+    let has_vm = |filename: &str| {
+        load_config_document(filename).is_ok_and(|(_, document)| {
+            get_configured_vms(filename, &document)
+                .iter()
+                .any(|vm| get_vm_name(vm) == Some(name))
+        })
+    };
+    if has_vm(filename) {
+        return filename.to_owned();
+    }
+    load_config_document(filename)
+        .and_then(|(_, document)| get_included_files(filename, &document))
+        .unwrap_or_default()
+        .into_iter()
+        .map(|path| path.to_string_lossy().into_owned())
+        .find(|path| has_vm(path))
+        .unwrap_or(filename.to_owned())
+}
+
+/// Where the entries of the `vms` list are in the lines of a config file.
+struct VmEntriesLayout {
+    /// How far the `-` starting each entry is indented.
+    indent: usize,
+    /// The lines of each entry, without the comments and blank lines after
+    /// it.
+    entries: Vec<Range<usize>>,
+    /// The line after the last entry, where new entries are inserted.
+    end: usize,
+}
+
+fn find_vm_entries(lines: &[&str]) -> Option<VmEntriesLayout> {
+    //! Finds the entries of the `vms` list in the lines of a config file.
+    //! Returns `None` if there is no `vms` list written in block style.
+    let is_ignored = |line: &str| line.trim().is_empty() || line.trim_start().starts_with('#');
+    let start: usize = lines
+        .iter()
+        .position(|line| line.strip_prefix("vms:").is_some_and(&is_ignored))?;
+    let mut indent: Option<usize> = None;
+    let mut entries: Vec<Range<usize>> = vec![];
+    let mut last_line: usize = start;
+    for (i, line) in lines.iter().enumerate().skip(start + 1) {
+        if is_ignored(line) {
+            continue;
+        }
+        let line_indent: usize = line.len() - line.trim_start().len();
+        let is_entry: bool = line.trim() == "-" || line.trim_start().starts_with("- ");
+        let entry_indent: usize = *indent.get_or_insert(line_indent);
+        if line_indent < entry_indent || (line_indent == entry_indent && !is_entry) {
+            break;
+        }
+        if line_indent == entry_indent {
+            if let Some(entry) = entries.last_mut() {
+                entry.end = last_line + 1;
+            }
+            entries.push(i..i + 1);
+        }
+        last_line = i;
+    }
+    if let Some(entry) = entries.last_mut() {
+        entry.end = last_line + 1;
+    }
+    Some(VmEntriesLayout {
+        indent: if entries.is_empty() {
+            2
+        } else {
+            indent.unwrap_or(2)
+        },
+        entries,
+        end: last_line + 1,
+    })
+}
+
+fn update_vm_entries(
+    contents: &str,
+    vms: &[serde_yaml::Value],
+    new_vms: &[serde_yaml::Value],
+) -> Option<String> {
+    //! Rewrites the entries of the `vms` list which changed from `vms` to
+    //! `new_vms` in the config file's contents, and appends the added ones,
+    //! keeping everything else, comments included, as it is. Returns `None`
+    //! if the entries can't be told apart in the file.
+    let lines: Vec<&str> = contents.lines().collect();
+    let layout: VmEntriesLayout = find_vm_entries(&lines)?;
+    if layout.entries.len() != vms.len() || new_vms.len() < vms.len() {
+        return None;
+    }
+    let format_entry = |vm: &serde_yaml::Value| -> Option<Vec<String>> {
+        let entry: String = serde_yaml::to_string(&vec![vm]).ok()?;
+        Some(
+            entry
+                .lines()
+                .map(|line| format!("{}{line}", " ".repeat(layout.indent)))
+                .collect(),
+        )
+    };
+
+    let mut updated: Vec<String> = vec![];
+    let mut position: usize = 0;
+    for ((entry, vm), new_vm) in layout.entries.iter().zip(vms).zip(new_vms) {
+        if vm != new_vm {
+            updated.extend(
+                lines[position..entry.start]
+                    .iter()
+                    .map(|line| line.to_string()),
+            );
+            updated.extend(format_entry(new_vm)?);
+            position = entry.end;
+        }
+    }
+    updated.extend(
+        lines[position..layout.end]
+            .iter()
+            .map(|line| line.to_string()),
+    );
+    for new_vm in &new_vms[vms.len()..] {
+        updated.extend(format_entry(new_vm)?);
+    }
+    updated.extend(lines[layout.end..].iter().map(|line| line.to_string()));
+    Some(updated.join("\n") + "\n")
+}
+
+fn update_settings(
+    contents: &str,
+    document: &serde_yaml::Value,
+    new_document: &serde_yaml::Value,
+) -> String {
+    //! Rewrites the lines of the settings outside of VMs which changed from
+    //! `document` to `new_document` in the config file's contents, where
+    //! they're written on a single line, keeping everything else as it is.
+    let mut lines: Vec<String> = contents.lines().map(str::to_owned).collect();
+    let Some(settings) = new_document.as_mapping() else {
+        return contents.to_owned();
+    };
+    for (key, value) in settings {
+        let (Some(key), false) = (key.as_str(), document.get(key) == Some(value)) else {
+            continue;
+        };
+        if value.is_mapping() || value.is_sequence() {
+            continue;
+        }
+        let Ok(value) = serde_yaml::to_string(value) else {
+            continue;
+        };
+        if let Some(line) = lines.iter_mut().find(|line| {
+            line.strip_prefix(key)
+                .is_some_and(|rest| rest.starts_with(':'))
+        }) {
+            *line = format!("{key}: {}", value.trim_end());
+        }
+    }
+    lines.join("\n") + "\n"
+}
+
+pub fn get_header_comments(contents: &str) -> String {
+    //! Returns the comment lines at the top of a config file, which both
+    //! formats start with `#`.
+    contents
+        .lines()
+        .take_while(|line| line.starts_with('#'))
+        .map(|line| format!("{line}\n"))
+        .collect()
+}
+
+fn save_config_document(
+    filename: &str,
+    contents: &str,
+    document: &serde_yaml::Value,
+    new_document: &serde_yaml::Value,
+) -> Result<(), String> {
+    //! Writes an edited config document back to the config file, after
+    //! checking that its VMs are still valid, as is the whole config, with
+    //! the files it includes, unless the file is one included by another. In
+    //! a YAML file, only the VM entries and single-line settings which
+    //! changed are rewritten where possible, keeping the comments in the rest
+    //! of the file; otherwise the whole file is rewritten, keeping only the
+    //! comments at its top. Either way, the previous file is saved as
+    //! `<filename>.bak`.
+    serde_yaml::from_value::<Vec<VMConfig>>(serde_yaml::Value::Sequence(get_configured_vms(
+        filename,
+        new_document,
+    )))
+    .map_err(|e| format!("The edited config would be invalid. {e}"))?;
+    if serde_yaml::from_value::<Config>(document.clone()).is_ok() {
+        let mut merged_document: serde_yaml::Value = new_document.clone();
+        for path in get_included_files(filename, new_document)? {
+            merge_included_file(&mut merged_document, &path)?;
+        }
+        Config::from_document(merged_document)
+            .map_err(|e| format!("The edited config would be invalid. {e}"))?;
+    }
+    let format: ConfigFormat = ConfigFormat::from_path(filename);
+    let get_vms = |document: &serde_yaml::Value| -> Vec<serde_yaml::Value> {
+        document["vms"].as_sequence().cloned().unwrap_or_default()
+    };
+    let updated: Option<String> = match format {
+        ConfigFormat::Yaml => {
+            let contents: String = update_settings(contents, document, new_document);
+            if get_vms(document) == get_vms(new_document) {
+                Some(contents)
+            } else {
+                update_vm_entries(&contents, &get_vms(document), &get_vms(new_document))
+            }
+            .filter(|updated| {
+                serde_yaml::from_str::<serde_yaml::Value>(updated)
+                    .ok()
+                    .as_ref()
+                    == Some(new_document)
+            })
+        }
+        ConfigFormat::Toml => None,
+    };
+    let new_contents: String = match updated {
+        Some(updated) => updated,
+        None => {
+            let header: String = get_header_comments(contents);
+            let new_contents: String = format
+                .serialize(new_document)
+                .map_err(|e| format!("Unable to serialize config. {e}"))?;
+            if header.is_empty() {
+                new_contents
+            } else {
+                format!("{header}\n{new_contents}")
+            }
+        }
+    };
+    let path: String = shellexpand::tilde(filename).to_string();
+    fs::copy(&path, format!("{path}.bak"))
+        .map_err(|e| format!("Unable to back up config file '{filename}'. {e}"))?;
+    fs::write(&path, new_contents)
+        .map_err(|e| format!("Unable to write config file '{filename}'. {e}"))
+}
+
+/// A change to a VM's entry in the config file, made by `config add-vm` and
+/// `config set`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum VmConfigEdit {
+    /// Sets a setting, given by its name, or names joined by `.` for nested
+    /// settings (e.g. `ssh.user`), to a value.
+    Set(String, serde_yaml::Value),
+    /// Removes a setting, so that the global setting or default applies.
+    Unset(String),
+    /// Adds a port mapping.
+    AddPort(PortMapping),
+    /// Removes the port mappings of a host port.
+    RemovePort(String),
+    /// Adds a qemu option, e.g. `-smp 4`.
+    AddOption(String),
+    /// Removes the qemu options which are, or whose flag is, the given one.
+    RemoveOption(String),
+}
+
+fn get_mapping<'a>(
+    value: &'a mut serde_yaml::Value,
+    key: &str,
+) -> Result<&'a mut serde_yaml::Mapping, String> {
+    if value.is_null() {
+        *value = serde_yaml::Value::Mapping(serde_yaml::Mapping::new());
+    }
+    value
+        .as_mapping_mut()
+        .ok_or(format!("'{key}' is not a mapping, so has no settings."))
+}
+
+fn get_sequence<'a>(
+    vm: &'a mut serde_yaml::Value,
+    key: &str,
+) -> Result<&'a mut serde_yaml::Sequence, String> {
+    let value: &mut serde_yaml::Value = get_mapping(vm, "vm")?
+        .entry(key.into())
+        .or_insert(serde_yaml::Value::Null);
+    if value.is_null() {
+        *value = serde_yaml::Value::Sequence(vec![]);
+    }
+    value
+        .as_sequence_mut()
+        .ok_or(format!("'{key}' is not a list."))
+}
+
+fn apply_vm_config_edit(vm: &mut serde_yaml::Value, edit: &VmConfigEdit) -> Result<(), String> {
+    //! Applies a change to a VM's entry, as parsed from the config file.
+    match edit {
+        VmConfigEdit::Set(key, value) => {
+            let mut names: Vec<&str> = key.split('.').collect();
+            let name: &str = names.pop().unwrap_or_default();
+            let mut target: &mut serde_yaml::Value = vm;
+            for (depth, parent) in names.iter().enumerate() {
+                target = get_mapping(target, &names[..depth].join("."))?
+                    .entry((*parent).into())
+                    .or_insert(serde_yaml::Value::Null);
+            }
+            get_mapping(target, &names.join("."))?.insert(name.into(), value.clone());
+            Ok(())
+        }
+        VmConfigEdit::Unset(key) => {
+            let mut names: Vec<&str> = key.split('.').collect();
+            let name: &str = names.pop().unwrap_or_default();
+            let mut target: Option<&mut serde_yaml::Value> = Some(vm);
+            for parent in &names {
+                target = target.and_then(|target| target.get_mut(*parent));
+            }
+            target
+                .and_then(|target| target.as_mapping_mut())
+                .and_then(|mapping| mapping.remove(name))
+                .map(|_| ())
+                .ok_or(format!("The VM has no setting '{key}'."))
+        }
+        VmConfigEdit::AddPort(port_mapping) => {
+            let port_mappings: &mut serde_yaml::Sequence = get_sequence(vm, "port_mappings")?;
+            if port_mappings
+                .iter()
+                .any(|existing| existing["host_port"].as_str() == Some(&port_mapping.host_port))
+            {
+                return Err(format!(
+                    "The VM already maps host port {}.",
+                    port_mapping.host_port
+                ));
+            }
+            port_mappings.push(
+                serde_yaml::to_value(port_mapping)
+                    .map_err(|e| format!("Unable to serialize port mapping. {e}"))?,
+            );
+            Ok(())
+        }
+        VmConfigEdit::RemovePort(host_port) => {
+            let port_mappings: &mut serde_yaml::Sequence = get_sequence(vm, "port_mappings")?;
+            let count: usize = port_mappings.len();
+            port_mappings.retain(|existing| existing["host_port"].as_str() != Some(host_port));
+            if port_mappings.len() == count {
+                return Err(format!("The VM doesn't map host port {host_port}."));
+            }
+            Ok(())
+        }
+        VmConfigEdit::AddOption(option) => {
+            let mut entry: serde_yaml::Mapping = serde_yaml::Mapping::new();
+            entry.insert("option".into(), option.as_str().into());
+            get_sequence(vm, "options")?.push(serde_yaml::Value::Mapping(entry));
+            Ok(())
+        }
+        VmConfigEdit::RemoveOption(option) => {
+            let options: &mut serde_yaml::Sequence = get_sequence(vm, "options")?;
+            let count: usize = options.len();
+            options.retain(|existing| {
+                existing["option"].as_str().is_none_or(|existing| {
+                    existing != option && QemuRunOption::new(existing).flag() != option
+                })
+            });
+            if options.len() == count {
+                return Err(format!("The VM has no option '{option}'."));
+            }
+            Ok(())
+        }
+    }
+}
+
+pub fn edit_vm_config_in_file(
+    filename: &str,
+    name: &str,
+    edits: &[VmConfigEdit],
+) -> Result<(), String> {
+    //! Changes the entry of the VM called `name` in the config file.
+    let (contents, document): (String, serde_yaml::Value) = load_config_document(filename)?;
+    let mut new_document: serde_yaml::Value = document.clone();
+    let vm: &mut serde_yaml::Value = if is_vm_file(filename) {
+        &mut new_document
+    } else {
+        new_document["vms"]
+            .as_sequence_mut()
+            .and_then(|vms| vms.iter_mut().find(|vm| get_vm_name(vm) == Some(name)))
+            .ok_or(format!(
+                "Config file '{filename}' has no VM called '{name}'."
+            ))?
+    };
+    for edit in edits {
+        apply_vm_config_edit(vm, edit)?;
+    }
+    save_config_document(filename, &contents, &document, &new_document)
+}
+
+pub fn add_vm_config_to_file(
+    filename: &str,
+    image_name: &str,
+    template_image_name: Option<&str>,
+    edits: &[VmConfigEdit],
+) -> Result<(), String> {
+    //! Appends a VM entry for `image_name` to the config file. The entry is a
+    //! copy of the one for `template_image_name` if there is one, and uses
+    //! the global options otherwise, with `edits` applied to it.
+    let (contents, document): (String, serde_yaml::Value) = load_config_document(filename)?;
+    let mut new_document: serde_yaml::Value = document.clone();
+    let vms: &mut Vec<serde_yaml::Value> = match &mut new_document["vms"] {
+        serde_yaml::Value::Sequence(vms) => vms,
+        vms @ serde_yaml::Value::Null => {
+            *vms = serde_yaml::Value::Sequence(vec![]);
+            vms.as_sequence_mut().unwrap()
+        }
+        _ => return Err(format!("'vms' in config file '{filename}' is not a list.")),
+    };
+    if vms
+        .iter()
+        .any(|vm| vm["image_name"].as_str() == Some(image_name))
+    {
+        return Err(format!(
+            "Config file '{filename}' already has a VM for image '{image_name}'."
+        ));
+    }
+
+    let mut entry: serde_yaml::Value = match template_image_name.and_then(|name| {
+        vms.iter()
+            .find(|vm| vm["image_name"].as_str() == Some(name))
+    }) {
+        Some(template) => template.clone(),
+        None => serde_yaml::to_value(VMConfig::new(image_name))
+            .map_err(|e| format!("Unable to serialize VM config. {e}"))?,
+    };
+    entry["image_name"] = serde_yaml::Value::String(image_name.to_owned());
+    for edit in edits {
+        apply_vm_config_edit(&mut entry, edit)?;
+    }
+    vms.push(entry);
+
+    save_config_document(filename, &contents, &document, &new_document)
+}
+
+pub fn set_setting_in_file(
+    filename: &str,
+    key: &str,
+    value: serde_yaml::Value,
+) -> Result<(), String> {
+    //! Changes a setting outside of VMs in the config file, such as
+    //! `base_images_directory`.
+    let (contents, document): (String, serde_yaml::Value) = load_config_document(filename)?;
+    let mut new_document: serde_yaml::Value = document.clone();
+    new_document
+        .as_mapping_mut()
+        .ok_or(format!("Config file '{filename}' is not a mapping."))?
+        .insert(key.into(), value);
+    save_config_document(filename, &contents, &document, &new_document)
+}
+
+pub fn rename_vm_config_in_file(
+    filename: &str,
+    image_name: &str,
+    new_image_name: &str,
+) -> Result<bool, String> {
+    //! Changes the `image_name` of every VM in the config file using the
+    //! image `image_name` to `new_image_name`. Returns whether any VM was
+    //! changed; the file is only rewritten if so.
+    let (contents, document): (String, serde_yaml::Value) = load_config_document(filename)?;
+    let mut new_document: serde_yaml::Value = document.clone();
+    let mut changed: bool = false;
+    if is_vm_file(filename) {
+        // the VM of a `vms.d` file may use the image it's named after
+        // without naming it.
+        if get_configured_vms(filename, &document)[0]["image_name"].as_str() == Some(image_name) {
+            new_document["image_name"] = serde_yaml::Value::String(new_image_name.to_owned());
+            changed = true;
+        }
+    } else if let Some(vms) = new_document["vms"].as_sequence_mut() {
+        for vm in vms {
+            if vm["image_name"].as_str() == Some(image_name) {
+                vm["image_name"] = serde_yaml::Value::String(new_image_name.to_owned());
+                changed = true;
+            }
+        }
+    }
+    if changed {
+        save_config_document(filename, &contents, &document, &new_document)?;
+    }
+    Ok(changed)
+}
+
+pub fn rename_image_in_config_files(
+    filename: &str,
+    image_name: &str,
+    new_image_name: &str,
+) -> Result<Vec<String>, String> {
+    //! Changes the `image_name` of every VM using the image `image_name` to
+    //! `new_image_name`, in the config file and in each file it includes.
+    //! Returns the files which were changed.
+    let (_, document): (String, serde_yaml::Value) = load_config_document(filename)?;
+    let mut changed: Vec<String> = vec![];
+    for file in [filename.to_owned()].into_iter().chain(
+        get_included_files(filename, &document)?
+            .into_iter()
+            .map(|path| path.to_string_lossy().into_owned()),
+    ) {
+        if rename_vm_config_in_file(&file, image_name, new_image_name)? {
+            changed.push(file);
+        }
+    }
+    Ok(changed)
+}
+
+fn enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Default)]
 pub struct VMConfig {
     /// Name of the image to use, as shown in `$ vm-manager -l`.
     image_name: String,
+    /// Name of the VM, which it's started, stopped and listed by, so several
+    /// VMs can run the same image with different settings. If `None`, the VM
+    /// is called by its `image_name`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// Labels the VM can be listed and stopped by with `--filter tag=...`,
+    /// e.g. `k8s` or `ci`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    /// Absolute path of the VM's image, which may use `~`, for images kept
+    /// outside of the images directories.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    image_path: Option<String>,
     /// List of port mappings to apply. When passed to `qemu-system`, each will look like:
     /// ```
     ///     hostfwd=tcp::host_port-:vm_port
     /// ```
+    #[serde(default)]
     port_mappings: Vec<PortMapping>,
     /// List of any arbitrary options to pass to `qemu-system`. Any `nic` options will be merged
     /// with `port_mappings`.
+    #[serde(default)]
     options: Vec<QemuRunOption>,
+    #[serde(default = "enabled")]
     use_global_options: bool,
+    #[serde(default = "enabled")]
     daemonize: bool,
+    /// Name of the profile, defined in the config's `profiles` section, the
+    /// VM inherits the settings it doesn't set itself from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    profile: Option<String>,
+    /// Variables the VM's settings can refer to as `${NAME}`, overriding the
+    /// config's own.
+    #[serde(
+        default,
+        skip_serializing_if = "BTreeMap::is_empty",
+        deserialize_with = "interpolate::deserialize_variables"
+    )]
+    variables: BTreeMap<String, String>,
+    /// Memory given to the guest, e.g. `8G` or `512M`. If set, any `-m`
+    /// option is replaced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    memory: Option<String>,
+    /// Whether the guest's memory is backed by the host's hugepages, which
+    /// must be reserved beforehand.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    hugepages: bool,
+    /// Whether the guest gets a virtio-balloon device, through which
+    /// `vm-manager balloon` can reclaim memory from it. On by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    balloon: Option<bool>,
+    /// Whether the guest gets a channel to the host for `qemu-guest-agent`,
+    /// through which `vm-manager exec` runs commands in it. On by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    guest_agent: Option<bool>,
+    /// Number of virtual CPUs given to the guest. If set, any `-smp` option
+    /// is replaced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cpus: Option<usize>,
+    /// Number of vCPUs `vm-manager scale` can grow the guest to while it
+    /// runs. Needs `cpus`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_cpus: Option<usize>,
+    /// Memory `vm-manager scale` can grow the guest to while it runs, e.g.
+    /// `32G`. Needs `memory`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_memory: Option<String>,
+    /// Number of DIMMs memory can be hotplugged into, if `max_memory` is
+    /// set. 4 by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    memory_slots: Option<usize>,
+    /// Host CPUs qemu runs on, and whether each vCPU is pinned to one of
+    /// them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cpu_affinity: Option<CpuAffinity>,
+    /// Machine type emulated for the guest, e.g. `q35` or `pc-i440fx`. If
+    /// set, any `-machine`/`-M` option is replaced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    machine: Option<String>,
+    /// Whether the guest gets an emulated TPM 2.0, backed by a `swtpm`
+    /// process run alongside the VM.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    tpm: bool,
+    /// Whether the guest boots with UEFI (OVMF) instead of BIOS.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    uefi: bool,
+    /// Whether the guest boots with UEFI Secure Boot enforced, with the
+    /// Microsoft keys enrolled. Implies `uefi`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    secure_boot: bool,
+    /// Installer ISO attached as a CD-ROM, which the guest boots from once.
+    /// May use `~`. If set, any `-cdrom` or `-boot` option is replaced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cdrom: Option<String>,
+    /// I/O limits of the guest's disk, so that a busy guest can't starve the
+    /// host and other guests of disk bandwidth.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    disk_throttling: Option<DiskThrottling>,
+    /// Settings used when the VM is network booted with `start --netboot`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    netboot: Option<NetbootConfig>,
+    /// Host directories shared with the guest.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    shares: Vec<Share>,
+    /// Host directory served to the guest by qemu's built-in SMB server, at
+    /// `\\10.0.2.4\qemu`. May use `~`. Needs user-mode networking.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    smb_share: Option<String>,
+    /// How the guest is connected to the network. Without it, the guest uses
+    /// user-mode networking, as set up by its `-nic` option and port mappings.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    network: Option<NetworkConfig>,
+    /// Host PCI devices, such as GPUs, passed through to the guest with VFIO.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pci_passthrough: Vec<PciDevice>,
+    /// Names of the private networks, defined in the config's `networks`
+    /// section, the guest gets an additional NIC on.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    private_networks: Vec<String>,
+    /// Credentials used to log in to the guest over SSH. Overrides the global
+    /// `ssh` section field by field.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ssh: Option<SshCredentials>,
+    /// How the VM's display is exposed. If set, any display options (`-vnc`,
+    /// `-spice`, `-display`) are replaced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    display: Option<DisplayType>,
+    /// Retention policy for backups of this VM's image. Overrides the global
+    /// `backups` section field by field.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    backups: Option<BackupPolicy>,
+    /// When `vm-manager supervise` starts the VM again after it exits.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    restart_policy: Option<RestartPolicy>,
 }
 
 impl VMConfig {
+    pub fn new(image_name: &str) -> Self {
+        //! Returns the config of a VM which runs in the background using the
+        //! global options, with no port mappings or options of its own.
+        Self {
+            image_name: image_name.to_owned(),
+            use_global_options: true,
+            daemonize: true,
+            ..Default::default()
+        }
+    }
+
     pub fn option_nic_present(&self) -> bool {
         //! Returns `true` if there is an option `-nic ...` present, and false otherwise.
         for option in &self.options {
@@ -173,9 +1493,540 @@ impl VMConfig {
         &self.image_name
     }
 
+    pub fn name(&self) -> &str {
+        //! Returns the name of the VM, which is its image name unless it has
+        //! a `name` of its own.
+        self.name.as_deref().unwrap_or(&self.image_name)
+    }
+
+    pub fn image_path(&self) -> Option<PathBuf> {
+        self.image_path
+            .as_ref()
+            .map(|path| PathBuf::from(shellexpand::tilde(path).as_ref()))
+    }
+
     pub fn options(&self) -> &Vec<QemuRunOption> {
         &self.options
     }
+
+    pub fn display(&self) -> Option<DisplayType> {
+        self.display
+    }
+
+    pub fn memory(&self) -> Option<&str> {
+        self.memory.as_deref()
+    }
+
+    pub fn hugepages(&self) -> bool {
+        self.hugepages
+    }
+
+    pub fn balloon(&self) -> bool {
+        self.balloon.unwrap_or(true)
+    }
+
+    pub fn restart_policy(&self) -> RestartPolicy {
+        self.restart_policy.unwrap_or_default()
+    }
+
+    pub fn guest_agent(&self) -> bool {
+        self.guest_agent.unwrap_or(true)
+    }
+
+    pub fn cpus(&self) -> Option<usize> {
+        self.cpus
+    }
+
+    pub fn max_cpus(&self) -> Option<usize> {
+        self.max_cpus
+    }
+
+    pub fn max_memory(&self) -> Option<&str> {
+        self.max_memory.as_deref()
+    }
+
+    pub fn memory_slots(&self) -> usize {
+        self.memory_slots.unwrap_or(DEFAULT_MEMORY_SLOTS)
+    }
+
+    pub fn cpu_affinity(&self) -> Option<&CpuAffinity> {
+        self.cpu_affinity.as_ref()
+    }
+
+    pub fn machine(&self) -> Option<&str> {
+        self.machine.as_deref()
+    }
+
+    pub fn tpm(&self) -> bool {
+        self.tpm
+    }
+
+    pub fn uefi(&self) -> bool {
+        self.uefi || self.secure_boot
+    }
+
+    pub fn secure_boot(&self) -> bool {
+        self.secure_boot
+    }
+
+    pub fn cdrom(&self) -> Option<&str> {
+        self.cdrom.as_deref()
+    }
+
+    pub fn disk_throttling(&self) -> Option<&DiskThrottling> {
+        self.disk_throttling.as_ref()
+    }
+
+    pub fn netboot(&self) -> Option<&NetbootConfig> {
+        self.netboot.as_ref()
+    }
+
+    pub fn shares(&self) -> &[Share] {
+        &self.shares
+    }
+
+    pub fn smb_share(&self) -> Option<&str> {
+        self.smb_share.as_deref()
+    }
+
+    pub fn network(&self) -> Option<&NetworkConfig> {
+        self.network.as_ref()
+    }
+
+    pub fn pci_passthrough(&self) -> &[PciDevice] {
+        &self.pci_passthrough
+    }
+
+    pub fn private_networks(&self) -> &[String] {
+        &self.private_networks
+    }
+}
+
+/// Credentials used by the subcommands which connect to a guest over its
+/// forwarded SSH port, such as `copy`.
+/// # Attributes:
+/// * `user` - The user to log in as. If `None`, ssh's default is used.
+/// * `identity_file` - A private key to authenticate with. May use `~`.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Default)]
+pub struct SshCredentials {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity_file: Option<String>,
+}
+
+/// Decides which backups `prune-backups` keeps. A backup is kept if either
+/// field keeps it; if neither is set, every backup is kept.
+/// # Attributes:
+/// * `keep_last` - Keep this many of the most recent backups.
+/// * `keep_days` - Keep every backup taken within this many days.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Default)]
+pub struct BackupPolicy {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_last: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_days: Option<u64>,
+}
+
+/// How the logs of VMs run in the background are kept from filling the
+/// disk. A log is rotated once it grows past `max_size`, and rotated logs are
+/// deleted once there are more than `keep` of them, or once older than
+/// `keep_days`.
+/// # Attributes:
+/// * `max_size` - The size past which a log is rotated, e.g. `10M`.
+/// * `keep` - How many rotated logs to keep of each log.
+/// * `keep_days` - Delete rotated logs last written more than this many days
+///   ago.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Default)]
+pub struct LogRotation {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_days: Option<u64>,
+}
+
+/// How the names of running VMs are published, so that their guests'
+/// services can be reached by name, e.g. `deb12.local:8080`.
+/// # Attributes:
+/// * `domain` - The domain names are published in. Defaults to `local`.
+/// * `hosts_file` - A hosts-style file in which a block is kept mapping the
+///   name of each running VM to `127.0.0.1`, noting its forwarded ports.
+/// * `mdns` - Whether to advertise the name of each running VM over mDNS
+///   with `avahi-publish`, as an alias of the host's address.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Default)]
+pub struct HostnamesConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hosts_file: Option<String>,
+    #[serde(default)]
+    pub mdns: bool,
+}
+
+/// A cloud image `image pull` can download.
+/// # Attributes:
+/// * `url` - Where the image is downloaded from.
+/// * `checksums_url` - A file of SHA-256 or SHA-512 checksums listing the
+///   image's, as written by `sha256sum` or in the BSD style.
+/// * `signature_url` - A detached signature of the checksums file, checked
+///   with `gpg`.
+/// * `signing_key` - The fingerprint of the only key the signature at
+///   `signature_url` is accepted from.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct CatalogImage {
+    pub url: String,
+    pub checksums_url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing_key: Option<String>,
+}
+
+/// A condition VMs are selected by with `--filter`.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum VmFilter {
+    /// VMs with the given tag, written `tag=<tag>`.
+    Tag(String),
+}
+
+impl VmFilter {
+    pub fn parse(filter: &str) -> Result<Self, String> {
+        //! Parses a filter as given on the command line, e.g. `tag=k8s`.
+        match filter.split_once('=') {
+            Some(("tag", tag)) if !tag.is_empty() => Ok(Self::Tag(tag.to_owned())),
+            _ => Err(format!(
+                "'{filter}' is not a filter. Filters look like 'tag=k8s'."
+            )),
+        }
+    }
+
+    pub fn matches(&self, tags: &[String]) -> bool {
+        //! Returns `true` if a VM with `tags` matches the filter.
+        match self {
+            Self::Tag(tag) => tags.contains(tag),
+        }
+    }
+}
+
+/// A VM of a group, given either by its name alone or with the VMs of the
+/// group it depends on and when it counts as ready for them.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+#[serde(untagged)]
+pub enum GroupMember {
+    Name(String),
+    Vm {
+        name: String,
+        /// VMs of the group which are started, and ready, before this one,
+        /// and stopped after it.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        depends_on: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ready: Option<ReadyCondition>,
+    },
+}
+
+impl GroupMember {
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Name(name) | Self::Vm { name, .. } => name,
+        }
+    }
+
+    pub fn depends_on(&self) -> &[String] {
+        match self {
+            Self::Name(_) => &[],
+            Self::Vm { depends_on, .. } => depends_on,
+        }
+    }
+
+    pub fn ready(&self) -> Option<&ReadyCondition> {
+        match self {
+            Self::Name(_) => None,
+            Self::Vm { ready, .. } => ready.as_ref(),
+        }
+    }
+}
+
+/// When a VM of a group counts as ready for the VMs depending on it, once
+/// it's started. Every condition set must hold.
+/// # Attributes:
+/// * `port` - A guest TCP port, forwarded to the host, which must accept
+///   connections.
+/// * `ssh` - Whether the guest's SSH server must answer on its forwarded SSH
+///   port.
+/// * `timeout` - How many seconds to wait for the conditions to hold. If
+///   `None`, uses `DEFAULT_READY_TIMEOUT` instead.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Default)]
+pub struct ReadyCondition {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<usize>,
+    #[serde(default)]
+    pub ssh: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+}
+
+/// A URL VM lifecycle events are posted to as JSON.
+/// # Attributes:
+/// * `url` - Where events are posted to.
+/// * `events` - The events posted; every event if empty.
+/// * `secret` - If set, each post is signed with an HMAC-SHA256 of its body
+///   keyed with it, sent in the `X-VM-Manager-Signature` header.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct Webhook {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<WebhookEvent>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+impl Webhook {
+    pub fn is_subscribed(&self, event: WebhookEvent) -> bool {
+        self.events.is_empty() || self.events.contains(&event)
+    }
+}
+
+/// VM lifecycle events which can be posted to webhooks.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum WebhookEvent {
+    /// A VM was started.
+    Started,
+    /// A VM was stopped through vm-manager, or its guest powered down.
+    Stopped,
+    /// A VM's qemu process exited without being stopped or powered down.
+    Crashed,
+    /// A backup of a VM's disk was taken.
+    BackupCompleted,
+}
+
+impl fmt::Display for WebhookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Started => write!(f, "started"),
+            Self::Stopped => write!(f, "stopped"),
+            Self::Crashed => write!(f, "crashed"),
+            Self::BackupCompleted => write!(f, "backup-completed"),
+        }
+    }
+}
+
+/// I/O limits of a disk, enforced by qemu. A total limit can't be combined
+/// with the read or write limit of the same kind.
+/// # Attributes:
+/// * `iops` - Read and write operations per second, in total.
+/// * `iops_read` - Read operations per second.
+/// * `iops_write` - Write operations per second.
+/// * `bps` - Bytes read and written per second, in total, e.g. `100M`.
+/// * `bps_read` - Bytes read per second.
+/// * `bps_write` - Bytes written per second.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Default)]
+pub struct DiskThrottling {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iops: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iops_read: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iops_write: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bps: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bps_read: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bps_write: Option<String>,
+}
+
+/// The host CPUs a VM runs on.
+/// # Attributes:
+/// * `cores` - The host CPUs qemu, and every thread it starts, may run on,
+///   e.g. `2-5` or `2,4,6`.
+/// * `pin_vcpus` - Whether each vCPU thread is pinned to a single CPU of
+///   `cores`, in order, once the VM has started.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct CpuAffinity {
+    pub cores: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pin_vcpus: bool,
+}
+
+/// Settings for network booting a VM with `start --netboot`. Without
+/// `tftp`, the guest PXE boots from whatever its network offers, e.g. a PXE
+/// server on a bridged network.
+/// # Attributes:
+/// * `tftp` - Directory served by qemu's built-in TFTP server on user-mode
+///   networking. May use `~`.
+/// * `bootfile` - File offered to the guest's PXE firmware, relative to
+///   `tftp`, or a URL for iPXE to fetch.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Default)]
+pub struct NetbootConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tftp: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bootfile: Option<String>,
+}
+
+/// A host directory shared with the guest, which mounts it by its tag, e.g.
+/// `mount -t virtiofs code /mnt/code` or
+/// `mount -t 9p -o trans=virtio,version=9p2000.L code /mnt/code`.
+/// # Attributes:
+/// * `path` - The host directory to share. May use `~`.
+/// * `tag` - The name the guest mounts the share by.
+/// * `readonly` - Whether the guest may only read the share.
+/// * `driver` - How the directory is shared.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct Share {
+    pub path: String,
+    pub tag: String,
+    #[serde(default)]
+    pub readonly: bool,
+    #[serde(default)]
+    pub driver: ShareDriver,
+}
+
+/// Ways a host directory can be shared with the guest.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ShareDriver {
+    /// virtiofs, served by a `virtiofsd` process run alongside the VM. Fast,
+    /// but needs the guest's `memory` to be set.
+    #[default]
+    Virtiofs,
+    /// 9p (virtio-9p), served by qemu itself.
+    #[serde(rename = "9p")]
+    NineP,
+}
+
+/// How a VM is connected to the network.
+/// # Attributes:
+/// * `mode` - The kind of network the guest's NIC is attached to.
+/// * `bridge` - The host bridge the guest is attached to, with
+///   `mode: bridge`, or the tap device is added to, with `mode: tap`.
+/// * `interface` - The name of the tap or macvtap device created for the
+///   VM. Defaults to one derived from the VM's name.
+/// * `parent` - The host interface a macvtap device is attached to.
+/// * `ipv6` - Whether user-mode networking offers the guest IPv6. qemu's
+///   default is to do so.
+/// * `ipv6_prefix` - The IPv6 prefix of the user-mode network, e.g.
+///   `fd00::/64`.
+/// * `ipv6_dns` - The address the guest reaches the host's DNS resolver on
+///   over IPv6, within `ipv6_prefix`.
+/// * `ipv4_prefix` - The IPv4 network of user-mode networking, e.g.
+///   `10.0.2.0/24`, which is qemu's default.
+/// * `ipv4_host` - The address the guest reaches the host on, within
+///   `ipv4_prefix`.
+/// * `ipv4_dns` - The address the guest reaches the host's DNS resolver on,
+///   within `ipv4_prefix`.
+/// * `dhcp_start` - The first address handed out by the built-in DHCP
+///   server, within `ipv4_prefix`.
+/// * `hostname` - The hostname the built-in DHCP server gives the guest.
+/// * `mac_address` - The MAC address of the guest's NIC. Without it, one is
+///   generated when the VM is first started, and kept for later starts.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Default)]
+pub struct NetworkConfig {
+    #[serde(default)]
+    pub mode: NetworkMode,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bridge: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv6: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv6_prefix: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv6_dns: Option<Ipv6Addr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv4_prefix: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv4_host: Option<Ipv4Addr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv4_dns: Option<Ipv4Addr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dhcp_start: Option<Ipv4Addr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac_address: Option<String>,
+}
+
+/// When a VM watched by `vm-manager supervise` is started again after it
+/// exits without having been stopped through vm-manager.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    /// Whenever it exits, including when the guest powers itself down.
+    Always,
+    /// Only when qemu crashes or is killed.
+    OnFailure,
+    /// Never; the VM isn't watched.
+    #[default]
+    Never,
+}
+
+impl fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Always => write!(f, "always"),
+            Self::OnFailure => write!(f, "on-failure"),
+            Self::Never => write!(f, "never"),
+        }
+    }
+}
+
+/// Kinds of network a guest's NIC can be attached to.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkMode {
+    /// qemu's user-mode NAT, reachable from the host through port mappings.
+    #[default]
+    User,
+    /// qemu's user-mode networking with `restrict=on`, which cuts the guest
+    /// off from the host and the internet. Only port mappings reach it.
+    Isolated,
+    /// A host bridge, attached to through `qemu-bridge-helper`, which puts
+    /// the guest on the bridge's network, e.g. the LAN.
+    Bridge,
+    /// A tap device created for the VM on start, and deleted on stop.
+    Tap,
+    /// A macvtap device on a host interface, created for the VM on start,
+    /// and deleted on stop. Puts the guest on the interface's network
+    /// without a bridge, though the host itself can't reach the guest.
+    Macvtap,
+}
+
+/// A private network segment shared by the VMs which join it, carried over
+/// a multicast socket on the host's loopback interface. Guests on it can
+/// reach each other, but nothing else, and need static addresses as there
+/// is no DHCP server on it.
+/// # Attributes:
+/// * `name` - The name VMs join the network by.
+/// * `multicast` - The multicast group and port the network is carried
+///   over, e.g. `230.0.0.1:1234`. Defaults to one derived from `name`.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct PrivateNetwork {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub multicast: Option<String>,
+}
+
+/// A host PCI device passed through to the guest with VFIO. The device, and
+/// every other device in its IOMMU group, must be bound to `vfio-pci` on the
+/// host.
+/// # Attributes:
+/// * `address` - The device's PCI address, as shown by `lspci -D`, e.g.
+///   `0000:01:00.0`. The domain may be omitted.
+/// * `romfile` - A ROM image used instead of the device's own, which some
+///   GPUs need. May use `~`.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct PciDevice {
+    pub address: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub romfile: Option<String>,
 }
 
 /// This struct is used to represent a host-to-vm port mapping.
@@ -183,9 +2034,13 @@ impl VMConfig {
 /// * `host_port` - a `String` used to represent the port on the host to use.
 /// * `vm_port` - a `String` used to represent the port on the vm to use.
 /// * `explicit` - A boolean representing whether or not the exact specified
-///     host port should be used. If 'true', then if that port is in use,
-///     the program will exit. If 'false', then the program will find the next
-///     highest available port.
+///   host port should be used. If 'true', then if that port is in use,
+///   the program will exit. If 'false', then the program will find the next
+///   highest available port.
+/// * `protocol` - The transport protocol forwarded, TCP by default.
+/// * `host_address` - The host address the forward listens on, e.g.
+///   `127.0.0.1` or `::1` to only accept connections from the host itself.
+///   All IPv4 addresses by default.
 #[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
 pub struct PortMapping {
     /// Host port to be used.
@@ -197,6 +2052,35 @@ pub struct PortMapping {
     /// will exit. If 'false', then the program will find the
     /// next highest available port.
     explicit: bool,
+    /// Transport protocol to forward.
+    #[serde(default, skip_serializing_if = "Protocol::is_tcp")]
+    protocol: Protocol,
+    /// Host address to listen on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    host_address: Option<IpAddr>,
+}
+
+/// Transport protocols a port can be forwarded for.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    pub fn is_tcp(&self) -> bool {
+        *self == Protocol::Tcp
+    }
+
+    pub fn as_str(&self) -> &str {
+        //! Returns the protocol's name, as used in `hostfwd=` rules.
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        }
+    }
 }
 
 impl PortMapping {
@@ -206,7 +2090,60 @@ impl PortMapping {
             host_port: host_port.to_owned(),
             vm_port: vm_port.to_owned(),
             explicit,
+            protocol: Protocol::Tcp,
+            host_address: None,
+        }
+    }
+
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        //! Parses a port mapping given as `[address:]host:guest[/protocol]`,
+        //! e.g. `8080:80`, `127.0.0.1:8080:80`, `[::1]:8080:80` or
+        //! `5353:53/udp`. The host port is used exactly.
+        let invalid = || {
+            format!(
+                "Invalid port mapping '{spec}'. Use '[address:]host:guest[/tcp|udp]', e.g. '8080:80'."
+            )
+        };
+        let (ports, protocol) = match spec.split_once('/') {
+            Some((ports, "tcp")) => (ports, Protocol::Tcp),
+            Some((ports, "udp")) => (ports, Protocol::Udp),
+            Some((_, protocol)) => {
+                return Err(format!(
+                    "Invalid protocol '{protocol}' in '{spec}'. Use 'tcp' or 'udp'."
+                ))
+            }
+            None => (spec, Protocol::Tcp),
+        };
+        // IPv6 addresses are written in brackets, e.g. `[::1]:8080:80`.
+        let (host_address, ports) = match ports.strip_prefix('[') {
+            Some(ports) => {
+                let (host_address, ports) = ports.split_once("]:").ok_or_else(invalid)?;
+                (Some(host_address), ports)
+            }
+            None => match ports.matches(':').count() {
+                2 => ports
+                    .split_once(':')
+                    .map(|(host_address, ports)| (Some(host_address), ports))
+                    .ok_or_else(invalid)?,
+                _ => (None, ports),
+            },
+        };
+        let host_address: Option<IpAddr> = match host_address {
+            Some(host_address) => Some(host_address.parse().map_err(|_| invalid())?),
+            None => None,
+        };
+        let (host_port, vm_port) = ports.split_once(':').ok_or_else(invalid)?;
+        let is_port = |port: &str| port.parse::<u16>().is_ok_and(|port| port != 0);
+        if !is_port(host_port) || !is_port(vm_port) {
+            return Err(invalid());
         }
+        Ok(Self {
+            host_port: host_port.to_owned(),
+            vm_port: vm_port.to_owned(),
+            explicit: true,
+            protocol,
+            host_address,
+        })
     }
 
     pub fn _host_port(&self) -> &str {
@@ -229,7 +2166,17 @@ impl PortMapping {
                 panic!("ERROR: For intended mapping from host port '{}' to VM port '{}', unable to read host port '{}' as an unsigned integer.", self.host_port, self.vm_port, self.host_port);
             }
         }
-        format!("hostfwd=tcp::{}-:{}", self.host_port, self.vm_port)
+        format!(
+            "hostfwd={}:{}:{}-:{}",
+            self.protocol.as_str(),
+            match self.host_address {
+                Some(IpAddr::V6(address)) => format!("[{address}]"),
+                Some(address) => address.to_string(),
+                None => String::new(),
+            },
+            self.host_port,
+            self.vm_port
+        )
     }
 }
 
@@ -287,6 +2234,30 @@ impl QemuRunOption {
         //! ```
         self.option.split(' ').count() > 1
     }
+    pub fn flag(&self) -> &str {
+        //! Returns the flag of the option, without its arguments.
+        //!
+        //! Example:
+        //!
+        //! ```
+        //! let option: QemuRunOption = QemuRunOption::new("-m 8G");
+        //! assert_eq!(option.flag(), "-m");
+        //! ```
+        self.option.split(' ').next().unwrap_or_default()
+    }
+    pub fn arguments(&self) -> &str {
+        //! Returns the arguments of the option, without its flag.
+        //!
+        //! Example:
+        //!
+        //! ```
+        //! let option: QemuRunOption = QemuRunOption::new("-nic user,model=virtio");
+        //! assert_eq!(option.arguments(), "user,model=virtio");
+        //! ```
+        self.option
+            .split_once(' ')
+            .map_or("", |(_, arguments)| arguments)
+    }
     pub fn get_opt_list(&self) -> Vec<&str> {
         //! Vectorizes tab- or space-separated options
         //!
@@ -348,6 +2319,7 @@ mod tests {
             ],
             use_global_options: true,
             daemonize: false,
+            ..Default::default()
         };
 
         let serialized_config: String = match serde_yaml::to_string(&config) {
@@ -372,6 +2344,7 @@ mod tests {
                     options: vec![],
                     use_global_options: true,
                     daemonize: false,
+                    ..Default::default()
                 },
             };
 
@@ -387,8 +2360,452 @@ mod tests {
             ],
             use_global_options: true,
             daemonize: false,
+            ..Default::default()
         };
 
         assert_eq!(deserialized_config, expected_config);
     }
+
+    #[test]
+    fn test_parse_port_mapping() {
+        let mut port_mapping: crate::config::PortMapping =
+            crate::config::PortMapping::parse("5353:53/udp").unwrap();
+        assert!(port_mapping.is_explicit_mapping());
+        assert_eq!(port_mapping.format_nic(), "hostfwd=udp::5353-:53");
+        assert_eq!(
+            crate::config::PortMapping::parse("8080:80").unwrap(),
+            crate::config::PortMapping::new("8080", "80", true)
+        );
+        let mut port_mapping: crate::config::PortMapping =
+            crate::config::PortMapping::parse("127.0.0.1:8080:80").unwrap();
+        assert_eq!(port_mapping.format_nic(), "hostfwd=tcp:127.0.0.1:8080-:80");
+        let mut port_mapping: crate::config::PortMapping =
+            crate::config::PortMapping::parse("[::1]:8080:80").unwrap();
+        assert_eq!(port_mapping.format_nic(), "hostfwd=tcp:[::1]:8080-:80");
+        assert!(crate::config::PortMapping::parse("localhost:8080:80").is_err());
+        assert!(crate::config::PortMapping::parse("8080").is_err());
+        assert!(crate::config::PortMapping::parse("8080:80/sctp").is_err());
+        assert!(crate::config::PortMapping::parse("70000:80").is_err());
+    }
+
+    #[test]
+    fn test_port_mapping_protocol() {
+        let source_string: &str =
+            "host_port: '5353'\nvm_port: '53'\nexplicit: true\nprotocol: udp\nhost_address: 127.0.0.1\n";
+        let mut port_mapping: crate::config::PortMapping =
+            serde_yaml::from_str(source_string).unwrap();
+        assert_eq!(port_mapping.format_nic(), "hostfwd=udp:127.0.0.1:5353-:53");
+        assert_eq!(serde_yaml::to_string(&port_mapping).unwrap(), source_string);
+
+        let mut port_mapping: crate::config::PortMapping =
+            crate::config::PortMapping::new("5555", "22", true);
+        assert_eq!(port_mapping.format_nic(), "hostfwd=tcp::5555-:22");
+    }
+
+    #[test]
+    fn test_add_vm_config_to_file() {
+        let path =
+            std::env::temp_dir().join(format!("vm-manager-config-test-{}.yml", std::process::id()));
+        let filename: &str = path.to_str().unwrap();
+        std::fs::write(
+            &path,
+            "global_qemu_options:\n- option: -m 8G\nvms:\n- image_name: base\n  port_mappings: []\n  options:\n  - option: -smp 2\n  use_global_options: true\n  daemonize: false\n",
+        )
+        .unwrap();
+
+        crate::config::add_vm_config_to_file(filename, "clone", Some("base"), &[]).unwrap();
+        crate::config::add_vm_config_to_file(filename, "fresh", None, &[]).unwrap();
+        assert!(crate::config::add_vm_config_to_file(filename, "fresh", None, &[]).is_err());
+        assert_eq!(
+            crate::config::rename_vm_config_in_file(filename, "fresh", "renamed"),
+            Ok(true)
+        );
+        assert_eq!(
+            crate::config::rename_vm_config_in_file(filename, "missing", "renamed"),
+            Ok(false)
+        );
+
+        let config = crate::config::Config::load_from_file(filename).unwrap();
+        let clone = config.get_vm_config_with_name("clone").unwrap();
+        assert!(!clone.daemonize());
+        assert_eq!(clone.options().len(), 2);
+        let fresh = config.get_vm_config_with_name("renamed").unwrap();
+        assert!(fresh.daemonize());
+        assert_eq!(fresh.options().len(), 1);
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(format!("{filename}.bak")).unwrap();
+    }
+
+    #[test]
+    fn test_edit_vm_config_in_file() {
+        use crate::config::{PortMapping, VmConfigEdit};
+
+        let directory = std::env::temp_dir().join(format!(
+            "vm-manager-config-edit-test-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("config.yml");
+        let filename: &str = path.to_str().unwrap();
+        std::fs::write(
+            &path,
+            "# lab VMs\nglobal_qemu_options: []\nvms:\n  # the web server\n  - image_name: web\n    port_mappings: []\n    options:\n    - option: -vnc none\n    use_global_options: true\n    daemonize: true\n\n  # the database\n  - image_name: db\n    port_mappings: []\n    options: []\n    use_global_options: true\n    daemonize: true\n# end of VMs\nshutdown_timeout: 10\n",
+        )
+        .unwrap();
+
+        crate::config::edit_vm_config_in_file(
+            filename,
+            "web",
+            &[
+                VmConfigEdit::Set("memory".to_owned(), "16G".into()),
+                VmConfigEdit::Set("ssh.user".to_owned(), "admin".into()),
+                VmConfigEdit::RemoveOption("-vnc".to_owned()),
+                VmConfigEdit::AddPort(PortMapping::parse("2222:22").unwrap()),
+            ],
+        )
+        .unwrap();
+        crate::config::add_vm_config_to_file(
+            filename,
+            "cache",
+            None,
+            &[VmConfigEdit::Set("cpus".to_owned(), 2.into())],
+        )
+        .unwrap();
+        assert!(crate::config::edit_vm_config_in_file(
+            filename,
+            "db",
+            &[VmConfigEdit::Set("cpus".to_owned(), "many".into())]
+        )
+        .is_err());
+        assert!(crate::config::edit_vm_config_in_file(
+            filename,
+            "db",
+            &[VmConfigEdit::Unset("memory".to_owned())]
+        )
+        .is_err());
+
+        let contents: String = std::fs::read_to_string(&path).unwrap();
+        for comment in [
+            "# lab VMs\n",
+            "  # the web server\n",
+            "\n  # the database\n  - image_name: db\n",
+            "# end of VMs\n",
+        ] {
+            assert!(
+                contents.contains(comment),
+                "lost '{comment}' in:\n{contents}"
+            );
+        }
+        let config = crate::config::Config::load_from_file(filename).unwrap();
+        let web = config.get_vm_config_with_name("web").unwrap();
+        assert_eq!(web.memory(), Some("16G"));
+        assert_eq!(
+            config.get_ssh_credentials("web").user.as_deref(),
+            Some("admin")
+        );
+        assert_eq!(web.options()[0].as_str(), "-nic hostfwd=tcp::2222-:22");
+        assert_eq!(
+            config.get_vm_config_with_name("cache").unwrap().cpus(),
+            Some(2)
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_load_included_files() {
+        let directory = std::env::temp_dir().join(format!(
+            "vm-manager-config-include-test-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(directory.join("projects")).unwrap();
+        std::fs::create_dir_all(directory.join("conf.d")).unwrap();
+        std::fs::create_dir_all(directory.join("vms.d")).unwrap();
+        let path = directory.join("config.yml");
+        let filename: &str = path.to_str().unwrap();
+        std::fs::write(
+            &path,
+            "include:\n- projects/*.yml\nshutdown_timeout: 10\nglobal_qemu_options:\n- option: -vnc none\nvms:\n- image_name: web\n  port_mappings: []\n  options: []\n  use_global_options: true\n  daemonize: true\n",
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("projects/db.yml"),
+            "vms:\n- image_name: db\n  port_mappings: []\n  options: []\n  use_global_options: true\n  daemonize: true\n",
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("conf.d/timeout.toml"),
+            "shutdown_timeout = 60\n\n[[global_qemu_options]]\noption = \"-smp 2\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("vms.d/cache.yml"),
+            "# the cache\nport_mappings: []\noptions: []\nuse_global_options: false\ndaemonize: true\n",
+        )
+        .unwrap();
+
+        let config = crate::config::Config::load_from_file(filename).unwrap();
+        assert_eq!(config.get_shutdown_timeout().as_secs(), 60);
+        let db = config.get_vm_config_with_name("db").unwrap();
+        assert_eq!(db.options()[0].as_str(), "-vnc none");
+        assert_eq!(db.options()[1].as_str(), "-smp 2");
+        assert!(config.get_vm_config_with_name("web").is_some());
+        assert_eq!(
+            crate::config::find_config_file_with_vm(filename, "db"),
+            directory.join("projects/db.yml").to_str().unwrap()
+        );
+        assert_eq!(
+            crate::config::find_config_file_with_vm(filename, "web"),
+            filename
+        );
+        assert!(config
+            .get_vm_config_with_name("cache")
+            .unwrap()
+            .options()
+            .is_empty());
+        let cache_file: String = crate::config::find_config_file_with_vm(filename, "cache");
+        assert!(cache_file.ends_with("/vms.d/cache.yml"));
+        crate::config::edit_vm_config_in_file(
+            &cache_file,
+            "cache",
+            &[crate::config::VmConfigEdit::Set(
+                "memory".to_owned(),
+                "2G".into(),
+            )],
+        )
+        .unwrap();
+        assert!(std::fs::read_to_string(&cache_file)
+            .unwrap()
+            .starts_with("# the cache\n"));
+        let config = crate::config::Config::load_from_file(filename).unwrap();
+        assert_eq!(
+            config.get_vm_config_with_name("cache").unwrap().memory(),
+            Some("2G")
+        );
+        // renaming an image updates the VMs using it wherever they're set up.
+        assert_eq!(
+            crate::config::rename_image_in_config_files(filename, "cache", "redis").unwrap(),
+            vec![cache_file.clone()]
+        );
+        assert_eq!(
+            crate::config::rename_image_in_config_files(filename, "db", "postgres").unwrap(),
+            vec![directory
+                .join("projects/db.yml")
+                .to_str()
+                .unwrap()
+                .to_owned()]
+        );
+        let config = crate::config::Config::load_from_file(filename).unwrap();
+        assert_eq!(
+            config.get_vm_config_with_name("redis").unwrap().memory(),
+            Some("2G")
+        );
+        assert!(config.get_vm_config_with_name("postgres").is_some());
+        assert!(config.get_vm_config_with_name("db").is_none());
+
+        std::fs::write(
+            directory.join("conf.d/web.yml"),
+            "vms:\n- image_name: web\n  port_mappings: []\n  options: []\n  use_global_options: true\n  daemonize: true\n",
+        )
+        .unwrap();
+        assert!(crate::config::Config::load_from_file(filename).is_err());
+
+        // a config file which can't be parsed is an error, not a panic.
+        std::fs::write(&path, "vms: [\n").unwrap();
+        assert_eq!(
+            crate::config::Config::load_from_file(filename)
+                .unwrap_err()
+                .kind(),
+            std::io::ErrorKind::InvalidData
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_apply_profiles_and_variables() {
+        let mut document: serde_yaml::Value = serde_yaml::from_str(
+            "global_qemu_options: []\nprofiles:\n  small:\n    memory: 2G\n    cpus: 1\n    options:\n    - option: -vnc none\n    port_mappings:\n    - host_port: '2222'\n      vm_port: '22'\n      explicit: true\n  ci:\n    profile: small\n    cpus: 2\n    daemonize: false\n    ssh:\n      user: ci\nvms:\n- image_name: runner\n  profile: ci\n  memory: 4G\n  options:\n  - option: -smp 2\n  port_mappings:\n- image_name: plain\n",
+        )
+        .unwrap();
+        crate::config::apply_profiles(&mut document).unwrap();
+        let config: crate::config::Config = serde_yaml::from_value(document).unwrap();
+
+        let runner = config.get_vm_config_with_name("runner").unwrap();
+        assert_eq!(runner.memory(), Some("4G"));
+        assert_eq!(runner.cpus(), Some(2));
+        assert!(!runner.daemonize);
+        assert!(runner.use_global_options);
+        assert_eq!(
+            runner
+                .options()
+                .iter()
+                .map(|option| option.as_str())
+                .collect::<Vec<&str>>(),
+            ["-vnc none", "-smp 2"]
+        );
+        assert_eq!(runner.port_mappings.len(), 1);
+        assert_eq!(
+            config.get_ssh_credentials("runner").user.as_deref(),
+            Some("ci")
+        );
+        let plain = config.get_vm_config_with_name("plain").unwrap();
+        assert_eq!(plain, &crate::config::VMConfig::new("plain"));
+
+        for (profiles, error) in [
+            ("{}", "no profile named 'ci'"),
+            (
+                "{ci: {profile: small}, small: {profile: ci}}",
+                "ci -> small -> ci",
+            ),
+        ] {
+            let mut document: serde_yaml::Value = serde_yaml::from_str(&format!(
+                "profiles: {profiles}\nvms:\n- image_name: runner\n  profile: ci\n"
+            ))
+            .unwrap();
+            let e: String = crate::config::apply_profiles(&mut document).unwrap_err();
+            assert!(e.contains(error), "{e}");
+        }
+
+        let config = crate::config::Config::from_document(
+            serde_yaml::from_str(
+                "variables:\n  shares: /srv/shares\nglobal_qemu_options:\n- option: -name ${VM_NAME}\nvms:\n- image_name: web\n  variables:\n    tag: www\n  options:\n  - option: -virtfs local,path=${shares}/${VM_NAME},mount_tag=${tag}\n",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            config
+                .get_vm_config_with_name("web")
+                .unwrap()
+                .options()
+                .iter()
+                .map(|option| option.as_str())
+                .collect::<Vec<&str>>(),
+            [
+                "-virtfs local,path=/srv/shares/web,mount_tag=www",
+                "-name web"
+            ]
+        );
+        assert!(crate::config::Config::from_document(
+            serde_yaml::from_str(
+                "base_images_directory: ${VM_NAME}\nglobal_qemu_options: []\nvms: []\n"
+            )
+            .unwrap()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_get_ssh_credentials() {
+        let config: crate::config::Config = serde_yaml::from_str(
+            "base_images_directory: ~/images\nglobal_qemu_options:\nssh:\n  user: root\n  identity_file: ~/.ssh/id_ed25519\nvms:\n- image_name: dev\n  port_mappings:\n  options:\n  use_global_options: true\n  daemonize: true\n  ssh:\n    user: dev\n- image_name: prod\n  port_mappings:\n  options:\n  use_global_options: true\n  daemonize: true\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.get_ssh_credentials("dev"),
+            crate::config::SshCredentials {
+                user: Some(String::from("dev")),
+                identity_file: Some(String::from("~/.ssh/id_ed25519")),
+            }
+        );
+        assert_eq!(
+            config.get_ssh_credentials("prod"),
+            crate::config::SshCredentials {
+                user: Some(String::from("root")),
+                identity_file: Some(String::from("~/.ssh/id_ed25519")),
+            }
+        );
+
+        let effective: VMConfig = config.get_effective_vm_config(&config.vms[1]);
+        assert_eq!(effective.ssh, Some(config.get_ssh_credentials("prod")));
+        assert_eq!(
+            effective.restart_policy,
+            Some(crate::config::RestartPolicy::Never)
+        );
+        assert_eq!(effective.balloon, Some(true));
+        assert_eq!(effective.memory_slots, None);
+    }
+
+    #[test]
+    fn test_filter_by_tags() {
+        let config: crate::config::Config = serde_yaml::from_str(
+            "global_qemu_options: []\nvms:\n- image_name: node1\n  tags: [k8s, ci]\n- image_name: node2\n  tags: [k8s]\n- image_name: desktop\n",
+        )
+        .unwrap();
+        let k8s = crate::config::VmFilter::parse("tag=k8s").unwrap();
+        let ci = crate::config::VmFilter::parse("tag=ci").unwrap();
+        assert!(config.matches_filters("node1", &[k8s.clone(), ci.clone()]));
+        assert!(!config.matches_filters("node2", &[k8s.clone(), ci]));
+        assert!(config.matches_filters("node2", std::slice::from_ref(&k8s)));
+        assert!(!config.matches_filters("desktop", &[k8s]));
+        assert!(config.matches_filters("desktop", &[]));
+        assert!(crate::config::VmFilter::parse("tag=").is_err());
+        assert!(crate::config::VmFilter::parse("color=red").is_err());
+    }
+
+    #[test]
+    fn test_group_start_order() {
+        let config: crate::config::Config = serde_yaml::from_str(
+            "global_qemu_options: []\nvms: []\ngroups:\n  app:\n  - name: web\n    depends_on: [db, cache]\n  - cache\n  - name: db\n    ready:\n      port: 5432\n",
+        )
+        .unwrap();
+        let members = config.get_group("app").unwrap();
+        assert_eq!(
+            members
+                .iter()
+                .map(|member| member.name())
+                .collect::<Vec<&str>>(),
+            vec!["cache", "db", "web"]
+        );
+        assert_eq!(members[1].ready().and_then(|ready| ready.port), Some(5432));
+        assert!(config.get_group("none").is_err());
+
+        for groups in [
+            "  app:\n  - name: web\n    depends_on: [db]\n",
+            "  app:\n  - name: a\n    depends_on: [b]\n  - name: b\n    depends_on: [a]\n",
+            "  app:\n  - db\n  - db\n",
+        ] {
+            let document: serde_yaml::Value = serde_yaml::from_str(&format!(
+                "global_qemu_options: []\nvms: []\ngroups:\n{groups}"
+            ))
+            .unwrap();
+            assert!(crate::config::Config::from_document(document).is_err());
+        }
+    }
+
+    #[test]
+    fn test_vm_names() {
+        let config = crate::config::Config::from_document(
+            serde_yaml::from_str(
+                "global_qemu_options:\n- option: -name ${VM_NAME}\nvms:\n- image_name: deb12\n  name: staging\n  memory: 4G\n- image_name: deb12\n  name: prod-sim\n  memory: 16G\n- image_name: deb12\n",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert!(config.get_vm_config_with_name("prod").is_none());
+        let prod = config.get_vm_config_with_name("prod-sim").unwrap();
+        assert_eq!((prod.name(), prod.image_name()), ("prod-sim", "deb12"));
+        assert_eq!(prod.memory(), Some("16G"));
+        assert_eq!(prod.options()[0].as_str(), "-name prod-sim");
+        assert_eq!(
+            config.get_vm_config_with_name("deb12").unwrap().name(),
+            "deb12"
+        );
+        assert_eq!(
+            config.get_vm_config_with_name("staging").unwrap().memory(),
+            Some("4G")
+        );
+
+        let e: String = crate::config::Config::from_document(
+            serde_yaml::from_str(
+                "global_qemu_options: []\nvms:\n- image_name: deb12\n  name: web\n- image_name: web\n",
+            )
+            .unwrap(),
+        )
+        .unwrap_err();
+        assert!(e.contains("More than one VM is called 'web'"), "{e}");
+    }
 }
@@ -0,0 +1,137 @@
+use std::fs;
+use std::path::Path;
+
+use crate::config::{get_header_comments, ConfigFormat, VMConfig};
+
+/// Extensions of the files `config init --scan` takes for disk images.
+const IMAGE_EXTENSIONS: [&str; 2] = ["img", "qcow2"];
+
+/// The start of the config file written by `config init`, up to its VMs.
+/// `{images_directory}` is replaced with the images directory.
+const CONFIG_TEMPLATE: &str = "\
+# vm-manager configuration, written by 'vm-manager config init'.
+#
+# Every setting is described in sample_config.yml, which the Debian package
+# installs to /etc/vm-manager/sample_config.yml.
+
+# Where disk images are kept, searched in order. New images go to the first
+# one, and backups to its 'backups' subdirectory.
+image_directories:
+  - {images_directory}
+
+# How many seconds to wait for a guest to power down before killing it.
+shutdown_timeout: 30
+
+# Credentials used by 'vm-manager ssh' and 'vm-manager copy'.
+# ssh:
+#   user: root
+#   identity_file: ~/.ssh/id_ed25519
+
+# Options given to every VM, unless it sets 'use_global_options: false'.
+global_qemu_options:
+  - option: -m 8G
+  - option: -daemonize
+  - option: -smp 4
+  - option: -accel kvm
+  - option: -accel tcg
+  - option: -cpu host
+  # '-vnc none' is needed to run headless
+  - option: -vnc none
+  - option: -nic user,model=virtio
+
+# One entry per VM, naming the image in the images directory it runs. Add
+# more with 'vm-manager image create' or by copying an entry, e.g.:
+#   - image_name: deb12
+#     port_mappings:
+#       - host_port: '5555'
+#         vm_port: '22'
+#         explicit: false
+#     options:
+#       - option: -m 16G
+#     use_global_options: true
+#     daemonize: true
+";
+
+pub fn find_images(directory: &Path) -> Result<Vec<String>, String> {
+    //! Returns the names of the disk images in `directory`, sorted.
+    let entries = fs::read_dir(directory).map_err(|e| {
+        format!(
+            "Unable to read image directory '{}'. {e}",
+            directory.display()
+        )
+    })?;
+    let mut image_names: Vec<String> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .extension()
+                    .is_some_and(|extension| IMAGE_EXTENSIONS.iter().any(|e| *e == extension))
+        })
+        .filter_map(|path| Some(path.file_stem()?.to_str()?.to_owned()))
+        .collect();
+    image_names.sort();
+    Ok(image_names)
+}
+
+pub fn get_config_template(
+    images_directory: &str,
+    image_names: &[String],
+) -> Result<String, String> {
+    //! Returns a commented config file to get started with, keeping images in
+    //! `images_directory` and with a VM using the global options for each
+    //! of `image_names`.
+    let images_directory: String = serde_yaml::to_string(images_directory)
+        .map_err(|e| format!("Unable to serialize images directory. {e}"))?;
+    let mut template: String =
+        CONFIG_TEMPLATE.replace("{images_directory}", images_directory.trim_end());
+    if image_names.is_empty() {
+        template.push_str("vms: []\n");
+    } else {
+        let vms: Vec<VMConfig> = image_names.iter().map(|name| VMConfig::new(name)).collect();
+        let vms: String = serde_yaml::to_string(&vms)
+            .map_err(|e| format!("Unable to serialize VM configs. {e}"))?;
+        template.push_str("vms:\n");
+        for line in vms.lines() {
+            template.push_str(&format!("  {line}\n"));
+        }
+    }
+    Ok(template)
+}
+
+pub fn get_toml_config_template(template: &str) -> Result<String, String> {
+    //! Returns the config file `get_config_template` returned as TOML, which
+    //! only keeps the comments at its top.
+    let document: serde_yaml::Value = ConfigFormat::Yaml.parse(template)?;
+    Ok(format!(
+        "{}\n{}",
+        get_header_comments(template),
+        ConfigFormat::Toml.serialize(&document)?
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_config_template, get_toml_config_template};
+    use crate::config::{Config, ConfigFormat};
+
+    #[test]
+    fn test_config_template() {
+        let image_names: Vec<String> = vec!["deb12".to_owned(), "win11".to_owned()];
+        let template: String = get_config_template("/srv/vm images", &image_names).unwrap();
+        assert!(template.contains("image_directories:\n  - /srv/vm images\n"));
+        let config: Config = serde_yaml::from_str(&template).unwrap();
+        assert_eq!(config.get_images_directory(), "/srv/vm images");
+        assert!(config.get_vm_config_with_name("deb12").is_some());
+        assert!(config.get_vm_config_with_name("win11").is_some());
+        let toml_config: Config = ConfigFormat::Toml
+            .parse(&get_toml_config_template(&template).unwrap())
+            .unwrap();
+        assert_eq!(toml_config, config);
+
+        let config: Config =
+            serde_yaml::from_str(&get_config_template("~/.vm-manager/disk-images", &[]).unwrap())
+                .unwrap();
+        assert!(config.get_vm_config_with_name("deb12").is_none());
+    }
+}
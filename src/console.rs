@@ -0,0 +1,86 @@
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// `Ctrl-]`, which detaches from the console, as in telnet and virsh.
+const ESCAPE_BYTE: u8 = 0x1d;
+
+pub fn run_stty(args: &[&str]) -> Result<String, String> {
+    //! Runs `stty` against the current terminal, returning its output.
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .output()
+        .map_err(|e| format!("Unable to run 'stty'. {e}"))?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    } else {
+        Err(format!(
+            "'stty' failed; is stdin a terminal? {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+pub fn attach_console(vm_name: &str, socket_path: &Path) -> Result<(), String> {
+    //! Attaches the current terminal to the serial console socket at
+    //! `socket_path` until the user presses `Ctrl-]`, or the VM closes the
+    //! console. The terminal is put in raw mode while attached, so that
+    //! control characters and line editing are handled by the guest.
+    let mut stream: UnixStream = UnixStream::connect(socket_path).map_err(|e| {
+        format!(
+            "Unable to connect to the serial console of '{vm_name}' at '{}'. {e}",
+            socket_path.display()
+        )
+    })?;
+    let mut reader: UnixStream = stream.try_clone().map_err(|e| e.to_string())?;
+
+    let saved_terminal_state: String = run_stty(&["-g"])?;
+    eprintln!("Connected to the serial console of '{vm_name}'. Escape character is '^]'.\r");
+    run_stty(&["raw", "-echo"])?;
+
+    // copy guest output to the terminal. When the VM closes the console there
+    // is nothing left to attach to, so restore the terminal and exit.
+    let restore_state: String = saved_terminal_state.clone();
+    thread::spawn(move || {
+        let mut buffer: [u8; 4096] = [0; 4096];
+        let mut stdout = std::io::stdout();
+        while let Ok(read) = reader.read(&mut buffer) {
+            if read == 0 || stdout.write_all(&buffer[..read]).is_err() {
+                break;
+            }
+            let _ = stdout.flush();
+        }
+        let _ = run_stty(&[&restore_state]);
+        eprintln!("\nConsole closed by the VM.");
+        std::process::exit(0);
+    });
+
+    // copy keystrokes to the guest, until the escape character is typed.
+    let mut buffer: [u8; 1024] = [0; 1024];
+    let mut stdin = std::io::stdin();
+    let result: Result<(), String> = loop {
+        let read: usize = match stdin.read(&mut buffer) {
+            Ok(0) => break Ok(()),
+            Ok(read) => read,
+            Err(e) => break Err(format!("Unable to read from the terminal. {e}")),
+        };
+        let input: &[u8] = &buffer[..read];
+        let (input, detach) = match input.iter().position(|byte| *byte == ESCAPE_BYTE) {
+            Some(position) => (&input[..position], true),
+            None => (input, false),
+        };
+        if let Err(e) = stream.write_all(input) {
+            break Err(format!("Unable to write to the serial console. {e}"));
+        }
+        if detach {
+            break Ok(());
+        }
+    };
+
+    run_stty(&[&saved_terminal_state])?;
+    eprintln!();
+    result
+}
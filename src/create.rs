@@ -0,0 +1,198 @@
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+
+use crate::catalog::{get_catalog, pull_image};
+use crate::config::{self, CatalogImage, Config, VmConfigEdit};
+use crate::image::{
+    create_image, get_image_info, get_new_image_path, is_shrinking, parse_size, resize_image,
+};
+use crate::parse_args::{Arguments, CreateOptions, ImageFormat, StartOptions};
+use crate::utils::{confirm, OutputStream};
+
+/// Size of the disk of a new VM, unless another is given.
+const DEFAULT_DISK_SIZE: &str = "20G";
+
+/// What the disk of a new VM starts out as.
+#[derive(Debug, PartialEq)]
+enum InstallSource {
+    /// A blank disk.
+    Blank,
+    /// A blank disk, with an installer ISO attached to boot from.
+    Iso(String),
+    /// A copy of a cloud image from the image catalog, by its name there.
+    CloudImage(String, CatalogImage),
+}
+
+fn ask(question: &str, default: Option<&str>) -> String {
+    //! Asks the user a question on the terminal, returning their answer, or
+    //! `default` if they don't give one.
+    match default {
+        Some(default) => print!("{question} [{default}] "),
+        None => print!("{question} "),
+    }
+    let _ = std::io::stdout().flush();
+    let mut answer: String = String::new();
+    let _ = std::io::stdin().read_line(&mut answer);
+    match answer.trim() {
+        "" => default.unwrap_or_default().to_owned(),
+        answer => answer.to_owned(),
+    }
+}
+
+fn get_install_source(answer: &str, config: &Config) -> Result<InstallSource, String> {
+    //! Tells an installer ISO, given by its path, from a cloud image, given
+    //! by its name in the image catalog. Nothing means a blank disk.
+    if answer.is_empty() {
+        return Ok(InstallSource::Blank);
+    }
+    if let Some(image) = get_catalog(config).remove(answer) {
+        return Ok(InstallSource::CloudImage(answer.to_owned(), image));
+    }
+    if Path::new(&*shellexpand::tilde(answer)).is_file() {
+        return Ok(InstallSource::Iso(answer.to_owned()));
+    }
+    Err(format!(
+        "'{answer}' is neither an installer ISO nor a cloud image. Run 'vm-manager image pull' to list the cloud images."
+    ))
+}
+
+pub fn create(
+    options: &CreateOptions,
+    config: &Config,
+    config_file: &str,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    //! Creates a new VM: its disk, blank or a copy of a cloud image, and its
+    //! entry in the config file, booting from an installer ISO if given one.
+    //! Whatever isn't given on the command line is asked for when run in a
+    //! terminal, including whether to start the VM.
+    let interactive: bool =
+        !options.yes && std::io::stdin().is_terminal() && std::io::stdout().is_terminal();
+    let name: String = match &options.name {
+        Some(name) => name.to_owned(),
+        None if interactive => ask("Name of the new VM:", None),
+        None => return Err("No name given! Must provide the name of the new VM.".to_owned()),
+    };
+    let path: PathBuf = get_new_image_path(&name, config)?;
+
+    let source: InstallSource = match (&options.iso, &options.cloud_image) {
+        (Some(iso), _) => get_install_source(iso, config)?,
+        (_, Some(cloud_image)) => get_install_source(cloud_image, config)?,
+        (None, None) if interactive => get_install_source(
+            &ask(
+                "Installer ISO (its path) or cloud image (e.g. 'ubuntu-24.04') to install from, if any:",
+                None,
+            ),
+            config,
+        )?,
+        (None, None) => InstallSource::Blank,
+    };
+    let size: String = match &options.size {
+        Some(size) => size.to_owned(),
+        None if interactive => ask("Disk size:", Some(DEFAULT_DISK_SIZE)),
+        None => DEFAULT_DISK_SIZE.to_owned(),
+    };
+    parse_size(&size).ok_or(format!("'{size}' is not a valid size."))?;
+    let memory: Option<String> = options.memory.clone().or_else(|| {
+        interactive
+            .then(|| ask("Memory, e.g. '8G' (nothing for the global options'):", None))
+            .filter(|memory| !memory.is_empty())
+    });
+    let cpus: Option<usize> = match options.cpus {
+        Some(cpus) => Some(cpus),
+        None if interactive => {
+            match ask("CPUs (nothing for the global options'):", None).as_str() {
+                "" => None,
+                cpus => Some(
+                    cpus.parse::<usize>()
+                        .map_err(|_| format!("'{cpus}' is not a number of CPUs."))?,
+                ),
+            }
+        }
+        None => None,
+    };
+
+    buffer.add_spacer();
+    match &source {
+        InstallSource::CloudImage(catalog_name, image) => {
+            pull_image(image, &path, !options.no_signature)?;
+            if is_shrinking(&size, get_image_info(&path)?.virtual_size)? {
+                buffer.addln(&format!(
+                    "Pulled '{catalog_name}' to '{}', keeping its size, as it's larger than {size}.",
+                    path.display()
+                ));
+            } else {
+                resize_image(&path, &size, false)?;
+                buffer.addln(&format!(
+                    "Pulled '{catalog_name}' to '{}' and grew it to {size}.",
+                    path.display()
+                ));
+            }
+        }
+        InstallSource::Blank | InstallSource::Iso(_) => {
+            create_image(&path, &size, ImageFormat::Qcow2)?;
+            buffer.addln(&format!(
+                "Created qcow2 image '{}' ({size}).",
+                path.display()
+            ));
+        }
+    }
+
+    let mut edits: Vec<VmConfigEdit> = vec![];
+    if let Some(memory) = memory {
+        edits.push(VmConfigEdit::Set("memory".to_owned(), memory.into()));
+    }
+    if let Some(cpus) = cpus {
+        edits.push(VmConfigEdit::Set("cpus".to_owned(), cpus.into()));
+    }
+    if let InstallSource::Iso(iso) = &source {
+        edits.push(VmConfigEdit::Set("cdrom".to_owned(), iso.as_str().into()));
+    }
+    config::add_vm_config_to_file(config_file, &name, None, &edits)?;
+    buffer.addln(&format!("Added VM '{name}' to '{config_file}'."));
+
+    if options.start || (interactive && confirm(&format!("Created VM '{name}'. Start it now?"))) {
+        // the config loaded before has no entry for the new VM yet.
+        let config: Config = Config::load_from_file(config_file).map_err(|e| e.to_string())?;
+        let args: Arguments =
+            Arguments::try_parse_from(["vm-manager", "-i", &name]).map_err(|e| e.to_string())?;
+        crate::run_command_start(&args, &StartOptions::default(), &config)?;
+        buffer.addln(&format!("Started VM '{name}'."));
+    }
+    if matches!(source, InstallSource::Iso(_)) {
+        buffer.addln(&format!(
+            "The VM boots from the installer once each time it starts. Once it's installed, run 'vm-manager config set {name} --unset cdrom'."
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_install_source, InstallSource};
+    use crate::config::Config;
+
+    #[test]
+    fn test_get_install_source() {
+        let config: Config = serde_yaml::from_str(
+            "global_qemu_options: []\nvms: []\nimage_catalog:\n  lab:\n    url: https://images.lab/lab.qcow2\n    checksums_url: https://images.lab/SHA256SUMS\n",
+        )
+        .unwrap();
+        assert_eq!(get_install_source("", &config), Ok(InstallSource::Blank));
+        assert!(matches!(
+            get_install_source("ubuntu-24.04", &config),
+            Ok(InstallSource::CloudImage(name, _)) if name == "ubuntu-24.04"
+        ));
+        assert!(matches!(
+            get_install_source("lab", &config),
+            Ok(InstallSource::CloudImage(_, image)) if image.url == "https://images.lab/lab.qcow2"
+        ));
+        assert_eq!(
+            get_install_source("Cargo.toml", &config),
+            Ok(InstallSource::Iso("Cargo.toml".to_owned()))
+        );
+        assert!(get_install_source("/nonexistent/installer.iso", &config).is_err());
+    }
+}
@@ -0,0 +1,566 @@
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs::{self, DirBuilder, OpenOptions, Permissions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::instance::{get_instance_vm_name, split_vm_name};
+use crate::parse_args::{Arguments, StartOptions};
+use crate::qemu_runner::{QemuRunner, StopOutcome};
+use crate::utils::{
+    get_daemon_socket_path, get_list_of_running_vms, PortClaims, RunningVmRecord, VmStatus,
+};
+use crate::{find_running_vm, matching, run_command_start, DAEMON_TOKEN_FILE};
+
+/// How long a connection may take to send its request, or to read the
+/// response, before it's dropped.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+/// The largest request body the daemon accepts.
+const MAXIMUM_BODY_SIZE: usize = 1 << 20;
+/// The largest start line and headers the daemon accepts, in bytes.
+const MAXIMUM_HEAD_SIZE: u64 = 16 << 10;
+/// The most headers the daemon accepts in a request.
+const MAXIMUM_HEADERS: usize = 64;
+/// The most connections the daemon answers at once. Any more are refused
+/// until one of them is answered.
+const MAXIMUM_CONNECTIONS: usize = 32;
+/// The environment variable holding the token `--remote` authenticates with
+/// over TCP.
+const TOKEN_VARIABLE: &str = "VM_MANAGER_TOKEN";
+
+/// An HTTP request, as read by the daemon.
+#[derive(Debug, PartialEq, Eq)]
+struct HttpRequest {
+    method: String,
+    path: String,
+    /// Header names are lowercased.
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// An HTTP response, whose body is always JSON.
+#[derive(Debug, PartialEq)]
+struct HttpResponse {
+    status: u16,
+    body: Value,
+}
+
+impl HttpResponse {
+    fn ok(body: Value) -> Self {
+        Self { status: 200, body }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self {
+            status,
+            body: json!({ "error": message }),
+        }
+    }
+}
+
+/// The body of `POST /vms/<name>/stop`.
+#[derive(Debug, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct StopRequest {
+    pub force: bool,
+    pub wait: bool,
+    /// Overrides the config's `shutdown_timeout`, in seconds.
+    pub timeout: Option<u64>,
+}
+
+fn read_head(reader: &mut impl BufRead) -> Result<(String, Vec<(String, String)>), String> {
+    //! Reads the start line and headers of an HTTP message, lowercasing the
+    //! header names. Refuses heads larger than `MAXIMUM_HEAD_SIZE`, or with
+    //! more than `MAXIMUM_HEADERS` headers.
+    let too_large: String = format!("The head is larger than {MAXIMUM_HEAD_SIZE} bytes.");
+    let mut lines = reader.by_ref().take(MAXIMUM_HEAD_SIZE).lines();
+    let start_line: String = lines
+        .next()
+        .ok_or("The connection closed before a request was sent.")?
+        .map_err(|e| e.to_string())?;
+    let mut headers: Vec<(String, String)> = vec![];
+    loop {
+        let line: String = lines
+            .next()
+            .ok_or(too_large.clone())?
+            .map_err(|e| e.to_string())?;
+        if line.trim().is_empty() {
+            break;
+        }
+        if headers.len() == MAXIMUM_HEADERS {
+            return Err(format!("There are more than {MAXIMUM_HEADERS} headers."));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or(format!("Invalid header '{line}'."))?;
+        headers.push((name.trim().to_lowercase(), value.trim().to_owned()));
+    }
+    Ok((start_line.trim_end().to_owned(), headers))
+}
+
+fn read_body(reader: &mut impl BufRead, headers: &[(String, String)]) -> Result<Vec<u8>, String> {
+    //! Reads the body of an HTTP message, as long as its `Content-Length`
+    //! says.
+    let length: usize = match headers.iter().find(|(name, _)| name == "content-length") {
+        Some((_, length)) => length
+            .parse()
+            .map_err(|_| format!("Invalid Content-Length '{length}'."))?,
+        None => 0,
+    };
+    if length > MAXIMUM_BODY_SIZE {
+        return Err(format!(
+            "The body is larger than {MAXIMUM_BODY_SIZE} bytes."
+        ));
+    }
+    let mut body: Vec<u8> = vec![0; length];
+    reader.read_exact(&mut body).map_err(|e| e.to_string())?;
+    Ok(body)
+}
+
+fn read_request(reader: &mut impl BufRead) -> Result<HttpRequest, String> {
+    let (request_line, headers) = read_head(reader)?;
+    let (method, path) = match request_line.split(' ').collect::<Vec<&str>>()[..] {
+        [method, path, _version] => (method.to_owned(), path.to_owned()),
+        _ => return Err(format!("Invalid request line '{request_line}'.")),
+    };
+    let body: Vec<u8> = read_body(reader, &headers)?;
+    Ok(HttpRequest {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+fn write_response(writer: &mut impl Write, response: &HttpResponse) -> std::io::Result<()> {
+    let body: String = response.body.to_string();
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        response.status,
+        reason_phrase(response.status),
+        body.len()
+    )?;
+    writer.flush()
+}
+
+fn to_json(value: impl Serialize) -> HttpResponse {
+    match serde_json::to_value(value) {
+        Ok(body) => HttpResponse::ok(body),
+        Err(e) => HttpResponse::error(500, &e.to_string()),
+    }
+}
+
+fn parse_body<T: for<'de> Deserialize<'de> + Default>(body: &[u8]) -> Result<T, String> {
+    //! Parses a JSON request body, an empty body meaning every default.
+    if body.iter().all(|byte| byte.is_ascii_whitespace()) {
+        return Ok(T::default());
+    }
+    serde_json::from_slice(body).map_err(|e| format!("Invalid request body. {e}"))
+}
+
+fn start_vm(vm_name: &str, body: &[u8], config: &Config) -> HttpResponse {
+    //! Starts a VM in the background, as `vm-manager start` does.
+    let options: StartOptions = match parse_body(body) {
+        Ok(options) => options,
+        Err(e) => return HttpResponse::error(400, &e),
+    };
+    let (image_name, instance) = split_vm_name(vm_name);
+    let mut args: Arguments = match Arguments::try_parse_from(["vm-manager", "-i", image_name]) {
+        Ok(args) => args,
+        Err(e) => return HttpResponse::error(400, &e.to_string()),
+    };
+    args.instance = instance.map(|instance| instance.to_owned());
+    if let Err(e) = run_command_start(&args, &options, config) {
+        return HttpResponse::error(400, &e);
+    }
+    match find_running_vm(image_name, instance) {
+        Ok(vm) => to_json(RunningVmRecord::from_runner(&vm, config)),
+        Err(_) => HttpResponse::ok(json!({})),
+    }
+}
+
+fn stop_vm(vm: &QemuRunner, body: &[u8], config: &Config) -> HttpResponse {
+    //! Stops a running VM, as `vm-manager stop` does. With `wait`, the
+    //! response tells how forcefully it had to be stopped.
+    let request: StopRequest = match parse_body(body) {
+        Ok(request) => request,
+        Err(e) => return HttpResponse::error(400, &e),
+    };
+    let timeout: Duration = request
+        .timeout
+        .map_or(config.get_shutdown_timeout(), Duration::from_secs);
+    if !request.wait {
+        return match vm.stop(request.force, timeout, config) {
+            Ok(()) => HttpResponse::ok(json!({ "image_name": vm.image_name() })),
+            Err(e) => HttpResponse::error(500, &e),
+        };
+    }
+    match vm.stop_and_wait(request.force, timeout, config) {
+        Ok(outcome) => HttpResponse::ok(json!({
+            "image_name": vm.image_name(),
+            "outcome": outcome.description(),
+            "exit_code": outcome.exit_code(),
+        })),
+        Err(e) => HttpResponse::error(500, &e),
+    }
+}
+
+fn handle_request(request: &HttpRequest, config: &Config) -> HttpResponse {
+    //! Routes a request to the endpoint it is for.
+    let path: &str = request.path.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let method: &str = request.method.as_str();
+    match segments[..] {
+        ["vms"] if method == "GET" => to_json(
+            get_list_of_running_vms()
+                .iter()
+                .map(|vm| RunningVmRecord::from_runner(vm, config))
+                .collect::<Vec<RunningVmRecord>>(),
+        ),
+        ["vms", vm_name, "start"] if method == "POST" => start_vm(vm_name, &request.body, config),
+        ["vms", vm_name] | ["vms", vm_name, "stop"] => {
+            let (image_name, instance) = split_vm_name(vm_name);
+            let vm: QemuRunner = match find_running_vm(image_name, instance) {
+                Ok(vm) => vm,
+                Err(e) => return HttpResponse::error(404, &e),
+            };
+            match (segments.len(), method) {
+                (2, "GET") => to_json(VmStatus::from_runner(&vm)),
+                (3, "POST") => stop_vm(&vm, &request.body, config),
+                _ => HttpResponse::error(405, &format!("{method} isn't allowed on '{path}'.")),
+            }
+        }
+        ["vms"] | ["vms", _, "start"] => {
+            HttpResponse::error(405, &format!("{method} isn't allowed on '{path}'."))
+        }
+        _ => HttpResponse::error(404, &format!("There is no endpoint '{path}'.")),
+    }
+}
+
+fn is_authorized(request: &HttpRequest, token: Option<&str>) -> bool {
+    //! Returns whether a request carries the token, if one is needed.
+    match token {
+        None => true,
+        Some(token) => request
+            .header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|given| {
+                // compared in full, so that timing doesn't reveal the token
+                given.len() == token.len()
+                    && given
+                        .bytes()
+                        .zip(token.bytes())
+                        .fold(0, |difference, (a, b)| difference | (a ^ b))
+                        == 0
+            }),
+    }
+}
+
+fn serve_connection<S: Read + Write>(mut stream: S, config: &Config, token: Option<&str>) {
+    //! Answers the single request sent on a connection.
+    let _claims: PortClaims = PortClaims;
+    let response: HttpResponse = {
+        let mut reader: BufReader<&mut S> = BufReader::new(&mut stream);
+        match read_request(&mut reader) {
+            Ok(request) if is_authorized(&request, token) => handle_request(&request, config),
+            Ok(_) => HttpResponse::error(401, "Missing or wrong token."),
+            Err(e) => HttpResponse::error(400, &e),
+        }
+    };
+    let _ = write_response(&mut stream, &response);
+}
+
+/// How many connections are being answered.
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// One of the `MAXIMUM_CONNECTIONS` connections answered at once, freed
+/// when dropped.
+struct ConnectionSlot;
+
+impl ConnectionSlot {
+    fn acquire() -> Option<Self> {
+        CONNECTIONS
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |connections| {
+                (connections < MAXIMUM_CONNECTIONS).then_some(connections + 1)
+            })
+            .ok()
+            .map(|_| ConnectionSlot)
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn spawn_connection<S: Read + Write + Send + 'static>(
+    mut stream: S,
+    config: &Arc<Config>,
+    token: Option<&Arc<String>>,
+) {
+    //! Answers a connection on its own thread, or refuses it while
+    //! `MAXIMUM_CONNECTIONS` others are being answered.
+    let Some(slot) = ConnectionSlot::acquire() else {
+        let response: HttpResponse =
+            HttpResponse::error(503, "Too many connections; try again later.");
+        let _ = write_response(&mut stream, &response);
+        return;
+    };
+    let (config, token) = (Arc::clone(config), token.map(Arc::clone));
+    thread::spawn(move || {
+        let _slot: ConnectionSlot = slot;
+        serve_connection(stream, &config, token.as_ref().map(|token| token.as_str()));
+    });
+}
+
+fn load_or_create_token() -> Result<String, String> {
+    //! Returns the token requests over TCP must carry, generating a random
+    //! one readable only by the current user if there is none yet.
+    let path: PathBuf = PathBuf::from(shellexpand::tilde(DAEMON_TOKEN_FILE).to_string());
+    if let Ok(token) = fs::read_to_string(&path) {
+        return Ok(token.trim().to_owned());
+    }
+    let mut random: [u8; 32] = [0; 32];
+    fs::File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut random))
+        .map_err(|e| format!("Unable to generate a token. {e}"))?;
+    let token: String = random.iter().map(|byte| format!("{byte:02x}")).collect();
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|mut file| file.write_all(token.as_bytes()))
+        .map_err(|e| format!("Unable to write token '{}'. {e}", path.display()))?;
+    Ok(token)
+}
+
+fn bind_socket() -> Result<UnixListener, String> {
+    //! Listens on the daemon's socket, replacing one left behind by a daemon
+    //! which is no longer running.
+    let socket_path: PathBuf = get_daemon_socket_path();
+    if socket_path.exists() {
+        if UnixStream::connect(&socket_path).is_ok() {
+            return Err(format!(
+                "A daemon is already listening on '{}'.",
+                socket_path.display()
+            ));
+        }
+        let _ = fs::remove_file(&socket_path);
+    }
+    // the socket is only chmodded once bound, so other users are kept from
+    // reaching it in the meantime by its directory.
+    if let Some(directory) = socket_path.parent() {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(directory)
+            .and_then(|_| fs::set_permissions(directory, Permissions::from_mode(0o700)))
+            .map_err(|e| {
+                format!(
+                    "Unable to create runtime directory '{}'. {e}",
+                    directory.display()
+                )
+            })?;
+    }
+    let listener: UnixListener = UnixListener::bind(&socket_path)
+        .map_err(|e| format!("Unable to listen on '{}'. {e}", socket_path.display()))?;
+    fs::set_permissions(&socket_path, Permissions::from_mode(0o600)).map_err(|e| e.to_string())?;
+    Ok(listener)
+}
+
+pub fn run_daemon(config: Config, listen: Option<&str>) -> Result<(), String> {
+    //! Serves the REST API on the daemon's socket, and on `listen` if given,
+    //! answering each connection on its own thread, up to
+    //! `MAXIMUM_CONNECTIONS` at once. Runs until interrupted.
+    matching::disable_picker();
+    let config: Arc<Config> = Arc::new(config);
+    let listener: UnixListener = bind_socket()?;
+    println!("Listening on '{}'.", get_daemon_socket_path().display());
+
+    if let Some(address) = listen {
+        let token: Arc<String> = Arc::new(load_or_create_token()?);
+        let tcp_listener: TcpListener = TcpListener::bind(address)
+            .map_err(|e| format!("Unable to listen on '{address}'. {e}"))?;
+        println!("Listening on '{address}', with the token in '{DAEMON_TOKEN_FILE}'.");
+        let config: Arc<Config> = Arc::clone(&config);
+        thread::spawn(move || {
+            for stream in tcp_listener.incoming().filter_map(|stream| stream.ok()) {
+                let _ = stream.set_read_timeout(Some(CONNECTION_TIMEOUT));
+                let _ = stream.set_write_timeout(Some(CONNECTION_TIMEOUT));
+                spawn_connection(stream, &config, Some(&token));
+            }
+        });
+    }
+
+    for stream in listener.incoming() {
+        let stream: UnixStream = stream.map_err(|e| e.to_string())?;
+        let _ = stream.set_read_timeout(Some(CONNECTION_TIMEOUT));
+        let _ = stream.set_write_timeout(Some(CONNECTION_TIMEOUT));
+        spawn_connection(stream, &config, None);
+    }
+    Ok(())
+}
+
+fn exchange<S: Read + Write>(mut stream: S, request: &[u8]) -> Result<HttpResponse, String> {
+    //! Sends a request to the daemon and reads its response.
+    stream
+        .write_all(request)
+        .map_err(|e| format!("Unable to send a request to the daemon. {e}"))?;
+    let mut reader: BufReader<S> = BufReader::new(stream);
+    let (status_line, headers) = read_head(&mut reader)?;
+    let status: u16 = status_line
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or(format!("Invalid response from the daemon '{status_line}'."))?;
+    let body: Vec<u8> = read_body(&mut reader, &headers)?;
+    let body: Value = serde_json::from_slice(&body)
+        .map_err(|e| format!("Invalid response from the daemon. {e}"))?;
+    Ok(HttpResponse { status, body })
+}
+
+pub fn send_request(
+    remote: &str,
+    method: &str,
+    path: &str,
+    body: Option<Value>,
+) -> Result<Value, String> {
+    //! Sends a request to a running daemon, over its socket if `remote` is
+    //! empty, or else over TCP to the address `remote`. Returns the body of
+    //! a successful response, or the error the daemon answered with.
+    let body: String = body.map_or(String::new(), |body| body.to_string());
+    let mut request: String = format!(
+        "{method} {path} HTTP/1.1\r\nHost: vm-manager\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+        body.len()
+    );
+    let response: HttpResponse = if remote.is_empty() {
+        request.push_str(&format!("\r\n{body}"));
+        let socket_path: PathBuf = get_daemon_socket_path();
+        let stream: UnixStream = UnixStream::connect(&socket_path).map_err(|e| {
+            format!(
+                "Unable to connect to the daemon at '{}'; is 'vm-manager daemon' running? {e}",
+                socket_path.display()
+            )
+        })?;
+        exchange(stream, request.as_bytes())?
+    } else {
+        let token: String = match std::env::var(TOKEN_VARIABLE) {
+            Ok(token) => token,
+            Err(_) => fs::read_to_string(shellexpand::tilde(DAEMON_TOKEN_FILE).to_string())
+                .map(|token| token.trim().to_owned())
+                .map_err(|e| {
+                    format!("Unable to read the daemon's token from '{DAEMON_TOKEN_FILE}'; set ${TOKEN_VARIABLE} instead. {e}")
+                })?,
+        };
+        request.push_str(&format!("Authorization: Bearer {token}\r\n\r\n{body}"));
+        let stream: TcpStream = TcpStream::connect(remote)
+            .map_err(|e| format!("Unable to connect to the daemon at '{remote}'. {e}"))?;
+        exchange(stream, request.as_bytes())?
+    };
+    if response.status == 200 {
+        Ok(response.body)
+    } else {
+        Err(response.body["error"].as_str().map_or(
+            format!("The daemon answered with status {}.", response.status),
+            |e| e.to_owned(),
+        ))
+    }
+}
+
+pub fn get_remote_vm_name(image: Option<&str>, instance: Option<&str>) -> Result<String, String> {
+    //! Returns how the daemon's endpoints name the VM given with
+    //! `-i`/`--image` and `--instance`.
+    let image_name: &str = image.ok_or("No image provided! Must provide an image name.")?;
+    Ok(match instance {
+        Some(instance) => get_instance_vm_name(image_name, instance),
+        None => image_name.to_owned(),
+    })
+}
+
+pub fn stop_outcome_exit_code(response: &Value) -> i32 {
+    //! Returns the exit code `vm-manager stop --wait` reports for the
+    //! outcome the daemon answered with.
+    response["exit_code"]
+        .as_i64()
+        .map_or(StopOutcome::PoweredDown.exit_code(), |code| code as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{handle_request, is_authorized, read_request, HttpRequest};
+    use crate::config::Config;
+    use std::io::BufReader;
+
+    #[test]
+    fn test_read_request() {
+        let raw: &[u8] = b"POST /vms/deb12@web1/stop HTTP/1.1\r\nHost: x\r\nContent-Length: 14\r\nAuthorization: Bearer abc\r\n\r\n{\"wait\": true}";
+        let request: HttpRequest = read_request(&mut BufReader::new(raw)).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/vms/deb12@web1/stop");
+        assert_eq!(request.body, b"{\"wait\": true}");
+        assert!(is_authorized(&request, Some("abc")));
+        assert!(!is_authorized(&request, Some("abd")));
+        assert!(is_authorized(&request, None));
+
+        assert!(read_request(&mut BufReader::new(&b"GET /vms\r\n\r\n"[..])).is_err());
+        assert!(read_request(&mut BufReader::new(
+            &b"POST /vms HTTP/1.1\r\nContent-Length: 99999999\r\n\r\n"[..]
+        ))
+        .is_err());
+        let long_header: String =
+            format!("GET /vms HTTP/1.1\r\nX: {}\r\n\r\n", "x".repeat(1 << 20));
+        assert!(read_request(&mut BufReader::new(long_header.as_bytes())).is_err());
+        let many_headers: String = format!("GET /vms HTTP/1.1\r\n{}\r\n", "X: x\r\n".repeat(65));
+        assert!(read_request(&mut BufReader::new(many_headers.as_bytes())).is_err());
+        assert!(read_request(&mut BufReader::new(&b"GET /vms HTTP/1.1\r\nX: x"[..])).is_err());
+
+        let config: Config = serde_yaml::from_str("global_qemu_options: []\nvms: []\n").unwrap();
+        let request = |method: &str, path: &str| HttpRequest {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            headers: vec![],
+            body: vec![],
+        };
+        assert_eq!(
+            handle_request(&request("GET", "/images"), &config).status,
+            404
+        );
+        assert_eq!(
+            handle_request(&request("DELETE", "/vms"), &config).status,
+            405
+        );
+    }
+}
@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+
+use crate::utils::find_open_port;
+
+/// First TCP port used by VNC displays; display `N` listens on `5900 + N`.
+const VNC_BASE_PORT: usize = 5900;
+/// First TCP port tried for SPICE displays.
+const SPICE_BASE_PORT: usize = 5930;
+/// Address remote displays are bound to. Use an SSH tunnel to reach them
+/// from another machine.
+const DISPLAY_ADDRESS: &str = "127.0.0.1";
+
+/// How a VM's graphical display is exposed.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DisplayType {
+    /// Headless, i.e. `-vnc none`.
+    #[default]
+    None,
+    /// A VNC server on the next free display number.
+    Vnc,
+    /// A SPICE server on the next free port.
+    Spice,
+    /// A GTK window on the host.
+    Gtk,
+}
+
+impl DisplayType {
+    pub fn qemu_args(&self) -> Vec<String> {
+        //! Returns the qemu arguments configuring this display, choosing a
+        //! free VNC display number or SPICE port if needed.
+        match self {
+            DisplayType::None => vec!["-vnc".to_owned(), "none".to_owned()],
+            DisplayType::Vnc => {
+                let display_number: usize = find_open_port(VNC_BASE_PORT) - VNC_BASE_PORT;
+                vec![
+                    "-vnc".to_owned(),
+                    format!("{DISPLAY_ADDRESS}:{display_number}"),
+                ]
+            }
+            DisplayType::Spice => {
+                let port: usize = find_open_port(SPICE_BASE_PORT);
+                vec![
+                    "-vnc".to_owned(),
+                    "none".to_owned(),
+                    "-spice".to_owned(),
+                    format!("port={port},addr={DISPLAY_ADDRESS},disable-ticketing=on"),
+                ]
+            }
+            DisplayType::Gtk => vec!["-display".to_owned(), "gtk".to_owned()],
+        }
+    }
+}
+
+pub fn is_display_option(option: &str) -> bool {
+    //! Returns `true` for qemu options which configure the display, and so
+    //! are replaced by a VM's `display` setting.
+    ["-vnc", "-spice", "-display"]
+        .iter()
+        .any(|prefix| option.starts_with(prefix))
+}
+
+pub fn get_display_url(args: &[String]) -> Option<String> {
+    //! Returns a `vnc://` or `spice://` URL for the display of a VM running
+    //! with the given command line, or `None` if it has no remote display.
+    let value_of = |flag: &str| -> Option<&String> {
+        args.iter()
+            .position(|arg| arg == flag)
+            .and_then(|position| args.get(position + 1))
+    };
+
+    if let Some(spice) = value_of("-spice") {
+        let property = |key: &str| -> Option<&str> {
+            spice.split(',').find_map(|part| part.strip_prefix(key))
+        };
+        let port: &str = property("port=")?;
+        let address: &str = property("addr=").unwrap_or(DISPLAY_ADDRESS);
+        return Some(format!("spice://{address}:{port}"));
+    }
+
+    let vnc: &str = value_of("-vnc")?.split(',').next()?;
+    let (address, display_number) = vnc.rsplit_once(':')?;
+    let port: usize = VNC_BASE_PORT + display_number.parse::<usize>().ok()?;
+    let address: &str = if address.is_empty() {
+        DISPLAY_ADDRESS
+    } else {
+        address
+    };
+    Some(format!("vnc://{address}:{port}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_display_url, is_display_option, DisplayType};
+
+    fn to_strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_get_display_url() {
+        assert_eq!(
+            get_display_url(&to_strings(&["qemu-system-x86_64", "-vnc", "127.0.0.1:3"])),
+            Some("vnc://127.0.0.1:5903".to_owned())
+        );
+        assert_eq!(
+            get_display_url(&to_strings(&[
+                "qemu-system-x86_64",
+                "-vnc",
+                ":0,password=on"
+            ])),
+            Some("vnc://127.0.0.1:5900".to_owned())
+        );
+        assert_eq!(
+            get_display_url(&to_strings(&[
+                "qemu-system-x86_64",
+                "-vnc",
+                "none",
+                "-spice",
+                "port=5931,addr=127.0.0.1,disable-ticketing=on"
+            ])),
+            Some("spice://127.0.0.1:5931".to_owned())
+        );
+        assert_eq!(
+            get_display_url(&to_strings(&["qemu-system-x86_64", "-vnc", "none"])),
+            None
+        );
+        assert_eq!(
+            get_display_url(&to_strings(&["qemu-system-x86_64", "-display", "gtk"])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_display_type() {
+        assert_eq!(
+            serde_yaml::from_str::<DisplayType>("spice").unwrap(),
+            DisplayType::Spice
+        );
+        assert_eq!(DisplayType::None.qemu_args(), vec!["-vnc", "none"]);
+        assert!(is_display_option("-vnc none"));
+        assert!(is_display_option("-display gtk"));
+        assert!(!is_display_option("-m 8G"));
+    }
+}
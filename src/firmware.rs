@@ -0,0 +1,174 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::NVRAM_DIRECTORY;
+
+/// OVMF builds shipped by common distributions, as (code, vars template)
+/// pairs, in order of preference.
+const OVMF_FIRMWARE: &[(&str, &str)] = &[
+    // Debian, Ubuntu
+    (
+        "/usr/share/OVMF/OVMF_CODE_4M.fd",
+        "/usr/share/OVMF/OVMF_VARS_4M.fd",
+    ),
+    (
+        "/usr/share/OVMF/OVMF_CODE.fd",
+        "/usr/share/OVMF/OVMF_VARS.fd",
+    ),
+    // Fedora
+    (
+        "/usr/share/edk2/ovmf/OVMF_CODE.fd",
+        "/usr/share/edk2/ovmf/OVMF_VARS.fd",
+    ),
+    // Arch
+    (
+        "/usr/share/edk2/x64/OVMF_CODE.4m.fd",
+        "/usr/share/edk2/x64/OVMF_VARS.4m.fd",
+    ),
+];
+
+/// Secure Boot enabled OVMF builds, paired with a vars template which has
+/// the Microsoft keys enrolled, so that signed shims and kernels boot.
+const OVMF_SECURE_BOOT_FIRMWARE: &[(&str, &str)] = &[
+    // Debian, Ubuntu
+    (
+        "/usr/share/OVMF/OVMF_CODE_4M.secboot.fd",
+        "/usr/share/OVMF/OVMF_VARS_4M.ms.fd",
+    ),
+    (
+        "/usr/share/OVMF/OVMF_CODE.secboot.fd",
+        "/usr/share/OVMF/OVMF_VARS.ms.fd",
+    ),
+    // Fedora
+    (
+        "/usr/share/edk2/ovmf/OVMF_CODE.secboot.fd",
+        "/usr/share/edk2/ovmf/OVMF_VARS.secboot.fd",
+    ),
+];
+
+/// A UEFI firmware build found on the host.
+/// # Attributes:
+/// * code - The read-only firmware code.
+/// * vars_template - The initial UEFI variable store, copied for each VM.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Firmware {
+    pub code: PathBuf,
+    pub vars_template: PathBuf,
+}
+
+pub fn find_firmware(secure_boot: bool) -> Option<Firmware> {
+    //! Returns the first OVMF build installed on the host, either with or
+    //! without Secure Boot.
+    get_searched_firmware(secure_boot)
+        .iter()
+        .map(|(code, vars_template)| Firmware {
+            code: PathBuf::from(code),
+            vars_template: PathBuf::from(vars_template),
+        })
+        .find(|firmware| firmware.code.is_file() && firmware.vars_template.is_file())
+}
+
+pub fn get_vars_path(vm_name: &str, secure_boot: bool) -> PathBuf {
+    //! Returns the path of a VM's own UEFI variable store. Secure Boot uses a
+    //! separate store, as its template holds the enrolled keys.
+    let file_name: String = if secure_boot {
+        format!("{vm_name}.secboot.fd")
+    } else {
+        format!("{vm_name}.fd")
+    };
+    PathBuf::from(shellexpand::tilde(&format!("{NVRAM_DIRECTORY}/{file_name}")).to_string())
+}
+
+fn create_vars(vars_template: &Path, vars_path: &Path) -> Result<(), String> {
+    //! Copies the vars template to a VM's variable store, unless it already
+    //! has one, in which case the boot entries and keys it holds are kept.
+    if vars_path.is_file() {
+        return Ok(());
+    }
+    if let Some(directory) = vars_path.parent() {
+        fs::create_dir_all(directory).map_err(|e| {
+            format!(
+                "Unable to create NVRAM directory '{}'. {e}",
+                directory.display()
+            )
+        })?;
+    }
+    fs::copy(vars_template, vars_path).map_err(|e| {
+        format!(
+            "Unable to copy UEFI vars template '{}' to '{}'. {e}",
+            vars_template.display(),
+            vars_path.display()
+        )
+    })?;
+    Ok(())
+}
+
+pub fn firmware_qemu_args(firmware: &Firmware, vars_path: &Path, secure_boot: bool) -> Vec<String> {
+    //! Returns the arguments booting the guest with the given firmware. With
+    //! Secure Boot, the flash is only writable from SMM, so that the guest OS
+    //! can't tamper with the enrolled keys.
+    let mut args: Vec<String> = vec![];
+    if secure_boot {
+        args.push("-global".to_owned());
+        args.push("driver=cfi.pflash01,property=secure,value=on".to_owned());
+    }
+    args.push("-drive".to_owned());
+    args.push(format!(
+        "if=pflash,format=raw,unit=0,readonly=on,file={}",
+        firmware.code.display()
+    ));
+    args.push("-drive".to_owned());
+    args.push(format!(
+        "if=pflash,format=raw,unit=1,file={}",
+        vars_path.display()
+    ));
+    args
+}
+
+pub fn prepare_firmware(vm_name: &str, secure_boot: bool) -> Result<Vec<String>, String> {
+    //! Finds the OVMF build to boot a VM with, creates the VM's variable
+    //! store if it has none yet, and returns the qemu arguments using them.
+    let firmware: Firmware = find_firmware(secure_boot).ok_or(if secure_boot {
+        "Unable to find a Secure Boot enabled OVMF build. Install OVMF (e.g. the 'ovmf' or 'edk2-ovmf' package); 'vm-manager doctor' lists the paths searched."
+    } else {
+        "Unable to find an OVMF build. Install OVMF (e.g. the 'ovmf' or 'edk2-ovmf' package); 'vm-manager doctor' lists the paths searched."
+    })?;
+    let vars_path: PathBuf = get_vars_path(vm_name, secure_boot);
+    create_vars(&firmware.vars_template, &vars_path)?;
+    Ok(firmware_qemu_args(&firmware, &vars_path, secure_boot))
+}
+
+pub fn get_searched_firmware(secure_boot: bool) -> &'static [(&'static str, &'static str)] {
+    //! Returns the (code, vars template) pairs searched for OVMF.
+    if secure_boot {
+        OVMF_SECURE_BOOT_FIRMWARE
+    } else {
+        OVMF_FIRMWARE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{firmware_qemu_args, get_vars_path, Firmware};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_firmware_qemu_args() {
+        let firmware: Firmware = Firmware {
+            code: PathBuf::from("/usr/share/OVMF/OVMF_CODE_4M.secboot.fd"),
+            vars_template: PathBuf::from("/usr/share/OVMF/OVMF_VARS_4M.ms.fd"),
+        };
+        let vars_path: PathBuf = get_vars_path("win11", true);
+        assert!(vars_path.ends_with("win11.secboot.fd"));
+        assert_ne!(vars_path, get_vars_path("win11", false));
+
+        let args: Vec<String> = firmware_qemu_args(&firmware, &vars_path, true);
+        assert_eq!(args[0], "-global");
+        assert_eq!(
+            args[3],
+            "if=pflash,format=raw,unit=0,readonly=on,file=/usr/share/OVMF/OVMF_CODE_4M.secboot.fd"
+        );
+        assert!(args[5].starts_with("if=pflash,format=raw,unit=1,file="));
+        assert_eq!(firmware_qemu_args(&firmware, &vars_path, false).len(), 4);
+    }
+}
@@ -0,0 +1,595 @@
+use clap::Parser;
+use std::cmp::max;
+use std::io::{ErrorKind, Read};
+use std::net::{SocketAddr, TcpStream};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, GroupMember, ReadyCondition};
+use crate::parse_args::{Arguments, StartOptions};
+use crate::qemu_runner::{QemuRunner, StopOutcome};
+use crate::run_command_start;
+use crate::ssh;
+use crate::utils::{find_image, get_list_of_running_vms, OutputStream};
+
+/// How many seconds a VM of a group may take to become ready, unless its
+/// `ready` condition sets a `timeout`.
+const DEFAULT_READY_TIMEOUT: u64 = 300;
+/// How long a single attempt to connect to a forwarded port may take.
+const PORT_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long to wait between attempts to connect to a forwarded port.
+const PORT_PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What became of a VM of a group when the group was brought up or down.
+#[derive(Debug, Eq, PartialEq, Clone)]
+enum MemberOutcome {
+    Started,
+    AlreadyRunning,
+    Restarted,
+    /// Stopped, with how it came to exit if it was waited for.
+    Stopped(Option<StopOutcome>),
+    NotRunning,
+    Failed(String),
+    /// Not started or restarted, as the VM named here isn't running and
+    /// ready.
+    Skipped(String),
+}
+
+impl MemberOutcome {
+    fn description(&self) -> String {
+        match self {
+            Self::Started => "started".to_owned(),
+            Self::AlreadyRunning => "already running".to_owned(),
+            Self::Restarted => "restarted".to_owned(),
+            Self::Stopped(None) => "stopped".to_owned(),
+            Self::Stopped(Some(outcome)) => outcome.description().to_owned(),
+            Self::NotRunning => "not running".to_owned(),
+            Self::Failed(e) => format!("failed: {e}"),
+            Self::Skipped(name) => format!("skipped, as '{name}' isn't ready"),
+        }
+    }
+
+    fn is_failure(&self) -> bool {
+        matches!(self, Self::Failed(_) | Self::Skipped(_))
+    }
+}
+
+fn find_running_member(name: &str) -> Option<QemuRunner> {
+    //! Returns the running VM called `name`, leaving out instances of it.
+    get_list_of_running_vms()
+        .into_iter()
+        .find(|vm| vm.instance().is_none() && vm.base_image_name() == name)
+}
+
+fn start_member(
+    name: &str,
+    instance: Option<&str>,
+    options: &StartOptions,
+    config: &Config,
+) -> Result<(), String> {
+    //! Starts a VM in the background, as `vm-manager start` does.
+    let mut args: Arguments =
+        Arguments::try_parse_from(["vm-manager", "-i", name]).map_err(|e| e.to_string())?;
+    args.instance = instance.map(|instance| instance.to_owned());
+    run_command_start(&args, options, config)
+}
+
+fn start_concurrently(
+    vms: &[(String, Vec<String>)],
+    start: impl Fn(usize) -> MemberOutcome + Sync,
+) -> Vec<(String, MemberOutcome)> {
+    //! Starts VMs, each given by its name and the names of the VMs it depends
+    //! on, with `start(index)`. Every VM whose dependencies are ready is
+    //! started at once, on a thread of its own, and a VM depending on one
+    //! which failed is skipped. Returns what became of each VM, in order.
+    let mut outcomes: Vec<Option<MemberOutcome>> = vec![None; vms.len()];
+    loop {
+        let outcome_of = |name: &str| {
+            vms.iter()
+                .position(|(other, _)| other == name)
+                .and_then(|index| outcomes[index].clone())
+        };
+        let mut startable: Vec<usize> = vec![];
+        let mut skipped: Vec<(usize, MemberOutcome)> = vec![];
+        for (index, (_, dependencies)) in vms.iter().enumerate() {
+            if outcomes[index].is_some() {
+                continue;
+            }
+            if let Some(failed) = dependencies.iter().find(|dependency| {
+                outcome_of(dependency).is_some_and(|outcome| outcome.is_failure())
+            }) {
+                skipped.push((index, MemberOutcome::Skipped(failed.to_owned())));
+            } else if dependencies
+                .iter()
+                .all(|dependency| outcome_of(dependency).is_some())
+            {
+                startable.push(index);
+            }
+        }
+        if startable.is_empty() && skipped.is_empty() {
+            break;
+        }
+        let start = &start;
+        let started: Vec<(usize, MemberOutcome)> = thread::scope(|scope| {
+            let threads: Vec<_> = startable
+                .iter()
+                .map(|&index| (index, scope.spawn(move || start(index))))
+                .collect();
+            threads
+                .into_iter()
+                .map(|(index, thread)| {
+                    let outcome: MemberOutcome = thread.join().unwrap_or_else(|_| {
+                        MemberOutcome::Failed("Starting the VM panicked.".to_owned())
+                    });
+                    (index, outcome)
+                })
+                .collect()
+        });
+        for (index, outcome) in skipped.into_iter().chain(started) {
+            outcomes[index] = Some(outcome);
+        }
+    }
+    vms.iter()
+        .zip(outcomes)
+        .map(|((name, _), outcome)| {
+            (
+                name.to_owned(),
+                outcome.unwrap_or(MemberOutcome::Failed(
+                    "It depends on VMs in a cycle.".to_owned(),
+                )),
+            )
+        })
+        .collect()
+}
+
+fn is_port_open(port: usize) -> bool {
+    //! Returns whether the guest accepts connections on the forwarded host
+    //! `port`. qemu's user-mode networking accepts the connection before the
+    //! guest does, and closes it right away if the guest refuses it, so the
+    //! connection has to stay open for a moment too.
+    let address: SocketAddr = match format!("127.0.0.1:{port}").parse() {
+        Ok(address) => address,
+        Err(_) => return false,
+    };
+    let mut stream: TcpStream = match TcpStream::connect_timeout(&address, PORT_PROBE_TIMEOUT) {
+        Ok(stream) => stream,
+        Err(_) => return false,
+    };
+    if stream.set_read_timeout(Some(PORT_PROBE_TIMEOUT)).is_err() {
+        return false;
+    }
+    let mut byte: [u8; 1] = [0; 1];
+    match stream.read(&mut byte) {
+        Ok(read) => read > 0,
+        Err(e) => matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut),
+    }
+}
+
+fn wait_until_ready(name: &str, ready: &ReadyCondition) -> Result<(), String> {
+    //! Waits for the running VM called `name` to meet its `ready` condition.
+    let vm: QemuRunner =
+        find_running_member(name).ok_or(format!("VM '{name}' is no longer running."))?;
+    let forwarded_port = |guest_port: usize| {
+        vm.forwarded_host_port(guest_port).ok_or(format!(
+            "VM '{name}' does not forward any host port to guest port {guest_port}."
+        ))
+    };
+    let timeout: Duration = Duration::from_secs(ready.timeout.unwrap_or(DEFAULT_READY_TIMEOUT));
+    let started_waiting: Instant = Instant::now();
+    if let Some(guest_port) = ready.port {
+        let port: usize = forwarded_port(guest_port)?;
+        while !is_port_open(port) {
+            if started_waiting.elapsed() > timeout {
+                return Err(format!(
+                    "Guest port {guest_port} didn't accept connections within {} seconds.",
+                    timeout.as_secs()
+                ));
+            }
+            sleep(PORT_PROBE_INTERVAL);
+        }
+    }
+    if ready.ssh {
+        ssh::wait_for_ssh(
+            forwarded_port(22)?,
+            timeout.saturating_sub(started_waiting.elapsed()),
+        )?;
+    }
+    Ok(())
+}
+
+fn bring_up(member: &GroupMember, config: &Config) -> MemberOutcome {
+    //! Starts a VM of a group unless it's already running, and waits for it
+    //! to be ready.
+    let outcome: MemberOutcome = if find_running_member(member.name()).is_some() {
+        MemberOutcome::AlreadyRunning
+    } else {
+        match start_member(member.name(), None, &StartOptions::default(), config) {
+            Ok(()) => MemberOutcome::Started,
+            Err(e) => return MemberOutcome::Failed(e),
+        }
+    };
+    match member
+        .ready()
+        .map(|ready| wait_until_ready(member.name(), ready))
+    {
+        Some(Err(e)) => MemberOutcome::Failed(e),
+        _ => outcome,
+    }
+}
+
+fn restart_member(member: &GroupMember, vm: &QemuRunner, config: &Config) -> MemberOutcome {
+    //! Restarts a running VM of a group with the command line it was running
+    //! with, and waits for it to be ready again.
+    if let Err(e) = vm.restart(config.get_shutdown_timeout(), config) {
+        return MemberOutcome::Failed(e);
+    }
+    match member
+        .ready()
+        .map(|ready| wait_until_ready(member.name(), ready))
+    {
+        Some(Err(e)) => MemberOutcome::Failed(e),
+        _ => MemberOutcome::Restarted,
+    }
+}
+
+fn restart_in_batches(
+    vms: &[&str],
+    max_unavailable: usize,
+    restart: impl Fn(usize) -> MemberOutcome + Sync,
+) -> Vec<MemberOutcome> {
+    //! Restarts VMs, given by name, with `restart(index)`, `max_unavailable`
+    //! at a time on threads of their own. Once a VM failed to restart, those
+    //! of the batches after it are skipped. Returns what became of each VM,
+    //! in order.
+    let mut outcomes: Vec<MemberOutcome> = vec![];
+    let mut failed: Option<&str> = None;
+    for (batch, names) in vms.chunks(max_unavailable).enumerate() {
+        if let Some(failed) = failed {
+            outcomes.extend(
+                names
+                    .iter()
+                    .map(|_| MemberOutcome::Skipped(failed.to_owned())),
+            );
+            continue;
+        }
+        let first: usize = batch * max_unavailable;
+        let restarted: Vec<MemberOutcome> = thread::scope(|scope| {
+            let threads: Vec<_> = (first..first + names.len())
+                .map(|index| {
+                    let restart = &restart;
+                    scope.spawn(move || restart(index))
+                })
+                .collect();
+            threads
+                .into_iter()
+                .map(|thread| {
+                    thread.join().unwrap_or_else(|_| {
+                        MemberOutcome::Failed("Restarting the VM panicked.".to_owned())
+                    })
+                })
+                .collect()
+        });
+        failed = names
+            .iter()
+            .zip(&restarted)
+            .find(|(_, outcome)| outcome.is_failure())
+            .map(|(name, _)| *name);
+        outcomes.extend(restarted);
+    }
+    outcomes
+}
+
+fn render_summary(outcomes: &[(String, MemberOutcome)]) -> Vec<String> {
+    //! Renders what became of each VM of a group as a table.
+    let name_width: usize = max(
+        outcomes
+            .iter()
+            .map(|(name, _)| name.len())
+            .max()
+            .unwrap_or(0),
+        "VM".len(),
+    );
+    let mut lines: Vec<String> = vec![
+        format!("{:name_width$} | Status", "VM"),
+        format!("{:-<name_width$}-+-------", ""),
+    ];
+    for (name, outcome) in outcomes {
+        lines.push(format!("{name:name_width$} | {}", outcome.description()));
+    }
+    lines
+}
+
+fn report(
+    vms: &str,
+    action: &str,
+    outcomes: &[(String, MemberOutcome)],
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    //! Prints the summary of starting or stopping VMs, described by `vms` in
+    //! errors. Returns an error if any of them failed to be.
+    buffer.add_spacer();
+    for line in render_summary(outcomes) {
+        buffer.addln(&line);
+    }
+    let num_failed: usize = outcomes
+        .iter()
+        .filter(|(_, outcome)| outcome.is_failure())
+        .count();
+    if num_failed > 0 {
+        Err(format!(
+            "{num_failed} of {} {vms} failed to {action}.",
+            outcomes.len()
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn up(group: &str, config: &Config, buffer: &mut OutputStream) -> Result<(), String> {
+    //! Starts every VM of a group which isn't already running, at once
+    //! except that each VM waits for the VMs it depends on to be ready. A VM
+    //! failing to start only keeps the VMs depending on it from being
+    //! started.
+    let members: Vec<&GroupMember> = config.get_group(group)?;
+    let vms: Vec<(String, Vec<String>)> = members
+        .iter()
+        .map(|member| (member.name().to_owned(), member.depends_on().to_vec()))
+        .collect();
+    let outcomes: Vec<(String, MemberOutcome)> =
+        start_concurrently(&vms, |index| bring_up(members[index], config));
+    report(
+        &format!("VMs of group '{group}'"),
+        "start",
+        &outcomes,
+        buffer,
+    )
+}
+
+pub fn start_all(
+    args: &Arguments,
+    options: &StartOptions,
+    config: &Config,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    //! Starts every image given with -i at once, each on a thread of its own,
+    //! and reports what became of each of them.
+    if args.foreground {
+        return Err("Several VMs can only be started in the background.".to_owned());
+    }
+    if args.ssh_port.is_some() || args.https_port.is_some() {
+        return Err(
+            "-p/--ssh-port and -s/--https-port can't be given when starting several VMs, as each needs ports of its own."
+                .to_owned(),
+        );
+    }
+    let mut vms: Vec<(String, Vec<String>)> = vec![];
+    for image in &args.images {
+        let (name, _) = find_image(image, config)?;
+        if vms.iter().any(|(other, _)| *other == name) {
+            return Err(format!("Image '{name}' is given more than once."));
+        }
+        vms.push((name, vec![]));
+    }
+    let outcomes: Vec<(String, MemberOutcome)> =
+        start_concurrently(&vms, |index| {
+            match start_member(&vms[index].0, args.instance.as_deref(), options, config) {
+                Ok(()) => MemberOutcome::Started,
+                Err(e) => MemberOutcome::Failed(e),
+            }
+        });
+    report("VMs", "start", &outcomes, buffer)
+}
+
+pub fn rolling_restart(
+    group: &str,
+    config: &Config,
+    max_unavailable: usize,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    //! Restarts the running VMs of a group `max_unavailable` at a time, in the
+    //! order they're started in, each batch only once the one before it is
+    //! ready again. A VM failing to restart or become ready stops the
+    //! restart, so that no more of the group is taken down.
+    let members: Vec<&GroupMember> = config.get_group(group)?;
+    let mut outcomes: Vec<Option<MemberOutcome>> = vec![None; members.len()];
+    let mut running: Vec<(usize, QemuRunner)> = vec![];
+    for (index, member) in members.iter().enumerate() {
+        match find_running_member(member.name()) {
+            Some(vm) => running.push((index, vm)),
+            None => outcomes[index] = Some(MemberOutcome::NotRunning),
+        }
+    }
+    let names: Vec<&str> = running
+        .iter()
+        .map(|(index, _)| members[*index].name())
+        .collect();
+    let restarted: Vec<MemberOutcome> = restart_in_batches(&names, max_unavailable, |i| {
+        let (index, vm) = &running[i];
+        restart_member(members[*index], vm, config)
+    });
+    for ((index, _), outcome) in running.iter().zip(restarted) {
+        outcomes[*index] = Some(outcome);
+    }
+    let outcomes: Vec<(String, MemberOutcome)> = members
+        .iter()
+        .zip(outcomes)
+        .map(|(member, outcome)| {
+            (
+                member.name().to_owned(),
+                outcome.unwrap_or(MemberOutcome::NotRunning),
+            )
+        })
+        .collect();
+    report(
+        &format!("VMs of group '{group}'"),
+        "restart",
+        &outcomes,
+        buffer,
+    )
+}
+
+pub fn down(
+    group: &str,
+    config: &Config,
+    force: bool,
+    wait: bool,
+    timeout: Duration,
+    buffer: &mut OutputStream,
+) -> Result<i32, String> {
+    //! Stops every running VM of a group, in the reverse of the order they're
+    //! started in. A VM depending on others has exited before they're
+    //! stopped. Returns the exit code to report, which with `wait` is that of
+    //! the most forceful way a VM was stopped.
+    let mut outcomes: Vec<(String, MemberOutcome)> = vec![];
+    let mut most_forceful: StopOutcome = StopOutcome::PoweredDown;
+    for member in config.get_group(group)?.into_iter().rev() {
+        let outcome: MemberOutcome = match find_running_member(member.name()) {
+            None => MemberOutcome::NotRunning,
+            Some(vm) if wait || !member.depends_on().is_empty() => {
+                match vm.stop_and_wait(force, timeout, config) {
+                    Ok(outcome) => {
+                        if wait {
+                            most_forceful = most_forceful.max(outcome);
+                        }
+                        MemberOutcome::Stopped(Some(outcome))
+                    }
+                    Err(e) => MemberOutcome::Failed(e),
+                }
+            }
+            Some(vm) => match vm.stop(force, timeout, config) {
+                Ok(()) => MemberOutcome::Stopped(None),
+                Err(e) => MemberOutcome::Failed(e),
+            },
+        };
+        outcomes.push((member.name().to_owned(), outcome));
+    }
+    report(
+        &format!("VMs of group '{group}'"),
+        "stop",
+        &outcomes,
+        buffer,
+    )
+    .map(|()| most_forceful.exit_code())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render_summary, report, restart_in_batches, start_concurrently, MemberOutcome};
+    use crate::qemu_runner::StopOutcome;
+    use crate::utils::{OutputStream, OutputStreamTarget};
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_start_concurrently() {
+        let vms: Vec<(String, Vec<String>)> = vec![
+            ("web".to_owned(), vec!["app".to_owned()]),
+            ("app".to_owned(), vec!["db".to_owned(), "cache".to_owned()]),
+            ("db".to_owned(), vec![]),
+            ("cache".to_owned(), vec![]),
+            ("queue".to_owned(), vec![]),
+            ("worker".to_owned(), vec!["queue".to_owned()]),
+        ];
+        let started: Mutex<Vec<String>> = Mutex::new(vec![]);
+        let outcomes: Vec<(String, MemberOutcome)> = start_concurrently(&vms, |index| {
+            let name: &str = &vms[index].0;
+            started.lock().unwrap().push(name.to_owned());
+            if name == "queue" {
+                MemberOutcome::Failed("No image matches 'queue'.".to_owned())
+            } else {
+                MemberOutcome::Started
+            }
+        });
+        assert_eq!(
+            outcomes
+                .iter()
+                .map(|(name, outcome)| format!("{name}: {}", outcome.description()))
+                .collect::<Vec<String>>(),
+            vec![
+                "web: started",
+                "app: started",
+                "db: started",
+                "cache: started",
+                "queue: failed: No image matches 'queue'.",
+                "worker: skipped, as 'queue' isn't ready",
+            ]
+        );
+        // each VM is only started after the VMs it depends on.
+        let started: Vec<String> = started.into_inner().unwrap();
+        let position = |name: &str| started.iter().position(|other| other == name).unwrap();
+        assert!(position("db") < position("app") && position("cache") < position("app"));
+        assert!(position("app") < position("web"));
+        assert!(!started.contains(&"worker".to_owned()));
+    }
+
+    #[test]
+    fn test_render_summary() {
+        let outcomes: Vec<(String, MemberOutcome)> = vec![
+            ("db".to_owned(), MemberOutcome::Started),
+            ("app-server".to_owned(), MemberOutcome::AlreadyRunning),
+            (
+                "web".to_owned(),
+                MemberOutcome::Failed("Image 'web' not found.".to_owned()),
+            ),
+            (
+                "cache".to_owned(),
+                MemberOutcome::Stopped(Some(StopOutcome::Killed)),
+            ),
+            ("api".to_owned(), MemberOutcome::Skipped("web".to_owned())),
+        ];
+        assert_eq!(
+            render_summary(&outcomes),
+            vec![
+                "VM         | Status",
+                "-----------+-------",
+                "db         | started",
+                "app-server | already running",
+                "web        | failed: Image 'web' not found.",
+                "cache      | killed with SIGKILL",
+                "api        | skipped, as 'web' isn't ready",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_restart_in_batches() {
+        let vms: Vec<&str> = vec!["web1", "web2", "web3", "web4", "web5"];
+        let restarted: Mutex<Vec<usize>> = Mutex::new(vec![]);
+        let outcomes: Vec<MemberOutcome> = restart_in_batches(&vms, 2, |index| {
+            restarted.lock().unwrap().push(index);
+            if vms[index] == "web3" {
+                MemberOutcome::Failed("Timed out.".to_owned())
+            } else {
+                MemberOutcome::Restarted
+            }
+        });
+        assert_eq!(
+            outcomes,
+            vec![
+                MemberOutcome::Restarted,
+                MemberOutcome::Restarted,
+                MemberOutcome::Failed("Timed out.".to_owned()),
+                MemberOutcome::Restarted,
+                MemberOutcome::Skipped("web3".to_owned()),
+            ]
+        );
+        // the batch after the one a VM failed in isn't taken down.
+        let mut restarted: Vec<usize> = restarted.into_inner().unwrap();
+        restarted.sort();
+        assert_eq!(restarted, vec![0, 1, 2, 3]);
+
+        let outcomes: Vec<(String, MemberOutcome)> = vms
+            .iter()
+            .map(|name| name.to_string())
+            .zip(outcomes)
+            .collect();
+        let mut buffer: OutputStream = OutputStream::new(OutputStreamTarget::Stdout);
+        assert_eq!(
+            report("VMs of group 'web'", "restart", &outcomes, &mut buffer),
+            Err("2 of 5 VMs of group 'web' failed to restart.".to_owned())
+        );
+        assert_eq!(
+            report("VMs of group 'web'", "restart", &outcomes[..2], &mut buffer),
+            Ok(())
+        );
+    }
+}
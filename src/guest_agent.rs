@@ -0,0 +1,260 @@
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::qmp::QmpMessage;
+use crate::utils::get_guest_agent_socket_path;
+
+/// The name of the virtio-serial port `qemu-guest-agent` listens on.
+pub const GUEST_AGENT_PORT_NAME: &str = "org.qemu.guest_agent.0";
+/// How long to wait for the guest agent to answer before giving up, as
+/// nothing answers if the guest doesn't run it.
+const GUEST_AGENT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often a command run with `guest-exec` is checked for having exited.
+const EXEC_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// The byte the guest agent sends before answering `guest-sync-delimited`.
+const SYNC_DELIMITER: u8 = 0xff;
+const BASE64_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// The outcome of a command run in the guest.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ExecOutput {
+    pub exit_code: i64,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+pub fn guest_agent_qemu_args(vm_name: &str) -> Vec<String> {
+    //! Returns the arguments giving the guest a virtio-serial channel to the
+    //! host, on which `qemu-guest-agent` listens in the guest.
+    vec![
+        "-chardev".to_owned(),
+        format!(
+            "socket,path={},server=on,wait=off,id=guest-agent",
+            get_guest_agent_socket_path(vm_name).display()
+        ),
+        "-device".to_owned(),
+        "virtio-serial".to_owned(),
+        "-device".to_owned(),
+        format!("virtserialport,chardev=guest-agent,name={GUEST_AGENT_PORT_NAME}"),
+    ]
+}
+
+pub fn decode_base64(data: &str) -> Result<Vec<u8>, String> {
+    //! Decodes the base64 the guest agent encodes command output in.
+    let mut decoded: Vec<u8> = vec![];
+    let mut bits: u32 = 0;
+    let mut bit_count: u32 = 0;
+    for byte in data.bytes().filter(|byte| !byte.is_ascii_whitespace()) {
+        if byte == b'=' {
+            break;
+        }
+        let value: u32 = BASE64_ALPHABET
+            .iter()
+            .position(|character| *character == byte)
+            .ok_or(format!("Invalid base64 '{data}'."))? as u32;
+        bits = (bits << 6) | value;
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            decoded.push((bits >> bit_count) as u8);
+            bits &= (1 << bit_count) - 1;
+        }
+    }
+    Ok(decoded)
+}
+
+/// A client for the QEMU guest agent running in a guest, connected to the
+/// host side of its virtio-serial channel.
+pub struct GuestAgentClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl GuestAgentClient {
+    pub fn connect(vm_name: &str, socket_path: &Path) -> Result<Self, String> {
+        //! Connects to the guest agent channel at `socket_path`, and syncs
+        //! with the agent, discarding any answers left over from earlier
+        //! clients.
+        let stream: UnixStream = UnixStream::connect(socket_path).map_err(|e| {
+            format!(
+                "Unable to connect to the guest agent channel of VM '{vm_name}' at '{}'; was it started with 'guest_agent' off? {e}",
+                socket_path.display()
+            )
+        })?;
+        stream
+            .set_read_timeout(Some(GUEST_AGENT_TIMEOUT))
+            .map_err(|e| e.to_string())?;
+        let writer: UnixStream = stream.try_clone().map_err(|e| e.to_string())?;
+        let mut client: Self = Self {
+            reader: BufReader::new(stream),
+            writer,
+        };
+        client.sync().map_err(|e| {
+            format!(
+                "The guest agent of VM '{vm_name}' isn't answering. Install and start 'qemu-guest-agent' in the guest. {e}"
+            )
+        })?;
+        Ok(client)
+    }
+
+    pub fn connect_to_vm(vm_name: &str) -> Result<Self, String> {
+        //! Connects to the guest agent of the given running VM.
+        let socket_path: PathBuf = get_guest_agent_socket_path(vm_name);
+        Self::connect(vm_name, &socket_path)
+    }
+
+    fn send(&mut self, command: &str, arguments: Option<Value>) -> Result<(), String> {
+        let mut request: Value = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+        self.writer
+            .write_all(format!("{request}\n").as_bytes())
+            .map_err(|e| format!("Unable to send '{command}' to the guest agent. {e}"))
+    }
+
+    fn read_message(&mut self) -> Result<QmpMessage, String> {
+        let mut line: Vec<u8> = vec![];
+        match self.reader.read_until(b'\n', &mut line) {
+            Ok(0) => Err("Guest agent channel closed by qemu.".to_owned()),
+            Ok(_) => {
+                let line: &[u8] = match line.iter().rposition(|byte| *byte == SYNC_DELIMITER) {
+                    Some(position) => &line[position + 1..],
+                    None => &line,
+                };
+                QmpMessage::parse(&String::from_utf8_lossy(line))
+            }
+            Err(e) => Err(format!("Unable to read from the guest agent. {e}")),
+        }
+    }
+
+    fn sync(&mut self) -> Result<(), String> {
+        //! Resynchronizes with the agent, which answers with a unique ID
+        //! once every earlier answer has been sent.
+        let id: u64 = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_micros() as u64 & 0xffffffff);
+        self.send("guest-sync-delimited", Some(json!({ "id": id })))?;
+        loop {
+            if let QmpMessage::Return { value, .. } = self.read_message()? {
+                if value.as_u64() == Some(id) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    pub fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value, String> {
+        //! Executes `command` in the guest agent, returning the contents of
+        //! the `return` member on success, or the error description on
+        //! failure.
+        self.send(command, arguments)?;
+        match self.read_message()? {
+            QmpMessage::Return { value, .. } => Ok(value),
+            QmpMessage::Error { error, .. } => Err(format!(
+                "Guest agent command '{command}' failed. {}",
+                error.desc
+            )),
+            message => Err(format!("Unexpected guest agent message {message:?}.")),
+        }
+    }
+
+    pub fn get_ip_addresses(&mut self) -> Result<Vec<String>, String> {
+        //! Returns the IP addresses of the guest's network interfaces, other
+        //! than loopback ones.
+        let interfaces: Value = self.execute("guest-network-get-interfaces", None)?;
+        Ok(interfaces
+            .as_array()
+            .into_iter()
+            .flatten()
+            .flat_map(|interface| {
+                interface["ip-addresses"]
+                    .as_array()
+                    .cloned()
+                    .unwrap_or_default()
+            })
+            .filter_map(|address| address["ip-address"].as_str().map(|a| a.to_owned()))
+            .filter(|address| {
+                address
+                    .parse::<IpAddr>()
+                    .is_ok_and(|address| !address.is_loopback())
+            })
+            .collect())
+    }
+
+    pub fn exec(&mut self, command: &[String]) -> Result<ExecOutput, String> {
+        //! Runs a program in the guest, waiting for it to exit, and returns
+        //! its exit code and output. The first element of `command` is the
+        //! program, which the guest looks up in its `PATH`.
+        let (program, arguments) = command.split_first().ok_or("No command given to run.")?;
+        let pid: Value = self.execute(
+            "guest-exec",
+            Some(json!({
+                "path": program,
+                "arg": arguments,
+                "capture-output": true,
+            })),
+        )?["pid"]
+            .clone();
+        loop {
+            let status: Value = self.execute("guest-exec-status", Some(json!({ "pid": pid })))?;
+            if status["exited"].as_bool() == Some(true) {
+                let decode = |name: &str| decode_base64(status[name].as_str().unwrap_or_default());
+                return Ok(ExecOutput {
+                    // a program killed by a signal has no exit code
+                    exit_code: status["exitcode"]
+                        .as_i64()
+                        .unwrap_or(128 + status["signal"].as_i64().unwrap_or(0)),
+                    stdout: decode("out-data")?,
+                    stderr: decode("err-data")?,
+                });
+            }
+            sleep(EXEC_POLL_INTERVAL);
+        }
+    }
+}
+
+pub fn with_frozen_filesystems<T>(
+    vm_name: &str,
+    action: impl FnOnce() -> Result<T, String>,
+) -> Result<T, String> {
+    //! Runs `action` while the guest's filesystems are frozen through its
+    //! guest agent, so that a copy of its disks taken meanwhile is
+    //! consistent. If the guest has no guest agent running, or its
+    //! filesystems can't be frozen, `action` is run regardless.
+    let mut agent: GuestAgentClient =
+        match GuestAgentClient::connect_to_vm(vm_name).and_then(|mut agent| {
+            agent.execute("guest-fsfreeze-freeze", None)?;
+            Ok(agent)
+        }) {
+            Ok(agent) => agent,
+            Err(e) => {
+                eprintln!("Not freezing the filesystems of VM '{vm_name}'. {e}");
+                return action();
+            }
+        };
+    let result: Result<T, String> = action();
+    agent
+        .execute("guest-fsfreeze-thaw", None)
+        .map_err(|e| format!("Unable to thaw the filesystems of VM '{vm_name}'. {e}"))?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode_base64;
+
+    #[test]
+    fn test_decode_base64() {
+        assert_eq!(decode_base64("aGVsbG8K").unwrap(), b"hello\n");
+        assert_eq!(decode_base64("aGk=").unwrap(), b"hi");
+        assert_eq!(decode_base64("YQ==").unwrap(), b"a");
+        assert_eq!(decode_base64("").unwrap(), b"");
+        assert!(decode_base64("a!b=").is_err());
+    }
+}
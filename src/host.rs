@@ -0,0 +1,106 @@
+use std::io::IsTerminal;
+use std::process::{Command, ExitStatus};
+
+/// The environment variable holding the command `--host` runs on the remote
+/// host, for when vm-manager isn't on its `PATH` there.
+const REMOTE_COMMAND_VARIABLE: &str = "VM_MANAGER_REMOTE_COMMAND";
+const DEFAULT_REMOTE_COMMAND: &str = "vm-manager";
+
+pub fn quote_shell_arg(arg: &str) -> String {
+    //! Quotes an argument for a shell, such as the remote user's shell, which
+    //! ssh passes the command line to as a single string.
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,@%+".contains(c))
+    {
+        arg.to_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+fn strip_host_args(args: &[String]) -> Vec<String> {
+    //! Returns the arguments vm-manager was run with, without the program
+    //! name and `--host`, which the remote vm-manager has no use for.
+    let mut stripped: Vec<String> = Vec::new();
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            stripped.push(arg.to_owned());
+            stripped.extend(args.by_ref().cloned());
+        } else if arg == "--host" {
+            args.next();
+        } else if !arg.starts_with("--host=") {
+            stripped.push(arg.to_owned());
+        }
+    }
+    stripped
+}
+
+fn get_remote_command_line(args: &[String]) -> String {
+    let remote_command: String = std::env::var(REMOTE_COMMAND_VARIABLE)
+        .unwrap_or_else(|_| DEFAULT_REMOTE_COMMAND.to_owned());
+    std::iter::once(remote_command)
+        .chain(strip_host_args(args).iter().map(|arg| quote_shell_arg(arg)))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+pub fn run_on_host(host: &str) -> Result<i32, String> {
+    //! Runs vm-manager on `host` over ssh with the arguments it was run with
+    //! here, so that the remote host's config, images and VMs are used, and
+    //! its output and exit code are those of the remote vm-manager. A
+    //! terminal is allocated on the remote host when running in one, for
+    //! commands such as `console` and `ssh`.
+    let args: Vec<String> = std::env::args().collect();
+    let tty_flag: &str = if std::io::stdin().is_terminal() && std::io::stdout().is_terminal() {
+        "-t"
+    } else {
+        "-T"
+    };
+    let status: ExitStatus = Command::new("ssh")
+        .args([tty_flag, "-q", host, "--", &get_remote_command_line(&args)])
+        .status()
+        .map_err(|e| format!("Unable to run 'ssh'. {e}"))?;
+    // ssh exits with 255 when it couldn't reach the host at all.
+    match status.code() {
+        Some(255) => Err(format!(
+            "Unable to run vm-manager on host '{host}' over ssh."
+        )),
+        Some(code) => Ok(code),
+        None => Err(format!("ssh to host '{host}' was interrupted ({status}).")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{quote_shell_arg, strip_host_args};
+
+    #[test]
+    fn test_strip_host_args() {
+        let to_strings =
+            |args: &[&str]| -> Vec<String> { args.iter().map(|arg| arg.to_string()).collect() };
+        assert_eq!(
+            strip_host_args(&to_strings(&[
+                "vm-manager",
+                "--host",
+                "admin@lab1",
+                "-r",
+                "stop",
+                "--host=admin@lab2",
+                "-i",
+                "deb12",
+            ])),
+            to_strings(&["-r", "stop", "-i", "deb12"])
+        );
+        assert_eq!(
+            strip_host_args(&to_strings(&["vm-manager", "type", "--", "--host"])),
+            to_strings(&["type", "--", "--host"])
+        );
+
+        assert_eq!(quote_shell_arg("deb12@web1"), "deb12@web1");
+        assert_eq!(quote_shell_arg(""), "''");
+        assert_eq!(quote_shell_arg("it's $HOME"), "'it'\\''s $HOME'");
+    }
+}
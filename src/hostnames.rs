@@ -0,0 +1,269 @@
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use crate::config::HostnamesConfig;
+use crate::ssh::get_host_alias;
+use crate::state::{read_pidfile, ForwardedPort};
+use crate::utils::{
+    get_list_of_running_vms, get_partial_path, is_process_running, run_shell_command,
+    wait_for_process_exit,
+};
+use crate::RUNTIME_DIRECTORY;
+
+/// The domain VM names are published in, unless the config sets another.
+const DEFAULT_DOMAIN: &str = "local";
+/// The lines enclosing the block vm-manager keeps in a hosts file.
+const HOSTS_BLOCK_START: &str = "# BEGIN vm-manager";
+const HOSTS_BLOCK_END: &str = "# END vm-manager";
+/// How long to wait for `avahi-publish` to exit once asked to.
+const AVAHI_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn get_hostname(vm_name: &str, hostnames: &HostnamesConfig) -> String {
+    //! Returns the name a running VM is published as, e.g. `deb12.local`.
+    format!(
+        "{}.{}",
+        get_host_alias(vm_name),
+        hostnames.domain.as_deref().unwrap_or(DEFAULT_DOMAIN)
+    )
+}
+
+fn get_avahi_pidfile_path(vm_name: &str) -> PathBuf {
+    //! Returns the path of the file holding the PID of the `avahi-publish`
+    //! advertising a VM.
+    PathBuf::from(
+        shellexpand::tilde(&format!("{RUNTIME_DIRECTORY}/{vm_name}.avahi.pid")).to_string(),
+    )
+}
+
+pub fn publish(vm_name: &str, hostnames: Option<&HostnamesConfig>) {
+    //! Publishes the name of a VM which just started, as the config's
+    //! `hostnames` say to. Failing to is only reported, as the VM itself
+    //! started.
+    if let Some(hostnames) = hostnames {
+        if hostnames.mdns {
+            if let Err(e) = start_avahi_publish(vm_name, hostnames) {
+                eprintln!("{e}");
+            }
+        }
+        update_hosts_file(hostnames);
+    }
+}
+
+pub fn unpublish(vm_name: &str, hostnames: Option<&HostnamesConfig>) {
+    //! Withdraws the name of a VM which stopped.
+    if let Some(hostnames) = hostnames {
+        stop_avahi_publish(vm_name);
+        update_hosts_file(hostnames);
+    }
+}
+
+fn get_host_address() -> Result<String, String> {
+    //! Returns the first address of the host's network interfaces, which
+    //! forwarded ports not bound to a single address are reached on.
+    let output = run_shell_command(&["hostname", "-I"])?;
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .map(|address| address.to_owned())
+        .ok_or("The host has no network address to advertise VMs on over mDNS.".to_owned())
+}
+
+fn start_avahi_publish(vm_name: &str, hostnames: &HostnamesConfig) -> Result<(), String> {
+    //! Starts an `avahi-publish` in the background advertising the VM's
+    //! name over mDNS as an alias of the host's address. It runs until the
+    //! VM is stopped.
+    // one left behind by a VM which was killed would hold the name.
+    stop_avahi_publish(vm_name);
+    let hostname: String = get_hostname(vm_name, hostnames);
+    let avahi = Command::new("avahi-publish")
+        .args(["--address", "--no-reverse", &hostname, &get_host_address()?])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("Unable to run 'avahi-publish'. Is it installed? {e}"))?;
+    let pidfile_path: PathBuf = get_avahi_pidfile_path(vm_name);
+    fs::write(&pidfile_path, avahi.id().to_string())
+        .map_err(|e| format!("Unable to write PID file '{}'. {e}", pidfile_path.display()))
+}
+
+fn stop_avahi_publish(vm_name: &str) {
+    //! Stops the `avahi-publish` advertising the VM, if one is running.
+    let pidfile_path: PathBuf = get_avahi_pidfile_path(vm_name);
+    if let Some(pid) = read_pidfile(&pidfile_path) {
+        if is_process_running(pid) {
+            let _ = run_shell_command(&["kill", &format!("{pid}")]);
+            if !wait_for_process_exit(pid, AVAHI_SHUTDOWN_TIMEOUT) {
+                eprintln!("'avahi-publish' (PID {pid}) of VM '{vm_name}' did not exit.");
+            }
+        }
+    }
+    let _ = fs::remove_file(pidfile_path);
+}
+
+fn update_hosts_file(hostnames: &HostnamesConfig) {
+    //! Rewrites the block of the config's `hosts_file`, if any, to map the
+    //! names of the running VMs.
+    let Some(hosts_file) = &hostnames.hosts_file else {
+        return;
+    };
+    let path: String = shellexpand::tilde(hosts_file).into_owned();
+    let vms: Vec<(String, Vec<ForwardedPort>)> = get_list_of_running_vms()
+        .into_iter()
+        .map(|vm| {
+            (
+                get_hostname(&vm.image_name(), hostnames),
+                vm.forwarded_ports().to_vec(),
+            )
+        })
+        .collect();
+    if let Err(e) = write_hosts_block(Path::new(&path), &vms) {
+        eprintln!("{e}");
+    }
+}
+
+fn write_hosts_block(path: &Path, vms: &[(String, Vec<ForwardedPort>)]) -> Result<(), String> {
+    //! Replaces the vm-manager block of the hosts file at `path`. VMs start
+    //! and stop at the same time in other threads and processes, so the
+    //! file is only read and rewritten while holding a lock file next to
+    //! it, and the new contents are moved over it rather than written into
+    //! it, so that it's never seen truncated.
+    // a symlinked hosts file is rewritten where it points to.
+    let path: PathBuf = fs::canonicalize(path).unwrap_or(path.to_path_buf());
+    if let Some(directory) = path.parent() {
+        let _ = fs::create_dir_all(directory);
+    }
+    let file_name: String = path
+        .file_name()
+        .map_or(String::new(), |name| name.to_string_lossy().to_string());
+    let lock_path: PathBuf = path.with_file_name(format!(".{file_name}.lock"));
+    let lock: File = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .map_err(|e| format!("Unable to open lock file '{}'. {e}", lock_path.display()))?;
+    lock.lock()
+        .map_err(|e| format!("Unable to lock '{}'. {e}", lock_path.display()))?;
+    // a missing hosts file is created, with only the block.
+    let contents: String = fs::read_to_string(&path).unwrap_or_default();
+    let partial_path: PathBuf = get_partial_path(&path);
+    fs::write(&partial_path, replace_hosts_block(&contents, vms))
+        .and_then(|_| match fs::metadata(&path) {
+            Ok(metadata) => fs::set_permissions(&partial_path, metadata.permissions()),
+            Err(_) => fs::set_permissions(&partial_path, Permissions::from_mode(0o644)),
+        })
+        .and_then(|_| fs::rename(&partial_path, &path))
+        .map_err(|e| {
+            let _ = fs::remove_file(&partial_path);
+            format!("Unable to write hosts file '{}'. {e}", path.display())
+        })
+}
+
+fn replace_hosts_block(contents: &str, vms: &[(String, Vec<ForwardedPort>)]) -> String {
+    //! Returns the hosts file `contents` with its vm-manager block replaced
+    //! by one mapping each named VM to `127.0.0.1`, with its forwarded ports
+    //! in a comment. Lines outside the block are kept as they are, and the
+    //! block is appended if there isn't one yet.
+    let mut block: Vec<String> = vec![HOSTS_BLOCK_START.to_owned()];
+    for (hostname, ports) in vms {
+        let ports: Vec<String> = ports.iter().map(|port| port.to_string()).collect();
+        block.push(if ports.is_empty() {
+            format!("127.0.0.1\t{hostname}")
+        } else {
+            format!("127.0.0.1\t{hostname}\t# {}", ports.join(", "))
+        });
+    }
+    block.push(HOSTS_BLOCK_END.to_owned());
+
+    let lines: Vec<&str> = contents.lines().collect();
+    let start: Option<usize> = lines.iter().position(|line| *line == HOSTS_BLOCK_START);
+    let end: Option<usize> = start.and_then(|start| {
+        lines[start..]
+            .iter()
+            .position(|line| *line == HOSTS_BLOCK_END)
+            .map(|end| start + end)
+    });
+    let mut new_lines: Vec<String> = vec![];
+    match (start, end) {
+        (Some(start), Some(end)) => {
+            new_lines.extend(lines[..start].iter().map(|line| line.to_string()));
+            new_lines.extend(block);
+            new_lines.extend(lines[end + 1..].iter().map(|line| line.to_string()));
+        }
+        _ => {
+            new_lines.extend(lines.iter().map(|line| line.to_string()));
+            new_lines.extend(block);
+        }
+    }
+    new_lines.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{replace_hosts_block, write_hosts_block};
+    use crate::state::ForwardedPort;
+    use std::path::PathBuf;
+    use std::{fs, thread};
+
+    #[test]
+    fn test_replace_hosts_block() {
+        let vms: Vec<(String, Vec<ForwardedPort>)> = vec![
+            (
+                "deb12.local".to_owned(),
+                ["tcp::5555-:22", "tcp::8080-:80"]
+                    .iter()
+                    .filter_map(|rule| ForwardedPort::parse(rule))
+                    .collect(),
+            ),
+            ("arch.web.local".to_owned(), vec![]),
+        ];
+        let block: &str = "# BEGIN vm-manager\n\
+                           127.0.0.1\tdeb12.local\t# 5555 -> 22/tcp, 8080 -> 80/tcp\n\
+                           127.0.0.1\tarch.web.local\n\
+                           # END vm-manager\n";
+        assert_eq!(
+            replace_hosts_block("127.0.0.1\tlocalhost\n", &vms),
+            format!("127.0.0.1\tlocalhost\n{block}")
+        );
+        assert_eq!(
+            replace_hosts_block(
+                "127.0.0.1\tlocalhost\n# BEGIN vm-manager\n127.0.0.1\told.local\n# END vm-manager\n::1\tlocalhost\n",
+                &vms
+            ),
+            format!("127.0.0.1\tlocalhost\n{block}::1\tlocalhost\n")
+        );
+        assert_eq!(
+            replace_hosts_block("", &[]),
+            "# BEGIN vm-manager\n# END vm-manager\n"
+        );
+    }
+
+    #[test]
+    fn test_write_hosts_block() {
+        let directory: PathBuf = std::env::temp_dir().join(format!("hosts-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path: PathBuf = directory.join("hosts");
+        fs::write(&path, "127.0.0.1\tlocalhost\n").unwrap();
+        // VMs starting at the same time never drop the file's own lines.
+        thread::scope(|scope| {
+            for name in ["deb12.local", "arch.local"] {
+                let path: &PathBuf = &path;
+                scope.spawn(move || {
+                    for _ in 0..50 {
+                        write_hosts_block(path, &[(name.to_owned(), vec![])]).unwrap();
+                        let contents: String = fs::read_to_string(path).unwrap();
+                        assert!(contents.starts_with("127.0.0.1\tlocalhost\n# BEGIN vm-manager\n"));
+                    }
+                });
+            }
+        });
+        let contents: String = fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.matches("# BEGIN vm-manager").count(), 1);
+        assert!(!directory.join(".hosts.partial").exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
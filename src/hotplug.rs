@@ -0,0 +1,299 @@
+use serde_json::{json, Value};
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::image::{get_image_info, parse_memory_size};
+use crate::qmp::{QmpClient, QmpEvent};
+
+/// Number of memory slots a guest with `max_memory` gets if it sets no
+/// `memory_slots`.
+pub const DEFAULT_MEMORY_SLOTS: usize = 4;
+/// How long the guest has to release a device being detached.
+const DEVICE_DETACH_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn memory_hotplug_arg(
+    memory: Option<&str>,
+    max_memory: &str,
+    slots: usize,
+) -> Result<String, String> {
+    //! Returns the argument of `-m` giving the guest `memory`, and room to
+    //! hotplug memory up to `max_memory` into `slots` DIMMs.
+    let memory: &str = memory
+        .ok_or("'max_memory' needs the guest's memory size. Set 'memory' in the VM config.")?;
+    let bytes: u64 = parse_memory_size(memory).ok_or(format!(
+        "Unable to read the guest's memory size '{memory}'."
+    ))?;
+    let max_bytes: u64 = parse_memory_size(max_memory)
+        .ok_or(format!("Unable to read 'max_memory' '{max_memory}'."))?;
+    if max_bytes < bytes {
+        return Err(format!(
+            "'max_memory' ({max_memory}) is less than the guest's memory ({memory})."
+        ));
+    }
+    if slots == 0 {
+        return Err("'memory_slots' must be at least 1.".to_owned());
+    }
+    Ok(format!("size={bytes},slots={slots},maxmem={max_bytes}"))
+}
+
+pub fn cpu_hotplug_arg(cpus: Option<usize>, max_cpus: usize) -> Result<String, String> {
+    //! Returns the argument of `-smp` giving the guest `cpus` vCPUs, and room
+    //! to hotplug vCPUs up to `max_cpus`.
+    let cpus: usize =
+        cpus.ok_or("'max_cpus' needs the guest's vCPU count. Set 'cpus' in the VM config.")?;
+    if max_cpus < cpus {
+        return Err(format!(
+            "'max_cpus' ({max_cpus}) is less than the guest's vCPU count ({cpus})."
+        ));
+    }
+    Ok(format!("{cpus},maxcpus={max_cpus}"))
+}
+
+pub fn parse_scale(value: &str, current: u64, parse: impl Fn(&str) -> Option<u64>) -> Option<u64> {
+    //! Returns how much to add to a resource currently at `current` to scale
+    //! it as asked: by `+amount`, or to an absolute amount. Resources can
+    //! only grow, as removing vCPUs and memory needs the guest's
+    //! cooperation.
+    match value.strip_prefix('+') {
+        Some(amount) => parse(amount).filter(|amount| *amount > 0),
+        None => parse(value)
+            .and_then(|target| target.checked_sub(current))
+            .filter(|amount| *amount > 0),
+    }
+}
+
+fn select_free_cpu_slots(cpus: &Value, count: usize) -> Result<Vec<Value>, String> {
+    //! Returns `count` of the vCPU slots listed by `query-hotpluggable-cpus`
+    //! which hold no vCPU yet, lowest first.
+    let slots: &Vec<Value> = cpus.as_array().ok_or(format!(
+        "Unexpected response to 'query-hotpluggable-cpus': {cpus}"
+    ))?;
+    let mut free: Vec<Value> = slots
+        .iter()
+        .filter(|slot| slot.get("qom-path").is_none())
+        .cloned()
+        .collect();
+    // qemu lists the slots from the highest down.
+    free.reverse();
+    if free.len() < count {
+        return Err(format!(
+            "The VM only has room for {} more vCPUs. Raise 'max_cpus' in its VM config, and restart it.",
+            free.len()
+        ));
+    }
+    free.truncate(count);
+    Ok(free)
+}
+
+pub fn hotplug_cpus(qmp: &mut QmpClient, count: usize) -> Result<(), String> {
+    //! Adds `count` vCPUs to a running guest, which needs room for them from
+    //! its `max_cpus`.
+    let cpus: Value = qmp.execute("query-hotpluggable-cpus", None)?;
+    for slot in select_free_cpu_slots(&cpus, count)? {
+        let mut arguments: Value = slot["props"].clone();
+        let id: String = format!(
+            "cpu-{}-{}-{}",
+            arguments["socket-id"].as_u64().unwrap_or(0),
+            arguments["core-id"].as_u64().unwrap_or(0),
+            arguments["thread-id"].as_u64().unwrap_or(0)
+        );
+        arguments["driver"] = slot["type"].clone();
+        arguments["id"] = json!(id);
+        qmp.execute("device_add", Some(arguments))?;
+    }
+    Ok(())
+}
+
+fn next_dimm_index(devices: &Value) -> usize {
+    //! Returns the lowest index not used by a memory DIMM listed by
+    //! `query-memory-devices`, for the ID of the next one.
+    let ids: Vec<&str> = devices
+        .as_array()
+        .map(|devices| {
+            devices
+                .iter()
+                .filter_map(|device| device["data"]["id"].as_str())
+                .collect()
+        })
+        .unwrap_or_default();
+    (0..)
+        .find(|index| !ids.contains(&format!("dimm{index}").as_str()))
+        .unwrap_or_default()
+}
+
+pub fn hotplug_memory(qmp: &mut QmpClient, bytes: u64) -> Result<(), String> {
+    //! Adds a DIMM of `bytes` of memory to a running guest, which needs a
+    //! free slot and room for it from its `memory_slots` and `max_memory`.
+    let devices: Value = qmp.execute("query-memory-devices", None)?;
+    let index: usize = next_dimm_index(&devices);
+    qmp.execute(
+        "object-add",
+        Some(json!({
+            "qom-type": "memory-backend-ram",
+            "id": format!("dimm{index}-mem"),
+            "size": bytes,
+        })),
+    )?;
+    qmp.execute(
+        "device_add",
+        Some(json!({
+            "driver": "pc-dimm",
+            "id": format!("dimm{index}"),
+            "memdev": format!("dimm{index}-mem"),
+        })),
+    )
+    .inspect_err(|_| {
+        let _ = qmp.execute(
+            "object-del",
+            Some(json!({ "id": format!("dimm{index}-mem") })),
+        );
+    })
+    .map(|_| ())
+}
+
+pub fn query_memory(qmp: &mut QmpClient) -> Result<u64, String> {
+    //! Returns how much memory a running guest has, including hotplugged
+    //! DIMMs, in bytes.
+    let summary: Value = qmp.execute("query-memory-size-summary", None)?;
+    summary["base-memory"]
+        .as_u64()
+        .map(|base| base + summary["plugged-memory"].as_u64().unwrap_or(0))
+        .ok_or(format!(
+            "Unexpected response to 'query-memory-size-summary': {summary}"
+        ))
+}
+
+fn get_disk_id(path: &Path) -> String {
+    //! Returns the ID of the block node and device of a disk attached from
+    //! `path`. It is derived from the path, so that the disk can be detached
+    //! by its path.
+    let mut hasher: DefaultHasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    format!("disk-{:08x}", hasher.finish() as u32)
+}
+
+fn canonicalize_disk_path(path: &Path) -> Result<PathBuf, String> {
+    //! Returns the absolute path of a disk image, which must exist.
+    fs::canonicalize(path)
+        .map_err(|e| format!("Unable to find disk image '{}'. {e}", path.display()))
+}
+
+pub fn attach_disk(qmp: &mut QmpClient, path: &Path, read_only: bool) -> Result<String, String> {
+    //! Attaches the disk image at `path` to a running guest as a virtio disk,
+    //! returning the ID of its device.
+    let path: PathBuf = canonicalize_disk_path(path)?;
+    let format: String = get_image_info(&path)?.format;
+    let id: String = get_disk_id(&path);
+    qmp.execute(
+        "blockdev-add",
+        Some(json!({
+            "driver": format,
+            "node-name": id,
+            "read-only": read_only,
+            "file": { "driver": "file", "filename": path },
+        })),
+    )?;
+    qmp.execute(
+        "device_add",
+        Some(json!({ "driver": "virtio-blk-pci", "id": id, "drive": id })),
+    )
+    .map_err(|e| {
+        let _ = qmp.execute("blockdev-del", Some(json!({ "node-name": id })));
+        format!("{e} Machines such as q35 need a free 'pcie-root-port' for each hotplugged disk.")
+    })?;
+    Ok(id)
+}
+
+pub fn detach_disk(qmp: &mut QmpClient, path: &Path) -> Result<(), String> {
+    //! Detaches a disk attached with `attach_disk` from a running guest,
+    //! once the guest has released it.
+    let path: PathBuf = canonicalize_disk_path(path)?;
+    let id: String = get_disk_id(&path);
+    qmp.execute("device_del", Some(json!({ "id": id })))
+        .map_err(|e| format!("No disk attached from '{}' was found. {e}", path.display()))?;
+    wait_for_device_deleted(qmp, &id).map_err(|_| {
+        format!(
+            "The guest didn't release disk '{}' within {} seconds. It may be in use, or the guest may not support hot-unplugging.",
+            path.display(),
+            DEVICE_DETACH_TIMEOUT.as_secs()
+        )
+    })?;
+    qmp.execute("blockdev-del", Some(json!({ "node-name": id })))
+        .map(|_| ())
+}
+
+pub fn wait_for_device_deleted(qmp: &mut QmpClient, id: &str) -> Result<(), String> {
+    //! Waits for qemu to report that the device `id`, which `device_del` was
+    //! asked to remove, has been released by the guest and removed.
+    let started_waiting: Instant = Instant::now();
+    loop {
+        let remaining: Duration = DEVICE_DETACH_TIMEOUT.saturating_sub(started_waiting.elapsed());
+        let event: QmpEvent = qmp.wait_for_event("DEVICE_DELETED", remaining)?;
+        if event.data["device"].as_str() == Some(id) {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        cpu_hotplug_arg, memory_hotplug_arg, next_dimm_index, parse_scale, select_free_cpu_slots,
+    };
+    use crate::image::parse_memory_size;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_scale() {
+        let parse_count = |count: &str| count.parse::<u64>().ok();
+        assert_eq!(parse_scale("+2", 4, parse_count), Some(2));
+        assert_eq!(parse_scale("6", 4, parse_count), Some(2));
+        assert_eq!(parse_scale("4", 4, parse_count), None);
+        assert_eq!(parse_scale("2", 4, parse_count), None);
+        assert_eq!(
+            parse_scale("+4G", 8 << 30, parse_memory_size),
+            Some(4 << 30)
+        );
+        assert_eq!(
+            parse_scale("12G", 8 << 30, parse_memory_size),
+            Some(4 << 30)
+        );
+    }
+
+    #[test]
+    fn test_hotplug_args() {
+        assert_eq!(
+            memory_hotplug_arg(Some("2G"), "8G", 4).unwrap(),
+            "size=2147483648,slots=4,maxmem=8589934592"
+        );
+        assert!(memory_hotplug_arg(Some("8G"), "2G", 4).is_err());
+        assert!(memory_hotplug_arg(None, "8G", 4).is_err());
+        assert!(memory_hotplug_arg(Some("2G"), "8G", 0).is_err());
+        assert_eq!(cpu_hotplug_arg(Some(2), 8).unwrap(), "2,maxcpus=8");
+        assert!(cpu_hotplug_arg(Some(4), 2).is_err());
+        assert!(cpu_hotplug_arg(None, 8).is_err());
+    }
+
+    #[test]
+    fn test_hotplug_selection() {
+        let cpus = json!([
+            {"type": "qemu64-x86_64-cpu", "vcpus-count": 1, "props": {"socket-id": 3, "core-id": 0, "thread-id": 0}},
+            {"type": "qemu64-x86_64-cpu", "vcpus-count": 1, "props": {"socket-id": 2, "core-id": 0, "thread-id": 0}},
+            {"type": "qemu64-x86_64-cpu", "vcpus-count": 1, "props": {"socket-id": 1, "core-id": 0, "thread-id": 0}, "qom-path": "/machine/unattached/device[1]"},
+            {"type": "qemu64-x86_64-cpu", "vcpus-count": 1, "props": {"socket-id": 0, "core-id": 0, "thread-id": 0}, "qom-path": "/machine/unattached/device[0]"}
+        ]);
+        let slots = select_free_cpu_slots(&cpus, 1).unwrap();
+        assert_eq!(slots[0]["props"]["socket-id"], 2);
+        assert!(select_free_cpu_slots(&cpus, 3).is_err());
+
+        let devices = json!([
+            {"type": "dimm", "data": {"id": "dimm0", "size": 1073741824}},
+            {"type": "dimm", "data": {"id": "dimm2", "size": 1073741824}}
+        ]);
+        assert_eq!(next_dimm_index(&devices), 1);
+        assert_eq!(next_dimm_index(&json!([])), 0);
+    }
+}
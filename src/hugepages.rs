@@ -0,0 +1,127 @@
+use std::fs;
+
+use crate::image::parse_memory_size;
+
+/// Where the kernel reports the host's memory, including its hugepages.
+const PROC_MEMINFO: &str = "/proc/meminfo";
+/// Where the kernel lists mounted filesystems.
+const PROC_MOUNTS: &str = "/proc/mounts";
+/// ID of the memory backend holding the guest's RAM in hugepages.
+const HUGEPAGES_MEMORY_ID: &str = "hugepages-mem";
+
+/// The host's hugepages, as reported by `/proc/meminfo`.
+/// # Attributes:
+/// * `total` - The number of hugepages reserved.
+/// * `available` - The number of reserved hugepages not yet used or
+///   promised to a process.
+/// * `page_size` - The size of a hugepage, in bytes.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct HugepagesInfo {
+    pub total: u64,
+    pub available: u64,
+    pub page_size: u64,
+}
+
+fn parse_meminfo(meminfo: &str) -> Option<HugepagesInfo> {
+    //! Returns the hugepages information in the contents of `/proc/meminfo`.
+    let field = |name: &str| {
+        meminfo.lines().find_map(|line| {
+            line.strip_prefix(name)?
+                .strip_prefix(':')?
+                .split_whitespace()
+                .next()?
+                .parse::<u64>()
+                .ok()
+        })
+    };
+    let free: u64 = field("HugePages_Free")?;
+    Some(HugepagesInfo {
+        total: field("HugePages_Total")?,
+        available: free.saturating_sub(field("HugePages_Rsvd").unwrap_or(0)),
+        page_size: field("Hugepagesize")? * 1024,
+    })
+}
+
+pub fn get_hugepages_info() -> Option<HugepagesInfo> {
+    //! Returns the host's hugepages, if the kernel supports them.
+    parse_meminfo(&fs::read_to_string(PROC_MEMINFO).ok()?)
+}
+
+pub fn find_hugetlbfs_mount() -> Option<String> {
+    //! Returns where a `hugetlbfs` is mounted, usually `/dev/hugepages`.
+    fs::read_to_string(PROC_MOUNTS)
+        .ok()?
+        .lines()
+        .find_map(
+            |line| match line.split_whitespace().collect::<Vec<&str>>()[..] {
+                [_, mount_point, "hugetlbfs", ..] => Some(mount_point.to_owned()),
+                _ => None,
+            },
+        )
+}
+
+fn check_hugepages(memory: u64, hugepages: Option<HugepagesInfo>) -> Result<(), String> {
+    //! Checks that enough hugepages are available to hold `memory` bytes.
+    let hugepages: HugepagesInfo = hugepages.ok_or("This host doesn't support hugepages.")?;
+    let needed: u64 = memory.div_ceil(hugepages.page_size);
+    if hugepages.available < needed {
+        return Err(format!(
+            "'hugepages: true' needs {needed} hugepages of {} KiB, but only {} of the host's {} are available. Reserve more with e.g. 'sysctl vm.nr_hugepages={}'.",
+            hugepages.page_size / 1024,
+            hugepages.available,
+            hugepages.total,
+            hugepages.total + needed - hugepages.available
+        ));
+    }
+    Ok(())
+}
+
+pub fn prepare_hugepages(memory: Option<&str>, share: bool) -> Result<Vec<String>, String> {
+    //! Checks that the host has enough hugepages reserved for the guest's
+    //! memory, and returns the arguments backing the guest's memory with
+    //! them. If `share` is set, the memory is shared, as virtiofs needs.
+    let memory: &str = memory
+        .ok_or("'hugepages: true' needs the guest's memory size. Set 'memory' in the VM config.")?;
+    let bytes: u64 = parse_memory_size(memory).ok_or(format!(
+        "Unable to read the guest's memory size '{memory}'."
+    ))?;
+    let mount: String = find_hugetlbfs_mount().ok_or(
+        "No 'hugetlbfs' is mounted, which 'hugepages: true' needs. Mount one with 'mount -t hugetlbfs hugetlbfs /dev/hugepages'.",
+    )?;
+    check_hugepages(bytes, get_hugepages_info())?;
+    Ok(vec![
+        "-object".to_owned(),
+        format!(
+            "memory-backend-file,id={HUGEPAGES_MEMORY_ID},size={bytes},mem-path={mount},prealloc=on{}",
+            if share { ",share=on" } else { "" }
+        ),
+        "-numa".to_owned(),
+        format!("node,memdev={HUGEPAGES_MEMORY_ID}"),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_hugepages, parse_meminfo, HugepagesInfo};
+
+    #[test]
+    fn test_parse_meminfo() {
+        let meminfo: &str = "MemTotal:       65536000 kB\nHugePages_Total:    4096\nHugePages_Free:     3000\nHugePages_Rsvd:      500\nHugePages_Surp:        0\nHugepagesize:       2048 kB\n";
+        let hugepages: HugepagesInfo = parse_meminfo(meminfo).unwrap();
+        assert_eq!(
+            hugepages,
+            HugepagesInfo {
+                total: 4096,
+                available: 2500,
+                page_size: 2 * 1024 * 1024,
+            }
+        );
+
+        // 4G needs 2048 pages of 2M, 8G needs 4096
+        assert!(check_hugepages(4 << 30, Some(hugepages)).is_ok());
+        assert!(check_hugepages(8 << 30, Some(hugepages))
+            .unwrap_err()
+            .contains("needs 4096 hugepages"));
+        assert!(check_hugepages(4 << 30, None).is_err());
+    }
+}
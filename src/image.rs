@@ -0,0 +1,524 @@
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::{Config, DiskThrottling, VMConfig};
+use crate::parse_args::ImageFormat;
+use crate::utils::{get_partial_path, run_interactive_command, run_shell_command};
+
+/// Extension of every image in the images directory.
+pub const IMAGE_EXTENSION: &str = "img";
+
+impl ImageFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImageFormat::Qcow2 => "qcow2",
+            ImageFormat::Raw => "raw",
+        }
+    }
+}
+
+pub fn get_image_path(name: &str, config: &Config) -> PathBuf {
+    //! Returns the path of the image called `name`, as listed by
+    //! `vm-manager -l`, where `name` may also be the name of a VM running an
+    //! image called differently. That's the VM's `image_path`, if it has
+    //! one, or else the path in the first images directory which has the
+    //! image. If none does, the path it would have in the primary images
+    //! directory is returned.
+    let vms: &[VMConfig] = config.get_vm_configs();
+    let vm: Option<&VMConfig> = vms.iter().find(|vm| vm.name() == name).or_else(|| {
+        vms.iter()
+            .find(|vm| vm.image_name() == name && vm.image_path().is_some())
+    });
+    if let Some(path) = vm.and_then(|vm| vm.image_path()) {
+        return path;
+    }
+    let image_name: &str = vm.map_or(name, |vm| vm.image_name());
+    let paths: Vec<PathBuf> = config
+        .get_image_directories()
+        .iter()
+        .map(|directory| {
+            PathBuf::from(
+                shellexpand::tilde(&format!("{directory}/{image_name}.{IMAGE_EXTENSION}"))
+                    .to_string(),
+            )
+        })
+        .collect();
+    paths
+        .iter()
+        .find(|path| path.exists())
+        .unwrap_or(&paths[0])
+        .to_owned()
+}
+
+pub fn get_new_image_path(name: &str, config: &Config) -> Result<PathBuf, String> {
+    //! Returns the path in the images directory of a new image called
+    //! `name`, refusing names which can't be used as an image name or which
+    //! are already taken.
+    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+        return Err(format!("'{name}' is not a valid image name."));
+    }
+    let path: PathBuf = get_image_path(name, config);
+    if path.exists() {
+        return Err(format!(
+            "An image named '{name}' already exists at '{}'.",
+            path.display()
+        ));
+    }
+    Ok(path)
+}
+
+/// The parts of `qemu-img info` output which this program cares about.
+#[derive(Debug, Deserialize, Eq, PartialEq, Clone)]
+pub struct ImageInfo {
+    /// The image format, e.g. `qcow2` or `raw`.
+    pub format: String,
+    /// The size of the disk as seen by the guest, in bytes.
+    #[serde(rename = "virtual-size")]
+    pub virtual_size: u64,
+    /// The absolute path of the image's backing file, if it is an overlay.
+    #[serde(rename = "full-backing-filename")]
+    pub backing_file: Option<PathBuf>,
+    /// Internal snapshots stored in a qcow2 image.
+    #[serde(default)]
+    pub snapshots: Vec<ImageSnapshot>,
+}
+
+/// An internal snapshot of a qcow2 image, as listed by `qemu-img info`.
+#[derive(Debug, Deserialize, Eq, PartialEq, Clone)]
+pub struct ImageSnapshot {
+    pub id: String,
+    pub name: String,
+    /// When the snapshot was taken, in seconds since the Unix epoch.
+    #[serde(rename = "date-sec")]
+    pub date: i64,
+    /// Size of the saved RAM and device state, which is 0 for snapshots of
+    /// the disk only.
+    #[serde(rename = "vm-state-size")]
+    pub vm_state_size: u64,
+}
+
+pub fn get_image_info(image_path: &Path) -> Result<ImageInfo, String> {
+    //! Inspects an image with `qemu-img info`. This also works on images in
+    //! use by a running VM.
+    let output = run_shell_command(&[
+        "qemu-img",
+        "info",
+        "--force-share",
+        "--output=json",
+        &image_path.display().to_string(),
+    ])?;
+    if !output.status.success() {
+        return Err(format!(
+            "Unable to inspect image '{}'. {}",
+            image_path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    serde_json::from_slice::<ImageInfo>(&output.stdout).map_err(|e| {
+        format!(
+            "Unexpected 'qemu-img info' output for '{}'. {e}",
+            image_path.display()
+        )
+    })
+}
+
+fn create_parent_directory(path: &Path) -> Result<(), String> {
+    //! Creates the directory a new image will be written to, if needed.
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory).map_err(|e| {
+            format!(
+                "Unable to create images directory '{}'. {e}",
+                directory.display()
+            )
+        })?;
+    }
+    Ok(())
+}
+
+pub fn run_qemu_img(args: &[&str]) -> Result<(), String> {
+    //! Runs `qemu-img` with the given arguments, returning its error output
+    //! if it fails.
+    let mut command: Vec<&str> = vec!["qemu-img"];
+    command.extend_from_slice(args);
+    let output = run_shell_command(&command)?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "'qemu-img {}' failed. {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+pub fn parse_size(size: &str) -> Option<u64> {
+    //! Parses a size as accepted by `qemu-img`, i.e. a number of bytes with
+    //! an optional `K`, `M`, `G`, `T`, `P` or `E` suffix (powers of 1024).
+    let size: &str = size.trim();
+    let (number, multiplier) = match size.chars().last()?.to_ascii_uppercase() {
+        'K' => (&size[..size.len() - 1], 1u64 << 10),
+        'M' => (&size[..size.len() - 1], 1 << 20),
+        'G' => (&size[..size.len() - 1], 1 << 30),
+        'T' => (&size[..size.len() - 1], 1 << 40),
+        'P' => (&size[..size.len() - 1], 1 << 50),
+        'E' => (&size[..size.len() - 1], 1 << 60),
+        _ => (size, 1),
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+pub fn throttling_drive_properties(throttling: &DiskThrottling) -> Result<Vec<String>, String> {
+    //! Returns the `-drive` properties applying a disk's I/O limits.
+    let mut properties: Vec<String> = vec![];
+    for (setting, property, limit) in [
+        ("iops", "iops-total", throttling.iops),
+        ("iops_read", "iops-read", throttling.iops_read),
+        ("iops_write", "iops-write", throttling.iops_write),
+    ] {
+        match limit {
+            Some(0) => return Err(format!("'{setting}' must be greater than 0.")),
+            Some(limit) => properties.push(format!("throttling.{property}={limit}")),
+            None => {}
+        }
+    }
+    for (setting, property, limit) in [
+        ("bps", "bps-total", &throttling.bps),
+        ("bps_read", "bps-read", &throttling.bps_read),
+        ("bps_write", "bps-write", &throttling.bps_write),
+    ] {
+        if let Some(limit) = limit {
+            let bytes: u64 = parse_size(limit).filter(|bytes| *bytes > 0).ok_or(format!(
+                "'{setting}' of '{limit}' is not a valid size, e.g. '100M'."
+            ))?;
+            properties.push(format!("throttling.{property}={bytes}"));
+        }
+    }
+    for (total, read, write) in [
+        (
+            "iops",
+            throttling.iops_read.is_some(),
+            throttling.iops_write.is_some(),
+        ),
+        (
+            "bps",
+            throttling.bps_read.is_some(),
+            throttling.bps_write.is_some(),
+        ),
+    ] {
+        let has_total: bool = properties
+            .iter()
+            .any(|property| property.starts_with(&format!("throttling.{total}-total=")));
+        if has_total && (read || write) {
+            return Err(format!(
+                "'{total}' can't be combined with '{total}_read' or '{total}_write'."
+            ));
+        }
+    }
+    Ok(properties)
+}
+
+pub fn parse_memory_size(memory: &str) -> Option<u64> {
+    //! Returns the size in bytes of the guest's memory as given to `-m`,
+    //! where a number without a suffix is in MiB.
+    let memory: &str = memory.trim().trim_start_matches("size=");
+    if memory.chars().all(|c| c.is_ascii_digit()) {
+        parse_size(&format!("{memory}M"))
+    } else {
+        parse_size(memory)
+    }
+}
+
+pub fn is_shrinking(size: &str, current_size: u64) -> Result<bool, String> {
+    //! Returns `true` if resizing an image of `current_size` bytes to `size`,
+    //! which may be relative (`+20G`, `-5G`), would make it smaller.
+    let invalid = || format!("'{size}' is not a valid size.");
+    if let Some(decrease) = size.strip_prefix('-') {
+        parse_size(decrease)
+            .map(|bytes| bytes > 0)
+            .ok_or_else(invalid)
+    } else if let Some(increase) = size.strip_prefix('+') {
+        parse_size(increase).map(|_| false).ok_or_else(invalid)
+    } else {
+        parse_size(size)
+            .map(|bytes| bytes < current_size)
+            .ok_or_else(invalid)
+    }
+}
+
+pub fn resize_image(path: &Path, size: &str, allow_shrinking: bool) -> Result<(), String> {
+    //! Resizes the image at `path` to `size`, which may be relative (`+20G`).
+    //! Shrinking an image discards whatever is stored past its new end, so
+    //! it is refused unless `allow_shrinking` is set.
+    let info: ImageInfo = get_image_info(path)?;
+    let shrinking: bool = is_shrinking(size, info.virtual_size)?;
+    if shrinking && !allow_shrinking {
+        return Err(format!(
+            "Resizing '{}' to '{size}' would shrink it, destroying any data past its new end. Shrink the guest's partitions first, then pass --force.",
+            path.display()
+        ));
+    }
+
+    let path: String = path.display().to_string();
+    let mut args: Vec<&str> = vec!["resize"];
+    if shrinking {
+        args.push("--shrink");
+    }
+    args.extend_from_slice(&["-f", &info.format, &path]);
+    // a leading '-' would otherwise be taken for an option
+    if size.starts_with('-') {
+        args.push("--");
+    }
+    args.push(size);
+    run_qemu_img(&args)
+}
+
+pub fn convert_image(source: &Path, destination: &Path, format: ImageFormat) -> Result<(), String> {
+    //! Converts the disk image at `source`, in any format `qemu-img` can read
+    //! (e.g. VMDK, VDI, VHDX or raw), into a new image at `destination`.
+    if !source.is_file() {
+        return Err(format!("'{}' is not a file.", source.display()));
+    }
+    write_converted_image(source, destination, format.as_str())
+}
+
+fn write_converted_image(source: &Path, destination: &Path, format: &str) -> Result<(), String> {
+    //! Writes a standalone copy of `source` in the given format to
+    //! `destination`. `qemu-img` shows its own progress, as large disks take
+    //! a while.
+    create_parent_directory(destination)?;
+    let partial_path: PathBuf = get_partial_path(destination);
+    if let Err(e) = run_interactive_command(&[
+        "qemu-img",
+        "convert",
+        "-p",
+        "-O",
+        format,
+        &source.display().to_string(),
+        &partial_path.display().to_string(),
+    ]) {
+        let _ = fs::remove_file(&partial_path);
+        return Err(e);
+    }
+    fs::rename(&partial_path, destination).map_err(|e| {
+        let _ = fs::remove_file(&partial_path);
+        format!("Unable to move '{}' into place. {e}", destination.display())
+    })
+}
+
+pub fn clone_image(source: &Path, destination: &Path, linked: bool) -> Result<(), String> {
+    //! Copies the image at `source` to `destination`. A linked clone is
+    //! instead a qcow2 overlay backed by `source`, which only stores what
+    //! changes in the clone, but breaks if `source` is modified or removed.
+    let info: ImageInfo = get_image_info(source)?;
+    if !linked {
+        return write_converted_image(source, destination, &info.format);
+    }
+    create_parent_directory(destination)?;
+    run_qemu_img(&[
+        "create",
+        "-f",
+        "qcow2",
+        "-F",
+        &info.format,
+        "-b",
+        &source.display().to_string(),
+        &destination.display().to_string(),
+    ])
+}
+
+pub fn rebase_image(path: &Path, backing_file: &Path) -> Result<(), String> {
+    //! Points an overlay at a new path of its backing image, which must hold
+    //! the same data as its old one, e.g. after the image was moved.
+    let info: ImageInfo = get_image_info(backing_file)?;
+    run_qemu_img(&[
+        "rebase",
+        "-u",
+        "-F",
+        &info.format,
+        "-b",
+        &backing_file.display().to_string(),
+        &path.display().to_string(),
+    ])
+}
+
+pub fn create_image(path: &Path, size: &str, format: ImageFormat) -> Result<(), String> {
+    //! Creates a blank image of the given size (e.g. `40G`) at `path`.
+    create_parent_directory(path)?;
+    run_qemu_img(&[
+        "create",
+        "-f",
+        format.as_str(),
+        &path.display().to_string(),
+        size,
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        get_image_path, get_new_image_path, is_shrinking, parse_memory_size, parse_size,
+        throttling_drive_properties, ImageInfo, ImageSnapshot,
+    };
+    use crate::config::Config;
+    use crate::config::DiskThrottling;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_get_new_image_path() {
+        let directory =
+            std::env::temp_dir().join(format!("vm-manager-image-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("taken.img"), "").unwrap();
+        let config: Config = serde_yaml::from_str(&format!(
+            "base_images_directory: {}\nglobal_qemu_options:\nvms:\n",
+            directory.display()
+        ))
+        .unwrap();
+
+        assert_eq!(
+            get_new_image_path("dev", &config),
+            Ok(directory.join("dev.img"))
+        );
+        assert!(get_new_image_path("taken", &config).is_err());
+        assert!(get_new_image_path("../escape", &config).is_err());
+        assert!(get_new_image_path("", &config).is_err());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_get_image_path_in_image_directories() {
+        let directory = std::env::temp_dir().join(format!(
+            "vm-manager-image-directories-test-{}",
+            std::process::id()
+        ));
+        let (ssd, hdd) = (directory.join("ssd"), directory.join("hdd"));
+        std::fs::create_dir_all(&ssd).unwrap();
+        std::fs::create_dir_all(&hdd).unwrap();
+        std::fs::write(ssd.join("small.img"), "").unwrap();
+        std::fs::write(hdd.join("big.img"), "").unwrap();
+        std::fs::write(hdd.join("small.img"), "").unwrap();
+        std::fs::write(directory.join("nas.qcow2"), "").unwrap();
+        let config: Config = serde_yaml::from_str(&format!(
+            "image_directories:\n- {}\n- {}\nglobal_qemu_options:\nvms:\n- image_name: stored\n  image_path: {}\n- image_name: big\n  name: bigger\n",
+            ssd.display(),
+            hdd.display(),
+            directory.join("nas.qcow2").display()
+        ))
+        .unwrap();
+
+        assert_eq!(get_image_path("small", &config), ssd.join("small.img"));
+        assert_eq!(get_image_path("big", &config), hdd.join("big.img"));
+        assert_eq!(get_new_image_path("new", &config), Ok(ssd.join("new.img")));
+        assert!(get_new_image_path("big", &config).is_err());
+        assert_eq!(
+            get_image_path("stored", &config),
+            directory.join("nas.qcow2")
+        );
+        assert_eq!(get_image_path("bigger", &config), hdd.join("big.img"));
+        assert_eq!(
+            crate::utils::get_list_of_images(crate::ImageLocation::WorkingImages, &config),
+            vec!["small", "big", "stored", "bigger"]
+        );
+        assert_eq!(
+            crate::utils::get_file_from_image_name("big", &config),
+            Some(hdd.join("big.img"))
+        );
+        assert_eq!(crate::utils::get_file_from_image_name("bi", &config), None);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("4k"), Some(4096));
+        assert_eq!(parse_size("20G"), Some(20 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("G"), None);
+        assert_eq!(parse_size("20GB"), None);
+        assert_eq!(parse_size("99999999E"), None);
+        assert_eq!(parse_memory_size("4G"), Some(4 << 30));
+        assert_eq!(parse_memory_size("2048"), Some(2 << 30));
+    }
+
+    #[test]
+    fn test_is_shrinking() {
+        let current: u64 = 20 * 1024 * 1024 * 1024;
+        assert_eq!(is_shrinking("+20G", current), Ok(false));
+        assert_eq!(is_shrinking("-1G", current), Ok(true));
+        assert_eq!(is_shrinking("40G", current), Ok(false));
+        assert_eq!(is_shrinking("20G", current), Ok(false));
+        assert_eq!(is_shrinking("10G", current), Ok(true));
+        assert!(is_shrinking("lots", current).is_err());
+    }
+
+    #[test]
+    fn test_parse_image_info() {
+        let info: ImageInfo = serde_json::from_str(
+            r#"{"virtual-size": 21474836480, "filename": "/images/deb12.img", "format": "qcow2", "backing-filename": "backups/deb12-20240131-154502.img", "full-backing-filename": "/images/backups/deb12-20240131-154502.img", "backing-filename-format": "qcow2"}"#,
+        )
+        .unwrap();
+        assert_eq!(info.format, "qcow2");
+        assert_eq!(info.virtual_size, 21474836480);
+        assert!(info.snapshots.is_empty());
+        assert_eq!(
+            info.backing_file,
+            Some(PathBuf::from("/images/backups/deb12-20240131-154502.img"))
+        );
+
+        let info: ImageInfo = serde_json::from_str(
+            r#"{"virtual-size": 21474836480, "filename": "/images/deb12.img", "format": "raw"}"#,
+        )
+        .unwrap();
+        assert_eq!(info.backing_file, None);
+
+        let info: ImageInfo = serde_json::from_str(
+            r#"{"snapshots": [{"icount": 0, "vm-clock-nsec": 0, "name": "pre-upgrade", "date-sec": 1706715902, "date-nsec": 0, "vm-clock-sec": 0, "id": "1", "vm-state-size": 0}], "virtual-size": 21474836480, "filename": "/images/deb12.img", "format": "qcow2"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            info.snapshots,
+            vec![ImageSnapshot {
+                id: "1".to_owned(),
+                name: "pre-upgrade".to_owned(),
+                date: 1706715902,
+                vm_state_size: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_throttling_drive_properties() {
+        let throttling: DiskThrottling = DiskThrottling {
+            iops: Some(500),
+            bps_read: Some("100M".to_owned()),
+            bps_write: Some("50M".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            throttling_drive_properties(&throttling).unwrap(),
+            vec![
+                "throttling.iops-total=500",
+                "throttling.bps-read=104857600",
+                "throttling.bps-write=52428800"
+            ]
+        );
+        assert!(throttling_drive_properties(&DiskThrottling {
+            bps: Some("1G".to_owned()),
+            ..throttling.clone()
+        })
+        .is_err());
+        assert!(throttling_drive_properties(&DiskThrottling {
+            iops: Some(0),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(throttling_drive_properties(&DiskThrottling {
+            bps: Some("fast".to_owned()),
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
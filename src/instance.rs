@@ -0,0 +1,129 @@
+use std::fs::{self, read_dir};
+use std::path::{Path, PathBuf};
+
+use crate::image::clone_image;
+use crate::INSTANCES_DIRECTORY;
+
+/// Separates the image name from the instance name in the name of a VM
+/// running as an instance, e.g. `deb12@web1`.
+pub const INSTANCE_SEPARATOR: char = '@';
+/// Extension of the overlay disk of each instance.
+const OVERLAY_EXTENSION: &str = "qcow2";
+
+pub fn get_instance_vm_name(image_name: &str, instance: &str) -> String {
+    //! Returns the name identifying the given instance of an image while it
+    //! runs, which is used for its runtime and state files.
+    format!("{image_name}{INSTANCE_SEPARATOR}{instance}")
+}
+
+pub fn split_vm_name(vm_name: &str) -> (&str, Option<&str>) {
+    //! Splits the name of a running VM into its image name and, for
+    //! instances, its instance name.
+    match vm_name.split_once(INSTANCE_SEPARATOR) {
+        Some((image_name, instance)) => (image_name, Some(instance)),
+        None => (vm_name, None),
+    }
+}
+
+pub fn validate_instance_name(instance: &str) -> Result<(), String> {
+    //! Instance names become file names, so they must be non-empty and may
+    //! not contain path separators or the instance separator.
+    if instance.is_empty()
+        || instance.starts_with('.')
+        || instance.contains(['/', INSTANCE_SEPARATOR])
+    {
+        return Err(format!(
+            "Invalid instance name '{instance}'. Instance names may not be empty, start with '.', or contain '/' or '{INSTANCE_SEPARATOR}'."
+        ));
+    }
+    Ok(())
+}
+
+pub fn get_instances_directory(image_name: &str) -> PathBuf {
+    //! Returns the directory holding the overlay disks of every instance of
+    //! the given image.
+    PathBuf::from(shellexpand::tilde(&format!("{INSTANCES_DIRECTORY}/{image_name}")).to_string())
+}
+
+pub fn get_overlay_path(image_name: &str, instance: &str) -> PathBuf {
+    //! Returns the path of the overlay disk of an instance.
+    get_instances_directory(image_name).join(format!("{instance}.{OVERLAY_EXTENSION}"))
+}
+
+pub fn create_overlay(
+    image_path: &Path,
+    image_name: &str,
+    instance: &str,
+) -> Result<PathBuf, String> {
+    //! Returns the overlay disk of an instance, first creating it as a qcow2
+    //! image backed by `image_path` if the instance is new. Existing
+    //! instances keep their overlay, and with it every change made to their
+    //! disk.
+    validate_instance_name(instance)?;
+    let overlay_path: PathBuf = get_overlay_path(image_name, instance);
+    if !overlay_path.is_file() {
+        clone_image(image_path, &overlay_path, true)?;
+    }
+    Ok(overlay_path)
+}
+
+pub fn list_instances(image_name: &str) -> Vec<String> {
+    //! Returns the names of every instance of the given image, sorted.
+    let entries = match read_dir(get_instances_directory(image_name)) {
+        Ok(entries) => entries,
+        // no instance of the image has been created yet
+        Err(_) => return vec![],
+    };
+    let mut instances: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == OVERLAY_EXTENSION)
+        })
+        .filter_map(|path| {
+            path.file_stem()
+                .and_then(|stem| stem.to_str())
+                .map(|stem| stem.to_owned())
+        })
+        .collect();
+    instances.sort();
+    instances
+}
+
+pub fn delete_instance(image_name: &str, instance: &str) -> Result<(), String> {
+    //! Deletes the overlay disk of an instance, discarding every change made
+    //! to its disk.
+    let overlay_path: PathBuf = get_overlay_path(image_name, instance);
+    if !overlay_path.is_file() {
+        return Err(format!(
+            "Image '{image_name}' has no instance named '{instance}'."
+        ));
+    }
+    fs::remove_file(&overlay_path)
+        .map_err(|e| format!("Unable to delete '{}'. {e}", overlay_path.display()))?;
+    // the directory is only kept around while it holds instances.
+    let _ = fs::remove_dir(get_instances_directory(image_name));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_instance_vm_name, split_vm_name, validate_instance_name};
+
+    #[test]
+    fn test_instance_vm_name() {
+        assert_eq!(get_instance_vm_name("deb12", "web1"), "deb12@web1");
+        assert_eq!(split_vm_name("deb12@web1"), ("deb12", Some("web1")));
+        assert_eq!(split_vm_name("deb12"), ("deb12", None));
+    }
+
+    #[test]
+    fn test_validate_instance_name() {
+        assert!(validate_instance_name("web1").is_ok());
+        assert!(validate_instance_name("").is_err());
+        assert!(validate_instance_name(".hidden").is_err());
+        assert!(validate_instance_name("a/b").is_err());
+        assert!(validate_instance_name("a@b").is_err());
+    }
+}
@@ -0,0 +1,139 @@
+use serde::de::{Deserialize, Deserializer, Error};
+use std::collections::BTreeMap;
+
+/// How deep variables may refer to other variables, which also stops a
+/// variable referring to itself.
+const MAX_DEPTH: usize = 16;
+
+/// The variable holding the home directory of the user running vm-manager.
+pub const HOME_VARIABLE: &str = "HOME";
+/// The variable holding the name of the VM a setting belongs to.
+pub const VM_NAME_VARIABLE: &str = "VM_NAME";
+
+fn interpolate_with_depth(
+    text: &str,
+    variables: &BTreeMap<String, String>,
+    depth: usize,
+) -> Result<String, String> {
+    if depth > MAX_DEPTH {
+        return Err(format!(
+            "Variables in '{text}' refer to each other more than {MAX_DEPTH} levels deep."
+        ));
+    }
+    let mut interpolated: String = String::with_capacity(text.len());
+    let mut rest: &str = text;
+    while let Some(start) = rest.find('$') {
+        interpolated.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(escaped) = rest.strip_prefix("$${") {
+            interpolated.push_str("${");
+            rest = escaped;
+        } else if let Some(reference) = rest.strip_prefix("${") {
+            let end: usize = reference
+                .find('}')
+                .ok_or(format!("Unclosed '${{' in '{text}'."))?;
+            let name: &str = &reference[..end];
+            let value: &String = variables
+                .get(name)
+                .ok_or(format!("Unknown variable '${{{name}}}' in '{text}'."))?;
+            interpolated.push_str(&interpolate_with_depth(value, variables, depth + 1)?);
+            rest = &reference[end + 1..];
+        } else {
+            interpolated.push('$');
+            rest = &rest[1..];
+        }
+    }
+    interpolated.push_str(rest);
+    Ok(interpolated)
+}
+
+pub fn interpolate(text: &str, variables: &BTreeMap<String, String>) -> Result<String, String> {
+    //! Replaces each `${NAME}` in `text` with the value of the variable
+    //! `NAME`, which may itself refer to other variables. `$${` stands for a
+    //! literal `${`, and any other `$` is left alone.
+    interpolate_with_depth(text, variables, 0)
+}
+
+pub fn interpolate_value(
+    value: &mut serde_yaml::Value,
+    variables: &BTreeMap<String, String>,
+) -> Result<(), String> {
+    //! Interpolates every string in a config document.
+    match value {
+        serde_yaml::Value::String(text) => *text = interpolate(text, variables)?,
+        serde_yaml::Value::Sequence(values) => {
+            for value in values {
+                interpolate_value(value, variables)?;
+            }
+        }
+        serde_yaml::Value::Mapping(mapping) => {
+            for value in mapping.values_mut() {
+                interpolate_value(value, variables)?;
+            }
+        }
+        serde_yaml::Value::Tagged(tagged) => interpolate_value(&mut tagged.value, variables)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+pub fn deserialize_variables<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, String>, D::Error> {
+    //! Deserializes variables, whose values may also be written as numbers
+    //! or booleans, e.g. `ssh_port: 2222`.
+    let variables: BTreeMap<String, serde_yaml::Value> =
+        Option::deserialize(deserializer)?.unwrap_or_default();
+    variables
+        .into_iter()
+        .map(|(name, value)| match value {
+            serde_yaml::Value::String(value) => Ok((name, value)),
+            serde_yaml::Value::Number(value) => Ok((name, value.to_string())),
+            serde_yaml::Value::Bool(value) => Ok((name, value.to_string())),
+            _ => Err(D::Error::custom(format!(
+                "variable '{name}' must be a string, number or boolean"
+            ))),
+        })
+        .collect()
+}
+
+pub fn get_builtin_variables() -> BTreeMap<String, String> {
+    //! Returns the variables every config value can use.
+    BTreeMap::from([(
+        HOME_VARIABLE.to_owned(),
+        shellexpand::tilde("~").into_owned(),
+    )])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::interpolate;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_interpolate() {
+        let variables: BTreeMap<String, String> = BTreeMap::from([
+            ("HOME".to_owned(), "/home/lab".to_owned()),
+            ("VM_NAME".to_owned(), "web".to_owned()),
+            ("shares".to_owned(), "${HOME}/shares".to_owned()),
+            ("loop".to_owned(), "${loop}".to_owned()),
+        ]);
+        assert_eq!(
+            interpolate(
+                "-virtfs local,path=${shares}/${VM_NAME},mount_tag=${VM_NAME}",
+                &variables
+            )
+            .unwrap(),
+            "-virtfs local,path=/home/lab/shares/web,mount_tag=web"
+        );
+        assert_eq!(
+            interpolate("echo $PATH $${VM_NAME} $", &variables).unwrap(),
+            "echo $PATH ${VM_NAME} $"
+        );
+        assert!(interpolate("${missing}", &variables)
+            .unwrap_err()
+            .contains("Unknown variable '${missing}'"));
+        assert!(interpolate("${VM_NAME", &variables).is_err());
+        assert!(interpolate("${loop}", &variables).is_err());
+    }
+}
@@ -0,0 +1,140 @@
+use serde_json::{json, Value};
+
+use crate::qmp::QmpClient;
+
+/// Other names accepted for keys, and the QEMU key codes they stand for.
+const KEY_ALIASES: &[(&str, &str)] = &[
+    ("enter", "ret"),
+    ("return", "ret"),
+    ("space", "spc"),
+    ("escape", "esc"),
+    ("control", "ctrl"),
+    ("del", "delete"),
+    ("ins", "insert"),
+    ("pageup", "pgup"),
+    ("pagedown", "pgdn"),
+    ("super", "meta_l"),
+    ("win", "meta_l"),
+];
+
+/// The key, and whether shift is held, typing each punctuation character on
+/// a US keyboard layout.
+const PUNCTUATION_KEYS: &[(char, &str, bool)] = &[
+    (' ', "spc", false),
+    ('\n', "ret", false),
+    ('\t', "tab", false),
+    ('-', "minus", false),
+    ('_', "minus", true),
+    ('=', "equal", false),
+    ('+', "equal", true),
+    ('[', "bracket_left", false),
+    ('{', "bracket_left", true),
+    (']', "bracket_right", false),
+    ('}', "bracket_right", true),
+    ('\\', "backslash", false),
+    ('|', "backslash", true),
+    (';', "semicolon", false),
+    (':', "semicolon", true),
+    ('\'', "apostrophe", false),
+    ('"', "apostrophe", true),
+    ('`', "grave_accent", false),
+    ('~', "grave_accent", true),
+    (',', "comma", false),
+    ('<', "comma", true),
+    ('.', "dot", false),
+    ('>', "dot", true),
+    ('/', "slash", false),
+    ('?', "slash", true),
+    ('!', "1", true),
+    ('@', "2", true),
+    ('#', "3", true),
+    ('$', "4", true),
+    ('%', "5", true),
+    ('^', "6", true),
+    ('&', "7", true),
+    ('*', "8", true),
+    ('(', "9", true),
+    (')', "0", true),
+];
+
+pub fn parse_key_combination(combination: &str) -> Result<Vec<String>, String> {
+    //! Returns the QEMU key codes of the keys pressed together in a key
+    //! combination such as `ctrl-alt-f2`. A lone `-` is the minus key.
+    if combination == "-" {
+        return Ok(vec!["minus".to_owned()]);
+    }
+    combination
+        .to_lowercase()
+        .split('-')
+        .map(|key| {
+            if key.is_empty() {
+                return Err(format!(
+                    "Invalid key combination '{combination}'. Join keys with '-', e.g. 'ctrl-alt-f2'."
+                ));
+            }
+            Ok(KEY_ALIASES
+                .iter()
+                .find(|(alias, _)| *alias == key)
+                .map_or(key, |(_, code)| code)
+                .to_owned())
+        })
+        .collect()
+}
+
+pub fn get_typing_keys(text: &str) -> Result<Vec<Vec<String>>, String> {
+    //! Returns the key combinations typing `text` on a US keyboard layout.
+    text.chars()
+        .map(|character| match character {
+            'a'..='z' | '0'..='9' => Ok(vec![character.to_string()]),
+            'A'..='Z' => Ok(vec![
+                "shift".to_owned(),
+                character.to_ascii_lowercase().to_string(),
+            ]),
+            _ => PUNCTUATION_KEYS
+                .iter()
+                .find(|(punctuation, _, _)| *punctuation == character)
+                .map(|(_, key, shift)| match shift {
+                    true => vec!["shift".to_owned(), key.to_string()],
+                    false => vec![key.to_string()],
+                })
+                .ok_or(format!("Unable to type '{character}'.")),
+        })
+        .collect()
+}
+
+pub fn send_keys(qmp: &mut QmpClient, keys: &[String]) -> Result<(), String> {
+    //! Presses the keys together, then releases them.
+    let keys: Vec<Value> = keys
+        .iter()
+        .map(|key| json!({ "type": "qcode", "data": key }))
+        .collect();
+    qmp.execute("send-key", Some(json!({ "keys": keys })))
+        .map(|_| ())
+        .map_err(|e| format!("Unable to send keys. {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_typing_keys, parse_key_combination};
+
+    #[test]
+    fn test_parse_key_combination() {
+        assert_eq!(
+            parse_key_combination("Ctrl-Alt-F2").unwrap(),
+            vec!["ctrl", "alt", "f2"]
+        );
+        assert_eq!(parse_key_combination("enter").unwrap(), vec!["ret"]);
+        assert_eq!(parse_key_combination("-").unwrap(), vec!["minus"]);
+        assert!(parse_key_combination("ctrl--").is_err());
+
+        assert_eq!(
+            get_typing_keys("aB?").unwrap(),
+            vec![
+                vec!["a".to_owned()],
+                vec!["shift".to_owned(), "b".to_owned()],
+                vec!["shift".to_owned(), "slash".to_owned()],
+            ]
+        );
+        assert!(get_typing_keys("é").is_err());
+    }
+}
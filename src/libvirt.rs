@@ -0,0 +1,1374 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::{
+    Config, DiskThrottling, NetworkMode, PciDevice, PortMapping, PrivateNetwork, QemuRunOption,
+    Share, ShareDriver, VMConfig, VmConfigEdit,
+};
+use crate::display::DisplayType;
+use crate::image::{convert_image, get_new_image_path, parse_memory_size, parse_size};
+use crate::network::{get_mac_address, get_network_device_name, nic_model, private_network_nics};
+use crate::parse_args::ImageFormat;
+use crate::state::ForwardedPort;
+
+/// Memory qemu gives a guest when it isn't told otherwise, in KiB.
+const DEFAULT_MEMORY_KIB: u64 = 128 * 1024;
+/// Options turned into parts of the domain, rather than left out of it.
+const TRANSLATED_OPTIONS: [&str; 16] = [
+    "-m",
+    "-smp",
+    "-machine",
+    "-M",
+    "-cpu",
+    "-accel",
+    "-enable-kvm",
+    "-nic",
+    "-drive",
+    "-cdrom",
+    "-boot",
+    "-vnc",
+    "-display",
+    "-daemonize",
+    "-nographic",
+    "-name",
+];
+
+/// A libvirt domain, as far as the settings of a VM can describe one.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Domain {
+    pub name: String,
+    /// Whether the domain runs with KVM rather than qemu's emulation.
+    pub kvm: bool,
+    pub memory_kib: u64,
+    pub vcpus: usize,
+    /// vCPUs the domain can be grown to while it runs.
+    pub max_vcpus: Option<usize>,
+    /// Host CPUs the domain runs on, e.g. `2-5`.
+    pub cpuset: Option<String>,
+    /// The CPU model, `host` passing the host's CPU through.
+    pub cpu_model: Option<String>,
+    pub machine: Option<String>,
+    pub uefi: bool,
+    pub secure_boot: bool,
+    pub hugepages: bool,
+    pub disks: Vec<DomainDisk>,
+    pub interfaces: Vec<DomainInterface>,
+    pub shares: Vec<Share>,
+    /// PCI addresses of the host devices passed through.
+    pub hostdevs: Vec<String>,
+    pub tpm: bool,
+    pub graphics: Option<DisplayType>,
+    pub balloon: bool,
+    pub guest_agent: bool,
+}
+
+/// A disk or CD-ROM of a domain.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct DomainDisk {
+    pub path: String,
+    /// The image format, e.g. `qcow2` or `raw`.
+    pub format: String,
+    /// The bus the disk is attached to: `ide`, `sata`, `scsi` or `virtio`.
+    pub bus: String,
+    pub cdrom: bool,
+    pub throttling: Option<DiskThrottling>,
+}
+
+/// What a domain's network interface is attached to.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum InterfaceSource {
+    /// User-mode networking, with the ports forwarded to the guest.
+    User(Vec<ForwardedPort>),
+    /// A libvirt virtual network, e.g. `default`.
+    Network(String),
+    /// A host bridge.
+    Bridge(String),
+    /// A tap device, optionally added to a bridge.
+    Tap(String, Option<String>),
+    /// A macvtap device on a host interface.
+    Macvtap(String),
+    /// A multicast group and port, e.g. `230.0.0.1:1234`.
+    Multicast(String),
+}
+
+/// A network interface of a domain.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct DomainInterface {
+    pub source: InterfaceSource,
+    /// The NIC model as libvirt names it, e.g. `virtio` or `e1000`.
+    pub model: String,
+    pub mac_address: Option<String>,
+}
+
+fn get_property<'a>(arguments: &'a str, name: &str) -> Option<&'a str> {
+    //! Returns the value of the property `name` in the comma separated
+    //! arguments of an option, e.g. `format` in `file=a.img,format=raw`.
+    arguments
+        .split(',')
+        .find_map(|property| property.strip_prefix(name)?.strip_prefix('='))
+}
+
+fn get_libvirt_nic_model(model: &str) -> String {
+    //! Returns libvirt's name for a qemu NIC model.
+    match model {
+        "virtio-net-pci" | "virtio-net" => "virtio".to_owned(),
+        model => model.to_owned(),
+    }
+}
+
+fn get_disk_bus(machine: Option<&str>) -> &'static str {
+    //! Returns the bus qemu attaches a `-drive` without an `if=` property
+    //! to, which is IDE emulated by AHCI on q35.
+    if machine.is_some_and(|machine| machine.contains("q35")) {
+        "sata"
+    } else {
+        "ide"
+    }
+}
+
+fn get_drive_disk(arguments: &str, default_bus: &str) -> Option<DomainDisk> {
+    //! Returns the disk added by a `-drive` option, if it names a file.
+    let path: &str = get_property(arguments, "file")?;
+    let bus: &str = match get_property(arguments, "if") {
+        Some("virtio") => "virtio",
+        Some("scsi") => "scsi",
+        _ => default_bus,
+    };
+    Some(DomainDisk {
+        path: path.to_owned(),
+        format: get_property(arguments, "format")
+            .unwrap_or("raw")
+            .to_owned(),
+        bus: bus.to_owned(),
+        cdrom: get_property(arguments, "media") == Some("cdrom"),
+        throttling: None,
+    })
+}
+
+fn get_user_interfaces(options: &[QemuRunOption], model: &str) -> Vec<DomainInterface> {
+    //! Returns the user-mode interfaces of the VM's `-nic` options, with
+    //! the ports forwarded through them. qemu gives a VM without any a
+    //! user-mode NIC of its own.
+    let nics: Vec<&QemuRunOption> = options
+        .iter()
+        .filter(|option| option.flag() == "-nic")
+        .collect();
+    if nics.is_empty() {
+        return vec![DomainInterface {
+            source: InterfaceSource::User(vec![]),
+            model: model.to_owned(),
+            mac_address: None,
+        }];
+    }
+    nics.iter()
+        .filter(|nic| {
+            let kind: &str = nic.arguments().split(',').next().unwrap_or_default();
+            kind.is_empty() || kind == "user" || kind.contains('=')
+        })
+        .map(|nic| DomainInterface {
+            source: InterfaceSource::User(ForwardedPort::from_command_line(&[nic
+                .arguments()
+                .to_owned()])),
+            model: get_property(nic.arguments(), "model")
+                .map_or(model.to_owned(), get_libvirt_nic_model),
+            mac_address: get_property(nic.arguments(), "mac").map(|mac| mac.to_owned()),
+        })
+        .collect()
+}
+
+pub fn get_domain(
+    vm: &VMConfig,
+    config: &Config,
+    disk: &Path,
+    disk_format: &str,
+) -> Result<(Domain, Vec<String>), String> {
+    //! Returns the libvirt domain closest to how the VM runs, with its disk
+    //! at `disk`, along with notes on the settings and options which have
+    //! no equivalent and are left out.
+    let mut notes: Vec<String> = vec![];
+    let options: &[QemuRunOption] = vm.options();
+    let find_option = |flags: &[&str]| -> Option<&str> {
+        options
+            .iter()
+            .find(|option| flags.contains(&option.flag()))
+            .map(|option| option.arguments())
+    };
+
+    let memory: Option<&str> = vm.memory().or_else(|| find_option(&["-m"]));
+    let memory_kib: u64 = match memory {
+        Some(memory) => {
+            parse_memory_size(memory.split(',').next().unwrap_or_default())
+                .ok_or(format!("'{memory}' is not a valid memory size."))?
+                / 1024
+        }
+        None => DEFAULT_MEMORY_KIB,
+    };
+    let vcpus: usize = vm
+        .cpus()
+        .or_else(|| {
+            let smp: &str = find_option(&["-smp"])?;
+            smp.split(',')
+                .next()
+                .and_then(|cpus| cpus.parse().ok())
+                .or_else(|| get_property(smp, "cpus")?.parse().ok())
+        })
+        .unwrap_or(1);
+    let machine: Option<String> = vm
+        .machine()
+        .map(|machine| machine.to_owned())
+        .or_else(|| {
+            let machine: &str = find_option(&["-machine", "-M"])?;
+            get_property(machine, "type")
+                .or(machine.split(',').next().filter(|kind| !kind.contains('=')))
+                .map(|machine| machine.to_owned())
+        })
+        .or(vm.secure_boot().then(|| "q35".to_owned()));
+    let kvm: bool = options.iter().any(|option| {
+        option.as_str() == "-enable-kvm"
+            || (option.flag() == "-accel" && option.arguments().starts_with("kvm"))
+            || (["-machine", "-M"].contains(&option.flag())
+                && get_property(option.arguments(), "accel")
+                    .is_some_and(|accel| accel.starts_with("kvm")))
+    });
+
+    let bus: &str = get_disk_bus(machine.as_deref());
+    let mut disks: Vec<DomainDisk> = vec![DomainDisk {
+        path: disk.display().to_string(),
+        format: disk_format.to_owned(),
+        bus: bus.to_owned(),
+        cdrom: false,
+        throttling: vm.disk_throttling().cloned(),
+    }];
+    for option in options.iter().filter(|option| option.flag() == "-drive") {
+        match get_drive_disk(option.arguments(), bus) {
+            Some(disk) => disks.push(disk),
+            None => notes.push(format!(
+                "Option '{}' names no file, so it was left out.",
+                option.as_str()
+            )),
+        }
+    }
+    let cdrom: Option<String> = vm
+        .cdrom()
+        .map(|cdrom| shellexpand::tilde(cdrom).to_string())
+        .or_else(|| find_option(&["-cdrom"]).map(|cdrom| cdrom.to_owned()));
+    if let Some(cdrom) = cdrom {
+        disks.push(DomainDisk {
+            path: cdrom,
+            format: "raw".to_owned(),
+            bus: bus.to_owned(),
+            cdrom: true,
+            throttling: None,
+        });
+    }
+
+    let model: String = get_libvirt_nic_model(&nic_model(options));
+    let network_mode: NetworkMode = vm
+        .network()
+        .map_or(NetworkMode::User, |network| network.mode);
+    let mut interfaces: Vec<DomainInterface> = match (network_mode, vm.network()) {
+        (NetworkMode::Bridge, Some(network)) => {
+            vec![DomainInterface {
+                source: InterfaceSource::Bridge(network.bridge.clone().ok_or(
+                    "'network: bridge' needs the name of the bridge, e.g. 'bridge: br0'.",
+                )?),
+                model: model.clone(),
+                mac_address: None,
+            }]
+        }
+        (NetworkMode::Tap, Some(network)) => vec![DomainInterface {
+            source: InterfaceSource::Tap(
+                get_network_device_name(vm.name(), network),
+                network.bridge.clone(),
+            ),
+            model: model.clone(),
+            mac_address: None,
+        }],
+        (NetworkMode::Macvtap, Some(network)) => vec![DomainInterface {
+            source: InterfaceSource::Macvtap(network.parent.clone().ok_or(
+                "'network: macvtap' needs the host interface to attach to, e.g. 'parent: eth0'.",
+            )?),
+            model: model.clone(),
+            mac_address: None,
+        }],
+        _ => {
+            if network_mode == NetworkMode::Isolated {
+                notes.push(
+                    "libvirt can't cut user-mode networking off from the host and the internet, so the isolated network is a plain user-mode one.".to_owned(),
+                );
+            }
+            if vm.smb_share().is_some() {
+                notes.push("libvirt has no SMB share, so 'smb_share' was left out.".to_owned());
+            }
+            get_user_interfaces(options, &model)
+        }
+    };
+    if let Some(interface) = interfaces.first_mut() {
+        if interface.mac_address.is_none() {
+            interface.mac_address = Some(get_mac_address(vm.name(), vm.network())?);
+        }
+    }
+    let private_networks: Vec<&PrivateNetwork> = vm
+        .private_networks()
+        .iter()
+        .map(|name| {
+            config.get_private_network(name).ok_or(format!(
+                "There is no network named '{name}' in the config's 'networks' section."
+            ))
+        })
+        .collect::<Result<Vec<&PrivateNetwork>, String>>()?;
+    for nic in private_network_nics(vm.name(), &private_networks, options)? {
+        interfaces.push(DomainInterface {
+            source: InterfaceSource::Multicast(
+                get_property(&nic, "mcast").unwrap_or_default().to_owned(),
+            ),
+            model: model.clone(),
+            mac_address: get_property(&nic, "mac").map(|mac| mac.to_owned()),
+        });
+    }
+
+    if vm.display() == Some(DisplayType::Gtk) {
+        notes.push("libvirt has no GTK display, so a VNC one is used instead.".to_owned());
+    }
+    if vm.max_memory().is_some() {
+        notes.push("Memory hotplug isn't exported, so 'max_memory' was left out.".to_owned());
+    }
+    if vm.netboot().is_some() {
+        notes.push("Network boot settings were left out.".to_owned());
+    }
+    let has_balloon_option = |option: &&QemuRunOption| {
+        option.flag() == "-device" && option.arguments().starts_with("virtio-balloon")
+    };
+    for option in options.iter().filter(|option| {
+        !TRANSLATED_OPTIONS.contains(&option.flag()) && !has_balloon_option(option)
+    }) {
+        notes.push(format!(
+            "Option '{}' has no libvirt equivalent here, so it was left out.",
+            option.as_str()
+        ));
+    }
+
+    let domain: Domain = Domain {
+        name: vm.name().to_owned(),
+        kvm,
+        memory_kib,
+        vcpus,
+        max_vcpus: vm.max_cpus(),
+        cpuset: vm.cpu_affinity().map(|affinity| affinity.cores.clone()),
+        cpu_model: find_option(&["-cpu"])
+            .and_then(|cpu| cpu.split(',').next())
+            .map(|cpu| cpu.to_owned()),
+        machine,
+        uefi: vm.uefi() || vm.secure_boot(),
+        secure_boot: vm.secure_boot(),
+        hugepages: vm.hugepages(),
+        disks,
+        interfaces,
+        shares: vm.shares().to_vec(),
+        hostdevs: vm
+            .pci_passthrough()
+            .iter()
+            .map(|device| device.address.clone())
+            .collect(),
+        tpm: vm.tpm(),
+        graphics: vm.display(),
+        balloon: vm.balloon() || options.iter().any(|option| has_balloon_option(&option)),
+        guest_agent: vm.guest_agent(),
+    };
+    Ok((domain, notes))
+}
+
+fn escape(text: &str) -> String {
+    //! Escapes text for use in an XML attribute or element.
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\'', "&apos;")
+        .replace('"', "&quot;")
+}
+
+fn render_pci_address(address: &str) -> String {
+    //! Returns the `<address>` of a PCI device given as `lspci -D` shows it,
+    //! e.g. `0000:01:00.0`. The domain may be omitted.
+    let (address, function) = address.rsplit_once('.').unwrap_or((address, "0"));
+    let mut parts: Vec<&str> = address.split(':').collect();
+    if parts.len() < 3 {
+        parts.insert(0, "0000");
+    }
+    format!(
+        "<address domain='0x{}' bus='0x{}' slot='0x{}' function='0x{function}'/>",
+        parts[0],
+        parts.get(1).unwrap_or(&"00"),
+        parts.get(2).unwrap_or(&"00")
+    )
+}
+
+fn render_iotune(throttling: &DiskThrottling) -> Vec<String> {
+    //! Returns the `<iotune>` elements applying a disk's I/O limits.
+    let bytes = |limit: &Option<String>| limit.as_deref().and_then(parse_size);
+    [
+        ("total_iops_sec", throttling.iops),
+        ("read_iops_sec", throttling.iops_read),
+        ("write_iops_sec", throttling.iops_write),
+        ("total_bytes_sec", bytes(&throttling.bps)),
+        ("read_bytes_sec", bytes(&throttling.bps_read)),
+        ("write_bytes_sec", bytes(&throttling.bps_write)),
+    ]
+    .iter()
+    .filter_map(|(name, limit)| limit.map(|limit| format!("<{name}>{limit}</{name}>")))
+    .collect()
+}
+
+pub fn render_domain_xml(domain: &Domain) -> String {
+    //! Returns the domain XML of `domain`, as `virsh define` takes it.
+    let mut lines: Vec<String> = vec![];
+    let mut add =
+        |indent: usize, line: String| lines.push(format!("{}{line}", "  ".repeat(indent)));
+    add(
+        0,
+        format!(
+            "<domain type='{}'>",
+            if domain.kvm { "kvm" } else { "qemu" }
+        ),
+    );
+    add(1, format!("<name>{}</name>", escape(&domain.name)));
+    add(
+        1,
+        format!("<memory unit='KiB'>{}</memory>", domain.memory_kib),
+    );
+    if domain.hugepages
+        || domain
+            .shares
+            .iter()
+            .any(|share| share.driver == ShareDriver::Virtiofs)
+    {
+        add(1, "<memoryBacking>".to_owned());
+        if domain.hugepages {
+            add(2, "<hugepages/>".to_owned());
+        } else {
+            add(2, "<source type='memfd'/>".to_owned());
+        }
+        add(2, "<access mode='shared'/>".to_owned());
+        add(1, "</memoryBacking>".to_owned());
+    }
+    let cpuset: String = domain.cpuset.as_ref().map_or(String::new(), |cpuset| {
+        format!(" cpuset='{}'", escape(cpuset))
+    });
+    match domain.max_vcpus {
+        Some(max_vcpus) => add(
+            1,
+            format!(
+                "<vcpu placement='static'{cpuset} current='{}'>{max_vcpus}</vcpu>",
+                domain.vcpus
+            ),
+        ),
+        None => add(
+            1,
+            format!("<vcpu placement='static'{cpuset}>{}</vcpu>", domain.vcpus),
+        ),
+    }
+    add(
+        1,
+        format!("<os{}>", if domain.uefi { " firmware='efi'" } else { "" }),
+    );
+    add(
+        2,
+        format!(
+            "<type arch='x86_64'{}>hvm</type>",
+            domain
+                .machine
+                .as_ref()
+                .map_or(String::new(), |machine| format!(
+                    " machine='{}'",
+                    escape(machine)
+                ))
+        ),
+    );
+    if domain.secure_boot {
+        add(2, "<firmware>".to_owned());
+        add(
+            3,
+            "<feature enabled='yes' name='enrolled-keys'/>".to_owned(),
+        );
+        add(3, "<feature enabled='yes' name='secure-boot'/>".to_owned());
+        add(2, "</firmware>".to_owned());
+        add(2, "<loader secure='yes'/>".to_owned());
+    }
+    if domain.disks.iter().any(|disk| disk.cdrom) {
+        add(2, "<boot dev='cdrom'/>".to_owned());
+    }
+    add(2, "<boot dev='hd'/>".to_owned());
+    add(1, "</os>".to_owned());
+    add(1, "<features>".to_owned());
+    add(2, "<acpi/>".to_owned());
+    add(2, "<apic/>".to_owned());
+    if domain.secure_boot {
+        add(2, "<smm state='on'/>".to_owned());
+    }
+    add(1, "</features>".to_owned());
+    match domain.cpu_model.as_deref() {
+        Some("host") => add(1, "<cpu mode='host-passthrough'/>".to_owned()),
+        Some(model) => {
+            add(1, "<cpu mode='custom' match='exact'>".to_owned());
+            add(
+                2,
+                format!("<model fallback='allow'>{}</model>", escape(model)),
+            );
+            add(1, "</cpu>".to_owned());
+        }
+        None => {}
+    }
+    add(1, "<devices>".to_owned());
+
+    let mut bus_disks: Vec<(String, usize)> = vec![];
+    for disk in &domain.disks {
+        let prefix: &str = match disk.bus.as_str() {
+            "ide" => "hd",
+            "virtio" => "vd",
+            _ => "sd",
+        };
+        let index: usize = match bus_disks.iter_mut().find(|(p, _)| p == prefix) {
+            Some((_, count)) => {
+                *count += 1;
+                *count - 1
+            }
+            None => {
+                bus_disks.push((prefix.to_owned(), 1));
+                0
+            }
+        };
+        add(
+            2,
+            format!(
+                "<disk type='file' device='{}'>",
+                if disk.cdrom { "cdrom" } else { "disk" }
+            ),
+        );
+        add(
+            3,
+            format!("<driver name='qemu' type='{}'/>", escape(&disk.format)),
+        );
+        add(3, format!("<source file='{}'/>", escape(&disk.path)));
+        add(
+            3,
+            format!(
+                "<target dev='{prefix}{}' bus='{}'/>",
+                (b'a' + index as u8) as char,
+                escape(&disk.bus)
+            ),
+        );
+        if let Some(throttling) = &disk.throttling {
+            add(3, "<iotune>".to_owned());
+            for element in render_iotune(throttling) {
+                add(4, element);
+            }
+            add(3, "</iotune>".to_owned());
+        }
+        if disk.cdrom {
+            add(3, "<readonly/>".to_owned());
+        }
+        add(2, "</disk>".to_owned());
+    }
+
+    for interface in &domain.interfaces {
+        let kind: &str = match &interface.source {
+            InterfaceSource::User(_) => "user",
+            InterfaceSource::Network(_) => "network",
+            InterfaceSource::Bridge(_) | InterfaceSource::Tap(_, Some(_)) => "bridge",
+            InterfaceSource::Tap(_, None) => "ethernet",
+            InterfaceSource::Macvtap(_) => "direct",
+            InterfaceSource::Multicast(_) => "mcast",
+        };
+        add(2, format!("<interface type='{kind}'>"));
+        if let Some(mac_address) = &interface.mac_address {
+            add(3, format!("<mac address='{}'/>", escape(mac_address)));
+        }
+        match &interface.source {
+            InterfaceSource::User(ports) => {
+                // libvirt only forwards ports through passt.
+                if !ports.is_empty() {
+                    add(3, "<backend type='passt'/>".to_owned());
+                }
+                for port in ports {
+                    let address: String = if port.host_address.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" address='{}'", escape(&port.host_address))
+                    };
+                    add(
+                        3,
+                        format!("<portForward proto='{}'{address}>", escape(&port.protocol)),
+                    );
+                    add(
+                        4,
+                        format!(
+                            "<range start='{}' to='{}'/>",
+                            port.host_port, port.guest_port
+                        ),
+                    );
+                    add(3, "</portForward>".to_owned());
+                }
+            }
+            InterfaceSource::Network(network) => {
+                add(3, format!("<source network='{}'/>", escape(network)));
+            }
+            InterfaceSource::Bridge(bridge) => {
+                add(3, format!("<source bridge='{}'/>", escape(bridge)));
+            }
+            InterfaceSource::Tap(device, bridge) => {
+                if let Some(bridge) = bridge {
+                    add(3, format!("<source bridge='{}'/>", escape(bridge)));
+                }
+                add(3, format!("<target dev='{}'/>", escape(device)));
+            }
+            InterfaceSource::Macvtap(parent) => {
+                add(
+                    3,
+                    format!("<source dev='{}' mode='bridge'/>", escape(parent)),
+                );
+            }
+            InterfaceSource::Multicast(group) => {
+                let (address, port) = group.rsplit_once(':').unwrap_or((group, ""));
+                add(
+                    3,
+                    format!(
+                        "<source address='{}' port='{}'/>",
+                        escape(address),
+                        escape(port)
+                    ),
+                );
+            }
+        }
+        add(3, format!("<model type='{}'/>", escape(&interface.model)));
+        add(2, "</interface>".to_owned());
+    }
+
+    for share in &domain.shares {
+        match share.driver {
+            ShareDriver::Virtiofs => {
+                add(
+                    2,
+                    "<filesystem type='mount' accessmode='passthrough'>".to_owned(),
+                );
+                add(3, "<driver type='virtiofs'/>".to_owned());
+            }
+            ShareDriver::NineP => add(
+                2,
+                "<filesystem type='mount' accessmode='mapped'>".to_owned(),
+            ),
+        }
+        add(
+            3,
+            format!(
+                "<source dir='{}'/>",
+                escape(&shellexpand::tilde(&share.path))
+            ),
+        );
+        add(3, format!("<target dir='{}'/>", escape(&share.tag)));
+        if share.readonly {
+            add(3, "<readonly/>".to_owned());
+        }
+        add(2, "</filesystem>".to_owned());
+    }
+
+    for address in &domain.hostdevs {
+        add(
+            2,
+            "<hostdev mode='subsystem' type='pci' managed='yes'>".to_owned(),
+        );
+        add(3, "<source>".to_owned());
+        add(4, render_pci_address(address));
+        add(3, "</source>".to_owned());
+        add(2, "</hostdev>".to_owned());
+    }
+
+    add(2, "<serial type='pty'/>".to_owned());
+    add(2, "<console type='pty'/>".to_owned());
+    if domain.guest_agent {
+        add(2, "<channel type='unix'>".to_owned());
+        add(
+            3,
+            "<target type='virtio' name='org.qemu.guest_agent.0'/>".to_owned(),
+        );
+        add(2, "</channel>".to_owned());
+    }
+    match domain.graphics {
+        Some(DisplayType::Vnc) | Some(DisplayType::Gtk) => {
+            add(2, "<graphics type='vnc' autoport='yes'/>".to_owned())
+        }
+        Some(DisplayType::Spice) => add(2, "<graphics type='spice' autoport='yes'/>".to_owned()),
+        Some(DisplayType::None) | None => {}
+    }
+    if domain.tpm {
+        add(2, "<tpm model='tpm-crb'>".to_owned());
+        add(3, "<backend type='emulator' version='2.0'/>".to_owned());
+        add(2, "</tpm>".to_owned());
+    }
+    add(
+        2,
+        format!(
+            "<memballoon model='{}'/>",
+            if domain.balloon { "virtio" } else { "none" }
+        ),
+    );
+    add(1, "</devices>".to_owned());
+    add(0, "</domain>".to_owned());
+    lines.join("\n")
+}
+
+fn get_child<'a, 'input>(
+    node: roxmltree::Node<'a, 'input>,
+    name: &str,
+) -> Option<roxmltree::Node<'a, 'input>> {
+    node.children().find(|child| child.has_tag_name(name))
+}
+
+fn get_children<'a, 'input>(
+    node: roxmltree::Node<'a, 'input>,
+    name: &'a str,
+) -> impl Iterator<Item = roxmltree::Node<'a, 'input>> + 'a {
+    node.children()
+        .filter(move |child| child.has_tag_name(name))
+}
+
+fn parse_memory_kib(node: roxmltree::Node) -> Result<u64, String> {
+    //! Returns the size of a libvirt `<memory>` element in KiB, which is its
+    //! unit unless it has another.
+    let amount: &str = node.text().unwrap_or_default().trim();
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("'{amount}' is not a valid memory size."))?;
+    let bytes: u64 = match node.attribute("unit").unwrap_or("KiB") {
+        "b" | "bytes" => 1,
+        "KB" => 1000,
+        "k" | "KiB" => 1 << 10,
+        "MB" => 1000 * 1000,
+        "M" | "MiB" => 1 << 20,
+        "GB" => 1000 * 1000 * 1000,
+        "G" | "GiB" => 1 << 30,
+        "TB" => 1000 * 1000 * 1000 * 1000,
+        "T" | "TiB" => 1 << 40,
+        unit => return Err(format!("Unknown memory unit '{unit}'.")),
+    };
+    Ok(amount.saturating_mul(bytes) / 1024)
+}
+
+fn parse_pci_address(address: roxmltree::Node) -> Option<String> {
+    //! Returns a PCI address as `lspci -D` shows it, e.g. `0000:01:00.0`,
+    //! from a libvirt `<address>` element.
+    let number = |name: &str| -> Option<u32> {
+        let value: &str = address.attribute(name)?;
+        match value.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16).ok(),
+            None => value.parse().ok(),
+        }
+    };
+    Some(format!(
+        "{:04x}:{:02x}:{:02x}.{:x}",
+        number("domain").unwrap_or(0),
+        number("bus")?,
+        number("slot")?,
+        number("function").unwrap_or(0)
+    ))
+}
+
+fn parse_interface(interface: roxmltree::Node, notes: &mut Vec<String>) -> Option<DomainInterface> {
+    //! Returns the interface described by an `<interface>` element, if
+    //! vm-manager can attach a guest the same way.
+    let source: Option<roxmltree::Node> = get_child(interface, "source");
+    let source_attribute = |name: &str| -> Option<String> {
+        source
+            .and_then(|source| source.attribute(name))
+            .map(|value| value.to_owned())
+    };
+    let source: InterfaceSource = match interface.attribute("type").unwrap_or_default() {
+        "user" => InterfaceSource::User(
+            get_children(interface, "portForward")
+                .flat_map(|forward| {
+                    get_children(forward, "range").filter_map(move |range| {
+                        let host_port: usize = range.attribute("start")?.parse().ok()?;
+                        Some(ForwardedPort {
+                            protocol: forward.attribute("proto").unwrap_or("tcp").to_owned(),
+                            host_address: forward
+                                .attribute("address")
+                                .unwrap_or_default()
+                                .to_owned(),
+                            host_port,
+                            guest_port: range
+                                .attribute("to")
+                                .and_then(|port| port.parse().ok())
+                                .unwrap_or(host_port),
+                        })
+                    })
+                })
+                .collect(),
+        ),
+        "network" => InterfaceSource::Network(source_attribute("network")?),
+        "bridge" => InterfaceSource::Bridge(source_attribute("bridge")?),
+        "ethernet" => InterfaceSource::Tap(
+            get_child(interface, "target")?.attribute("dev")?.to_owned(),
+            None,
+        ),
+        "direct" => InterfaceSource::Macvtap(source_attribute("dev")?),
+        "mcast" => InterfaceSource::Multicast(format!(
+            "{}:{}",
+            source_attribute("address")?,
+            source_attribute("port")?
+        )),
+        kind => {
+            notes.push(format!(
+                "The '{kind}' interface has no equivalent, so it was left out."
+            ));
+            return None;
+        }
+    };
+    Some(DomainInterface {
+        source,
+        model: get_child(interface, "model")
+            .and_then(|model| model.attribute("type"))
+            .unwrap_or("virtio")
+            .to_owned(),
+        mac_address: get_child(interface, "mac")
+            .and_then(|mac| mac.attribute("address"))
+            .map(|mac| mac.to_owned()),
+    })
+}
+
+pub fn parse_domain_xml(xml: &str) -> Result<(Domain, Vec<String>), String> {
+    //! Returns the domain described by libvirt domain XML, as `virsh dumpxml`
+    //! prints it, along with notes on the devices which were left out.
+    let document: roxmltree::Document =
+        roxmltree::Document::parse(xml).map_err(|e| format!("Unable to parse domain XML. {e}"))?;
+    let root: roxmltree::Node = document.root_element();
+    if !root.has_tag_name("domain") {
+        return Err("The XML is not a libvirt domain.".to_owned());
+    }
+    let mut notes: Vec<String> = vec![];
+    let name: String = get_child(root, "name")
+        .and_then(|name| name.text())
+        .ok_or("The domain has no name.")?
+        .trim()
+        .to_owned();
+    let memory_kib: u64 = match get_child(root, "currentMemory").or(get_child(root, "memory")) {
+        Some(memory) => parse_memory_kib(memory)?,
+        None => DEFAULT_MEMORY_KIB,
+    };
+    let vcpu: Option<roxmltree::Node> = get_child(root, "vcpu");
+    let max_vcpus: usize = vcpu
+        .and_then(|vcpu| vcpu.text()?.trim().parse().ok())
+        .unwrap_or(1);
+    let vcpus: usize = vcpu
+        .and_then(|vcpu| vcpu.attribute("current")?.parse().ok())
+        .unwrap_or(max_vcpus);
+    let os: Option<roxmltree::Node> = get_child(root, "os");
+    let loader: Option<roxmltree::Node> = os.and_then(|os| get_child(os, "loader"));
+    let secure_boot: bool = loader.and_then(|loader| loader.attribute("secure")) == Some("yes")
+        || os
+            .and_then(|os| get_child(os, "firmware"))
+            .is_some_and(|firmware| {
+                get_children(firmware, "feature").any(|feature| {
+                    feature.attribute("name") == Some("secure-boot")
+                        && feature.attribute("enabled") == Some("yes")
+                })
+            });
+    let uefi: bool = os.and_then(|os| os.attribute("firmware")) == Some("efi")
+        || loader.and_then(|loader| loader.attribute("type")) == Some("pflash")
+        || secure_boot;
+    let cpu_model: Option<String> =
+        get_child(root, "cpu").and_then(|cpu| match cpu.attribute("mode") {
+            Some("host-passthrough") | Some("host-model") => Some("host".to_owned()),
+            _ => Some(get_child(cpu, "model")?.text()?.trim().to_owned()),
+        });
+
+    let devices: roxmltree::Node =
+        get_child(root, "devices").ok_or("The domain has no devices.")?;
+    let mut disks: Vec<DomainDisk> = vec![];
+    for disk in get_children(devices, "disk") {
+        let source: Option<&str> = get_child(disk, "source")
+            .and_then(|source| source.attribute("file").or(source.attribute("dev")));
+        let device: &str = disk.attribute("device").unwrap_or("disk");
+        match (device, source) {
+            ("disk" | "cdrom", Some(source)) => disks.push(DomainDisk {
+                path: source.to_owned(),
+                format: get_child(disk, "driver")
+                    .and_then(|driver| driver.attribute("type"))
+                    .unwrap_or("raw")
+                    .to_owned(),
+                bus: get_child(disk, "target")
+                    .and_then(|target| target.attribute("bus"))
+                    .unwrap_or("ide")
+                    .to_owned(),
+                cdrom: device == "cdrom",
+                throttling: None,
+            }),
+            ("cdrom", None) => {}
+            _ => notes.push(format!(
+                "A '{device}' which isn't a local file or block device was left out."
+            )),
+        }
+    }
+    let interfaces: Vec<DomainInterface> = get_children(devices, "interface")
+        .filter_map(|interface| parse_interface(interface, &mut notes))
+        .collect();
+    let shares: Vec<Share> = get_children(devices, "filesystem")
+        .filter_map(|filesystem| {
+            Some(Share {
+                path: get_child(filesystem, "source")?
+                    .attribute("dir")?
+                    .to_owned(),
+                tag: get_child(filesystem, "target")?
+                    .attribute("dir")?
+                    .to_owned(),
+                readonly: get_child(filesystem, "readonly").is_some(),
+                driver: match get_child(filesystem, "driver")
+                    .and_then(|driver| driver.attribute("type"))
+                {
+                    Some("virtiofs") => ShareDriver::Virtiofs,
+                    _ => ShareDriver::NineP,
+                },
+            })
+        })
+        .collect();
+    let hostdevs: Vec<String> = get_children(devices, "hostdev")
+        .filter(|hostdev| hostdev.attribute("type") == Some("pci"))
+        .filter_map(|hostdev| {
+            parse_pci_address(get_child(get_child(hostdev, "source")?, "address")?)
+        })
+        .collect();
+    let graphics: Option<DisplayType> =
+        get_children(devices, "graphics").find_map(|graphics| match graphics.attribute("type") {
+            Some("vnc") => Some(DisplayType::Vnc),
+            Some("spice") => Some(DisplayType::Spice),
+            _ => None,
+        });
+
+    let domain: Domain = Domain {
+        name,
+        kvm: root.attribute("type") == Some("kvm"),
+        memory_kib,
+        vcpus,
+        max_vcpus: (max_vcpus > vcpus).then_some(max_vcpus),
+        cpuset: vcpu
+            .and_then(|vcpu| vcpu.attribute("cpuset"))
+            .map(|cpuset| cpuset.to_owned()),
+        cpu_model,
+        machine: os
+            .and_then(|os| get_child(os, "type"))
+            .and_then(|kind| kind.attribute("machine"))
+            .map(|machine| machine.to_owned()),
+        uefi,
+        secure_boot,
+        hugepages: get_child(root, "memoryBacking")
+            .is_some_and(|backing| get_child(backing, "hugepages").is_some()),
+        disks,
+        interfaces,
+        shares,
+        hostdevs,
+        tpm: get_child(devices, "tpm").is_some(),
+        graphics,
+        balloon: get_child(devices, "memballoon")
+            .is_none_or(|balloon| balloon.attribute("model") != Some("none")),
+        guest_agent: get_children(devices, "channel").any(|channel| {
+            get_child(channel, "target").and_then(|target| target.attribute("name"))
+                == Some("org.qemu.guest_agent.0")
+        }),
+    };
+    Ok((domain, notes))
+}
+
+fn format_memory(memory_kib: u64) -> String {
+    //! Returns memory as the VM config gives it, e.g. `4G` or `1536M`.
+    if memory_kib.is_multiple_of(1 << 20) {
+        format!("{}G", memory_kib >> 20)
+    } else if memory_kib.is_multiple_of(1 << 10) {
+        format!("{}M", memory_kib >> 10)
+    } else {
+        format!("{memory_kib}K")
+    }
+}
+
+fn get_qemu_nic_model(model: &str) -> String {
+    //! Returns qemu's name for a libvirt NIC model.
+    match model {
+        "virtio" => "virtio-net-pci".to_owned(),
+        model => model.to_owned(),
+    }
+}
+
+pub fn get_vm_config_edits(domain: &Domain) -> Result<(Vec<VmConfigEdit>, Vec<String>), String> {
+    //! Returns the changes to a new VM's entry making it run like `domain`,
+    //! apart from its disks, along with notes on what was left out.
+    let mut notes: Vec<String> = vec![];
+    let set = |key: &str, value: serde_yaml::Value| VmConfigEdit::Set(key.to_owned(), value);
+    let mut edits: Vec<VmConfigEdit> = vec![
+        set("memory", format_memory(domain.memory_kib).into()),
+        set("cpus", domain.vcpus.into()),
+    ];
+    if let Some(max_vcpus) = domain.max_vcpus {
+        edits.push(set("max_cpus", max_vcpus.into()));
+    }
+    if let Some(cpuset) = &domain.cpuset {
+        edits.push(set("cpu_affinity.cores", cpuset.as_str().into()));
+    }
+    // libvirt pins machine types to the qemu version the domain was defined
+    // with, e.g. `pc-q35-8.2`, which the host's qemu may not have.
+    match domain.machine.as_deref() {
+        Some(machine) if machine.contains("q35") => edits.push(set("machine", "q35".into())),
+        Some(machine) if machine == "pc" || machine.starts_with("pc-i440fx") => {}
+        Some(machine) => edits.push(set("machine", machine.into())),
+        None => {}
+    }
+    if domain.secure_boot {
+        edits.push(set("secure_boot", true.into()));
+    } else if domain.uefi {
+        edits.push(set("uefi", true.into()));
+    }
+    if domain.hugepages {
+        edits.push(set("hugepages", true.into()));
+    }
+    if domain.tpm {
+        edits.push(set("tpm", true.into()));
+    }
+    if !domain.balloon {
+        edits.push(set("balloon", false.into()));
+    }
+    match domain.graphics {
+        Some(DisplayType::Vnc) => edits.push(set("display", "vnc".into())),
+        Some(DisplayType::Spice) => edits.push(set("display", "spice".into())),
+        _ => {}
+    }
+    if let Some(cdrom) = domain.disks.iter().find(|disk| disk.cdrom) {
+        edits.push(set("cdrom", cdrom.path.as_str().into()));
+    }
+
+    let mut interfaces = domain.interfaces.iter();
+    if let Some(interface) = interfaces.next() {
+        match &interface.source {
+            InterfaceSource::User(ports) => {
+                for port in ports {
+                    let host_address: String = match port.host_address.as_str() {
+                        "" => String::new(),
+                        address if address.contains(':') => format!("[{address}]:"),
+                        address => format!("{address}:"),
+                    };
+                    edits.push(VmConfigEdit::AddPort(PortMapping::parse(&format!(
+                        "{host_address}{}:{}/{}",
+                        port.host_port, port.guest_port, port.protocol
+                    ))?));
+                }
+            }
+            InterfaceSource::Network(network) => notes.push(format!(
+                "The guest uses user-mode networking instead of libvirt network '{network}'. Forward the ports it serves with port_mappings."
+            )),
+            InterfaceSource::Bridge(bridge) => {
+                edits.push(set("network.mode", "bridge".into()));
+                edits.push(set("network.bridge", bridge.as_str().into()));
+            }
+            InterfaceSource::Tap(device, bridge) => {
+                edits.push(set("network.mode", "tap".into()));
+                edits.push(set("network.interface", device.as_str().into()));
+                if let Some(bridge) = bridge {
+                    edits.push(set("network.bridge", bridge.as_str().into()));
+                }
+            }
+            InterfaceSource::Macvtap(parent) => {
+                edits.push(set("network.mode", "macvtap".into()));
+                edits.push(set("network.parent", parent.as_str().into()));
+            }
+            InterfaceSource::Multicast(group) => notes.push(format!(
+                "The interface on multicast group '{group}' was left out. Join the VM to a private network in the config's 'networks' section instead."
+            )),
+        }
+        if let Some(mac_address) = &interface.mac_address {
+            edits.push(set("network.mac_address", mac_address.as_str().into()));
+        }
+        if interface.model != "virtio" {
+            notes.push(format!(
+                "The guest's NIC model was '{}'. Add the option '-nic user,model={}' if it needs the same model.",
+                interface.model,
+                get_qemu_nic_model(&interface.model)
+            ));
+        }
+    }
+    let more_interfaces: usize = interfaces.count();
+    if more_interfaces > 0 {
+        notes.push(format!(
+            "Only the first interface was imported, leaving out {more_interfaces} more."
+        ));
+    }
+
+    if !domain.shares.is_empty() {
+        edits.push(set(
+            "shares",
+            serde_yaml::to_value(&domain.shares)
+                .map_err(|e| format!("Unable to serialize shares. {e}"))?,
+        ));
+    }
+    if !domain.hostdevs.is_empty() {
+        let devices: Vec<PciDevice> = domain
+            .hostdevs
+            .iter()
+            .map(|address| PciDevice {
+                address: address.clone(),
+                romfile: None,
+            })
+            .collect();
+        edits.push(set(
+            "pci_passthrough",
+            serde_yaml::to_value(&devices)
+                .map_err(|e| format!("Unable to serialize PCI devices. {e}"))?,
+        ));
+    }
+    Ok((edits, notes))
+}
+
+pub fn import_disks(
+    domain: &Domain,
+    name: &str,
+    config: &Config,
+    link: bool,
+) -> Result<Vec<VmConfigEdit>, String> {
+    //! Copies the disks of `domain` to qcow2 images in the images directory,
+    //! the first named `name` and the others after it, or with `link` uses
+    //! them where they are. Returns the changes to the new VM's entry
+    //! attaching the disks after the first.
+    let disks: Vec<&DomainDisk> = domain.disks.iter().filter(|disk| !disk.cdrom).collect();
+    if disks.is_empty() {
+        return Err(format!("Domain '{}' has no disk to import.", domain.name));
+    }
+    let mut edits: Vec<VmConfigEdit> = vec![];
+    let mut converted: Vec<PathBuf> = vec![];
+    for (index, disk) in disks.iter().enumerate() {
+        match import_disk(disk, index, name, config, link, &mut converted) {
+            Ok(edit) => edits.extend(edit),
+            Err(e) => {
+                // the VM isn't added, so nothing would use the copies.
+                for path in &converted {
+                    let _ = fs::remove_file(path);
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(edits)
+}
+
+fn import_disk(
+    disk: &DomainDisk,
+    index: usize,
+    name: &str,
+    config: &Config,
+    link: bool,
+    converted: &mut Vec<PathBuf>,
+) -> Result<Option<VmConfigEdit>, String> {
+    //! Imports the `index`th disk of a domain as `import_disks` does, adding
+    //! the path of a copied image to `converted`. Returns the change to the
+    //! new VM's entry attaching the disk, if it needs one.
+    let (path, format): (String, &str) = if link {
+        (disk.path.clone(), disk.format.as_str())
+    } else {
+        let image_name: String = match index {
+            0 => name.to_owned(),
+            _ => format!("{name}-disk{index}"),
+        };
+        let path: PathBuf = get_new_image_path(&image_name, config)?;
+        // the path is checked before the disk is copied to it.
+        if index > 0 {
+            get_drive_file(&path.display().to_string())?;
+        }
+        convert_image(Path::new(&disk.path), &path, ImageFormat::Qcow2)?;
+        converted.push(path.clone());
+        (path.display().to_string(), ImageFormat::Qcow2.as_str())
+    };
+    if index == 0 {
+        return Ok(link.then(|| VmConfigEdit::Set("image_path".to_owned(), path.into())));
+    }
+    let interface: &str = match disk.bus.as_str() {
+        "virtio" => ",if=virtio",
+        "scsi" => ",if=scsi",
+        _ => "",
+    };
+    Ok(Some(VmConfigEdit::AddOption(format!(
+        "-drive file={},format={format}{interface}",
+        get_drive_file(&path)?
+    ))))
+}
+
+fn get_drive_file(path: &str) -> Result<String, String> {
+    //! Returns `path` as the `file` of a `-drive` option, with its commas
+    //! doubled so qemu doesn't take them to separate properties. Options in
+    //! the config are split on spaces, so paths with whitespace are refused.
+    if path.contains(char::is_whitespace) {
+        return Err(format!(
+            "Unable to attach disk '{path}', as options can't hold paths containing whitespace."
+        ));
+    }
+    Ok(path.replace(',', ",,"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        get_drive_disk, get_drive_file, get_vm_config_edits, parse_domain_xml, render_domain_xml,
+        Domain, DomainDisk, DomainInterface, InterfaceSource,
+    };
+    use crate::config::{PortMapping, VmConfigEdit};
+    use crate::state::ForwardedPort;
+
+    #[test]
+    fn test_render_domain_xml() {
+        assert_eq!(
+            get_drive_disk("file=/srv/data.img,if=virtio,format=qcow2", "ide"),
+            Some(DomainDisk {
+                path: "/srv/data.img".to_owned(),
+                format: "qcow2".to_owned(),
+                bus: "virtio".to_owned(),
+                cdrom: false,
+                throttling: None,
+            })
+        );
+        assert_eq!(get_drive_disk("if=none,id=drive0", "ide"), None);
+        assert_eq!(
+            get_drive_file("/srv/a,b.qcow2"),
+            Ok("/srv/a,,b.qcow2".to_owned())
+        );
+        assert!(get_drive_file("/srv/my disk.qcow2").is_err());
+
+        let domain: Domain = Domain {
+            name: "web".to_owned(),
+            kvm: true,
+            memory_kib: 4 * 1024 * 1024,
+            vcpus: 2,
+            cpu_model: Some("host".to_owned()),
+            machine: Some("q35".to_owned()),
+            disks: vec![DomainDisk {
+                path: "/srv/vms/web.img".to_owned(),
+                format: "qcow2".to_owned(),
+                bus: "sata".to_owned(),
+                cdrom: false,
+                throttling: None,
+            }],
+            interfaces: vec![DomainInterface {
+                source: InterfaceSource::User(vec![
+                    ForwardedPort::parse("tcp::5555-:22").unwrap(),
+                    ForwardedPort::parse("udp:127.0.0.1:5353-:53").unwrap(),
+                ]),
+                model: "virtio".to_owned(),
+                mac_address: Some("52:54:00:12:34:56".to_owned()),
+            }],
+            balloon: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            render_domain_xml(&domain),
+            "\
+<domain type='kvm'>
+  <name>web</name>
+  <memory unit='KiB'>4194304</memory>
+  <vcpu placement='static'>2</vcpu>
+  <os>
+    <type arch='x86_64' machine='q35'>hvm</type>
+    <boot dev='hd'/>
+  </os>
+  <features>
+    <acpi/>
+    <apic/>
+  </features>
+  <cpu mode='host-passthrough'/>
+  <devices>
+    <disk type='file' device='disk'>
+      <driver name='qemu' type='qcow2'/>
+      <source file='/srv/vms/web.img'/>
+      <target dev='sda' bus='sata'/>
+    </disk>
+    <interface type='user'>
+      <mac address='52:54:00:12:34:56'/>
+      <backend type='passt'/>
+      <portForward proto='tcp'>
+        <range start='5555' to='22'/>
+      </portForward>
+      <portForward proto='udp' address='127.0.0.1'>
+        <range start='5353' to='53'/>
+      </portForward>
+      <model type='virtio'/>
+    </interface>
+    <serial type='pty'/>
+    <console type='pty'/>
+    <memballoon model='virtio'/>
+  </devices>
+</domain>"
+        );
+        assert_eq!(
+            parse_domain_xml(&render_domain_xml(&domain)),
+            Ok((domain, vec![]))
+        );
+    }
+
+    #[test]
+    fn test_parse_domain_xml() {
+        let xml: &str = "\
+<domain type='kvm' id='3'>
+  <name>db</name>
+  <uuid>0b3e9d5e-4c1f-4f9a-9a57-5d9b8f1c2a10</uuid>
+  <memory unit='KiB'>8388608</memory>
+  <currentMemory unit='MiB'>6144</currentMemory>
+  <vcpu placement='static' current='2'>4</vcpu>
+  <os firmware='efi'>
+    <type arch='x86_64' machine='pc-q35-8.2'>hvm</type>
+  </os>
+  <devices>
+    <emulator>/usr/bin/qemu-system-x86_64</emulator>
+    <disk type='file' device='disk'>
+      <driver name='qemu' type='qcow2'/>
+      <source file='/var/lib/libvirt/images/db.qcow2'/>
+      <target dev='vda' bus='virtio'/>
+    </disk>
+    <disk type='network' device='disk'>
+      <source protocol='rbd' name='pool/data'/>
+    </disk>
+    <disk type='file' device='cdrom'>
+      <target dev='sda' bus='sata'/>
+    </disk>
+    <interface type='network'>
+      <mac address='52:54:00:ab:cd:ef'/>
+      <source network='default'/>
+      <model type='virtio'/>
+    </interface>
+    <hostdev mode='subsystem' type='pci' managed='yes'>
+      <source>
+        <address domain='0x0000' bus='0x01' slot='0x00' function='0x1'/>
+      </source>
+    </hostdev>
+    <memballoon model='none'/>
+  </devices>
+</domain>
+";
+        let (domain, notes) = parse_domain_xml(xml).unwrap();
+        assert_eq!(domain.memory_kib, 6 * 1024 * 1024);
+        assert_eq!((domain.vcpus, domain.max_vcpus), (2, Some(4)));
+        assert_eq!(domain.disks.len(), 1);
+        assert_eq!(domain.hostdevs, ["0000:01:00.1"]);
+        assert_eq!(notes.len(), 1);
+        let (edits, notes) = get_vm_config_edits(&domain).unwrap();
+        let set = |key: &str, value: serde_yaml::Value| VmConfigEdit::Set(key.to_owned(), value);
+        assert!(edits.contains(&set("memory", "6G".into())));
+        assert!(edits.contains(&set("max_cpus", 4.into())));
+        assert!(edits.contains(&set("machine", "q35".into())));
+        assert!(edits.contains(&set("uefi", true.into())));
+        assert!(edits.contains(&set("balloon", false.into())));
+        assert!(edits.contains(&set("network.mac_address", "52:54:00:ab:cd:ef".into())));
+        assert_eq!(notes.len(), 1);
+
+        let (domain, _) = parse_domain_xml(
+            "<domain type='qemu'><name>web</name><devices><interface type='user'>\
+             <portForward proto='tcp' address='::1'><range start='8080' to='80'/></portForward>\
+             </interface></devices></domain>",
+        )
+        .unwrap();
+        let (edits, _) = get_vm_config_edits(&domain).unwrap();
+        assert!(edits.contains(&VmConfigEdit::AddPort(
+            PortMapping::parse("[::1]:8080:80").unwrap()
+        )));
+        assert!(parse_domain_xml("<network><name>default</name></network>").is_err());
+    }
+}
@@ -0,0 +1,365 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime};
+
+use crate::config::LogRotation;
+use crate::image::parse_size;
+use crate::instance::split_vm_name;
+use crate::{matching, LOGS_DIRECTORY};
+
+/// The ID of the character device the guest's serial console is on.
+const SERIAL_CONSOLE_ID: &str = "serial-console";
+/// How often a followed log is checked for new output.
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// How many of the last lines qemu printed a failed start quotes.
+const STARTUP_ERROR_LINES: usize = 10;
+/// The size past which a log is rotated, unless the config's `logs` says
+/// otherwise.
+const DEFAULT_MAX_LOG_SIZE: u64 = 10 << 20;
+/// How many rotated logs are kept of each log, unless the config's `logs`
+/// says otherwise.
+const DEFAULT_ROTATED_LOGS_KEPT: usize = 5;
+
+/// The logs kept for each VM run in the background.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogKind {
+    /// What the guest wrote to its serial console.
+    Console,
+    /// What qemu itself printed, such as errors.
+    Qemu,
+}
+
+impl LogKind {
+    const ALL: [Self; 2] = [Self::Console, Self::Qemu];
+
+    fn file_name(&self) -> &'static str {
+        match self {
+            Self::Console => "console.log",
+            Self::Qemu => "qemu.log",
+        }
+    }
+}
+
+pub fn get_log_directory(vm_name: &str) -> PathBuf {
+    //! Returns the directory holding the logs of a VM.
+    PathBuf::from(shellexpand::tilde(&format!("{LOGS_DIRECTORY}/{vm_name}")).to_string())
+}
+
+pub fn get_log_path(vm_name: &str, kind: LogKind) -> PathBuf {
+    get_log_directory(vm_name).join(kind.file_name())
+}
+
+pub fn serial_console_args(vm_name: &str, socket_path: &Path) -> Vec<String> {
+    //! Returns the arguments exposing the guest's serial console on a unix
+    //! socket, for `vm-manager console`, which also append everything the
+    //! guest writes to it to the VM's console log.
+    vec![
+        "-chardev".to_owned(),
+        format!(
+            "socket,id={SERIAL_CONSOLE_ID},path={},server=on,wait=off,logfile={},logappend=on",
+            socket_path.display(),
+            get_log_path(vm_name, LogKind::Console).display()
+        ),
+        "-serial".to_owned(),
+        format!("chardev:{SERIAL_CONSOLE_ID}"),
+    ]
+}
+
+pub fn open_qemu_log(vm_name: &str) -> Result<(File, u64), String> {
+    //! Opens the log qemu's output is appended to, creating the VM's log
+    //! directory if needed, and marks the start of a new run in it. Returns
+    //! the log and where the new run's output starts.
+    let directory: PathBuf = get_log_directory(vm_name);
+    fs::create_dir_all(&directory).map_err(|e| {
+        format!(
+            "Unable to create log directory '{}'. {e}",
+            directory.display()
+        )
+    })?;
+    let path: PathBuf = get_log_path(vm_name, LogKind::Qemu);
+    let mut log: File = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| format!("Unable to open log '{}'. {e}", path.display()))?;
+    writeln!(
+        log,
+        "--- Starting VM '{vm_name}' at {} ---",
+        chrono::Local::now().format("%Y-%m-%d %H:%M:%S")
+    )
+    .map_err(|e| format!("Unable to write to log '{}'. {e}", path.display()))?;
+    let start: u64 = log.stream_position().map_err(|e| e.to_string())?;
+    Ok((log, start))
+}
+
+fn get_last_lines(contents: &str, count: usize) -> &str {
+    //! Returns the last `count` lines of `contents`, or all of them if
+    //! `count` is 0.
+    let contents: &str = contents.trim_end_matches('\n');
+    if count == 0 {
+        return contents;
+    }
+    match contents.rmatch_indices('\n').nth(count - 1) {
+        Some((index, _)) => &contents[index + 1..],
+        None => contents,
+    }
+}
+
+pub fn read_startup_errors(vm_name: &str, start: u64) -> String {
+    //! Returns the last lines qemu printed to its log since `start`, which
+    //! tell why a VM failed to start.
+    let path: PathBuf = get_log_path(vm_name, LogKind::Qemu);
+    let mut output: Vec<u8> = Vec::new();
+    if let Ok(mut log) = File::open(&path) {
+        let _ = log
+            .seek(SeekFrom::Start(start))
+            .and_then(|_| log.read_to_end(&mut output));
+    }
+    get_last_lines(&String::from_utf8_lossy(&output), STARTUP_ERROR_LINES).to_owned()
+}
+
+pub fn find_logged_vm(pattern: &str, instance: Option<&str>) -> Result<String, String> {
+    //! Returns the name of the VM with logs whose image name matches
+    //! `pattern`, as the given instance of it, preferring an exact match.
+    //! The VM doesn't have to be running.
+    let directory: String = shellexpand::tilde(LOGS_DIRECTORY).to_string();
+    let logged_vms: Vec<String> = fs::read_dir(&directory)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .filter(|vm_name| split_vm_name(vm_name).1 == instance)
+                .collect()
+        })
+        .unwrap_or_default();
+    if !logged_vms
+        .iter()
+        .any(|vm_name| matching::matches(pattern, split_vm_name(vm_name).0))
+    {
+        return Err(format!(
+            "No VM matching '{pattern}'{} has any logs. Only VMs run in the background are logged.",
+            instance.map_or(String::new(), |instance| format!(
+                " as instance '{instance}'"
+            ))
+        ));
+    }
+    matching::select(
+        pattern,
+        logged_vms,
+        |vm_name| split_vm_name(vm_name).0.to_owned(),
+        "VM with logs",
+    )
+}
+
+fn get_rotated_path(path: &Path, number: usize) -> PathBuf {
+    //! Returns the path of a rotated log, `<log>.1` being the newest.
+    PathBuf::from(format!("{}.{number}", path.display()))
+}
+
+fn list_rotated_logs(path: &Path) -> Vec<(usize, PathBuf)> {
+    //! Returns the number and path of each rotated log of a log.
+    let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+        return vec![];
+    };
+    let prefix: String = format!("{file_name}.");
+    let Ok(entries) = path
+        .parent()
+        .map_or(Ok(None), |parent| fs::read_dir(parent).map(Some))
+    else {
+        return vec![];
+    };
+    entries
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let name: String = entry.ok()?.file_name().into_string().ok()?;
+            let number: usize = name.strip_prefix(&prefix)?.parse().ok()?;
+            Some((number, get_rotated_path(path, number)))
+        })
+        .collect()
+}
+
+fn rotate_log(path: &Path, keep: usize) -> Result<(), String> {
+    //! Moves the contents of a log to `<log>.1`, after moving each older
+    //! rotated log one number up. The log is copied and then emptied rather
+    //! than moved, as qemu keeps appending to it while the VM runs.
+    let mut rotated: Vec<(usize, PathBuf)> = list_rotated_logs(path);
+    rotated.sort();
+    for (number, rotated_path) in rotated.iter().rev() {
+        fs::rename(rotated_path, get_rotated_path(path, number + 1))
+            .map_err(|e| format!("Unable to rotate log '{}'. {e}", rotated_path.display()))?;
+    }
+    if keep > 0 {
+        fs::copy(path, get_rotated_path(path, 1))
+            .map_err(|e| format!("Unable to rotate log '{}'. {e}", path.display()))?;
+    }
+    OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|log| log.set_len(0))
+        .map_err(|e| format!("Unable to empty log '{}'. {e}", path.display()))
+}
+
+fn is_rotated_log_kept(
+    number: usize,
+    modified: Option<SystemTime>,
+    keep: usize,
+    keep_days: Option<u64>,
+) -> bool {
+    //! Returns whether the rotation settings keep a rotated log with the
+    //! given number, last written at `modified`.
+    let too_old: bool = keep_days
+        .zip(modified)
+        .and_then(|(days, modified)| {
+            SystemTime::now()
+                .duration_since(modified)
+                .ok()
+                .map(|age| age > Duration::from_secs(days * 24 * 60 * 60))
+        })
+        .unwrap_or(false);
+    number <= keep && !too_old
+}
+
+pub fn rotate_vm_logs(vm_name: &str, rotation: &LogRotation) -> Result<Vec<String>, String> {
+    //! Rotates each log of a VM which has grown past the maximum size, and
+    //! deletes the rotated logs the rotation settings no longer keep.
+    //! Returns what was done.
+    let max_size: u64 = match &rotation.max_size {
+        Some(size) => parse_size(size).ok_or(format!(
+            "Invalid 'max_size' '{size}' in the config's 'logs' section. Use e.g. '10M'."
+        ))?,
+        None => DEFAULT_MAX_LOG_SIZE,
+    };
+    let keep: usize = rotation.keep.unwrap_or(DEFAULT_ROTATED_LOGS_KEPT);
+    let mut done: Vec<String> = Vec::new();
+    for kind in LogKind::ALL {
+        let path: PathBuf = get_log_path(vm_name, kind);
+        if fs::metadata(&path).is_ok_and(|metadata| metadata.len() > max_size) {
+            rotate_log(&path, keep)?;
+            done.push(format!("Rotated '{}'.", path.display()));
+        }
+        for (number, rotated_path) in list_rotated_logs(&path) {
+            let modified: Option<SystemTime> = fs::metadata(&rotated_path)
+                .and_then(|metadata| metadata.modified())
+                .ok();
+            if !is_rotated_log_kept(number, modified, keep, rotation.keep_days) {
+                fs::remove_file(&rotated_path).map_err(|e| {
+                    format!("Unable to delete log '{}'. {e}", rotated_path.display())
+                })?;
+                done.push(format!("Deleted '{}'.", rotated_path.display()));
+            }
+        }
+    }
+    Ok(done)
+}
+
+pub fn rotate_all_logs(rotation: &LogRotation) -> Result<Vec<String>, String> {
+    //! Rotates and prunes the logs of every VM which has any.
+    let directory: String = shellexpand::tilde(LOGS_DIRECTORY).to_string();
+    let mut vm_names: Vec<String> = fs::read_dir(&directory)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .collect()
+        })
+        .unwrap_or_default();
+    vm_names.sort();
+    let mut done: Vec<String> = Vec::new();
+    for vm_name in vm_names {
+        done.extend(rotate_vm_logs(&vm_name, rotation)?);
+    }
+    Ok(done)
+}
+
+pub fn show_log(path: &Path, lines: usize, follow: bool) -> Result<(), String> {
+    //! Prints the last `lines` lines of a log, or all of it if `lines` is 0.
+    //! With `follow`, keeps printing whatever is added to it until
+    //! interrupted, starting over if the log is truncated or replaced.
+    let mut log: File =
+        File::open(path).map_err(|e| format!("Unable to open log '{}'. {e}", path.display()))?;
+    let mut contents: Vec<u8> = Vec::new();
+    log.read_to_end(&mut contents)
+        .map_err(|e| format!("Unable to read log '{}'. {e}", path.display()))?;
+    let mut stdout = std::io::stdout();
+    let tail: String = get_last_lines(&String::from_utf8_lossy(&contents), lines).to_owned();
+    if !tail.is_empty() {
+        let _ = writeln!(stdout, "{tail}");
+    }
+    if !follow {
+        return Ok(());
+    }
+
+    let mut position: u64 = contents.len() as u64;
+    loop {
+        let _ = stdout.flush();
+        sleep(FOLLOW_POLL_INTERVAL);
+        let current: fs::Metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            // between being rotated and created again
+            Err(_) => continue,
+        };
+        let replaced: bool = log
+            .metadata()
+            .is_ok_and(|opened| opened.ino() != current.ino());
+        if replaced || current.len() < position {
+            log = File::open(path)
+                .map_err(|e| format!("Unable to open log '{}'. {e}", path.display()))?;
+            position = 0;
+        }
+        let mut added: Vec<u8> = Vec::new();
+        log.seek(SeekFrom::Start(position))
+            .and_then(|_| log.read_to_end(&mut added))
+            .map_err(|e| format!("Unable to read log '{}'. {e}", path.display()))?;
+        position += added.len() as u64;
+        let _ = stdout.write_all(&added);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_last_lines, get_rotated_path, is_rotated_log_kept, rotate_log};
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_get_last_lines() {
+        let log: &str = "one\ntwo\nthree\n";
+        assert_eq!(get_last_lines(log, 2), "two\nthree");
+        assert_eq!(get_last_lines(log, 3), "one\ntwo\nthree");
+        assert_eq!(get_last_lines(log, 10), "one\ntwo\nthree");
+        assert_eq!(get_last_lines(log, 0), "one\ntwo\nthree");
+        assert_eq!(get_last_lines("", 5), "");
+    }
+
+    #[test]
+    fn test_rotate_log() {
+        let directory =
+            std::env::temp_dir().join(format!("vm-manager-logs-test-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("console.log");
+        fs::write(&path, "first\n").unwrap();
+        rotate_log(&path, 5).unwrap();
+        fs::write(&path, "second\n").unwrap();
+        rotate_log(&path, 5).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        assert_eq!(
+            fs::read_to_string(get_rotated_path(&path, 1)).unwrap(),
+            "second\n"
+        );
+        assert_eq!(
+            fs::read_to_string(get_rotated_path(&path, 2)).unwrap(),
+            "first\n"
+        );
+        fs::remove_dir_all(&directory).unwrap();
+
+        let now: Option<SystemTime> = Some(SystemTime::now());
+        let last_month: Option<SystemTime> =
+            SystemTime::now().checked_sub(Duration::from_secs(30 * 24 * 60 * 60));
+        assert!(is_rotated_log_kept(2, now, 5, None));
+        assert!(!is_rotated_log_kept(6, now, 5, None));
+        assert!(is_rotated_log_kept(1, last_month, 5, None));
+        assert!(!is_rotated_log_kept(1, last_month, 5, Some(7)));
+    }
+}
@@ -1,28 +1,125 @@
+mod affinity;
+mod backup;
+mod catalog;
 mod config;
+mod config_init;
+mod console;
+mod create;
+mod daemon;
+mod display;
+mod firmware;
+mod group;
+mod guest_agent;
+mod host;
+mod hostnames;
+mod hotplug;
+mod hugepages;
+mod image;
+mod instance;
+mod interpolate;
+mod keyboard;
+mod libvirt;
+mod logs;
+mod matching;
+mod monitor;
+mod netboot;
+mod network;
 mod parse_args;
 mod qemu_runner;
+mod qmp;
+mod saved_state;
+mod screenshot;
+mod shares;
+mod snapshot;
+mod ssh;
+mod state;
+mod stats;
+mod supervisor;
+mod systemd;
+mod tpm;
+mod tui;
+mod usb;
 mod utils;
+mod vagrant;
+mod vfio;
+mod webhooks;
+mod xdg;
 
 use crate::{
-    qemu_runner::QemuRunner,
+    qemu_runner::{format_command, QemuRunner, StopOutcome},
     utils::{
-        get_file_from_image_name, get_list_of_images, get_list_of_running_vms,
-        print_running_vm_table, OutputStream, OutputStreamTarget,
+        confirm, find_image, find_in_path, get_list_of_images, get_list_of_running_vms,
+        get_serial_socket_path, print_forwarded_ports, print_running_vm_records, print_running_vms,
+        print_vm_status, run_interactive_command, OutputStream, OutputStreamTarget, PortClaims,
+        RunningVmRecord, VmStatus,
     },
 };
 
 use anyhow::Result;
 use clap::Parser;
-use config::Config;
-use parse_args::Arguments;
-use tilde_expand;
+use config::{CatalogImage, Config, ConfigFormat, WebhookEvent};
+use parse_args::{
+    Arguments, Compression, ConfigCommand, DiskCommand, ExportCommand, ImageCommand, ImportCommand,
+    InstanceCommand, OutputFormat, SnapshotCommand, StartOptions, SystemdCommand, UsbCommand,
+    VmConfigChanges,
+};
+use serde_json::json;
+use ssh::SshTarget;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 const DEFAULT_SSH_PORT: usize = 5555;
 const DEFAULT_HTTPS_PORT: usize = 8081;
+/// Memory given to guests started without a VM config or `--memory`.
+const DEFAULT_MEMORY: &str = "8G";
+/// Number of CPUs given to guests started without a VM config or `--cpus`.
+const DEFAULT_CPUS: usize = 4;
+/// Images directory of installations from before vm-manager followed the
+/// XDG base directory specification, still used while it exists.
 const IMAGES_DIRECTORY: &str = "~/.vm-manager/disk-images";
 #[allow(unused)]
 const BACKUP_IMAGES_DIRECTORY: &str = "~/.vm-manager/disk-images/backups";
+/// Config file of installations from before vm-manager followed the XDG base
+/// directory specification, still used while it exists.
 const CONFIG_FILE: &str = "~/.vm-manager/config.yml";
+/// The legacy config file used instead of `CONFIG_FILE` when only it exists.
+const TOML_CONFIG_FILE: &str = "~/.vm-manager/config.toml";
+/// Directory holding per-VM runtime files, such as QMP sockets.
+const RUNTIME_DIRECTORY: &str = "~/.vm-manager/run";
+/// Directory holding a state file for each running VM.
+const STATE_DIRECTORY: &str = "~/.vm-manager/state";
+/// Directory deleted images are moved to, unless deleted permanently.
+const TRASH_DIRECTORY: &str = "~/.vm-manager/trash";
+/// Directory holding the overlay disks of each image's instances.
+const INSTANCES_DIRECTORY: &str = "~/.vm-manager/instances";
+/// Directory holding the persistent state of each VM's emulated TPM.
+const TPM_DIRECTORY: &str = "~/.vm-manager/tpm";
+/// Directory holding the UEFI variable store of each VM booted with UEFI.
+const NVRAM_DIRECTORY: &str = "~/.vm-manager/nvram";
+/// Directory holding the MAC address generated for each VM which doesn't
+/// set one.
+const MAC_ADDRESS_DIRECTORY: &str = "~/.vm-manager/mac-addresses";
+/// Directory holding the RAM and device state of each VM saved with
+/// `vm-manager save`.
+const SAVED_STATE_DIRECTORY: &str = "~/.vm-manager/saved-states";
+/// Directory holding the qemu and serial console logs of each VM run in the
+/// background.
+const LOGS_DIRECTORY: &str = "~/.vm-manager/logs";
+/// File holding the token requests to `vm-manager daemon` over TCP must
+/// carry.
+const DAEMON_TOKEN_FILE: &str = "~/.vm-manager/daemon-token";
+/// File holding the commands run in `vm-manager monitor` sessions.
+const MONITOR_HISTORY_FILE: &str = "~/.vm-manager/monitor-history";
+/// Directory systemd looks for user units in.
+const SYSTEMD_USER_UNIT_DIRECTORY: &str = "~/.config/systemd/user";
+/// The least memory `vm-manager balloon` shrinks a guest to, in bytes, as
+/// less is unlikely to keep any guest alive.
+const MINIMUM_BALLOON_TARGET: u64 = 128 << 20;
+/// Seconds to wait for a guest to power down before killing it.
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 30;
 
 /// Options for disk image location.
 enum ImageLocation {
@@ -35,14 +132,97 @@ enum ImageLocation {
 fn main() {
     let args = Arguments::parse();
 
-    let config_file: String = if let Some(file) = args.config_file {
-        file
+    matching::set_match_mode(if args.exact {
+        matching::MatchMode::Exact
+    } else if args.glob {
+        matching::MatchMode::Glob
+    } else if args.regex {
+        matching::MatchMode::Regex
     } else {
-        String::from_utf8(tilde_expand::tilde_expand(CONFIG_FILE.as_bytes())).unwrap()
-    };
+        matching::MatchMode::Substring
+    });
+    if let Some(Err(e)) = args
+        .images
+        .iter()
+        .map(|image| matching::check_pattern(image))
+        .find(Result::is_err)
+    {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+    if args.images.len() > 1
+        && (args.remote.is_some() || !matches!(args.command, Some(parse_args::Command::Start(_))))
+    {
+        eprintln!("Only 'start' takes more than one -i/--image, and not with --remote.");
+        std::process::exit(1);
+    }
+
+    if let Some(host) = &args.host {
+        let exit_code: i32 = host::run_on_host(host).unwrap_or_else(|e| {
+            eprintln!("{e}");
+            1
+        });
+        std::process::exit(exit_code)
+    }
+
+    if let Some(remote) = &args.remote {
+        let mut buffer: OutputStream = OutputStream::new(OutputStreamTarget::Stdout);
+        let exit_code: i32 = run_remote(&args, remote, &mut buffer).unwrap_or_else(|e| {
+            buffer.add_spacer();
+            buffer.addln(&e);
+            1
+        });
+        buffer.flush();
+        std::process::exit(exit_code)
+    }
+
+    let config_file: String = args
+        .config_file
+        .clone()
+        .unwrap_or_else(xdg::get_default_config_file);
+
+    // there is no config to load yet when creating one.
+    if let Some(parse_args::Command::Config {
+        command: ConfigCommand::Init { ref scan, force },
+    }) = args.command
+    {
+        let mut buffer: OutputStream = OutputStream::new(OutputStreamTarget::Stdout);
+        let exit_code: i32 =
+            match run_command_config_init(&config_file, scan.as_deref(), force, &mut buffer) {
+                Ok(()) => 0,
+                Err(e) => {
+                    buffer.add_spacer();
+                    buffer.addln(&e);
+                    1
+                }
+            };
+        buffer.flush();
+        std::process::exit(exit_code)
+    }
+
+    // the config file may be among what's moved.
+    if let Some(parse_args::Command::MigratePaths { dry_run }) = args.command {
+        let mut buffer: OutputStream = OutputStream::new(OutputStreamTarget::Stdout);
+        let exit_code: i32 = match run_command_migrate_paths(dry_run, &mut buffer) {
+            Ok(()) => 0,
+            Err(e) => {
+                buffer.add_spacer();
+                buffer.addln(&e);
+                1
+            }
+        };
+        buffer.flush();
+        std::process::exit(exit_code)
+    }
 
     let config: Config = match Config::load_from_file(&config_file) {
         Ok(config) => config,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            eprintln!(
+                "Config file '{config_file}' does not exist. Run 'vm-manager config init' to create one."
+            );
+            std::process::exit(1);
+        }
         Err(e) => {
             eprintln!("Failed to load config from file '{config_file}'. {e}");
             std::process::exit(1);
@@ -51,11 +231,31 @@ fn main() {
 
     // used for collecting string output
     let mut buffer: OutputStream = OutputStream::new(OutputStreamTarget::Stdout);
+    // set by commands whose successful outcomes differ in exit code
+    let mut exit_code: i32 = 0;
 
     if args.list_images {
         buffer.addln("--------------------\nImages\n--------------------");
-        for file in get_list_of_images(ImageLocation::WorkingImages, &config) {
-            buffer.addln(&file);
+        let images: Vec<(String, Vec<String>)> =
+            get_list_of_images(ImageLocation::WorkingImages, &config)
+                .into_iter()
+                .filter(|image| config.matches_filters(image, &args.filter))
+                .map(|image| {
+                    let tags: Vec<String> = config.get_tags(&image);
+                    (image, tags)
+                })
+                .collect();
+        let width: usize = images
+            .iter()
+            .map(|(image, _)| image.len())
+            .max()
+            .unwrap_or(0);
+        for (image, tags) in images {
+            if tags.is_empty() {
+                buffer.addln(&image);
+            } else {
+                buffer.addln(&format!("{image:width$}  [{}]", tags.join(", ")));
+            }
         }
     }
 
@@ -69,30 +269,243 @@ fn main() {
 
     if args.list_running_vms {
         buffer.add_spacer();
-        let running_vms = get_list_of_running_vms(&config);
-        if running_vms.is_empty() {
+        let running_vms: Vec<QemuRunner> = get_list_of_running_vms()
+            .into_iter()
+            .filter(|vm| config.matches_filters(&vm.base_image_name(), &args.filter))
+            .collect();
+        if args.output != OutputFormat::Table {
+            // machine-readable formats are printed as-is, even when empty.
+            print_running_vms(
+                &running_vms,
+                &config,
+                args.output,
+                args.running_vm_columns().as_deref(),
+                &mut buffer,
+            );
+        } else if running_vms.is_empty() {
             buffer.addln("No machines running.");
         } else {
             buffer.addln("--------------------\nRunning VMs\n--------------------");
-            print_running_vm_table(&running_vms, &mut buffer);
+            print_running_vms(
+                &running_vms,
+                &config,
+                args.output,
+                args.running_vm_columns().as_deref(),
+                &mut buffer,
+            );
         }
     }
 
     let command_result = match args.command {
-        Some(parse_args::Command::Start) => run_command_start(
-            args.image,
-            args.ssh_port,
-            args.https_port,
-            args.foreground,
+        Some(parse_args::Command::Start(ref options)) if args.images.len() > 1 => {
+            group::start_all(&args, options, &config, &mut buffer)
+        }
+        Some(parse_args::Command::Start(ref options)) => run_command_start(&args, options, &config),
+        Some(parse_args::Command::Stop {
+            all,
+            force,
+            wait,
+            timeout,
+        }) => run_command_stop(
+            &args,
+            &config,
+            all,
+            force,
+            wait,
+            timeout.map_or(config.get_shutdown_timeout(), Duration::from_secs),
+            &mut buffer,
+        )
+        .map(|code| exit_code = code),
+        Some(parse_args::Command::Restart { dry_run }) => {
+            run_command_restart(args.image(), args.instance.as_deref(), dry_run, &config)
+        }
+        Some(parse_args::Command::Create(ref options)) => {
+            create::create(options, &config, &config_file, &mut buffer)
+        }
+        Some(parse_args::Command::Up { ref group }) => group::up(group, &config, &mut buffer),
+        Some(parse_args::Command::RollingRestart {
+            ref group,
+            max_unavailable,
+        }) => group::rolling_restart(group, &config, max_unavailable as usize, &mut buffer),
+        Some(parse_args::Command::Down {
+            ref group,
+            force,
+            wait,
+            timeout,
+        }) => group::down(
+            group,
+            &config,
+            force,
+            wait,
+            timeout.map_or(config.get_shutdown_timeout(), Duration::from_secs),
+            &mut buffer,
+        )
+        .map(|code| exit_code = code),
+        Some(parse_args::Command::Screenshot { ref output }) => run_command_screenshot(
+            args.image(),
+            args.instance.as_deref(),
+            output.as_deref(),
+            &mut buffer,
+        ),
+        Some(parse_args::Command::Sendkey { ref keys }) => {
+            run_command_sendkey(args.image(), args.instance.as_deref(), keys)
+        }
+        Some(parse_args::Command::Type { ref text, enter }) => {
+            run_command_type(args.image(), args.instance.as_deref(), text, enter)
+        }
+        Some(parse_args::Command::Save) => {
+            run_command_save(args.image(), args.instance.as_deref(), &config, &mut buffer)
+        }
+        Some(parse_args::Command::ResumeFrom { discard }) => run_command_resume_from(
+            args.image(),
+            args.instance.as_deref(),
+            discard,
             &config,
+            &mut buffer,
+        ),
+        Some(parse_args::Command::Status) => {
+            run_command_status(args.image(), args.instance.as_deref(), &mut buffer)
+        }
+        Some(parse_args::Command::Logs {
+            ref command,
+            qemu,
+            follow,
+            lines,
+        }) => match command {
+            Some(parse_args::LogsCommand::Prune) => run_command_logs_prune(
+                args.image().as_deref(),
+                args.instance.as_deref(),
+                &config,
+                &mut buffer,
+            ),
+            None => run_command_logs(args.image(), args.instance.as_deref(), qemu, follow, lines),
+        },
+        Some(parse_args::Command::Stats { watch, interval }) => run_command_stats(
+            args.image().as_deref(),
+            args.instance.as_deref(),
+            watch,
+            Duration::from_secs(interval),
+            &mut buffer,
+        ),
+        Some(parse_args::Command::Copy {
+            recursive,
+            ref paths,
+        }) => run_command_copy(
+            args.image(),
+            args.instance.as_deref(),
+            paths,
+            recursive,
+            &config,
+        ),
+        Some(parse_args::Command::Console) => {
+            run_command_console(args.image(), args.instance.as_deref())
+        }
+        Some(parse_args::Command::Exec { ref command }) => {
+            run_command_exec(args.image(), args.instance.as_deref(), command)
+        }
+        Some(parse_args::Command::Ip) => {
+            run_command_ip(args.image(), args.instance.as_deref(), &mut buffer)
+        }
+        Some(parse_args::Command::SshConfig { write }) => {
+            run_command_ssh_config(write, &config, &mut buffer)
+        }
+        Some(parse_args::Command::Ports) => run_command_ports(
+            args.image(),
+            args.instance.as_deref(),
+            args.output,
+            &mut buffer,
+        ),
+        Some(parse_args::Command::Monitor) => {
+            run_command_monitor(args.image(), args.instance.as_deref())
+        }
+        Some(parse_args::Command::Display { launch }) => {
+            run_command_display(args.image(), args.instance.as_deref(), launch, &mut buffer)
+        }
+        Some(parse_args::Command::Backup {
+            pause,
+            no_freeze,
+            incremental,
+            compress,
+        }) => run_command_backup(
+            args.image(),
+            pause,
+            !no_freeze,
+            incremental,
+            compress,
+            &config,
+            &mut buffer,
+        ),
+        Some(parse_args::Command::Restore { ref backup }) => {
+            run_command_restore(args.image(), backup.clone(), &config, &mut buffer)
+        }
+        Some(parse_args::Command::PruneBackups { dry_run }) => {
+            run_command_prune_backups(args.image(), dry_run, &config, &mut buffer)
+        }
+        Some(parse_args::Command::Snapshot { ref command }) => {
+            run_command_snapshot(command, args.image(), &config, &mut buffer)
+        }
+        Some(parse_args::Command::Image { ref command }) => {
+            run_command_image(command, args.image(), &config, &config_file, &mut buffer)
+        }
+        Some(parse_args::Command::Export { ref command }) => {
+            run_command_export(command, args.image(), &config, &mut buffer)
+        }
+        Some(parse_args::Command::Import { ref command }) => {
+            run_command_import(command, &config, &config_file, &mut buffer)
+        }
+        Some(parse_args::Command::Balloon { ref target }) => run_command_balloon(
+            args.image(),
+            args.instance.as_deref(),
+            target.as_deref(),
+            &mut buffer,
+        ),
+        Some(parse_args::Command::Scale {
+            ref cpus,
+            ref memory,
+        }) => run_command_scale(
+            args.image(),
+            args.instance.as_deref(),
+            cpus.as_deref(),
+            memory.as_deref(),
+            &mut buffer,
+        ),
+        Some(parse_args::Command::Disk { ref command }) => {
+            run_command_disk(command, args.image(), args.instance.as_deref(), &mut buffer)
+        }
+        Some(parse_args::Command::Usb { ref command }) => {
+            run_command_usb(command, args.image(), args.instance.as_deref(), &mut buffer)
+        }
+        Some(parse_args::Command::Doctor) => run_command_doctor(&mut buffer),
+        Some(parse_args::Command::Config { ref command }) => run_command_config(
+            command,
+            args.image(),
+            args.instance.as_deref(),
+            &config,
+            &config_file,
+            &mut buffer,
+        ),
+        Some(parse_args::Command::Instance { ref command }) => {
+            run_command_instance(command, args.image(), &config, &mut buffer)
+        }
+        Some(parse_args::Command::Supervise) => supervisor::supervise(&config),
+        Some(parse_args::Command::Tui) => tui::run_dashboard(&config),
+        Some(parse_args::Command::Daemon { ref listen }) => {
+            daemon::run_daemon(config.clone(), listen.as_deref())
+        }
+        Some(parse_args::Command::Systemd { ref command }) => run_command_systemd(
+            command,
+            args.image(),
+            args.instance.as_deref(),
+            &config_file,
+            &config,
+            &mut buffer,
         ),
-        Some(parse_args::Command::Stop) => run_command_stop(args.image, &config),
         _ => Ok(()),
     };
 
     if let Err(e) = command_result {
         match args.command {
-            Some(parse_args::Command::Start) => {
+            Some(parse_args::Command::Start(_)) => {
                 buffer.add_spacer();
                 buffer.addln(&format!(
                     "{e}\n\n--------------------\nImages\n--------------------"
@@ -101,86 +514,2254 @@ fn main() {
                     buffer.addln(&file);
                 }
             }
-            Some(parse_args::Command::Stop) => {
+            Some(parse_args::Command::Stop { .. })
+            | Some(parse_args::Command::Restart { .. })
+            | Some(parse_args::Command::Save) => {
                 buffer.add_spacer();
                 buffer.addln(e.as_str());
-                let running_vms: Vec<QemuRunner> = get_list_of_running_vms(&config);
+                let running_vms: Vec<QemuRunner> = get_list_of_running_vms();
                 if !running_vms.is_empty() {
                     buffer.addln("\n--------------------\nRunning VMs\n--------------------");
-                    print_running_vm_table(&running_vms, &mut buffer);
+                    print_running_vms(
+                        &running_vms,
+                        &config,
+                        OutputFormat::Table,
+                        None,
+                        &mut buffer,
+                    );
                 }
             }
-            _ => (),
+            _ => {
+                buffer.add_spacer();
+                buffer.addln(e.as_str());
+            }
         }
         buffer.flush();
         std::process::exit(1)
     }
     buffer.flush();
+    if exit_code != 0 {
+        std::process::exit(exit_code)
+    }
 }
 
 fn run_command_start(
-    image: Option<String>,
-    ssh_port: Option<usize>,
-    https_port: Option<usize>,
-    foreground: bool,
+    args: &Arguments,
+    options: &StartOptions,
     config: &Config,
 ) -> Result<(), String> {
-    if let Some(image_name) = image {
+    // the ports picked for the VM are only needed until qemu has bound them.
+    let _claims: PortClaims = PortClaims;
+    if let Some(image_name) = args.image() {
         let mut runner: QemuRunner = QemuRunner::default();
-        if let Some(pathbuf) = get_file_from_image_name(&image_name, config) {
-            runner.set_image_file(pathbuf);
-        } else {
-            return Err(format!(
-                "Could not find unique image matching '{}'.",
-                image_name
-            ));
+        runner.set_ephemeral(options.ephemeral);
+        runner.set_dry_run(options.dry_run);
+        if let Some(memory) = &options.memory {
+            runner.set_memory(memory);
+        }
+        if let Some(cpus) = options.cpus {
+            runner.set_cpus(cpus);
+        }
+        if let Some(machine) = &options.machine {
+            runner.set_machine(machine);
         }
-        if let Some(vm) = config.get_vm_config_with_image_name(&image_name) {
+        if let Some(cdrom) = &options.cdrom {
+            runner.set_cdrom(cdrom);
+        }
+        runner.set_published_ports(&options.publish);
+        if options.netboot {
+            runner.set_netboot(config::NetbootConfig {
+                tftp: options.tftp.clone(),
+                bootfile: options.bootfile.clone(),
+            });
+        }
+        let (full_image_name, pathbuf) = find_image(&image_name, config)?;
+        runner.set_image_file(pathbuf);
+        runner.set_image_name(&full_image_name);
+        if let Some(instance) = &args.instance {
+            if !options.dry_run {
+                instance::create_overlay(runner.image_path(), &runner.base_image_name(), instance)?;
+            }
+            runner.set_instance(instance);
+        }
+        if let Some(vm) = config.get_vm_config_with_name(&runner.base_image_name()) {
             runner.add_vm_config(vm);
+            if args.foreground {
+                runner.set_daemonization_option(false);
+            }
         } else {
-            if let Some(port) = ssh_port {
+            if let Some(port) = args.ssh_port {
                 runner.set_ssh_port(port);
             }
-            if let Some(port) = https_port {
+            if let Some(port) = args.https_port {
                 runner.set_https_port(port);
             }
-            runner.set_daemonization_option(!foreground);
+            runner.set_daemonization_option(!args.foreground);
         }
 
+        saved_state::check_not_saved(&runner.image_name())?;
+        let running_vms: Vec<QemuRunner> = get_list_of_running_vms();
+        // --force only allows sharing a disk, never a VM's name.
+        runner.check_not_running(&running_vms)?;
+        // the disk stays locked until qemu has opened it, which is once
+        // `start` returns, or for as long as a VM in the foreground runs.
+        let _disk_locks: Vec<File> = if options.force || options.dry_run {
+            vec![]
+        } else {
+            // a VM already running on the disk is named, rather than
+            // reported as one being started.
+            let locks: Result<Vec<File>, String> = runner.lock_disk();
+            runner.check_disk_not_in_use(&running_vms)?;
+            locks?
+        };
+        if options.wait_for_ssh.is_some() && args.foreground {
+            return Err(
+                "--wait-for-ssh is only available for VMs run in the background.".to_owned(),
+            );
+        }
         runner.start(config)?;
+        if options.dry_run {
+            return Ok(());
+        }
+        if let Some(timeout) = options.wait_for_ssh {
+            let vm: QemuRunner = get_list_of_running_vms()
+                .into_iter()
+                .find(|vm| vm.image_name() == runner.image_name())
+                .ok_or(format!(
+                    "VM '{}' is no longer running; --wait-for-ssh is only available for VMs run in the background.",
+                    runner.image_name()
+                ))?;
+            let port: usize = vm.forwarded_host_port(22).ok_or(format!(
+                "VM '{}' does not forward any host port to guest port 22.",
+                vm.image_name()
+            ))?;
+            let waited: Duration = ssh::wait_for_ssh(port, Duration::from_secs(timeout))?;
+            println!(
+                "The SSH server of VM '{}' answered on port {port} after {} seconds.",
+                vm.image_name(),
+                waited.as_secs()
+            );
+        }
         Ok(())
     } else {
         Err("No image provided! Must provide an image name.".to_owned())
     }
 }
 
-fn run_command_stop(image: Option<String>, config: &Config) -> Result<(), String> {
-    if get_list_of_running_vms(config).is_empty() {
+fn run_command_stop(
+    args: &Arguments,
+    config: &Config,
+    all: bool,
+    force: bool,
+    wait: bool,
+    timeout: Duration,
+    buffer: &mut OutputStream,
+) -> Result<i32, String> {
+    //! Stops one running VM, every one, or every one matching the filters
+    //! given with --filter. Returns the exit code to report, which with
+    //! `wait` tells how forcefully the VMs had to be stopped.
+    if get_list_of_running_vms().is_empty() {
         return Err("No VMs running.".to_owned());
     }
 
+    if all || (args.image().is_none() && !args.filter.is_empty()) {
+        let vms: Vec<QemuRunner> = get_list_of_running_vms()
+            .into_iter()
+            .filter(|vm| config.matches_filters(&vm.base_image_name(), &args.filter))
+            .collect();
+        if vms.is_empty() {
+            return Err("No running VMs match the filter.".to_owned());
+        }
+        run_command_stop_all(vms, config, force, wait, timeout, buffer)
+    } else if let Some(image_name) = args.image() {
+        let vm: QemuRunner = find_running_vm(&image_name, args.instance.as_deref())?;
+        if !wait {
+            return vm.stop(force, timeout, config).map(|()| 0);
+        }
+        let outcome: StopOutcome = vm.stop_and_wait(force, timeout, config)?;
+        buffer.add_spacer();
+        buffer.addln(&format!(
+            "VM '{}' {}.",
+            vm.image_name(),
+            outcome.description()
+        ));
+        Ok(outcome.exit_code())
+    } else {
+        Err("No image provided! Must provide an image name, --all or --filter.".to_owned())
+    }
+}
+
+fn run_command_stop_all(
+    vms: Vec<QemuRunner>,
+    config: &Config,
+    force: bool,
+    wait: bool,
+    timeout: Duration,
+    buffer: &mut OutputStream,
+) -> Result<i32, String> {
+    //! Stops each of `vms`, reporting the outcome for each one. Returns an
+    //! error if any of them failed to stop, or else the exit code of the most
+    //! forceful outcome.
+    let mut num_failed: usize = 0;
+    let mut most_forceful: StopOutcome = StopOutcome::PoweredDown;
+
+    buffer.add_spacer();
+    for vm in vms {
+        let result: Result<Option<StopOutcome>, String> = if wait {
+            vm.stop_and_wait(force, timeout, config).map(Some)
+        } else {
+            vm.stop(force, timeout, config).map(|()| None)
+        };
+        match result {
+            Ok(None) => buffer.addln(&format!("Stopped '{}'.", vm.image_name())),
+            Ok(Some(outcome)) => {
+                most_forceful = most_forceful.max(outcome);
+                buffer.addln(&format!(
+                    "VM '{}' {}.",
+                    vm.image_name(),
+                    outcome.description()
+                ));
+            }
+            Err(e) => {
+                num_failed += 1;
+                buffer.addln(&format!("Failed to stop '{}'. {e}", vm.image_name()));
+            }
+        }
+    }
+
+    if num_failed > 0 {
+        Err(format!("Failed to stop {num_failed} VM(s)."))
+    } else {
+        Ok(most_forceful.exit_code())
+    }
+}
+
+fn run_command_restart(
+    image: Option<String>,
+    instance: Option<&str>,
+    dry_run: bool,
+    config: &Config,
+) -> Result<(), String> {
+    if let Some(image_name) = image {
+        if get_list_of_running_vms().is_empty() {
+            return Err("No VMs running.".to_owned());
+        }
+        let vm: QemuRunner = find_running_vm(&image_name, instance)?;
+        if dry_run {
+            let args: Vec<&str> = vm.command_line().iter().map(|arg| arg.as_str()).collect();
+            println!("{}", format_command(&[], &args, None));
+            return Ok(());
+        }
+        vm.restart(config.get_shutdown_timeout(), config)
+    } else {
+        Err("No image provided! Must provide an image name.".to_owned())
+    }
+}
+
+fn run_command_screenshot(
+    image: Option<String>,
+    instance: Option<&str>,
+    output: Option<&str>,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    if let Some(image_name) = image {
+        let vm: QemuRunner = find_running_vm(&image_name, instance)?;
+        let output: String = output.map_or_else(
+            || {
+                format!(
+                    "{}-{}.png",
+                    vm.image_name(),
+                    chrono::Local::now().format("%Y%m%d-%H%M%S")
+                )
+            },
+            |output| output.to_owned(),
+        );
+        screenshot::take_screenshot(&mut vm.qmp_client()?, Path::new(&output))?;
+        buffer.add_spacer();
+        buffer.addln(&format!(
+            "Saved a screenshot of VM '{}' to '{output}'.",
+            vm.image_name()
+        ));
+        Ok(())
+    } else {
+        Err("No image provided! Must provide an image name.".to_owned())
+    }
+}
+
+fn run_command_sendkey(
+    image: Option<String>,
+    instance: Option<&str>,
+    keys: &[String],
+) -> Result<(), String> {
+    if let Some(image_name) = image {
+        let combinations: Vec<Vec<String>> = keys
+            .iter()
+            .map(|combination| keyboard::parse_key_combination(combination))
+            .collect::<Result<_, _>>()?;
+        let mut qmp: qmp::QmpClient = find_running_vm(&image_name, instance)?.qmp_client()?;
+        for combination in combinations {
+            keyboard::send_keys(&mut qmp, &combination)?;
+        }
+        Ok(())
+    } else {
+        Err("No image provided! Must provide an image name.".to_owned())
+    }
+}
+
+fn run_command_type(
+    image: Option<String>,
+    instance: Option<&str>,
+    text: &str,
+    enter: bool,
+) -> Result<(), String> {
+    if let Some(image_name) = image {
+        let mut combinations: Vec<Vec<String>> = keyboard::get_typing_keys(text)?;
+        if enter {
+            combinations.push(vec!["ret".to_owned()]);
+        }
+        let mut qmp: qmp::QmpClient = find_running_vm(&image_name, instance)?.qmp_client()?;
+        for combination in combinations {
+            keyboard::send_keys(&mut qmp, &combination)?;
+        }
+        Ok(())
+    } else {
+        Err("No image provided! Must provide an image name.".to_owned())
+    }
+}
+
+fn run_command_save(
+    image: Option<String>,
+    instance: Option<&str>,
+    config: &Config,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    if let Some(image_name) = image {
+        if get_list_of_running_vms().is_empty() {
+            return Err("No VMs running.".to_owned());
+        }
+        let vm: QemuRunner = find_running_vm(&image_name, instance)?;
+        let path: PathBuf = saved_state::save_vm(&vm, config)?;
+        buffer.add_spacer();
+        buffer.addln(&format!(
+            "Saved VM '{}' to '{}'. Resume it with 'vm-manager resume-from -i {image_name}'.",
+            vm.image_name(),
+            path.display()
+        ));
+        Ok(())
+    } else {
+        Err("No image provided! Must provide an image name.".to_owned())
+    }
+}
+
+fn run_command_resume_from(
+    image: Option<String>,
+    instance: Option<&str>,
+    discard: bool,
+    config: &Config,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    //! Resumes, or discards, a VM saved with `vm-manager save`.
     if let Some(image_name) = image {
-        let mut found_image: bool = false;
-        for image in get_list_of_images(ImageLocation::WorkingImages, config) {
-            if image.contains(&image_name) {
-                found_image = true;
-                break;
+        let mut saved_vms: Vec<QemuRunner> = saved_state::list_saved_vms()
+            .into_iter()
+            .filter(|vm| vm.matches(&image_name, instance))
+            .collect();
+        let vm: QemuRunner = match saved_vms.len() {
+            1 => saved_vms.remove(0),
+            0 => return Err(format!("No saved VM matches '{image_name}'.")),
+            _ => {
+                return Err(format!(
+                    "Several saved VMs match '{image_name}': {}.",
+                    saved_vms
+                        .iter()
+                        .map(|vm| vm.image_name())
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                ))
             }
+        };
+        buffer.add_spacer();
+        if discard {
+            saved_state::discard_saved_vm(&vm.image_name());
+            buffer.addln(&format!(
+                "Discarded the saved state of VM '{}'.",
+                vm.image_name()
+            ));
+        } else {
+            saved_state::resume_saved_vm(&vm, config)?;
+            buffer.addln(&format!("Resumed VM '{}'.", vm.image_name()));
         }
-        if !found_image {
-            Err("No VM running on image with name matching {image_name}.".to_string())
+        Ok(())
+    } else {
+        Err("No image provided! Must provide an image name.".to_owned())
+    }
+}
+
+fn run_command_logs(
+    image: Option<String>,
+    instance: Option<&str>,
+    qemu: bool,
+    follow: bool,
+    lines: usize,
+) -> Result<(), String> {
+    if let Some(image_name) = image {
+        let vm_name: String = logs::find_logged_vm(&image_name, instance)?;
+        let kind: logs::LogKind = if qemu {
+            logs::LogKind::Qemu
         } else {
-            let vms: Vec<QemuRunner> = get_list_of_running_vms(config);
-            for vm in vms {
-                if vm.image_name().contains(&image_name) {
-                    return vm.stop();
-                }
+            logs::LogKind::Console
+        };
+        logs::show_log(&logs::get_log_path(&vm_name, kind), lines, follow)
+    } else {
+        Err("No image provided! Must provide an image name.".to_owned())
+    }
+}
+
+fn run_command_logs_prune(
+    image: Option<&str>,
+    instance: Option<&str>,
+    config: &Config,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    let rotation: config::LogRotation = config.get_log_rotation();
+    let done: Vec<String> = match image {
+        Some(image_name) => {
+            logs::rotate_vm_logs(&logs::find_logged_vm(image_name, instance)?, &rotation)?
+        }
+        None => logs::rotate_all_logs(&rotation)?,
+    };
+    buffer.add_spacer();
+    if done.is_empty() {
+        buffer.addln("No logs to rotate or delete.");
+    }
+    for line in done {
+        buffer.addln(&line);
+    }
+    Ok(())
+}
+
+fn run_command_stats(
+    image: Option<&str>,
+    instance: Option<&str>,
+    watch: bool,
+    interval: Duration,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    //! Prints the resource usage of the running VMs, or of those matching
+    //! `image`, once or, with `watch`, every `interval` until interrupted.
+    let get_vms = || -> Vec<QemuRunner> {
+        get_list_of_running_vms()
+            .into_iter()
+            .filter(|vm| image.is_none_or(|image| vm.matches(image, instance)))
+            .collect()
+    };
+    if !watch {
+        let vms: Vec<QemuRunner> = get_vms();
+        buffer.add_spacer();
+        if vms.is_empty() {
+            buffer.addln("No machines running.");
+        } else {
+            stats::print_stats_table(&stats::collect_stats(&vms, stats::SAMPLE_INTERVAL), buffer);
+        }
+        return Ok(());
+    }
+
+    // the first sample is taken over a second, so as not to wait a whole
+    // interval for anything to show.
+    let mut sample_interval: Duration = stats::SAMPLE_INTERVAL.min(interval);
+    loop {
+        let vms: Vec<QemuRunner> = get_vms();
+        let vm_stats: Vec<stats::VmStats> = stats::collect_stats(&vms, sample_interval);
+        // clear the terminal and move the cursor home before redrawing
+        buffer.addln(&format!(
+            "\x1b[2J\x1b[HEvery {}s: vm-manager stats    {}",
+            interval.as_secs(),
+            chrono::Local::now().format("%Y-%m-%d %H:%M:%S")
+        ));
+        buffer.add_spacer();
+        if vm_stats.is_empty() {
+            buffer.addln("No machines running.");
+        } else {
+            stats::print_stats_table(&vm_stats, buffer);
+        }
+        buffer.flush();
+        sample_interval = interval;
+    }
+}
+
+fn run_command_status(
+    image: Option<String>,
+    instance: Option<&str>,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    if let Some(image_name) = image {
+        let vm: QemuRunner = match get_list_of_running_vms()
+            .into_iter()
+            .find(|vm| vm.matches(&image_name, instance))
+        {
+            Some(vm) => vm,
+            None => {
+                buffer.add_spacer();
+                buffer.addln(&format!(
+                    "No VM running on image with name matching '{image_name}'{}.",
+                    instance.map_or(String::new(), |instance| format!(
+                        " as instance '{instance}'"
+                    ))
+                ));
+                return Ok(());
             }
-            Err(format!(
-                "Could not find a VM running with image name matching pattern '{image_name}'."
-            ))
+        };
+
+        print_vm_status(&VmStatus::from_runner(&vm), buffer);
+        Ok(())
+    } else {
+        Err("No image provided! Must provide an image name.".to_owned())
+    }
+}
+
+fn find_running_vm(image_name: &str, instance: Option<&str>) -> Result<QemuRunner, String> {
+    //! Finds the only running VM matching `image_name`, running as the given
+    //! instance of its image, if any.
+    let running_vms: Vec<QemuRunner> = get_list_of_running_vms()
+        .into_iter()
+        .filter(|vm| vm.instance() == instance)
+        .collect();
+    let kind: String = match instance {
+        Some(instance) => format!("VM running as instance '{instance}'"),
+        None => "running VM".to_owned(),
+    };
+    matching::select(image_name, running_vms, |vm| vm.base_image_name(), &kind)
+}
+
+fn get_ssh_target(
+    image_name: &str,
+    instance: Option<&str>,
+    config: &Config,
+) -> Result<SshTarget, String> {
+    //! Finds the running VM matching `image_name`, and returns what's needed
+    //! to reach its guest over SSH.
+    let vm: QemuRunner = find_running_vm(image_name, instance)?;
+    let port: usize = vm.forwarded_host_port(22).ok_or(format!(
+        "VM '{}' does not forward any host port to guest port 22.",
+        vm.image_name()
+    ))?;
+    Ok(SshTarget::new(
+        port,
+        config.get_ssh_credentials(&vm.base_image_name()),
+    ))
+}
+
+fn run_command_copy(
+    image: Option<String>,
+    instance: Option<&str>,
+    paths: &[String],
+    recursive: bool,
+    config: &Config,
+) -> Result<(), String> {
+    if let Some(image_name) = image {
+        let target: SshTarget = get_ssh_target(&image_name, instance, config)?;
+        let args: Vec<String> = target.scp_args(paths, recursive)?;
+        run_interactive_command(&args.iter().map(|arg| arg.as_str()).collect::<Vec<&str>>())
+    } else {
+        Err("No image provided! Must provide an image name.".to_owned())
+    }
+}
+
+fn run_command_exec(
+    image: Option<String>,
+    instance: Option<&str>,
+    command: &[String],
+) -> Result<(), String> {
+    //! Runs a command in a running VM's guest, passing its output through.
+    if let Some(image_name) = image {
+        let vm: QemuRunner = find_running_vm(&image_name, instance)?;
+        let output: guest_agent::ExecOutput =
+            guest_agent::GuestAgentClient::connect_to_vm(&vm.image_name())?.exec(command)?;
+        let _ = std::io::stdout().write_all(&output.stdout);
+        let _ = std::io::stderr().write_all(&output.stderr);
+        if output.exit_code != 0 {
+            return Err(format!(
+                "'{}' exited with code {} in VM '{}'.",
+                command.join(" "),
+                output.exit_code,
+                vm.image_name()
+            ));
         }
+        Ok(())
     } else {
         Err("No image provided! Must provide an image name.".to_owned())
     }
 }
+
+fn run_command_ip(
+    image: Option<String>,
+    instance: Option<&str>,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    let vms: Vec<QemuRunner> = match image {
+        Some(image_name) => vec![find_running_vm(&image_name, instance)?],
+        None => get_list_of_running_vms(),
+    };
+    if vms.is_empty() {
+        return Err("No VMs running.".to_owned());
+    }
+    let name_width: usize = vms
+        .iter()
+        .map(|vm| vm.image_name().len())
+        .max()
+        .unwrap_or_default()
+        .max("Image Name".len());
+    buffer.add_spacer();
+    buffer.addln(&format!("{:name_width$} | IP Addresses", "Image Name"));
+    buffer.addln(&format!("{:-<name_width$}-+-{:-<12}", "", ""));
+    for vm in vms {
+        let addresses: Vec<String> = guest_agent::GuestAgentClient::connect_to_vm(&vm.image_name())
+            .and_then(|mut agent| agent.get_ip_addresses())
+            .unwrap_or_else(|_| {
+                network::find_addresses_by_mac(&network::get_nic_mac_addresses(vm.command_line()))
+            });
+        buffer.addln(&format!(
+            "{:name_width$} | {}",
+            vm.image_name(),
+            if addresses.is_empty() {
+                "unknown".to_owned()
+            } else {
+                addresses.join(", ")
+            }
+        ));
+    }
+    Ok(())
+}
+
+fn run_command_ssh_config(
+    write: bool,
+    config: &Config,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    //! Prints SSH client config entries for the running VMs, or writes them
+    //! to the config's `ssh_config_file`.
+    if write {
+        ssh::write_config_file(config)?;
+        buffer.add_spacer();
+        buffer.addln(&format!(
+            "Wrote SSH client config for the running VMs to '{}'.",
+            config.get_ssh_config_file().unwrap_or_default()
+        ));
+    } else {
+        buffer.add(&ssh::get_config_entries(config));
+    }
+    Ok(())
+}
+
+fn run_command_ports(
+    image: Option<String>,
+    instance: Option<&str>,
+    output_format: OutputFormat,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    //! Lists the ports forwarded to a running VM, as recorded when it was
+    //! started.
+    let image_name: String = image.ok_or("No image provided! Must provide an image name.")?;
+    let vm: QemuRunner = find_running_vm(&image_name, instance)?;
+    buffer.add_spacer();
+    if output_format == OutputFormat::Table && vm.forwarded_ports().is_empty() {
+        buffer.addln(&format!("VM '{}' has no forwarded ports.", vm.image_name()));
+    } else {
+        print_forwarded_ports(vm.forwarded_ports(), output_format, buffer);
+    }
+    Ok(())
+}
+
+fn run_command_monitor(image: Option<String>, instance: Option<&str>) -> Result<(), String> {
+    if let Some(image_name) = image {
+        let vm: QemuRunner = find_running_vm(&image_name, instance)?;
+        monitor::run_monitor(&vm.image_name(), &mut vm.qmp_client()?)
+    } else {
+        Err("No image provided! Must provide an image name.".to_owned())
+    }
+}
+
+fn run_command_console(image: Option<String>, instance: Option<&str>) -> Result<(), String> {
+    if let Some(image_name) = image {
+        let vm: QemuRunner = find_running_vm(&image_name, instance)?;
+        let socket_path: PathBuf = get_serial_socket_path(&vm.image_name());
+        if !socket_path.exists() {
+            return Err(format!(
+                "VM '{}' has no serial console socket. Only VMs run in the background, without a '-serial' option of their own, have one.",
+                vm.image_name()
+            ));
+        }
+        console::attach_console(&vm.image_name(), &socket_path)
+    } else {
+        Err("No image provided! Must provide an image name.".to_owned())
+    }
+}
+
+fn run_command_display(
+    image: Option<String>,
+    instance: Option<&str>,
+    launch: bool,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    if let Some(image_name) = image {
+        let vm: QemuRunner = find_running_vm(&image_name, instance)?;
+        let url: String = vm.display_url().ok_or(format!(
+            "VM '{}' has no VNC or SPICE display. Set 'display: vnc' or 'display: spice' in its configuration.",
+            vm.image_name()
+        ))?;
+        if launch {
+            run_interactive_command(&["remote-viewer", &url])
+        } else {
+            buffer.add_spacer();
+            buffer.addln(&url);
+            Ok(())
+        }
+    } else {
+        Err("No image provided! Must provide an image name.".to_owned())
+    }
+}
+
+fn run_command_balloon(
+    image: Option<String>,
+    instance: Option<&str>,
+    target: Option<&str>,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    //! Reports the memory of a running VM's guest, after first asking the
+    //! guest to shrink or grow to `target`, if given.
+    if let Some(image_name) = image {
+        let vm: QemuRunner = find_running_vm(&image_name, instance)?;
+        let mut qmp: qmp::QmpClient = vm.qmp_client()?;
+        let current: u64 = qmp.query_balloon().map_err(|e| {
+            format!(
+                "{e} VM '{}' may have no memory balloon; see 'balloon' in its VM config.",
+                vm.image_name()
+            )
+        })?;
+        buffer.add_spacer();
+        if let Some(target) = target {
+            let bytes: u64 = image::parse_size(target)
+                .filter(|bytes| *bytes >= MINIMUM_BALLOON_TARGET)
+                .ok_or(format!(
+                    "Invalid target '{target}'. Use a size of at least {} MiB, e.g. '4G'.",
+                    MINIMUM_BALLOON_TARGET >> 20
+                ))?;
+            qmp.set_balloon(bytes)?;
+            buffer.addln(&format!(
+                "Asked the guest of VM '{}' to change its memory from {} MiB to {} MiB.",
+                vm.image_name(),
+                current >> 20,
+                bytes >> 20
+            ));
+        } else {
+            buffer.addln(&format!(
+                "The guest of VM '{}' has {} MiB of memory.",
+                vm.image_name(),
+                current >> 20
+            ));
+        }
+        Ok(())
+    } else {
+        Err("No image provided! Must provide an image name.".to_owned())
+    }
+}
+
+fn run_command_scale(
+    image: Option<String>,
+    instance: Option<&str>,
+    cpus: Option<&str>,
+    memory: Option<&str>,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    //! Hotplugs vCPUs and memory into a running VM's guest.
+    if let Some(image_name) = image {
+        if cpus.is_none() && memory.is_none() {
+            return Err("Nothing to scale! Must provide --cpus and/or --memory.".to_owned());
+        }
+        let vm: QemuRunner = find_running_vm(&image_name, instance)?;
+        let mut qmp: qmp::QmpClient = vm.qmp_client()?;
+        buffer.add_spacer();
+        if let Some(cpus) = cpus {
+            let current: u64 = qmp.query_vcpu_threads()?.len() as u64;
+            let count: u64 = hotplug::parse_scale(cpus, current, |count| count.parse().ok())
+                .ok_or(format!(
+                    "Invalid --cpus '{cpus}'. Use e.g. '+2', or a number above the guest's {current} vCPUs."
+                ))?;
+            hotplug::hotplug_cpus(&mut qmp, count as usize)?;
+            buffer.addln(&format!(
+                "Added {count} vCPUs to VM '{}' (now {}).",
+                vm.image_name(),
+                current + count
+            ));
+        }
+        if let Some(memory) = memory {
+            let current: u64 = hotplug::query_memory(&mut qmp)?;
+            let bytes: u64 = hotplug::parse_scale(memory, current, image::parse_memory_size)
+                .ok_or(format!(
+                "Invalid --memory '{memory}'. Use e.g. '+4G', or a size above the guest's {} MiB.",
+                current >> 20
+            ))?;
+            hotplug::hotplug_memory(&mut qmp, bytes).map_err(|e| {
+                format!("{e} Check 'max_memory' and 'memory_slots' in the VM config.")
+            })?;
+            buffer.addln(&format!(
+                "Added {} MiB of memory to VM '{}' (now {} MiB).",
+                bytes >> 20,
+                vm.image_name(),
+                (current + bytes) >> 20
+            ));
+        }
+        Ok(())
+    } else {
+        Err("No image provided! Must provide an image name.".to_owned())
+    }
+}
+
+fn run_command_disk(
+    command: &DiskCommand,
+    image: Option<String>,
+    instance: Option<&str>,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    //! Hot-attaches and detaches disks of a running VM over QMP.
+    let image_name: String = image.ok_or("No image provided! Must provide an image name.")?;
+    let vm: QemuRunner = find_running_vm(&image_name, instance)?;
+    let mut qmp: qmp::QmpClient = vm.qmp_client()?;
+
+    buffer.add_spacer();
+    match command {
+        DiskCommand::Attach { path, read_only } => {
+            let id: String = hotplug::attach_disk(&mut qmp, Path::new(path), *read_only)?;
+            buffer.addln(&format!(
+                "Attached '{path}' to VM '{}' as device '{id}'.",
+                vm.image_name()
+            ));
+        }
+        DiskCommand::Detach { path } => {
+            hotplug::detach_disk(&mut qmp, Path::new(path))?;
+            buffer.addln(&format!("Detached '{path}' from VM '{}'.", vm.image_name()));
+        }
+    }
+    Ok(())
+}
+
+fn run_command_usb(
+    command: &UsbCommand,
+    image: Option<String>,
+    instance: Option<&str>,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    //! Hot-plugs host USB devices into a running VM over QMP.
+    let image_name: String = image.ok_or("No image provided! Must provide an image name.")?;
+    let vm: QemuRunner = find_running_vm(&image_name, instance)?;
+    let mut qmp: qmp::QmpClient = vm.qmp_client()?;
+
+    buffer.add_spacer();
+    match command {
+        UsbCommand::Attach { device } => {
+            usb::attach_usb_device(&mut qmp, device)?;
+            buffer.addln(&format!(
+                "Attached USB device '{device}' to VM '{}'.",
+                vm.image_name()
+            ));
+        }
+        UsbCommand::Detach { device } => {
+            usb::detach_usb_device(&mut qmp, device)?;
+            buffer.addln(&format!(
+                "Detached USB device '{device}' from VM '{}'.",
+                vm.image_name()
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn run_command_backup(
+    image: Option<String>,
+    pause: bool,
+    freeze: bool,
+    incremental: bool,
+    compress: Option<Compression>,
+    config: &Config,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    if let Some(image_name) = image {
+        let image_path: PathBuf = find_image(&image_name, config)?.1;
+        let backup_directory: PathBuf =
+            PathBuf::from(shellexpand::tilde(&config.get_backup_images_directory()).to_string());
+
+        let running_vm: Option<QemuRunner> = get_list_of_running_vms()
+            .into_iter()
+            .find(|vm| vm.disk_path() == image_path);
+        let create_backup = || match compress {
+            Some(compression) => {
+                backup::create_compressed_backup(&image_path, &backup_directory, compression)
+            }
+            None => backup::create_backup(&image_path, &backup_directory),
+        };
+        // the filesystems of a running guest with a guest agent are frozen
+        // while it is backed up, so that the backup is consistent.
+        let while_frozen =
+            |vm: &QemuRunner, action: &mut dyn FnMut() -> Result<PathBuf, String>| {
+                if freeze {
+                    guest_agent::with_frozen_filesystems(&vm.image_name(), action)
+                } else {
+                    action()
+                }
+            };
+        let backup_path: PathBuf = match running_vm {
+            None if incremental => {
+                backup::create_incremental_backup(&image_path, &backup_directory, None)?
+            }
+            None => create_backup()?,
+            // the running VM is switched over to the new overlay, so it
+            // doesn't need to be paused.
+            Some(vm) if incremental => {
+                let mut client = vm.qmp_client()?;
+                while_frozen(&vm, &mut || {
+                    backup::create_incremental_backup(
+                        &image_path,
+                        &backup_directory,
+                        Some(&mut client),
+                    )
+                })?
+            }
+            Some(vm) if pause => {
+                // pausing the guest flushes its disks, so the copy is
+                // consistent.
+                let mut client = vm.qmp_client()?;
+                while_frozen(&vm, &mut || {
+                    client.pause()?;
+                    let result = create_backup();
+                    client.resume()?;
+                    result
+                })?
+            }
+            Some(vm) => {
+                return Err(format!(
+                "VM '{}' is running. Stop it first, pass --pause to pause it during the backup, or use --incremental.",
+                vm.image_name()
+            ))
+            }
+        };
+
+        webhooks::notify(
+            config.get_webhooks(),
+            WebhookEvent::BackupCompleted,
+            &image_path.file_stem().unwrap_or_default().to_string_lossy(),
+            json!({ "path": backup_path, "incremental": incremental }),
+        );
+        buffer.add_spacer();
+        buffer.addln(&format!("Backed up to '{}'.", backup_path.display()));
+        Ok(())
+    } else {
+        Err("No image provided! Must provide an image name.".to_owned())
+    }
+}
+
+fn run_command_restore(
+    image: Option<String>,
+    backup_name: Option<String>,
+    config: &Config,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    if let Some(image_name) = image {
+        let image_path: PathBuf = find_image(&image_name, config)?.1;
+        if let Some(vm) = get_list_of_running_vms()
+            .into_iter()
+            .find(|vm| vm.image_path() == &image_path)
+        {
+            return Err(format!(
+                "VM '{}' is running. Stop it before restoring a backup.",
+                vm.image_name()
+            ));
+        }
+
+        let backup_directory: PathBuf =
+            PathBuf::from(shellexpand::tilde(&config.get_backup_images_directory()).to_string());
+        let full_image_name: String = image_path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or(format!("Invalid image path '{}'.", image_path.display()))?
+            .to_owned();
+        check_no_instances(&full_image_name, "restore a backup of")?;
+        // backups are listed newest first
+        let backups: Vec<backup::Backup> =
+            backup::list_backups(&backup_directory, &full_image_name)
+                .into_iter()
+                .filter(|backup| {
+                    backup_name.as_ref().is_none_or(|name| {
+                        backup
+                            .path
+                            .file_name()
+                            .is_some_and(|file_name| file_name.to_string_lossy().contains(name))
+                    })
+                })
+                .collect();
+        let backup: &backup::Backup = match (backups.as_slice(), &backup_name) {
+            ([], _) => {
+                return Err(format!(
+                    "Could not find a backup of '{full_image_name}'{}.",
+                    backup_name.map_or(String::new(), |name| format!(" matching '{name}'"))
+                ))
+            }
+            ([backup], _) | ([backup, ..], None) => backup,
+            (_, Some(name)) => {
+                return Err(format!("Could not find unique backup matching '{name}'."))
+            }
+        };
+
+        backup::restore_backup(&backup.path, &image_path)?;
+        buffer.add_spacer();
+        buffer.addln(&format!(
+            "Restored '{}' from '{}'.",
+            image_path.display(),
+            backup.path.display()
+        ));
+        Ok(())
+    } else {
+        Err("No image provided! Must provide an image name.".to_owned())
+    }
+}
+
+fn run_command_prune_backups(
+    image: Option<String>,
+    dry_run: bool,
+    config: &Config,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    //! Deletes the backups of the given image, or of every image, which the
+    //! configured retention policy doesn't keep. With `dry_run`, they are
+    //! only listed.
+    let image_paths: Vec<PathBuf> = if let Some(image_name) = image {
+        vec![find_image(&image_name, config)?.1]
+    } else {
+        get_list_of_images(ImageLocation::WorkingImages, config)
+            .into_iter()
+            .map(|name| image::get_image_path(&name, config))
+            .collect()
+    };
+    let backup_directory: PathBuf =
+        PathBuf::from(shellexpand::tilde(&config.get_backup_images_directory()).to_string());
+    let now = chrono::Local::now().naive_local();
+
+    let mut num_pruned: usize = 0;
+    let mut num_failed: usize = 0;
+    buffer.add_spacer();
+    for image_path in image_paths {
+        let image_name: String = match image_path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(image_name) => image_name.to_owned(),
+            None => continue,
+        };
+        let backups: Vec<backup::Backup> = backup::list_backups(&backup_directory, &image_name);
+        let policy = config.get_backup_policy(&image_name);
+        let pruned: Vec<&backup::Backup> = backup::select_backups_to_prune(&backups, &policy, &now);
+        let kept: Vec<&backup::Backup> = backups
+            .iter()
+            .filter(|backup| !pruned.contains(backup))
+            .collect();
+        let in_use: Vec<PathBuf> = backup::get_backups_in_use(&image_path, &kept);
+        for backup in pruned {
+            if in_use.contains(&backup.path) {
+                buffer.addln(&format!(
+                    "Keeping '{}', as newer incremental backups depend on it.",
+                    backup.path.display()
+                ));
+                continue;
+            }
+            if dry_run {
+                buffer.addln(&format!("Would remove '{}'.", backup.path.display()));
+                num_pruned += 1;
+                continue;
+            }
+            match std::fs::remove_file(&backup.path) {
+                Ok(()) => {
+                    buffer.addln(&format!("Removed '{}'.", backup.path.display()));
+                    num_pruned += 1;
+                }
+                Err(e) => {
+                    buffer.addln(&format!(
+                        "Failed to remove '{}'. {e}",
+                        backup.path.display()
+                    ));
+                    num_failed += 1;
+                }
+            }
+        }
+    }
+
+    if num_pruned == 0 && num_failed == 0 {
+        buffer.addln("No backups to prune.");
+    }
+    if num_failed > 0 {
+        Err(format!("Failed to remove {num_failed} backup(s)."))
+    } else {
+        Ok(())
+    }
+}
+
+fn run_command_image(
+    command: &ImageCommand,
+    image: Option<String>,
+    config: &Config,
+    config_file: &str,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    match command {
+        ImageCommand::Create { name, size, format } => {
+            let path: PathBuf = image::get_new_image_path(name, config)?;
+            image::create_image(&path, size, *format)?;
+            buffer.add_spacer();
+            buffer.addln(&format!(
+                "Created {} image '{}' ({size}).",
+                format.as_str(),
+                path.display()
+            ));
+            Ok(())
+        }
+        ImageCommand::Resize { size, force } => {
+            let path: PathBuf = get_stopped_image_path(image, config)?;
+            image::resize_image(&path, size, *force)?;
+            buffer.add_spacer();
+            buffer.addln(&format!("Resized '{}' to '{size}'.", path.display()));
+            Ok(())
+        }
+        ImageCommand::Convert { from, name, format } => {
+            let source: PathBuf = PathBuf::from(shellexpand::tilde(from).to_string());
+            let path: PathBuf = image::get_new_image_path(name, config)?;
+            image::convert_image(&source, &path, *format)?;
+            buffer.add_spacer();
+            buffer.addln(&format!(
+                "Imported '{}' as {} image '{}'.",
+                source.display(),
+                format.as_str(),
+                path.display()
+            ));
+            Ok(())
+        }
+        ImageCommand::Pull {
+            catalog_name,
+            name,
+            add_config,
+            no_signature,
+        } => {
+            let catalog: BTreeMap<String, CatalogImage> = catalog::get_catalog(config);
+            buffer.add_spacer();
+            let Some(catalog_name) = catalog_name else {
+                buffer.addln("Images which can be pulled:");
+                for (name, image) in &catalog {
+                    buffer.addln(&format!("  {name:16} {}", image.url));
+                }
+                return Ok(());
+            };
+            let image: &CatalogImage = catalog.get(catalog_name).ok_or(format!(
+                "'{catalog_name}' is not in the image catalog. Run 'vm-manager image pull' to list the images which can be pulled."
+            ))?;
+            let name: &str = name.as_deref().unwrap_or(catalog_name);
+            let path: PathBuf = image::get_new_image_path(name, config)?;
+            catalog::pull_image(image, &path, !no_signature)?;
+            buffer.addln(&format!(
+                "Pulled '{catalog_name}' as qcow2 image '{}'.",
+                path.display()
+            ));
+            if *add_config {
+                config::add_vm_config_to_file(config_file, name, None, &[])?;
+                buffer.addln(&format!("Added VM '{name}' to '{config_file}'."));
+            }
+            Ok(())
+        }
+        ImageCommand::Clone {
+            name,
+            linked,
+            add_config,
+        } => {
+            let source: PathBuf = get_stopped_image_path(image, config)?;
+            let path: PathBuf = image::get_new_image_path(name, config)?;
+            image::clone_image(&source, &path, *linked)?;
+            buffer.add_spacer();
+            buffer.addln(&format!(
+                "Cloned '{}' to '{}'{}.",
+                source.display(),
+                path.display(),
+                if *linked { " as a linked clone" } else { "" }
+            ));
+            if *add_config {
+                let source_name: Option<String> = source
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| config.get_vm_config_with_name(stem))
+                    .map(|vm| vm.image_name().to_owned());
+                config::add_vm_config_to_file(config_file, name, source_name.as_deref(), &[])?;
+                buffer.addln(&format!("Added VM '{name}' to '{config_file}'."));
+            }
+            Ok(())
+        }
+        ImageCommand::Rename { name } => {
+            let source: PathBuf = get_stopped_image_path(image, config)?;
+            let path: PathBuf = image::get_new_image_path(name, config)?;
+            let source_name: String = source
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .ok_or(format!("Invalid image path '{}'.", source.display()))?
+                .to_owned();
+            check_no_instances(&source_name, "rename")?;
+            std::fs::rename(&source, &path).map_err(|e| {
+                format!(
+                    "Unable to rename '{}' to '{}'. {e}",
+                    source.display(),
+                    path.display()
+                )
+            })?;
+            buffer.add_spacer();
+            buffer.addln(&format!(
+                "Renamed '{}' to '{}'.",
+                source.display(),
+                path.display()
+            ));
+            for file in config::rename_image_in_config_files(config_file, &source_name, name)? {
+                buffer.addln(&format!("Updated VM '{source_name}' in '{file}'."));
+            }
+            Ok(())
+        }
+        ImageCommand::Delete { yes, permanent } => {
+            let path: PathBuf = get_stopped_image_path(image, config)?;
+
+            // linked clones and incremental backups can't outlive the image
+            // they are backed by.
+            let image_name: String = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .ok_or(format!("Invalid image path '{}'.", path.display()))?
+                .to_owned();
+            let dependents: Vec<String> = get_list_of_images(ImageLocation::WorkingImages, config)
+                .into_iter()
+                .map(|name| image::get_image_path(&name, config))
+                .filter(|other| backup::get_backing_chain(other).contains(&path))
+                .chain(
+                    instance::list_instances(&image_name)
+                        .iter()
+                        .map(|name| instance::get_overlay_path(&image_name, name)),
+                )
+                .map(|other| other.display().to_string())
+                .collect();
+            if !dependents.is_empty() {
+                return Err(format!(
+                    "Refusing to delete '{}', as these images are backed by it: {}",
+                    path.display(),
+                    dependents.join(", ")
+                ));
+            }
+
+            let action: &str = if *permanent {
+                "Permanently delete"
+            } else {
+                "Move to trash"
+            };
+            if !yes && !confirm(&format!("{action} '{}'?", path.display())) {
+                return Err("Aborted.".to_owned());
+            }
+
+            buffer.add_spacer();
+            if *permanent {
+                std::fs::remove_file(&path)
+                    .map_err(|e| format!("Unable to delete '{}'. {e}", path.display()))?;
+                buffer.addln(&format!("Deleted '{}'.", path.display()));
+            } else {
+                let trash_directory: PathBuf =
+                    PathBuf::from(shellexpand::tilde(TRASH_DIRECTORY).to_string());
+                std::fs::create_dir_all(&trash_directory).map_err(|e| {
+                    format!(
+                        "Unable to create trash directory '{}'. {e}",
+                        trash_directory.display()
+                    )
+                })?;
+                // timestamped, so that images deleted under the same name
+                // don't overwrite each other.
+                let trash_path: PathBuf = trash_directory.join(
+                    backup::get_backup_file_name(&path, &chrono::Local::now().naive_local())
+                        .ok_or(format!("Invalid image path '{}'.", path.display()))?,
+                );
+                std::fs::rename(&path, &trash_path).map_err(|e| {
+                    format!(
+                        "Unable to move '{}' to '{}'. {e}",
+                        path.display(),
+                        trash_path.display()
+                    )
+                })?;
+                buffer.addln(&format!(
+                    "Moved '{}' to '{}'.",
+                    path.display(),
+                    trash_path.display()
+                ));
+            }
+            Ok(())
+        }
+    }
+}
+
+fn run_command_export(
+    command: &ExportCommand,
+    image: Option<String>,
+    config: &Config,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    match command {
+        ExportCommand::Libvirt => {
+            let image_name: String =
+                image.ok_or("No image provided! Must provide an image name.")?;
+            let vm: &config::VMConfig = matching::select(
+                &image_name,
+                config.get_vm_configs().iter().collect(),
+                |vm| vm.name().to_owned(),
+                "VM",
+            )?;
+            let vm: config::VMConfig = config.get_effective_vm_config(vm);
+            let path: PathBuf = find_image(vm.image_name(), config)?.1;
+            let format: String = image::get_image_info(&path)?.format;
+            let (domain, notes) = libvirt::get_domain(&vm, config, &path, &format)?;
+            for note in notes {
+                eprintln!("{note}");
+            }
+            buffer.add(&libvirt::render_domain_xml(&domain));
+            Ok(())
+        }
+    }
+}
+
+fn run_command_import(
+    command: &ImportCommand,
+    config: &Config,
+    config_file: &str,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    match command {
+        ImportCommand::Vagrant { box_name, name } => {
+            let name: String = name
+                .clone()
+                .unwrap_or_else(|| vagrant::get_default_name(box_name));
+            let path: PathBuf = image::get_new_image_path(&name, config)?;
+            vagrant::import_box(box_name, &path)?;
+            buffer.add_spacer();
+            buffer.addln(&format!(
+                "Imported box '{box_name}' as qcow2 image '{}'.",
+                path.display()
+            ));
+            let mut edits: Vec<config::VmConfigEdit> = vec![config::VmConfigEdit::Set(
+                "ssh.user".to_owned(),
+                vagrant::VAGRANT_USER.into(),
+            )];
+            if let Some(key) = vagrant::find_insecure_key() {
+                edits.push(config::VmConfigEdit::Set(
+                    "ssh.identity_file".to_owned(),
+                    key.into(),
+                ));
+            }
+            config::add_vm_config_to_file(config_file, &name, None, &edits)?;
+            buffer.addln(&format!("Added VM '{name}' to '{config_file}'."));
+            Ok(())
+        }
+        ImportCommand::Libvirt {
+            domain_xml,
+            name,
+            link,
+        } => {
+            let xml: String = if domain_xml == "-" {
+                std::io::read_to_string(std::io::stdin())
+                    .map_err(|e| format!("Unable to read domain XML from stdin. {e}"))?
+            } else {
+                std::fs::read_to_string(shellexpand::tilde(domain_xml).as_ref())
+                    .map_err(|e| format!("Unable to read '{domain_xml}'. {e}"))?
+            };
+            let (domain, mut notes) = libvirt::parse_domain_xml(&xml)?;
+            let name: &str = name.as_deref().unwrap_or(&domain.name);
+            let (mut edits, edit_notes) = libvirt::get_vm_config_edits(&domain)?;
+            notes.extend(edit_notes);
+            edits.extend(libvirt::import_disks(&domain, name, config, *link)?);
+            config::add_vm_config_to_file(config_file, name, None, &edits)?;
+            for note in notes {
+                eprintln!("{note}");
+            }
+            buffer.add_spacer();
+            buffer.addln(&format!(
+                "Imported domain '{}' as VM '{name}' in '{config_file}'.",
+                domain.name
+            ));
+            Ok(())
+        }
+    }
+}
+
+fn get_stopped_image_path(image: Option<String>, config: &Config) -> Result<PathBuf, String> {
+    //! Returns the path of the image matching `image`, refusing images in
+    //! use by a running VM.
+    let image_name: String = image.ok_or("No image provided! Must provide an image name.")?;
+    let path: PathBuf = find_image(&image_name, config)?.1;
+    if let Some(vm) = get_list_of_running_vms()
+        .into_iter()
+        .find(|vm| vm.image_path() == &path)
+    {
+        return Err(format!(
+            "VM '{}' is running. Stop it first.",
+            vm.image_name()
+        ));
+    }
+    Ok(path)
+}
+
+fn run_command_config_init(
+    config_file: &str,
+    scan: Option<&str>,
+    force: bool,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    //! Creates vm-manager's directories and writes a commented config file to
+    //! start from. With `scan`, that directory of existing images is used as
+    //! the images directory, and a VM is configured for each image in it.
+    let config_path: PathBuf = PathBuf::from(shellexpand::tilde(config_file).to_string());
+    if config_path.exists() && !force {
+        return Err(format!(
+            "Config file '{config_file}' already exists. Pass --force to replace it."
+        ));
+    }
+
+    let (images_directory, image_names): (String, Vec<String>) = match scan {
+        Some(directory) => {
+            let directory: PathBuf = std::fs::canonicalize(shellexpand::tilde(directory).as_ref())
+                .map_err(|e| format!("Unable to find image directory '{directory}'. {e}"))?;
+            let image_names: Vec<String> = config_init::find_images(&directory)?;
+            (directory.display().to_string(), image_names)
+        }
+        None => (xdg::get_default_images_directory(), vec![]),
+    };
+    let backup_directory: String = format!("{images_directory}/backups");
+
+    buffer.add_spacer();
+    let mut directories: Vec<PathBuf> = vec![
+        PathBuf::from(shellexpand::tilde(&images_directory).to_string()),
+        PathBuf::from(shellexpand::tilde(&backup_directory).to_string()),
+    ];
+    if let Some(directory) = config_path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        directories.insert(0, directory.to_owned());
+    }
+    for directory in directories {
+        if !directory.is_dir() {
+            std::fs::create_dir_all(&directory).map_err(|e| {
+                format!("Unable to create directory '{}'. {e}", directory.display())
+            })?;
+            buffer.addln(&format!("Created directory '{}'.", directory.display()));
+        }
+    }
+
+    let mut template: String = config_init::get_config_template(&images_directory, &image_names)?;
+    if ConfigFormat::from_path(config_file) == ConfigFormat::Toml {
+        template = config_init::get_toml_config_template(&template)?;
+    }
+    if config_path.exists() {
+        let backup_path: String = format!("{}.bak", config_path.display());
+        std::fs::copy(&config_path, &backup_path)
+            .map_err(|e| format!("Unable to back up config file '{config_file}'. {e}"))?;
+        buffer.addln(&format!(
+            "Kept the previous config file as '{backup_path}'."
+        ));
+    }
+    std::fs::write(&config_path, template)
+        .map_err(|e| format!("Unable to write config file '{config_file}'. {e}"))?;
+    buffer.addln(&format!("Wrote config file '{}'.", config_path.display()));
+    for image_name in &image_names {
+        buffer.addln(&format!("Added VM '{image_name}'."));
+    }
+    Ok(())
+}
+
+fn run_command_migrate_paths(dry_run: bool, buffer: &mut OutputStream) -> Result<(), String> {
+    //! Moves the config file and images directory of an installation from
+    //! before vm-manager followed the XDG base directory specification to
+    //! their XDG locations, then points the config and any overlay disks
+    //! backed by a moved image at the images' new paths.
+    let moves: Vec<(PathBuf, PathBuf)> = xdg::get_legacy_moves();
+    buffer.add_spacer();
+    if moves.is_empty() {
+        buffer.addln("There is no config file or images directory in '~/.vm-manager' to migrate.");
+        return Ok(());
+    }
+    if let Some((_, destination)) = moves.iter().find(|(_, destination)| destination.exists()) {
+        return Err(format!(
+            "'{}' already exists. Move it out of the way before migrating.",
+            destination.display()
+        ));
+    }
+    if dry_run {
+        for (source, destination) in &moves {
+            buffer.addln(&format!(
+                "Would move '{}' to '{}'.",
+                source.display(),
+                destination.display()
+            ));
+        }
+        return Ok(());
+    }
+    if !get_list_of_running_vms().is_empty() {
+        return Err(
+            "Stop every running VM before migrating, as their disks may be moved.".to_owned(),
+        );
+    }
+
+    for (source, destination) in &moves {
+        if let Some(directory) = destination.parent() {
+            std::fs::create_dir_all(directory).map_err(|e| {
+                format!("Unable to create directory '{}'. {e}", directory.display())
+            })?;
+        }
+        std::fs::rename(source, destination).map_err(|e| {
+            format!(
+                "Unable to move '{}' to '{}'. {e}",
+                source.display(),
+                destination.display()
+            )
+        })?;
+        buffer.addln(&format!(
+            "Moved '{}' to '{}'.",
+            source.display(),
+            destination.display()
+        ));
+    }
+
+    let legacy_images_directory: PathBuf =
+        PathBuf::from(shellexpand::tilde(IMAGES_DIRECTORY).as_ref());
+    let Some((_, images_directory)) = moves
+        .iter()
+        .find(|(source, _)| *source == legacy_images_directory)
+    else {
+        return Ok(());
+    };
+
+    // a config which names the old images directory would otherwise create
+    // it again.
+    let config_file: String = xdg::get_default_config_file();
+    if Path::new(&config_file).is_file() {
+        let (_, document) = config::load_config_document(&config_file)?;
+        let is_legacy = |directory: &serde_yaml::Value| {
+            directory.as_str().is_some_and(|directory| {
+                Path::new(shellexpand::tilde(directory).as_ref()) == legacy_images_directory
+            })
+        };
+        let new_directory: serde_yaml::Value = images_directory.display().to_string().into();
+        if is_legacy(&document["base_images_directory"]) {
+            config::set_setting_in_file(
+                &config_file,
+                "base_images_directory",
+                new_directory.clone(),
+            )?;
+            buffer.addln(&format!(
+                "Set 'base_images_directory' to '{}' in '{config_file}'.",
+                images_directory.display()
+            ));
+        }
+        if let Some(directories) = document["image_directories"].as_sequence() {
+            if directories.iter().any(is_legacy) {
+                let directories: Vec<serde_yaml::Value> = directories
+                    .iter()
+                    .map(|directory| match is_legacy(directory) {
+                        true => new_directory.clone(),
+                        false => directory.clone(),
+                    })
+                    .collect();
+                config::set_setting_in_file(&config_file, "image_directories", directories.into())?;
+                buffer.addln(&format!(
+                    "Replaced '{}' with '{}' in 'image_directories' in '{config_file}'.",
+                    legacy_images_directory.display(),
+                    images_directory.display()
+                ));
+            }
+        }
+    }
+
+    // overlays, such as linked clones and the disks of instances, refer to
+    // their backing image by its absolute path.
+    let mut directories: Vec<PathBuf> =
+        vec![images_directory.clone(), images_directory.join("backups")];
+    if let Ok(entries) = std::fs::read_dir(shellexpand::tilde(INSTANCES_DIRECTORY).as_ref()) {
+        directories.extend(
+            entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.is_dir()),
+        );
+    }
+    let mut failed: bool = false;
+    for directory in directories {
+        let Ok(entries) = std::fs::read_dir(&directory) else {
+            continue;
+        };
+        for path in entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file())
+        {
+            let Some(backing_file) = image::get_image_info(&path)
+                .ok()
+                .and_then(|info| info.backing_file)
+            else {
+                continue;
+            };
+            let Ok(relative_path) = backing_file.strip_prefix(&legacy_images_directory) else {
+                continue;
+            };
+            let new_backing_file: PathBuf = images_directory.join(relative_path);
+            match image::rebase_image(&path, &new_backing_file) {
+                Ok(()) => buffer.addln(&format!(
+                    "Pointed '{}' at '{}'.",
+                    path.display(),
+                    new_backing_file.display()
+                )),
+                Err(e) => {
+                    buffer.addln(&e);
+                    failed = true;
+                }
+            }
+        }
+    }
+    if failed {
+        return Err(
+            "Some overlay disks still refer to the old images directory. Point them at the moved images with 'qemu-img rebase -u'."
+                .to_owned(),
+        );
+    }
+    Ok(())
+}
+
+fn get_vm_config_edits(changes: &VmConfigChanges) -> Result<Vec<config::VmConfigEdit>, String> {
+    //! Returns the changes to a VM's config entry given on the command line,
+    //! in the order settings, removals and additions.
+    let mut edits: Vec<config::VmConfigEdit> = vec![];
+    for setting in &changes.settings {
+        let (key, value) = setting
+            .split_once('=')
+            .filter(|(key, _)| !key.is_empty())
+            .ok_or(format!(
+                "Invalid setting '{setting}'. Use KEY=VALUE, e.g. 'memory=16G'."
+            ))?;
+        let value: serde_yaml::Value = serde_yaml::from_str(value)
+            .map_err(|e| format!("Invalid value of setting '{key}'. {e}"))?;
+        edits.push(config::VmConfigEdit::Set(key.to_owned(), value));
+    }
+    edits.extend(
+        changes
+            .unset
+            .iter()
+            .cloned()
+            .map(config::VmConfigEdit::Unset),
+    );
+    edits.extend(
+        changes
+            .remove_ports
+            .iter()
+            .cloned()
+            .map(config::VmConfigEdit::RemovePort),
+    );
+    edits.extend(
+        changes
+            .remove_options
+            .iter()
+            .cloned()
+            .map(config::VmConfigEdit::RemoveOption),
+    );
+    edits.extend(
+        changes
+            .add_ports
+            .iter()
+            .cloned()
+            .map(config::VmConfigEdit::AddPort),
+    );
+    edits.extend(
+        changes
+            .add_options
+            .iter()
+            .cloned()
+            .map(config::VmConfigEdit::AddOption),
+    );
+    Ok(edits)
+}
+
+fn run_command_config(
+    command: &ConfigCommand,
+    image: Option<String>,
+    instance: Option<&str>,
+    config: &Config,
+    config_file: &str,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    match command {
+        ConfigCommand::Init { .. } => {
+            unreachable!("'config init' is run before the config is loaded")
+        }
+        ConfigCommand::AddVm {
+            name,
+            from,
+            changes,
+        } => {
+            config::add_vm_config_to_file(
+                config_file,
+                name,
+                from.as_deref(),
+                &get_vm_config_edits(changes)?,
+            )?;
+            buffer.add_spacer();
+            buffer.addln(&format!("Added VM '{name}' to '{config_file}'."));
+            Ok(())
+        }
+        ConfigCommand::Set { name, changes } => {
+            let edits: Vec<config::VmConfigEdit> = get_vm_config_edits(changes)?;
+            if edits.is_empty() {
+                return Err(
+                    "No changes given! Pass KEY=VALUE settings or options such as --add-port."
+                        .to_owned(),
+                );
+            }
+            // the VM may be configured in a file the config file includes.
+            let vm_config_file: String = config::find_config_file_with_vm(config_file, name);
+            config::edit_vm_config_in_file(&vm_config_file, name, &edits)?;
+            buffer.add_spacer();
+            buffer.addln(&format!("Updated VM '{name}' in '{vm_config_file}'."));
+            Ok(())
+        }
+        ConfigCommand::Convert { output, force } => {
+            let output_path: String = shellexpand::tilde(output).to_string();
+            if Path::new(&output_path).exists() && !force {
+                return Err(format!(
+                    "'{output}' already exists. Pass --force to replace it."
+                ));
+            }
+            let (_, document) = config::load_config_document(config_file)?;
+            let format: ConfigFormat = ConfigFormat::from_path(output);
+            let contents: String = format
+                .serialize(&document)
+                .map_err(|e| format!("Unable to convert config file '{config_file}'. {e}"))?;
+            std::fs::write(&output_path, contents)
+                .map_err(|e| format!("Unable to write '{output}'. {e}"))?;
+            buffer.add_spacer();
+            buffer.addln(&format!(
+                "Converted '{config_file}' to {} in '{output}'. Comments are not carried over.",
+                match format {
+                    ConfigFormat::Yaml => "YAML",
+                    ConfigFormat::Toml => "TOML",
+                }
+            ));
+            Ok(())
+        }
+        ConfigCommand::Show {
+            effective: false,
+            argv: false,
+        } => {
+            let contents: String =
+                std::fs::read_to_string(shellexpand::tilde(config_file).as_ref())
+                    .map_err(|e| format!("Unable to read config file '{config_file}'. {e}"))?;
+            buffer.add(contents.trim_end());
+            Ok(())
+        }
+        ConfigCommand::Show { argv, .. } => {
+            let vms: Vec<&config::VMConfig> = config
+                .get_vm_configs()
+                .iter()
+                .filter(|vm| {
+                    image
+                        .as_ref()
+                        .is_none_or(|image| matching::matches(image, vm.name()))
+                })
+                .collect();
+            if vms.is_empty() {
+                return Err(format!(
+                    "No VM in config file '{config_file}' matches '{}'.",
+                    image.unwrap_or_default()
+                ));
+            }
+            if *argv {
+                for vm in &vms {
+                    let mut runner: QemuRunner = QemuRunner::default();
+                    runner.set_image_file(find_image(vm.name(), config)?.1);
+                    runner.set_image_name(vm.name());
+                    if let Some(instance) = instance {
+                        runner.set_instance(instance);
+                    }
+                    runner.add_vm_config(vm);
+                    let command_line: Vec<String> = runner
+                        .get_command_line(config)
+                        .map_err(|e| format!("VM '{}': {e}", runner.image_name()))?;
+                    if vms.len() > 1 {
+                        buffer.addln(&format!("# {}", runner.image_name()));
+                    }
+                    buffer.addln(
+                        &command_line
+                            .iter()
+                            .map(|arg| host::quote_shell_arg(arg))
+                            .collect::<Vec<String>>()
+                            .join(" "),
+                    );
+                }
+                return Ok(());
+            }
+
+            // the settings which aren't per VM are shown resolved as well.
+            let mut document: serde_yaml::Mapping = serde_yaml::Mapping::new();
+            document.insert(
+                "image_directories".into(),
+                config.get_image_directories().into(),
+            );
+            document.insert(
+                "shutdown_timeout".into(),
+                config.get_shutdown_timeout().as_secs().into(),
+            );
+            let vms: Vec<config::VMConfig> = vms
+                .into_iter()
+                .map(|vm| config.get_effective_vm_config(vm))
+                .collect();
+            document.insert(
+                "vms".into(),
+                serde_yaml::to_value(vms)
+                    .map_err(|e| format!("Unable to serialize VM configs. {e}"))?,
+            );
+            let document: String = serde_yaml::to_string(&document)
+                .map_err(|e| format!("Unable to serialize config. {e}"))?;
+            buffer.add(document.trim_end());
+            Ok(())
+        }
+    }
+}
+
+fn run_command_doctor(buffer: &mut OutputStream) -> Result<(), String> {
+    //! Reports which of the programs and firmware used by vm-manager are
+    //! installed. Only a missing qemu is an error, as everything else is
+    //! only needed by some VM options or subcommands.
+    let programs: [(&str, &str); 9] = [
+        ("qemu-system-x86_64", "required"),
+        ("qemu-img", "required by 'image', 'snapshot' and 'backup'"),
+        ("swtpm", "required by 'tpm: true'"),
+        ("scp", "required by 'copy'"),
+        ("remote-viewer", "required by 'display --launch'"),
+        ("zstd", "required by 'backup --compress zstd'"),
+        ("gzip", "required by 'backup --compress gzip'"),
+        ("taskset", "required by 'cpu_affinity'"),
+        ("curl", "required by 'webhooks'"),
+    ];
+
+    buffer.add_spacer();
+    buffer.addln(
+        "--------------------
+Programs
+--------------------",
+    );
+    for (program, needed_for) in programs {
+        buffer.addln(&match find_in_path(program) {
+            Some(path) => format!("{program}: {}", path.display()),
+            None => format!("{program}: not found ({needed_for})"),
+        });
+    }
+    buffer.addln(&match shares::find_virtiofsd() {
+        Some(path) => format!("virtiofsd: {}", path.display()),
+        None => "virtiofsd: not found (required by virtiofs shares)".to_owned(),
+    });
+    buffer.addln(&match shares::find_smbd() {
+        Some(path) => format!("smbd: {}", path.display()),
+        None => "smbd: not found (required by 'smb_share')".to_owned(),
+    });
+    buffer.addln(&match network::find_bridge_helper() {
+        Some(path) => format!("qemu-bridge-helper: {}", path.display()),
+        None => "qemu-bridge-helper: not found (required by 'network: bridge')".to_owned(),
+    });
+    buffer.addln(&format!(
+        "KVM: {}",
+        if std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/kvm")
+            .is_ok()
+        {
+            "available"
+        } else {
+            "unavailable (VMs fall back to the much slower TCG)"
+        }
+    ));
+    buffer.addln(&match hugepages::get_hugepages_info() {
+        Some(info) if info.total > 0 => format!(
+            "Hugepages: {} of {} available ({} KiB each){}",
+            info.available,
+            info.total,
+            info.page_size / 1024,
+            if hugepages::find_hugetlbfs_mount().is_some() {
+                ""
+            } else {
+                ", but no hugetlbfs is mounted"
+            }
+        ),
+        _ => "Hugepages: none reserved (required by 'hugepages: true')".to_owned(),
+    });
+
+    buffer.add_spacer();
+    buffer.addln(
+        "--------------------
+Firmware
+--------------------",
+    );
+    for (secure_boot, label, needed_for) in [
+        (false, "UEFI", "'uefi: true'"),
+        (true, "Secure Boot", "'secure_boot: true'"),
+    ] {
+        match firmware::find_firmware(secure_boot) {
+            Some(found) => {
+                buffer.addln(&format!("{label} code: {}", found.code.display()));
+                buffer.addln(&format!(
+                    "{label} vars template: {}",
+                    found.vars_template.display()
+                ));
+            }
+            None => {
+                buffer.addln(&format!(
+                    "{label}: not found (required by {needed_for}). Searched:"
+                ));
+                for (code, vars_template) in firmware::get_searched_firmware(secure_boot) {
+                    buffer.addln(&format!("    {code} + {vars_template}"));
+                }
+            }
+        }
+    }
+
+    buffer.add_spacer();
+    buffer.addln(
+        "--------------------
+PCI passthrough
+--------------------",
+    );
+    let iommu_enabled: bool =
+        std::fs::read_dir(vfio::SYSFS_IOMMU_GROUPS).is_ok_and(|mut groups| groups.next().is_some());
+    buffer.addln(&format!(
+        "IOMMU: {}",
+        if iommu_enabled {
+            "enabled"
+        } else {
+            "disabled (add 'intel_iommu=on' or 'amd_iommu=on' to the kernel command line)"
+        }
+    ));
+    buffer.addln(&format!(
+        "vfio-pci: {}",
+        if PathBuf::from(vfio::SYSFS_VFIO_PCI_DRIVER).is_dir() {
+            "loaded"
+        } else {
+            "not loaded (run 'modprobe vfio-pci')"
+        }
+    ));
+
+    if find_in_path("qemu-system-x86_64").is_none() {
+        Err("qemu-system-x86_64 is not installed.".to_owned())
+    } else {
+        Ok(())
+    }
+}
+
+fn check_no_instances(image_name: &str, action: &str) -> Result<(), String> {
+    //! Refuses to `action` an image which instances are backed by, as their
+    //! overlay disks would no longer match it.
+    let instances: Vec<String> = instance::list_instances(image_name);
+    if instances.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "Refusing to {action} '{image_name}', as it has instances: {}. Delete them first with 'vm-manager instance -i {image_name} delete'.",
+            instances.join(", ")
+        ))
+    }
+}
+
+fn run_command_instance(
+    command: &InstanceCommand,
+    image: Option<String>,
+    config: &Config,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    let image_name: String = image.ok_or("No image provided! Must provide an image name.")?;
+    let path: PathBuf = find_image(&image_name, config)?.1;
+    let full_image_name: String = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or(format!("Invalid image path '{}'.", path.display()))?
+        .to_owned();
+    let running_instances: Vec<String> = get_list_of_running_vms()
+        .into_iter()
+        .filter(|vm| vm.image_path() == &path)
+        .filter_map(|vm| vm.instance().map(|instance| instance.to_owned()))
+        .collect();
+
+    buffer.add_spacer();
+    match command {
+        InstanceCommand::List => {
+            let instances: Vec<String> = instance::list_instances(&full_image_name);
+            if instances.is_empty() {
+                buffer.addln(&format!("'{full_image_name}' has no instances."));
+            }
+            for name in instances {
+                buffer.addln(&format!(
+                    "{name}{}",
+                    if running_instances.contains(&name) {
+                        " (running)"
+                    } else {
+                        ""
+                    }
+                ));
+            }
+        }
+        InstanceCommand::Delete { name, yes } => {
+            if running_instances.contains(name) {
+                return Err(format!(
+                    "Instance '{name}' of '{full_image_name}' is running. Stop it first."
+                ));
+            }
+            if !yes
+                && !confirm(&format!(
+                    "Delete instance '{name}' of '{full_image_name}', and every change made to its disk?"
+                ))
+            {
+                return Err("Aborted.".to_owned());
+            }
+            instance::delete_instance(&full_image_name, name)?;
+            buffer.addln(&format!(
+                "Deleted instance '{name}' of '{full_image_name}'."
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn run_command_snapshot(
+    command: &SnapshotCommand,
+    image: Option<String>,
+    config: &Config,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    //! Snapshots of a running VM are taken live over QMP, and include its
+    //! RAM. Those of a stopped VM only hold its disk.
+    let image_name: String = image.ok_or("No image provided! Must provide an image name.")?;
+    let path: PathBuf = find_image(&image_name, config)?.1;
+    let mut client: Option<qmp::QmpClient> = match get_list_of_running_vms()
+        .into_iter()
+        .find(|vm| vm.disk_path() == path)
+    {
+        Some(vm) => Some(vm.qmp_client()?),
+        None => None,
+    };
+
+    buffer.add_spacer();
+    match command {
+        SnapshotCommand::Create { name } => {
+            match client.as_mut() {
+                Some(client) => snapshot::create_live_snapshot(client, &path, name)?,
+                None => snapshot::create_snapshot(&path, name)?,
+            }
+            buffer.addln(&format!(
+                "Created snapshot '{name}' of '{}'.",
+                path.display()
+            ));
+        }
+        SnapshotCommand::List => {
+            let snapshots = match client.as_mut() {
+                Some(client) => snapshot::list_live_snapshots(client, &path)?,
+                None => snapshot::list_snapshots(&path)?,
+            };
+            if snapshots.is_empty() {
+                buffer.addln(&format!("'{}' has no snapshots.", path.display()));
+            } else {
+                snapshot::print_snapshot_table(&snapshots, buffer);
+            }
+        }
+        SnapshotCommand::Revert { name } => {
+            match client.as_mut() {
+                Some(client) => snapshot::revert_live_snapshot(client, &path, name)?,
+                None => snapshot::revert_snapshot(&path, name)?,
+            }
+            buffer.addln(&format!(
+                "Reverted '{}' to snapshot '{name}'.",
+                path.display()
+            ));
+        }
+        SnapshotCommand::Delete { name } => {
+            match client.as_mut() {
+                Some(client) => snapshot::delete_live_snapshot(client, &path, name)?,
+                None => snapshot::delete_snapshot(&path, name)?,
+            }
+            buffer.addln(&format!(
+                "Deleted snapshot '{name}' of '{}'.",
+                path.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn run_command_systemd(
+    command: &SystemdCommand,
+    image: Option<String>,
+    instance: Option<&str>,
+    config_file: &str,
+    config: &Config,
+    buffer: &mut OutputStream,
+) -> Result<(), String> {
+    let image_name: String = image.ok_or("No image provided! Must provide an image name.")?;
+    let path: PathBuf = find_image(&image_name, config)?.1;
+    let full_image_name: String = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or(format!("Invalid image path '{}'.", path.display()))?
+        .to_owned();
+    let vm_name: String = match instance {
+        Some(instance) => instance::get_instance_vm_name(&full_image_name, instance),
+        None => full_image_name.clone(),
+    };
+
+    match command {
+        SystemdCommand::Generate { enable } => {
+            // the service runs from another directory, so the paths given to
+            // it have to be absolute
+            let executable: PathBuf = std::env::current_exe()
+                .map_err(|e| format!("Unable to determine the path of vm-manager. {e}"))?;
+            let config_file: PathBuf = std::fs::canonicalize(config_file)
+                .map_err(|e| format!("Unable to find config file '{config_file}'. {e}"))?;
+            let unit_path: PathBuf = systemd::write_unit(
+                &vm_name,
+                &systemd::ServiceOptions {
+                    executable: &executable,
+                    config_file: &config_file,
+                    image_name: &full_image_name,
+                    instance,
+                    shutdown_timeout: config.get_shutdown_timeout(),
+                },
+                *enable,
+            )?;
+            let unit_name: String = systemd::get_unit_name(&vm_name);
+            buffer.add_spacer();
+            buffer.addln(&format!(
+                "Wrote systemd user service '{}' for VM '{vm_name}'.",
+                unit_path.display()
+            ));
+            if *enable {
+                buffer.addln(&format!(
+                    "Enabled it; start it now with 'systemctl --user start {unit_name}'."
+                ));
+            } else {
+                buffer.addln(&format!(
+                    "Enable it with 'systemctl --user daemon-reload && systemctl --user enable --now {unit_name}'."
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_remote(args: &Arguments, remote: &str, buffer: &mut OutputStream) -> Result<i32, String> {
+    //! Has a running `vm-manager daemon` list, start, stop or show the status
+    //! of VMs, as `remote` says how to reach it. Returns the exit code to
+    //! report.
+    if args.list_running_vms {
+        let mut records: Vec<RunningVmRecord> =
+            serde_json::from_value(daemon::send_request(remote, "GET", "/vms", None)?)
+                .map_err(|e| format!("Invalid response from the daemon. {e}"))?;
+        records.retain(|record| {
+            args.filter
+                .iter()
+                .all(|filter| filter.matches(&record.tags))
+        });
+        buffer.add_spacer();
+        if args.output != OutputFormat::Table {
+            print_running_vm_records(
+                &records,
+                args.output,
+                args.running_vm_columns().as_deref(),
+                buffer,
+            );
+        } else if records.is_empty() {
+            buffer.addln("No machines running.");
+        } else {
+            buffer.addln("--------------------\nRunning VMs\n--------------------");
+            print_running_vm_records(
+                &records,
+                args.output,
+                args.running_vm_columns().as_deref(),
+                buffer,
+            );
+        }
+    }
+
+    let vm_name = || daemon::get_remote_vm_name(args.image().as_deref(), args.instance.as_deref());
+    match &args.command {
+        None => Ok(0),
+        Some(parse_args::Command::Start(options)) => {
+            if args.foreground {
+                return Err("--foreground isn't available with --remote.".to_owned());
+            }
+            if options.dry_run {
+                return Err("--dry-run isn't available with --remote.".to_owned());
+            }
+            let mut options: serde_json::Value =
+                serde_json::to_value(options).map_err(|e| e.to_string())?;
+            if remote.is_empty() {
+                // the daemon runs on this host, but in another directory
+                for name in ["cdrom", "tftp"] {
+                    if let Some(path) = options[name].as_str() {
+                        options[name] = std::path::absolute(path)
+                            .map_err(|e| format!("Invalid path '{path}'. {e}"))?
+                            .display()
+                            .to_string()
+                            .into();
+                    }
+                }
+            }
+            daemon::send_request(
+                remote,
+                "POST",
+                &format!("/vms/{}/start", vm_name()?),
+                Some(options),
+            )?;
+            Ok(0)
+        }
+        Some(parse_args::Command::Stop {
+            all: false,
+            force,
+            wait,
+            timeout,
+        }) => {
+            let response: serde_json::Value = daemon::send_request(
+                remote,
+                "POST",
+                &format!("/vms/{}/stop", vm_name()?),
+                Some(serde_json::to_value(daemon::StopRequest {
+                    force: *force,
+                    wait: *wait,
+                    timeout: *timeout,
+                })
+                .map_err(|e| e.to_string())?),
+            )?;
+            if *wait {
+                buffer.add_spacer();
+                buffer.addln(&format!(
+                    "VM '{}' {}.",
+                    response["image_name"].as_str().unwrap_or_default(),
+                    response["outcome"].as_str().unwrap_or_default()
+                ));
+            }
+            Ok(daemon::stop_outcome_exit_code(&response))
+        }
+        Some(parse_args::Command::Status) => {
+            let status: VmStatus = serde_json::from_value(daemon::send_request(
+                remote,
+                "GET",
+                &format!("/vms/{}", vm_name()?),
+                None,
+            )?)
+            .map_err(|e| format!("Invalid response from the daemon. {e}"))?;
+            print_vm_status(&status, buffer);
+            Ok(0)
+        }
+        Some(_) => Err(
+            "Only listing running VMs (-r), start, stop without --all, and status are available with --remote."
+                .to_owned(),
+        ),
+    }
+}
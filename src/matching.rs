@@ -0,0 +1,213 @@
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+/// How names given with `-i` are matched against the names of images and
+/// VMs.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub enum MatchMode {
+    /// Names containing the pattern match, and a name equal to it is
+    /// preferred over the others.
+    #[default]
+    Substring,
+    /// Only the name equal to the pattern matches.
+    Exact,
+    /// Names matching the pattern as a shell glob, e.g. `deb1?`, match.
+    Glob,
+    /// Names in which the pattern, a regular expression, finds a match.
+    Regex,
+}
+
+/// The match mode chosen with `--exact`, `--glob` or `--regex`. Unlike the
+/// config, it's a flag of the whole invocation, and lookups such as
+/// `QemuRunner::matches` run far from the parsed arguments. Before it's set,
+/// names match as substrings.
+static MATCH_MODE: OnceLock<MatchMode> = OnceLock::new();
+
+/// Whether an ambiguous name may be resolved by asking the user to pick a
+/// candidate. The daemon can't ask anyone, even when run in a terminal.
+static PICKER_ENABLED: AtomicBool = AtomicBool::new(true);
+
+pub fn set_match_mode(mode: MatchMode) {
+    let _ = MATCH_MODE.set(mode);
+}
+
+pub fn disable_picker() {
+    PICKER_ENABLED.store(false, Ordering::Relaxed);
+}
+
+fn get_match_mode() -> MatchMode {
+    MATCH_MODE.get().copied().unwrap_or_default()
+}
+
+pub fn check_pattern(pattern: &str) -> Result<(), String> {
+    //! Refuses a pattern which isn't valid in the match mode.
+    match get_match_mode() {
+        MatchMode::Glob => glob::Pattern::new(pattern)
+            .map(|_| ())
+            .map_err(|e| format!("'{pattern}' is not a valid glob. {e}")),
+        MatchMode::Regex => Regex::new(pattern)
+            .map(|_| ())
+            .map_err(|e| format!("'{pattern}' is not a valid regular expression. {e}")),
+        MatchMode::Substring | MatchMode::Exact => Ok(()),
+    }
+}
+
+fn matches_in_mode(mode: MatchMode, pattern: &str, name: &str) -> bool {
+    match mode {
+        MatchMode::Substring => name.contains(pattern),
+        MatchMode::Exact => name == pattern,
+        MatchMode::Glob => glob::Pattern::new(pattern).is_ok_and(|glob| glob.matches(name)),
+        MatchMode::Regex => Regex::new(pattern).is_ok_and(|regex| regex.is_match(name)),
+    }
+}
+
+pub fn matches(pattern: &str, name: &str) -> bool {
+    //! Returns `true` if `name` matches `pattern` in the match mode.
+    matches_in_mode(get_match_mode(), pattern, name)
+}
+
+fn is_fuzzy_match(query: &str, name: &str) -> bool {
+    //! Returns `true` if the characters of `query` appear in `name` in the
+    //! same order, e.g. `d12` in `debian12`.
+    let mut name_chars = name.chars();
+    query
+        .chars()
+        .all(|query_char| name_chars.any(|name_char| name_char == query_char))
+}
+
+fn pick<T>(
+    pattern: &str,
+    mut candidates: Vec<T>,
+    name: impl Fn(&T) -> String,
+    kind: &str,
+) -> Result<T, String> {
+    //! Asks the user on the terminal which of `candidates` they meant, by
+    //! number or by typing part of its name to narrow the list down.
+    let names: Vec<String> = candidates.iter().map(name).collect();
+    let mut shown: Vec<usize> = (0..names.len()).collect();
+    loop {
+        println!("'{pattern}' matches more than one {kind}:");
+        for (number, index) in shown.iter().enumerate() {
+            println!("  {}) {}", number + 1, names[*index]);
+        }
+        print!("Pick one by number, or type part of its name (nothing to cancel): ");
+        let _ = std::io::stdout().flush();
+        let mut answer: String = String::new();
+        if std::io::stdin().read_line(&mut answer).is_err() || answer.trim().is_empty() {
+            return Err(format!("No {kind} was picked."));
+        }
+        let answer: &str = answer.trim();
+        if let Ok(number) = answer.parse::<usize>() {
+            if (1..=shown.len()).contains(&number) {
+                return Ok(candidates.swap_remove(shown[number - 1]));
+            }
+        }
+        let narrowed: Vec<usize> = shown
+            .iter()
+            .copied()
+            .filter(|index| is_fuzzy_match(answer, &names[*index]))
+            .collect();
+        match narrowed.as_slice() {
+            [index] => return Ok(candidates.swap_remove(*index)),
+            [] => println!("Nothing listed matches '{answer}'."),
+            _ => shown = narrowed,
+        }
+    }
+}
+
+fn select_in_mode<T>(
+    mode: MatchMode,
+    interactive: bool,
+    pattern: &str,
+    candidates: Vec<T>,
+    name: impl Fn(&T) -> String,
+    kind: &str,
+) -> Result<T, String> {
+    let mut matching: Vec<T> = candidates
+        .into_iter()
+        .filter(|candidate| matches_in_mode(mode, pattern, &name(candidate)))
+        .collect();
+    if mode == MatchMode::Substring && matching.len() > 1 {
+        if let Some(index) = matching
+            .iter()
+            .position(|candidate| name(candidate) == pattern)
+        {
+            return Ok(matching.swap_remove(index));
+        }
+    }
+    match matching.len() {
+        1 => Ok(matching.remove(0)),
+        0 => Err(format!("No {kind} matches '{pattern}'.")),
+        _ if interactive => pick(pattern, matching, name, kind),
+        _ => Err(format!(
+            "'{pattern}' matches more than one {kind}: {}. Give a longer name, or use --exact, --glob or --regex.",
+            matching.iter().map(name).collect::<Vec<String>>().join(", ")
+        )),
+    }
+}
+
+pub fn select<T>(
+    pattern: &str,
+    candidates: Vec<T>,
+    name: impl Fn(&T) -> String,
+    kind: &str,
+) -> Result<T, String> {
+    //! Returns the only one of `candidates`, each called `name(candidate)`,
+    //! which matches `pattern` in the match mode. If several do, the user is
+    //! asked to pick one when run in a terminal. `kind` names what the
+    //! candidates are in errors, which list every candidate matching if
+    //! there are several.
+    let interactive: bool = PICKER_ENABLED.load(Ordering::Relaxed)
+        && std::io::stdin().is_terminal()
+        && std::io::stdout().is_terminal();
+    select_in_mode(
+        get_match_mode(),
+        interactive,
+        pattern,
+        candidates,
+        name,
+        kind,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_fuzzy_match, matches_in_mode, select_in_mode, MatchMode};
+
+    #[test]
+    fn test_select() {
+        let images: Vec<&str> = vec!["debian11", "debian12", "deb", "arch"];
+        let select = |mode: MatchMode, pattern: &str| {
+            select_in_mode(
+                mode,
+                false,
+                pattern,
+                images.clone(),
+                |name| name.to_string(),
+                "image",
+            )
+        };
+        assert_eq!(select(MatchMode::Substring, "12"), Ok("debian12"));
+        assert_eq!(select(MatchMode::Substring, "deb"), Ok("deb"));
+        assert_eq!(
+            select(MatchMode::Substring, "debian"),
+            Err("'debian' matches more than one image: debian11, debian12. Give a longer name, or use --exact, --glob or --regex.".to_owned())
+        );
+        assert_eq!(
+            select(MatchMode::Exact, "debian"),
+            Err("No image matches 'debian'.".to_owned())
+        );
+        assert_eq!(select(MatchMode::Glob, "*11"), Ok("debian11"));
+        assert!(select(MatchMode::Glob, "deb*").is_err());
+        assert_eq!(select(MatchMode::Regex, "^a"), Ok("arch"));
+        assert_eq!(select(MatchMode::Regex, "n1[2-9]$"), Ok("debian12"));
+        assert!(!matches_in_mode(MatchMode::Regex, "(", "arch"));
+
+        assert!(is_fuzzy_match("d12", "debian12"));
+        assert!(is_fuzzy_match("", "arch"));
+        assert!(!is_fuzzy_match("21", "debian12"));
+    }
+}
@@ -0,0 +1,309 @@
+use serde_json::Value;
+use std::fs;
+use std::io::{BufRead, Read, Write};
+use std::path::PathBuf;
+
+use crate::console::run_stty;
+use crate::qmp::QmpClient;
+use crate::MONITOR_HISTORY_FILE;
+
+/// The most commands kept in the monitor's history.
+const HISTORY_LENGTH: usize = 1000;
+
+/// A key pressed while editing a line.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Key {
+    Character(char),
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    /// `Ctrl-U`, which clears the line.
+    Clear,
+    /// `Ctrl-C`, which abandons the line.
+    Interrupt,
+    /// `Ctrl-D`, which exits on an empty line.
+    EndOfFile,
+    Enter,
+    Unknown,
+}
+
+/// What to do after a key was pressed.
+#[derive(Debug, PartialEq, Eq)]
+enum Edit {
+    Continue,
+    Submit(String),
+    Abandon,
+    Exit,
+}
+
+/// The line being edited, and the history it can be replaced from.
+struct LineEditor {
+    line: Vec<char>,
+    cursor: usize,
+    history: Vec<String>,
+    /// The position in `history` of the line shown, which is
+    /// `history.len()` for a new line.
+    history_position: usize,
+}
+
+impl LineEditor {
+    fn new(history: Vec<String>) -> Self {
+        Self {
+            line: vec![],
+            cursor: 0,
+            history_position: history.len(),
+            history,
+        }
+    }
+
+    fn show_history(&mut self, position: usize) {
+        //! Replaces the line with the command at `position` in the history,
+        //! or with an empty line past its end.
+        self.history_position = position;
+        self.line = self
+            .history
+            .get(position)
+            .map_or(vec![], |command| command.chars().collect());
+        self.cursor = self.line.len();
+    }
+
+    fn edit(&mut self, key: Key) -> Edit {
+        //! Applies a key press to the line.
+        match key {
+            Key::Character(character) => {
+                self.line.insert(self.cursor, character);
+                self.cursor += 1;
+            }
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+            }
+            Key::Delete if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+            }
+            Key::Left => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right => self.cursor = (self.cursor + 1).min(self.line.len()),
+            Key::Home => self.cursor = 0,
+            Key::End => self.cursor = self.line.len(),
+            Key::Up if self.history_position > 0 => self.show_history(self.history_position - 1),
+            Key::Down if self.history_position < self.history.len() => {
+                self.show_history(self.history_position + 1)
+            }
+            Key::Clear => {
+                self.line.clear();
+                self.cursor = 0;
+            }
+            Key::Interrupt => {
+                self.show_history(self.history.len());
+                return Edit::Abandon;
+            }
+            Key::EndOfFile if self.line.is_empty() => return Edit::Exit,
+            Key::Enter => {
+                let line: String = self.line.iter().collect::<String>().trim().to_owned();
+                if !line.is_empty() && self.history.last() != Some(&line) {
+                    self.history.push(line.clone());
+                }
+                self.show_history(self.history.len());
+                return Edit::Submit(line);
+            }
+            _ => (),
+        }
+        Edit::Continue
+    }
+}
+
+fn parse_keys(input: &[u8]) -> Vec<Key> {
+    //! Splits input read from a terminal in raw mode into key presses.
+    let text: String = String::from_utf8_lossy(input).to_string();
+    let mut characters = text.chars().peekable();
+    let mut keys: Vec<Key> = vec![];
+    while let Some(character) = characters.next() {
+        keys.push(match character {
+            '\r' | '\n' => Key::Enter,
+            '\x7f' | '\x08' => Key::Backspace,
+            '\x01' => Key::Home,
+            '\x05' => Key::End,
+            '\x15' => Key::Clear,
+            '\x03' => Key::Interrupt,
+            '\x04' => Key::EndOfFile,
+            '\x1b' if characters.next_if(|c| *c == '[' || *c == 'O').is_some() => {
+                let mut sequence: String = String::new();
+                for character in characters.by_ref() {
+                    sequence.push(character);
+                    if !character.is_ascii_digit() && character != ';' {
+                        break;
+                    }
+                }
+                match sequence.as_str() {
+                    "A" => Key::Up,
+                    "B" => Key::Down,
+                    "C" => Key::Right,
+                    "D" => Key::Left,
+                    "H" | "1~" => Key::Home,
+                    "F" | "4~" => Key::End,
+                    "3~" => Key::Delete,
+                    _ => Key::Unknown,
+                }
+            }
+            character if character.is_control() => Key::Unknown,
+            character => Key::Character(character),
+        });
+    }
+    keys
+}
+
+fn get_history_path() -> PathBuf {
+    //! Returns the path of the monitor's history file.
+    PathBuf::from(shellexpand::tilde(MONITOR_HISTORY_FILE).to_string())
+}
+
+fn read_history() -> Vec<String> {
+    //! Returns the commands run in earlier monitor sessions.
+    fs::read_to_string(get_history_path())
+        .map(|history| history.lines().map(|line| line.to_owned()).collect())
+        .unwrap_or_default()
+}
+
+fn write_history(history: &[String]) {
+    //! Saves the most recent commands for later monitor sessions.
+    let start: usize = history.len().saturating_sub(HISTORY_LENGTH);
+    let _ = fs::write(get_history_path(), history[start..].join("\n") + "\n");
+}
+
+fn run_monitor_command(qmp: &mut QmpClient, command: &str) -> String {
+    //! Runs a command, returning its output. Commands starting with `{` are
+    //! QMP commands, e.g. `{"execute": "query-status"}`, and the rest are
+    //! human monitor (HMP) commands, e.g. `info status`.
+    let result: Result<String, String> = if command.starts_with('{') {
+        serde_json::from_str::<Value>(command)
+            .map_err(|e| format!("Invalid QMP command. {e}"))
+            .and_then(|command| {
+                let name: &str = command["execute"]
+                    .as_str()
+                    .ok_or("QMP commands must name what to 'execute'.")?;
+                qmp.execute(name, command.get("arguments").cloned())
+            })
+            .map(|output| serde_json::to_string_pretty(&output).unwrap_or_default())
+    } else {
+        qmp.human_monitor_command(command)
+    };
+    match result {
+        Ok(output) => output.trim_end().to_owned(),
+        Err(e) => e,
+    }
+}
+
+pub fn run_monitor(vm_name: &str, qmp: &mut QmpClient) -> Result<(), String> {
+    //! Runs an interactive session against the monitor of a VM, with line
+    //! editing and a history kept across sessions. If stdin isn't a
+    //! terminal, commands are read from it line by line instead.
+    let saved_terminal_state: String = match run_stty(&["-g"]) {
+        Ok(state) => state,
+        Err(_) => {
+            for command in std::io::stdin().lock().lines() {
+                let command: String = command.map_err(|e| e.to_string())?;
+                if !command.trim().is_empty() {
+                    println!("{}", run_monitor_command(qmp, command.trim()));
+                }
+            }
+            return Ok(());
+        }
+    };
+    eprintln!("Connected to the monitor of '{vm_name}'. Type 'help' for HMP commands, or a QMP command such as '{{\"execute\": \"query-status\"}}'. Press Ctrl-D to exit.");
+    let prompt: String = format!("({vm_name}) ");
+    let mut editor: LineEditor = LineEditor::new(read_history());
+    let mut stdout = std::io::stdout();
+    let mut stdin = std::io::stdin();
+    let mut buffer: [u8; 1024] = [0; 1024];
+    run_stty(&["raw", "-echo"])?;
+
+    let result: Result<(), String> = 'session: loop {
+        let remaining: usize = editor.line.len() - editor.cursor;
+        let _ = write!(
+            stdout,
+            "\r\x1b[K{prompt}{}{}",
+            editor.line.iter().collect::<String>(),
+            if remaining > 0 {
+                format!("\x1b[{remaining}D")
+            } else {
+                String::new()
+            }
+        );
+        let _ = stdout.flush();
+        let read: usize = match stdin.read(&mut buffer) {
+            Ok(0) => break Ok(()),
+            Ok(read) => read,
+            Err(e) => break Err(format!("Unable to read from the terminal. {e}")),
+        };
+        for key in parse_keys(&buffer[..read]) {
+            match editor.edit(key) {
+                Edit::Continue => (),
+                Edit::Abandon => {
+                    let _ = write!(stdout, "^C\r\n");
+                }
+                Edit::Exit => break 'session Ok(()),
+                Edit::Submit(command) => {
+                    let _ = write!(stdout, "\r\n");
+                    if command == "quit" || command == "q" {
+                        let _ = write!(
+                            stdout,
+                            "Not passing 'quit' on, as it would kill the VM; use 'vm-manager stop --force'.\r\n"
+                        );
+                    } else if !command.is_empty() {
+                        let output: String = run_monitor_command(qmp, &command);
+                        if !output.is_empty() {
+                            let _ = write!(stdout, "{}\r\n", output.replace('\n', "\r\n"));
+                        }
+                    }
+                }
+            }
+        }
+    };
+
+    run_stty(&[&saved_terminal_state])?;
+    println!();
+    write_history(&editor.history);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_keys, Edit, Key, LineEditor};
+
+    #[test]
+    fn test_line_editor() {
+        assert_eq!(
+            parse_keys(b"ab\x1b[D\x1b[3~\x7f\r"),
+            vec![
+                Key::Character('a'),
+                Key::Character('b'),
+                Key::Left,
+                Key::Delete,
+                Key::Backspace,
+                Key::Enter,
+            ]
+        );
+
+        let mut editor: LineEditor = LineEditor::new(vec!["info status".to_owned()]);
+        for key in parse_keys(b"info kvmx\x1b[D\x1b[3~") {
+            assert_eq!(editor.edit(key), Edit::Continue);
+        }
+        assert_eq!(editor.edit(Key::Enter), Edit::Submit("info kvm".to_owned()));
+        assert_eq!(editor.edit(Key::Up), Edit::Continue);
+        assert_eq!(editor.edit(Key::Up), Edit::Continue);
+        assert_eq!(
+            editor.edit(Key::Enter),
+            Edit::Submit("info status".to_owned())
+        );
+        assert_eq!(
+            editor.history,
+            vec!["info status", "info kvm", "info status"]
+        );
+        assert_eq!(editor.edit(Key::EndOfFile), Edit::Exit);
+    }
+}
@@ -0,0 +1,95 @@
+use std::path::PathBuf;
+
+use crate::config::NetbootConfig;
+
+/// Network backends a `-nic` option may name before its other properties.
+const NIC_BACKENDS: &[&str] = &[
+    "user",
+    "tap",
+    "bridge",
+    "socket",
+    "vde",
+    "netmap",
+    "l2tpv3",
+    "vhost-user",
+    "vmnet-host",
+    "vmnet-shared",
+    "vmnet-bridged",
+    "af-xdp",
+    "stream",
+    "dgram",
+    "none",
+];
+
+pub fn is_user_nic(nic: &str) -> bool {
+    //! Returns `true` if the arguments of a `-nic` option select user-mode
+    //! networking, which is qemu's default when no backend is named.
+    match nic.split(',').next() {
+        Some(backend) if NIC_BACKENDS.contains(&backend) => backend == "user",
+        _ => true,
+    }
+}
+
+pub fn user_net_properties(netboot: &NetbootConfig) -> Result<Vec<String>, String> {
+    //! Returns the user-mode networking properties serving `netboot.tftp`
+    //! over the built-in TFTP server, and offering `netboot.bootfile` to the
+    //! guest's PXE firmware via DHCP. The bootfile may also be a URL, which
+    //! iPXE will fetch over HTTP.
+    let mut properties: Vec<String> = vec![];
+    if let Some(tftp) = &netboot.tftp {
+        let tftp: PathBuf = PathBuf::from(shellexpand::tilde(tftp).to_string());
+        if !tftp.is_dir() {
+            return Err(format!(
+                "TFTP directory '{}' does not exist.",
+                tftp.display()
+            ));
+        }
+        properties.push(format!("tftp={}", tftp.display()));
+    }
+    if let Some(bootfile) = &netboot.bootfile {
+        properties.push(format!("bootfile={bootfile}"));
+    }
+    Ok(properties)
+}
+
+pub fn add_nic_properties(nic: &str, properties: &[String]) -> String {
+    //! Appends properties to the arguments of a `-nic` option.
+    if properties.is_empty() {
+        nic.to_owned()
+    } else if nic.is_empty() {
+        properties.join(",")
+    } else {
+        format!("{nic},{}", properties.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_nic_properties, is_user_nic};
+
+    #[test]
+    fn test_is_user_nic() {
+        assert!(is_user_nic("user,model=virtio"));
+        assert!(is_user_nic("hostfwd=tcp::5555-:22"));
+        assert!(is_user_nic(""));
+        assert!(!is_user_nic("bridge,br=br0"));
+        assert!(!is_user_nic("tap,ifname=tap0"));
+    }
+
+    #[test]
+    fn test_add_nic_properties() {
+        let properties: Vec<String> = vec![
+            "tftp=/srv/tftp".to_owned(),
+            "bootfile=pxelinux.0".to_owned(),
+        ];
+        assert_eq!(
+            add_nic_properties("user,model=virtio", &properties),
+            "user,model=virtio,tftp=/srv/tftp,bootfile=pxelinux.0"
+        );
+        assert_eq!(
+            add_nic_properties("", &properties),
+            "tftp=/srv/tftp,bootfile=pxelinux.0"
+        );
+        assert_eq!(add_nic_properties("user", &[]), "user");
+    }
+}
@@ -0,0 +1,1002 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::config::{NetworkConfig, NetworkMode, PrivateNetwork, QemuRunOption};
+use crate::netboot::{add_nic_properties, is_user_nic};
+use crate::state::ForwardedPort;
+use crate::utils::{find_in_path, run_shell_command};
+use crate::MAC_ADDRESS_DIRECTORY;
+
+/// Where distributions install `qemu-bridge-helper`.
+const BRIDGE_HELPER_LOCATIONS: &[&str] = &[
+    "/usr/lib/qemu/qemu-bridge-helper",
+    "/usr/libexec/qemu-bridge-helper",
+    "/usr/lib/qemu-bridge-helper",
+];
+/// The file listing which bridges `qemu-bridge-helper` lets users attach to.
+const BRIDGE_CONF: &str = "/etc/qemu/bridge.conf";
+/// Where the kernel lists the host's network interfaces.
+const SYSFS_NET: &str = "/sys/class/net";
+/// NIC model used when the VM's options don't name one.
+const DEFAULT_NIC_MODEL: &str = "virtio-net-pci";
+/// The device tap devices are created through.
+const TUN_DEVICE: &str = "/dev/net/tun";
+/// The capability needed to create and configure network devices.
+const CAP_NET_ADMIN: u32 = 12;
+/// File descriptor a macvtap device is handed to qemu on.
+const MACVTAP_FD: usize = 3;
+/// How long to wait for udev to create the device node of a macvtap.
+const MACVTAP_DEVICE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Port private networks are carried over, unless their `multicast` is set.
+const PRIVATE_NETWORK_PORT: u16 = 1234;
+/// The IPv4 network of user-mode networking, unless `ipv4_prefix` is set.
+const DEFAULT_IPV4_PREFIX: &str = "10.0.2.0/24";
+/// Where the kernel lists the addresses its neighbours were seen with.
+const PROC_NET_ARP: &str = "/proc/net/arp";
+/// Where dnsmasq commonly keeps its DHCP leases, which list the addresses
+/// handed to bridged guests by a DHCP server on the host.
+const DHCP_LEASE_FILES: &[&str] = &[
+    "/var/lib/misc/dnsmasq.leases",
+    "/var/lib/dnsmasq/dnsmasq.leases",
+    "/var/lib/libvirt/dnsmasq/default.leases",
+];
+
+pub fn find_bridge_helper() -> Option<PathBuf> {
+    //! Returns the path of `qemu-bridge-helper`, if it is installed.
+    BRIDGE_HELPER_LOCATIONS
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.is_file())
+}
+
+fn is_bridge_allowed(conf_path: &Path, bridge: &str) -> bool {
+    //! Returns `true` if a `qemu-bridge-helper` ACL file, or a file it
+    //! includes, allows attaching to `bridge`. As with the helper itself,
+    //! a `deny` rule wins over any `allow` rule.
+    let mut allowed: bool = false;
+    let contents: String = fs::read_to_string(conf_path).unwrap_or_default();
+    for line in contents.lines().map(|line| line.trim()) {
+        match line.split_once(char::is_whitespace) {
+            Some(("allow", name)) if [bridge, "all"].contains(&name.trim()) => allowed = true,
+            Some(("deny", name)) if [bridge, "all"].contains(&name.trim()) => return false,
+            Some(("include", path)) => {
+                allowed |= is_bridge_allowed(Path::new(path.trim()), bridge);
+            }
+            _ => {}
+        }
+    }
+    allowed
+}
+
+pub fn nic_model(options: &[QemuRunOption]) -> String {
+    //! Returns the NIC model named by the VM's `-nic` option, if it has one,
+    //! so that switching network modes keeps the guest's NIC the same.
+    options
+        .iter()
+        .filter(|option| option.flag() == "-nic")
+        .flat_map(|option| option.arguments().split(','))
+        .find_map(|property| property.strip_prefix("model="))
+        .unwrap_or(DEFAULT_NIC_MODEL)
+        .to_owned()
+}
+
+pub fn update_user_nics(
+    options: &mut Vec<QemuRunOption>,
+    update: impl Fn(&str) -> String,
+    needed_by: &str,
+) -> Result<(), String> {
+    //! Replaces the arguments of each user-mode `-nic` option with `update`
+    //! applied to them. A VM with no `-nic` option gets a user-mode one
+    //! first, while a VM with only other kinds of NIC can't be updated, so
+    //! the error names what the update was `needed_by`.
+    if !options.iter().any(|option| option.flag() == "-nic") {
+        options.push(QemuRunOption::new("-nic user"));
+    }
+    let mut has_user_nic: bool = false;
+    for option in options.iter_mut() {
+        if option.flag() == "-nic" && is_user_nic(option.arguments()) {
+            has_user_nic = true;
+            *option = QemuRunOption::new(&format!("-nic {}", update(option.arguments())));
+        }
+    }
+    if has_user_nic {
+        Ok(())
+    } else {
+        Err(format!("{needed_by} requires user-mode networking."))
+    }
+}
+
+fn parse_ipv4_prefix(prefix: &str) -> Result<(Ipv4Addr, u32), String> {
+    //! Returns the address and length of an IPv4 prefix such as
+    //! `10.0.2.0/24`. The network must hold at least the host, DNS server
+    //! and a guest, so prefixes longer than 29 bits are refused.
+    prefix
+        .split_once('/')
+        .and_then(|(address, length)| {
+            Some((
+                address.parse::<Ipv4Addr>().ok()?,
+                length.parse::<u32>().ok().filter(|length| *length <= 29)?,
+            ))
+        })
+        .ok_or(format!(
+            "Invalid IPv4 prefix '{prefix}'. Use the form '10.0.2.0/24', with a length of at most 29."
+        ))
+}
+
+fn is_in_ipv4_prefix(address: Ipv4Addr, (network, length): (Ipv4Addr, u32)) -> bool {
+    //! Returns `true` if `address` is within the IPv4 prefix.
+    let mask: u32 = u32::MAX.checked_shl(32 - length).unwrap_or(0);
+    u32::from(address) & mask == u32::from(network) & mask
+}
+
+pub fn user_nic_properties(network: Option<&NetworkConfig>) -> Result<Vec<String>, String> {
+    //! Returns the user-mode networking properties set by the VM's `network`
+    //! section, such as its IPv4 and IPv6 addressing and DNS servers. An
+    //! isolated network is user-mode networking cut off from the host and
+    //! the outside world, other than through its port forwards.
+    let network: &NetworkConfig = match network {
+        Some(network) if [NetworkMode::User, NetworkMode::Isolated].contains(&network.mode) => {
+            network
+        }
+        _ => return Ok(vec![]),
+    };
+    let mut properties: Vec<String> = vec![];
+    if network.mode == NetworkMode::Isolated {
+        properties.push("restrict=on".to_owned());
+    }
+    let prefix: (Ipv4Addr, u32) = parse_ipv4_prefix(
+        network
+            .ipv4_prefix
+            .as_deref()
+            .unwrap_or(DEFAULT_IPV4_PREFIX),
+    )?;
+    if let Some(ipv4_prefix) = &network.ipv4_prefix {
+        properties.push(format!("net={ipv4_prefix}"));
+    }
+    for (setting, property, address) in [
+        ("ipv4_host", "host", network.ipv4_host),
+        ("ipv4_dns", "dns", network.ipv4_dns),
+        ("dhcp_start", "dhcpstart", network.dhcp_start),
+    ] {
+        if let Some(address) = address {
+            if !is_in_ipv4_prefix(address, prefix) {
+                return Err(format!(
+                    "'{setting}' {address} is not within the network's IPv4 prefix {}/{}.",
+                    prefix.0, prefix.1
+                ));
+            }
+            properties.push(format!("{property}={address}"));
+        }
+    }
+    if let Some(hostname) = &network.hostname {
+        let is_valid: bool = !hostname.is_empty()
+            && hostname
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+        if !is_valid {
+            return Err(format!(
+                "Invalid hostname '{hostname}'. Hostnames may only contain letters, digits, '-' and '.'."
+            ));
+        }
+        properties.push(format!("hostname={hostname}"));
+    }
+    if network.ipv6 == Some(false) {
+        if network.ipv6_prefix.is_some() || network.ipv6_dns.is_some() {
+            return Err("'ipv6_prefix' and 'ipv6_dns' can't be set with 'ipv6: false'.".to_owned());
+        }
+        properties.push("ipv6=off".to_owned());
+    } else if network.ipv6 == Some(true) {
+        properties.push("ipv6=on".to_owned());
+    }
+    if let Some(prefix) = &network.ipv6_prefix {
+        let is_valid: bool = prefix.split_once('/').is_some_and(|(address, length)| {
+            address.parse::<Ipv6Addr>().is_ok()
+                && length.parse::<u8>().is_ok_and(|length| length <= 128)
+        });
+        if !is_valid {
+            return Err(format!(
+                "Invalid IPv6 prefix '{prefix}'. Use the form 'fd00::/64'."
+            ));
+        }
+        properties.push(format!("ipv6-net={prefix}"));
+    }
+    if let Some(dns) = &network.ipv6_dns {
+        properties.push(format!("ipv6-dns={dns}"));
+    }
+    Ok(properties)
+}
+
+pub fn publish_ports(nic: &str, rules: &[String]) -> String {
+    //! Adds `hostfwd=` rules to the arguments of a user-mode `-nic` option.
+    //! Existing rules forwarding the same host port or guest port over the
+    //! same protocol are replaced.
+    let forwards: Vec<ForwardedPort> = rules
+        .iter()
+        .filter_map(|rule| rule.strip_prefix("hostfwd="))
+        .filter_map(ForwardedPort::parse)
+        .collect();
+    let conflicts = |property: &str| {
+        property
+            .strip_prefix("hostfwd=")
+            .and_then(ForwardedPort::parse)
+            .is_some_and(|existing| {
+                forwards.iter().any(|forward| {
+                    forward.protocol == existing.protocol
+                        && (forward.host_port == existing.host_port
+                            || forward.guest_port == existing.guest_port)
+                })
+            })
+    };
+    let mut properties: Vec<&str> = nic
+        .split(',')
+        .filter(|property| !property.is_empty() && !conflicts(property))
+        .collect();
+    properties.extend(rules.iter().map(|rule| rule.as_str()));
+    properties.join(",")
+}
+
+fn bridge_nic(bridge: Option<&str>, model: &str) -> Result<String, String> {
+    //! Checks that the host is set up for the VM to attach to `bridge`
+    //! through `qemu-bridge-helper`, and returns the `-nic` arguments doing
+    //! so.
+    let bridge: &str =
+        bridge.ok_or("'network: bridge' needs the name of the bridge, e.g. 'bridge: br0'.")?;
+    if !Path::new(SYSFS_NET).join(bridge).join("bridge").is_dir() {
+        return Err(format!(
+            "There is no bridge '{bridge}' on this host. Create it, e.g. with 'nmcli connection add type bridge ifname {bridge}', and add the host's LAN interface to it."
+        ));
+    }
+    let helper: PathBuf = find_bridge_helper().ok_or(
+        "Unable to find 'qemu-bridge-helper', which bridged networking needs. It usually comes with qemu (e.g. the 'qemu-system-common' package).",
+    )?;
+    let is_setuid: bool =
+        fs::metadata(&helper).is_ok_and(|metadata| metadata.permissions().mode() & 0o4000 != 0);
+    if !is_setuid {
+        return Err(format!(
+            "'{}' must be setuid root to attach VMs to bridges. Run 'sudo chmod u+s {}'.",
+            helper.display(),
+            helper.display()
+        ));
+    }
+    if !is_bridge_allowed(Path::new(BRIDGE_CONF), bridge) {
+        return Err(format!(
+            "'qemu-bridge-helper' does not allow attaching to '{bridge}'. Add the line 'allow {bridge}' to '{BRIDGE_CONF}'."
+        ));
+    }
+    Ok(format!(
+        "bridge,br={bridge},helper={},model={model}",
+        helper.display()
+    ))
+}
+
+fn read_process_status(field: &str) -> Option<String> {
+    //! Returns a field of `/proc/self/status`, such as `Uid` or `CapEff`.
+    fs::read_to_string("/proc/self/status")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix(&format!("{field}:")))
+        .map(|value| value.trim().to_owned())
+}
+
+fn get_user_id() -> String {
+    //! Returns the real user ID of this process.
+    read_process_status("Uid")
+        .and_then(|uid| uid.split_whitespace().next().map(|uid| uid.to_owned()))
+        .unwrap_or_else(|| "0".to_owned())
+}
+
+fn get_privileged_prefix(device: &str) -> Result<Vec<&'static str>, String> {
+    //! Returns the prefix needed to run `ip` with `CAP_NET_ADMIN`: nothing if
+    //! this process already has it, or `sudo -n` if the user may use sudo
+    //! without a password.
+    let capabilities: u64 = read_process_status("CapEff")
+        .and_then(|capabilities| u64::from_str_radix(&capabilities, 16).ok())
+        .unwrap_or(0);
+    if capabilities & (1 << CAP_NET_ADMIN) != 0 {
+        return Ok(vec![]);
+    }
+    if run_shell_command(&["sudo", "-n", "true"]).is_ok_and(|output| output.status.success()) {
+        return Ok(vec!["sudo", "-n"]);
+    }
+    Err(format!(
+        "Creating network device '{device}' needs the CAP_NET_ADMIN capability. Run vm-manager as root, or allow your user to run 'ip' with sudo without a password."
+    ))
+}
+
+fn run_privileged(prefix: &[&str], command: &[&str]) -> Result<(), String> {
+    //! Runs a command with the given privilege prefix, failing if it does.
+    let mut args: Vec<&str> = prefix.to_vec();
+    args.extend_from_slice(command);
+    let output: Output = run_shell_command(&args)?;
+    if !output.status.success() {
+        return Err(format!(
+            "'{}' failed. {}",
+            command.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+fn hash_vm_name(vm_name: &str) -> u64 {
+    //! Returns a hash of a VM's name, from which the names and addresses of
+    //! its network devices are derived.
+    let mut hasher: DefaultHasher = DefaultHasher::new();
+    vm_name.hash(&mut hasher);
+    hasher.finish()
+}
+
+pub fn get_network_device_name(vm_name: &str, network: &NetworkConfig) -> String {
+    //! Returns the name of the tap or macvtap device of a VM: its
+    //! `interface` setting, or one derived from the VM's name, as interface
+    //! names are limited to 15 characters.
+    network
+        .interface
+        .clone()
+        .unwrap_or_else(|| format!("vm{:08x}", hash_vm_name(vm_name) as u32))
+}
+
+pub fn parse_mac_address(address: &str) -> Result<String, String> {
+    //! Returns a MAC address in the lowercase, colon separated form qemu and
+    //! `ip` use. Multicast addresses can't be given to a NIC.
+    let octets: Vec<u8> = address
+        .trim()
+        .split([':', '-'])
+        .map(|octet| match octet.len() {
+            2 => u8::from_str_radix(octet, 16).ok(),
+            _ => None,
+        })
+        .collect::<Option<Vec<u8>>>()
+        .filter(|octets| octets.len() == 6 && octets[0] & 1 == 0)
+        .ok_or(format!(
+            "Invalid MAC address '{address}'. Use six hexadecimal octets, e.g. '52:54:00:12:34:56', with an even first octet."
+        ))?;
+    Ok(octets
+        .iter()
+        .map(|octet| format!("{octet:02x}"))
+        .collect::<Vec<String>>()
+        .join(":"))
+}
+
+fn get_mac_address_path(vm_name: &str) -> PathBuf {
+    //! Returns the path of the file holding the generated MAC address of a
+    //! VM.
+    PathBuf::from(shellexpand::tilde(&format!("{MAC_ADDRESS_DIRECTORY}/{vm_name}")).to_string())
+}
+
+fn generate_mac_address() -> Result<String, String> {
+    //! Returns a random MAC address in qemu's `52:54:00` range.
+    let mut octets: [u8; 3] = [0; 3];
+    File::open("/dev/urandom")
+        .and_then(|mut random| random.read_exact(&mut octets))
+        .map_err(|e| format!("Unable to generate a MAC address. {e}"))?;
+    Ok(format!(
+        "52:54:00:{:02x}:{:02x}:{:02x}",
+        octets[0], octets[1], octets[2]
+    ))
+}
+
+pub fn get_mac_address(vm_name: &str, network: Option<&NetworkConfig>) -> Result<String, String> {
+    //! Returns the MAC address of a VM's NIC: its `mac_address` setting, or
+    //! the one generated when the VM was first started, so that the guest's
+    //! DHCP leases and static assignments survive restarts.
+    if let Some(address) = network.and_then(|network| network.mac_address.as_deref()) {
+        return parse_mac_address(address);
+    }
+    let path: PathBuf = get_mac_address_path(vm_name);
+    if let Ok(address) = fs::read_to_string(&path) {
+        if let Ok(address) = parse_mac_address(&address) {
+            return Ok(address);
+        }
+    }
+    let address: String = generate_mac_address()?;
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory).map_err(|e| {
+            format!(
+                "Unable to create MAC address directory '{}'. {e}",
+                directory.display()
+            )
+        })?;
+    }
+    fs::write(&path, format!("{address}\n"))
+        .map_err(|e| format!("Unable to write '{}'. {e}", path.display()))?;
+    Ok(address)
+}
+
+pub fn set_nic_mac_address(options: &mut [QemuRunOption], address: &str) {
+    //! Gives the VM's first NIC the MAC address, unless its `-nic` option
+    //! already sets one. Without a `-nic` option, qemu's default NIC is
+    //! left as it is.
+    if let Some(option) = options.iter_mut().find(|option| option.flag() == "-nic") {
+        let has_mac: bool = option
+            .arguments()
+            .split(',')
+            .any(|property| property.starts_with("mac="));
+        if !has_mac {
+            *option = QemuRunOption::new(&format!(
+                "-nic {}",
+                add_nic_properties(option.arguments(), &[format!("mac={address}")])
+            ));
+        }
+    }
+}
+
+fn is_network_device(device: &str) -> bool {
+    //! Returns `true` if the host has a network interface named `device`.
+    Path::new(SYSFS_NET).join(device).exists()
+}
+
+fn check_network_device_setup(vm_name: &str, network: &NetworkConfig) -> Result<(), String> {
+    //! Checks that a VM's tap or macvtap device can be created on start.
+    let device: String = get_network_device_name(vm_name, network);
+    if device.is_empty() || device.len() > 15 || device.contains(['/', ' ', ',']) {
+        return Err(format!(
+            "Invalid interface name '{device}'. Interface names must be 1 to 15 characters long, and may not contain '/', ' ' or ','."
+        ));
+    }
+    if find_in_path("ip").is_none() {
+        return Err(
+            "Unable to find 'ip', which tap and macvtap networking need. Install 'iproute2'."
+                .to_owned(),
+        );
+    }
+    match network.mode {
+        NetworkMode::Tap => {
+            if !Path::new(TUN_DEVICE).exists() {
+                return Err(format!(
+                    "'{TUN_DEVICE}' does not exist. Load the 'tun' module with 'modprobe tun'."
+                ));
+            }
+            if let Some(bridge) = &network.bridge {
+                if !Path::new(SYSFS_NET).join(bridge).join("bridge").is_dir() {
+                    return Err(format!("There is no bridge '{bridge}' on this host."));
+                }
+            }
+        }
+        NetworkMode::Macvtap => {
+            let parent: &str = network.parent.as_deref().ok_or(
+                "'network: macvtap' needs the host interface to attach to, e.g. 'parent: eth0'.",
+            )?;
+            if !is_network_device(parent) {
+                return Err(format!(
+                    "There is no network interface '{parent}' on this host."
+                ));
+            }
+        }
+        _ => {}
+    }
+    get_privileged_prefix(&device)?;
+    Ok(())
+}
+
+pub fn create_network_device(
+    vm_name: &str,
+    network: &NetworkConfig,
+    mac_address: &str,
+) -> Result<(), String> {
+    //! Creates and brings up the tap or macvtap device of a VM. A tap device
+    //! is owned by the current user, so that qemu may open it, and added to
+    //! `bridge` if set. A macvtap device gets the guest's MAC address. A
+    //! device left behind by a VM which was killed is replaced.
+    if ![NetworkMode::Tap, NetworkMode::Macvtap].contains(&network.mode) {
+        return Ok(());
+    }
+    let device: String = get_network_device_name(vm_name, network);
+    let prefix: Vec<&str> = get_privileged_prefix(&device)?;
+    if is_network_device(&device) {
+        run_privileged(&prefix, &["ip", "link", "delete", &device])?;
+    }
+
+    let user_id: String = get_user_id();
+    if network.mode == NetworkMode::Tap {
+        run_privileged(
+            &prefix,
+            &[
+                "ip", "tuntap", "add", "dev", &device, "mode", "tap", "user", &user_id,
+            ],
+        )?;
+        if let Some(bridge) = &network.bridge {
+            run_privileged(&prefix, &["ip", "link", "set", &device, "master", bridge])
+                .inspect_err(|_| delete_network_device(vm_name, network))?;
+        }
+    } else {
+        let parent: &str = network.parent.as_deref().unwrap_or_default();
+        run_privileged(
+            &prefix,
+            &[
+                "ip",
+                "link",
+                "add",
+                "link",
+                parent,
+                "name",
+                &device,
+                "address",
+                mac_address,
+                "type",
+                "macvtap",
+                "mode",
+                "bridge",
+            ],
+        )?;
+    }
+    run_privileged(&prefix, &["ip", "link", "set", &device, "up"])
+        .inspect_err(|_| delete_network_device(vm_name, network))?;
+
+    if network.mode == NetworkMode::Macvtap {
+        // the device node is created by udev, and owned by root.
+        let node: PathBuf = get_macvtap_device_path(&device)
+            .inspect_err(|_| delete_network_device(vm_name, network))?;
+        let started_waiting: Instant = Instant::now();
+        while !node.exists() {
+            if started_waiting.elapsed() > MACVTAP_DEVICE_TIMEOUT {
+                delete_network_device(vm_name, network);
+                return Err(format!("'{}' was not created.", node.display()));
+            }
+            sleep(Duration::from_millis(100));
+        }
+        if user_id != "0" {
+            run_privileged(&prefix, &["chown", &user_id, &node.display().to_string()])
+                .inspect_err(|_| delete_network_device(vm_name, network))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn delete_network_device(vm_name: &str, network: &NetworkConfig) {
+    //! Deletes the tap or macvtap device of a VM, if it still exists.
+    if ![NetworkMode::Tap, NetworkMode::Macvtap].contains(&network.mode) {
+        return;
+    }
+    let device: String = get_network_device_name(vm_name, network);
+    if !is_network_device(&device) {
+        return;
+    }
+    let result: Result<(), String> = get_privileged_prefix(&device)
+        .and_then(|prefix| run_privileged(&prefix, &["ip", "link", "delete", &device]));
+    if let Err(e) = result {
+        eprintln!("Unable to delete network device '{device}' of VM '{vm_name}'. {e}");
+    }
+}
+
+fn get_macvtap_device_path(device: &str) -> Result<PathBuf, String> {
+    //! Returns the character device qemu reads and writes a macvtap's
+    //! packets through, `/dev/tap<ifindex>`.
+    let ifindex: String = fs::read_to_string(Path::new(SYSFS_NET).join(device).join("ifindex"))
+        .map_err(|e| format!("Unable to read the interface index of '{device}'. {e}"))?;
+    Ok(PathBuf::from(format!("/dev/tap{}", ifindex.trim())))
+}
+
+pub fn get_launch_prefix(
+    vm_name: &str,
+    network: Option<&NetworkConfig>,
+) -> Result<Vec<String>, String> {
+    //! Returns the command qemu must be run through for its network to
+    //! work. qemu can't open a macvtap device by name, so a shell opens it
+    //! on the file descriptor qemu is told to use, and then runs qemu.
+    match network {
+        Some(network) if network.mode == NetworkMode::Macvtap => Ok(vec![
+            "sh".to_owned(),
+            "-c".to_owned(),
+            format!("exec \"$@\" {MACVTAP_FD}<>\"$0\""),
+            get_macvtap_device_path(&get_network_device_name(vm_name, network))?
+                .display()
+                .to_string(),
+        ]),
+        _ => Ok(vec![]),
+    }
+}
+
+fn get_multicast_address(network: &PrivateNetwork) -> Result<SocketAddrV4, String> {
+    //! Returns the multicast group and port a private network is carried
+    //! over: its `multicast` setting, or a group in `230.0.0.0/8` derived
+    //! from its name.
+    match &network.multicast {
+        Some(multicast) => multicast
+            .parse::<SocketAddrV4>()
+            .ok()
+            .filter(|address| address.ip().is_multicast())
+            .ok_or(format!(
+                "Invalid multicast address '{multicast}' of network '{}'. Use the form '230.0.0.1:1234'.",
+                network.name
+            )),
+        None => {
+            let hash: [u8; 8] = hash_vm_name(&network.name).to_be_bytes();
+            Ok(SocketAddrV4::new(
+                Ipv4Addr::new(230, hash[0], hash[1], hash[2]),
+                PRIVATE_NETWORK_PORT,
+            ))
+        }
+    }
+}
+
+pub fn private_network_nics(
+    vm_name: &str,
+    networks: &[&PrivateNetwork],
+    options: &[QemuRunOption],
+) -> Result<Vec<String>, String> {
+    //! Returns the `-nic` arguments joining the VM to each private network.
+    //! Each NIC gets a MAC address derived from the VM's and network's
+    //! names, so the guest sees the same NIC on every start.
+    let model: String = nic_model(options);
+    let mut nics: Vec<String> = vec![];
+    for network in networks {
+        let hash: [u8; 8] = hash_vm_name(&format!("{vm_name}/{}", network.name)).to_be_bytes();
+        nics.push(format!(
+            "socket,mcast={},localaddr=127.0.0.1,model={model},mac=52:54:00:{:02x}:{:02x}:{:02x}",
+            get_multicast_address(network)?,
+            hash[0],
+            hash[1],
+            hash[2]
+        ));
+    }
+    Ok(nics)
+}
+
+fn check_isolated_options(options: &[QemuRunOption]) -> Result<(), String> {
+    //! Checks that the VM's options only give it user-mode NICs, which an
+    //! isolated network restricts, as any other network would let the guest
+    //! out.
+    for option in options {
+        let is_allowed: bool = match option.flag() {
+            "-nic" => is_user_nic(option.arguments()) || option.arguments() == "none",
+            "-net" | "-netdev" => false,
+            _ => true,
+        };
+        if !is_allowed {
+            return Err(format!(
+                "'network: isolated' only allows user-mode '-nic' options, but the VM has '{}'.",
+                option.as_str()
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub fn network_nic(
+    vm_name: &str,
+    network: Option<&NetworkConfig>,
+    options: &[QemuRunOption],
+    mac_address: &str,
+) -> Result<Option<String>, String> {
+    //! Returns the `-nic` arguments connecting the VM as its `network`
+    //! setting asks, or `None` for user-mode networking, which keeps the
+    //! VM's own `-nic` options and port mappings.
+    let network: &NetworkConfig = match network {
+        Some(network) => network,
+        None => return Ok(None),
+    };
+    let model: String = nic_model(options);
+    match network.mode {
+        NetworkMode::User => Ok(None),
+        NetworkMode::Isolated => check_isolated_options(options).map(|_| None),
+        NetworkMode::Bridge => bridge_nic(network.bridge.as_deref(), &model).map(Some),
+        NetworkMode::Tap => {
+            check_network_device_setup(vm_name, network)?;
+            Ok(Some(format!(
+                "tap,ifname={},script=no,downscript=no,model={model}",
+                get_network_device_name(vm_name, network)
+            )))
+        }
+        NetworkMode::Macvtap => {
+            check_network_device_setup(vm_name, network)?;
+            Ok(Some(format!(
+                "tap,fd={MACVTAP_FD},model={model},mac={mac_address}"
+            )))
+        }
+    }
+}
+
+pub fn get_nic_mac_addresses(args: &[String]) -> Vec<String> {
+    //! Returns the MAC addresses set on the NICs of a qemu command line.
+    args.iter()
+        .flat_map(|arg| arg.split(','))
+        .filter_map(|property| property.strip_prefix("mac="))
+        .filter_map(|address| parse_mac_address(address).ok())
+        .collect()
+}
+
+fn parse_arp_table(table: &str) -> Vec<(String, String)> {
+    //! Returns the MAC address and IP address of each complete entry of
+    //! `/proc/net/arp`.
+    table
+        .lines()
+        .skip(1)
+        .filter_map(
+            |line| match line.split_whitespace().collect::<Vec<&str>>()[..] {
+                [address, _, flags, mac_address, ..] if flags != "0x0" => {
+                    Some((mac_address.to_lowercase(), address.to_owned()))
+                }
+                _ => None,
+            },
+        )
+        .collect()
+}
+
+fn parse_dnsmasq_leases(leases: &str) -> Vec<(String, String)> {
+    //! Returns the MAC address and IP address of each lease in a dnsmasq
+    //! leases file, whose lines start with the lease's expiry time.
+    leases
+        .lines()
+        .filter_map(
+            |line| match line.split_whitespace().collect::<Vec<&str>>()[..] {
+                [_, mac_address, address, ..] => {
+                    Some((mac_address.to_lowercase(), address.to_owned()))
+                }
+                _ => None,
+            },
+        )
+        .collect()
+}
+
+pub fn find_addresses_by_mac(mac_addresses: &[String]) -> Vec<String> {
+    //! Returns the IP addresses the host has seen used by any of the MAC
+    //! addresses, in its ARP table or in the leases of a DHCP server running
+    //! on it. This finds the addresses of bridged guests, but not those of
+    //! guests on user-mode networking, which the host never sees.
+    let mut entries: Vec<(String, String)> =
+        parse_arp_table(&fs::read_to_string(PROC_NET_ARP).unwrap_or_default());
+    for leases in DHCP_LEASE_FILES {
+        entries.extend(parse_dnsmasq_leases(
+            &fs::read_to_string(leases).unwrap_or_default(),
+        ));
+    }
+    let mut addresses: Vec<String> = vec![];
+    for (mac_address, address) in entries {
+        if mac_addresses.contains(&mac_address) && !addresses.contains(&address) {
+            addresses.push(address);
+        }
+    }
+    addresses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        get_network_device_name, get_nic_mac_addresses, is_bridge_allowed, network_nic, nic_model,
+        parse_arp_table, parse_dnsmasq_leases, parse_mac_address, private_network_nics,
+        publish_ports, set_nic_mac_address, update_user_nics, user_nic_properties,
+    };
+    use crate::config::{NetworkConfig, NetworkMode, PrivateNetwork, QemuRunOption};
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_is_bridge_allowed() {
+        let directory: PathBuf =
+            std::env::temp_dir().join(format!("vm-manager-test-bridge-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let conf: PathBuf = directory.join("bridge.conf");
+        let included: PathBuf = directory.join("lan.conf");
+        fs::write(&included, "allow br1\n").unwrap();
+        fs::write(
+            &conf,
+            format!(
+                "# bridges\nallow br0\ndeny br2\ninclude {}\n",
+                included.display()
+            ),
+        )
+        .unwrap();
+
+        assert!(is_bridge_allowed(&conf, "br0"));
+        assert!(is_bridge_allowed(&conf, "br1"));
+        assert!(!is_bridge_allowed(&conf, "br2"));
+        assert!(!is_bridge_allowed(&conf, "virbr0"));
+        assert!(!is_bridge_allowed(&directory.join("missing.conf"), "br0"));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_nic_model() {
+        assert_eq!(
+            nic_model(&[QemuRunOption::new(
+                "-nic user,model=e1000,hostfwd=tcp::5555-:22"
+            )]),
+            "e1000"
+        );
+        assert_eq!(nic_model(&[QemuRunOption::new("-m 8G")]), "virtio-net-pci");
+    }
+
+    #[test]
+    fn test_network_device_name() {
+        let network: NetworkConfig = NetworkConfig {
+            mode: NetworkMode::Tap,
+            ..Default::default()
+        };
+        let device: String = get_network_device_name("deb12@web1", &network);
+        assert!(device.starts_with("vm") && device.len() == 10);
+        assert_eq!(device, get_network_device_name("deb12@web1", &network));
+        assert_ne!(device, get_network_device_name("deb12@web2", &network));
+        assert_eq!(
+            get_network_device_name(
+                "deb12",
+                &NetworkConfig {
+                    interface: Some("tap0".to_owned()),
+                    ..network
+                }
+            ),
+            "tap0"
+        );
+    }
+
+    #[test]
+    fn test_mac_address() {
+        assert_eq!(
+            parse_mac_address("52-54-00-AB-cd-0E").unwrap(),
+            "52:54:00:ab:cd:0e"
+        );
+        assert!(parse_mac_address("52:54:00:ab:cd").is_err());
+        assert!(parse_mac_address("52:54:00:ab:cd:0g").is_err());
+        // multicast
+        assert!(parse_mac_address("01:00:5e:00:00:01").is_err());
+
+        let mut options: Vec<QemuRunOption> = vec![
+            QemuRunOption::new("-m 8G"),
+            QemuRunOption::new("-nic user,model=virtio"),
+            QemuRunOption::new("-nic user,mac=52:54:00:00:00:02"),
+        ];
+        set_nic_mac_address(&mut options, "52:54:00:00:00:01");
+        assert_eq!(
+            options[1].as_str(),
+            "-nic user,model=virtio,mac=52:54:00:00:00:01"
+        );
+        assert_eq!(options[2].as_str(), "-nic user,mac=52:54:00:00:00:02");
+        set_nic_mac_address(&mut options[2..], "52:54:00:00:00:01");
+        assert_eq!(options[2].as_str(), "-nic user,mac=52:54:00:00:00:02");
+    }
+
+    #[test]
+    fn test_private_network_nics() {
+        let lab: PrivateNetwork = PrivateNetwork {
+            name: "lab".to_owned(),
+            multicast: Some("230.0.0.1:1234".to_owned()),
+        };
+        let k8s: PrivateNetwork = PrivateNetwork {
+            name: "k8s".to_owned(),
+            multicast: None,
+        };
+        let options: Vec<QemuRunOption> = vec![QemuRunOption::new("-nic user,model=e1000")];
+        let nics: Vec<String> = private_network_nics("node1", &[&lab, &k8s], &options).unwrap();
+        assert!(nics[0].starts_with(
+            "socket,mcast=230.0.0.1:1234,localaddr=127.0.0.1,model=e1000,mac=52:54:00:"
+        ));
+        assert!(nics[1].starts_with("socket,mcast=230."));
+        assert_eq!(
+            nics,
+            private_network_nics("node1", &[&lab, &k8s], &options).unwrap()
+        );
+        assert_ne!(
+            nics[0],
+            private_network_nics("node2", &[&lab], &options).unwrap()[0]
+        );
+
+        let unicast: PrivateNetwork = PrivateNetwork {
+            multicast: Some("10.0.0.1:1234".to_owned()),
+            ..lab
+        };
+        assert!(private_network_nics("node1", &[&unicast], &options).is_err());
+    }
+
+    #[test]
+    fn test_publish_ports() {
+        let rules: Vec<String> = vec![
+            "hostfwd=tcp::2222-:22".to_owned(),
+            "hostfwd=udp::5353-:53".to_owned(),
+        ];
+        assert_eq!(
+            publish_ports(
+                "user,model=virtio,hostfwd=tcp::5555-:22,hostfwd=tcp::8081-:443",
+                &rules
+            ),
+            "user,model=virtio,hostfwd=tcp::8081-:443,hostfwd=tcp::2222-:22,hostfwd=udp::5353-:53"
+        );
+        assert_eq!(publish_ports("", &rules[..1]), "hostfwd=tcp::2222-:22");
+    }
+
+    #[test]
+    fn test_user_nic_properties() {
+        let network: NetworkConfig = NetworkConfig {
+            ipv6_prefix: Some("fd00:1::/64".to_owned()),
+            ipv6_dns: Some("fd00:1::3".parse().unwrap()),
+            ..Default::default()
+        };
+        let properties: Vec<String> = user_nic_properties(Some(&network)).unwrap();
+        assert_eq!(
+            properties,
+            vec!["ipv6-net=fd00:1::/64", "ipv6-dns=fd00:1::3"]
+        );
+
+        let mut options: Vec<QemuRunOption> = vec![QemuRunOption::new("-m 8G")];
+        update_user_nics(&mut options, |nic| format!("{nic},ipv6=off"), "IPv6").unwrap();
+        assert_eq!(options[1].as_str(), "-nic user,ipv6=off");
+
+        let mut options: Vec<QemuRunOption> = vec![QemuRunOption::new("-nic bridge,br=br0")];
+        assert!(update_user_nics(&mut options, |nic| nic.to_owned(), "IPv6").is_err());
+        assert!(user_nic_properties(Some(&NetworkConfig {
+            ipv6_prefix: Some("fd00::".to_owned()),
+            ..Default::default()
+        }))
+        .is_err());
+
+        let network: NetworkConfig = NetworkConfig {
+            ipv4_prefix: Some("192.168.76.0/24".to_owned()),
+            ipv4_host: Some("192.168.76.2".parse().unwrap()),
+            dhcp_start: Some("192.168.76.100".parse().unwrap()),
+            hostname: Some("web1".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            user_nic_properties(Some(&network)).unwrap(),
+            vec![
+                "net=192.168.76.0/24",
+                "host=192.168.76.2",
+                "dhcpstart=192.168.76.100",
+                "hostname=web1"
+            ]
+        );
+        let network: NetworkConfig = NetworkConfig {
+            mode: NetworkMode::Isolated,
+            ipv6: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(
+            user_nic_properties(Some(&network)).unwrap(),
+            vec!["restrict=on", "ipv6=off"]
+        );
+        assert!(network_nic(
+            "deb12",
+            Some(&network),
+            &[QemuRunOption::new("-nic user,model=virtio")],
+            "52:54:00:00:00:01"
+        )
+        .unwrap()
+        .is_none());
+        assert!(network_nic(
+            "deb12",
+            Some(&network),
+            &[QemuRunOption::new("-netdev tap,id=net0")],
+            "52:54:00:00:00:01"
+        )
+        .is_err());
+
+        // addresses must be within the prefix, which defaults to qemu's
+        assert!(user_nic_properties(Some(&NetworkConfig {
+            ipv4_dns: Some("192.168.76.3".parse().unwrap()),
+            ..Default::default()
+        }))
+        .is_err());
+        assert!(user_nic_properties(Some(&NetworkConfig {
+            ipv4_prefix: Some("10.0.2.0/30".to_owned()),
+            ..Default::default()
+        }))
+        .is_err());
+    }
+
+    #[test]
+    fn test_guest_addresses() {
+        let args: Vec<String> = vec![
+            "-nic".to_owned(),
+            "bridge,br=br0,model=virtio-net-pci,mac=52:54:00:AB:CD:EF".to_owned(),
+        ];
+        assert_eq!(get_nic_mac_addresses(&args), vec!["52:54:00:ab:cd:ef"]);
+
+        let table: &str = "IP address       HW type     Flags       HW address            Mask     Device\n192.168.1.20     0x1         0x2         52:54:00:ab:cd:ef     *        br0\n192.168.1.21     0x1         0x0         00:00:00:00:00:00     *        br0\n";
+        assert_eq!(
+            parse_arp_table(table),
+            vec![("52:54:00:ab:cd:ef".to_owned(), "192.168.1.20".to_owned())]
+        );
+        let leases: &str = "1700000000 52:54:00:ab:cd:ef 192.168.122.5 dev *\n";
+        assert_eq!(
+            parse_dnsmasq_leases(leases),
+            vec![("52:54:00:ab:cd:ef".to_owned(), "192.168.122.5".to_owned())]
+        );
+    }
+}
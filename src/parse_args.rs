@@ -1,28 +1,942 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{PortMapping, VmFilter};
 
 #[derive(Subcommand, Debug)]
 pub enum Command {
     /// Must specify at least -i/--image, where the argument given to
     /// -i/--image is a unique substring of a name output by 'vm-manager -l' or
-    /// 'vm-manager --list-images'.
-    Start,
+    /// 'vm-manager --list-images'. With --instance, the VM runs as that
+    /// instance of the image, on an overlay disk backed by the image, so that
+    /// several instances of one image can run at once. Given -i more than
+    /// once, the VMs are started at the same time in the background, and
+    /// what became of each is reported.
+    Start(StartOptions),
+    /// Creates a new VM in one go: its disk, blank or a copy of a cloud
+    /// image, and its entry in the config file, attaching an installer ISO
+    /// to boot from if given one. Whatever isn't given is asked for when run
+    /// in a terminal, as is whether to start the VM, e.g.
+    ///     vm-manager create
+    ///     vm-manager create web --cloud-image ubuntu-24.04 --size 40G --memory 4G --start
+    #[clap(verbatim_doc_comment)]
+    Create(CreateOptions),
+    /// Must specify at least -i/--image, where the argument given to
+    /// -i/--image is a unique substring of a name output by 'vm-manager -r' or
+    /// 'vm-manager --list-running-vms', or --all, or --filter to stop every
+    /// running VM matching it, e.g. 'vm-manager stop --filter tag=ci'.
+    ///
+    /// With --wait, exits with 0 if the guest powered down, 2 if qemu had to
+    /// be sent SIGTERM, 3 if it had to be sent SIGKILL, 4 if it was still
+    /// running even then, and 1 on errors. With --all, the most forceful
+    /// outcome decides.
+    Stop {
+        /// Stop every running VM instead of a single one.
+        #[clap(long, short = 'a')]
+        all: bool,
+        /// Kill the VM immediately instead of first asking the guest to
+        /// power down.
+        #[clap(long)]
+        force: bool,
+        /// Block until qemu has actually exited, sending it SIGTERM and then
+        /// SIGKILL whenever it hasn't exited within the timeout.
+        #[clap(long)]
+        wait: bool,
+        /// How many seconds to wait for the guest to power down, and with
+        /// --wait for qemu to exit after each signal. Overrides
+        /// 'shutdown_timeout' from the config file.
+        #[clap(long, value_name = "SECONDS")]
+        timeout: Option<u64>,
+    },
     /// Must specify at least -i/--image, where the argument given to
     /// -i/--image is a unique substring of a name output by 'vm-manager -r' or
-    /// 'vm-manager --list-running-vms'.
-    Stop,
+    /// 'vm-manager --list-running-vms'. The VM is stopped, then started again
+    /// with the same ports and options it was running with.
+    Restart {
+        /// Print the qemu command the VM would be started again with,
+        /// without stopping or running anything.
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Starts every VM of a group from the config's 'groups' section which
+    /// isn't already running, e.g. the nodes of a cluster, and reports what
+    /// became of each of them. VMs are started after the VMs they depend on
+    /// are ready.
+    Up {
+        /// Name of the group.
+        group: String,
+    },
+    /// Restarts the running VMs of a group from the config's 'groups'
+    /// section a few at a time, as 'vm-manager restart' does, e.g. to have a
+    /// simulated cluster pick up updated images without all of it going down
+    /// at once. Each batch is only restarted once the one before it is ready
+    /// again, and a VM failing to restart stops the rolling restart.
+    RollingRestart {
+        /// Name of the group.
+        group: String,
+        /// How many VMs of the group may be restarting at the same time.
+        #[clap(
+            long,
+            value_name = "COUNT",
+            default_value_t = 1,
+            value_parser = clap::value_parser!(u64).range(1..)
+        )]
+        max_unavailable: u64,
+    },
+    /// Stops every running VM of a group from the config's 'groups' section,
+    /// in the reverse of the order they're started in, and reports what
+    /// became of each of them. Exit codes are those of 'vm-manager stop'.
+    Down {
+        /// Name of the group.
+        group: String,
+        /// Kill the VMs immediately instead of first asking the guests to
+        /// power down.
+        #[clap(long)]
+        force: bool,
+        /// Block until qemu has actually exited for each VM, as 'vm-manager
+        /// stop --wait' does.
+        #[clap(long)]
+        wait: bool,
+        /// How many seconds to wait for each guest to power down. Overrides
+        /// 'shutdown_timeout' from the config file.
+        #[clap(long, value_name = "SECONDS")]
+        timeout: Option<u64>,
+    },
+    /// Must specify at least -i/--image. Saves the RAM and device state of a
+    /// running VM to disk and stops it, so that it can later carry on where
+    /// it left off with 'vm-manager resume-from', e.g. across a host reboot.
+    /// Only available for VMs run in the background.
+    Save,
+    /// Must specify at least -i/--image. Starts a VM saved with 'vm-manager
+    /// save' again from its saved state, without booting the guest.
+    ResumeFrom {
+        /// Delete the saved state instead of resuming from it, so that the VM
+        /// can be booted afresh.
+        #[clap(long)]
+        discard: bool,
+    },
+    /// Must specify at least -i/--image. Reports whether a VM is running on
+    /// the matching image, along with its PID, forwarded ports, uptime and
+    /// full qemu command line.
+    Status,
+    /// Must specify at least -i/--image. Shows the serial console output of
+    /// a VM run in the background, which is kept across runs in
+    /// '~/.vm-manager/logs/<vm>/console.log', or what qemu printed. The VM
+    /// doesn't have to be running. Logs are rotated as set in the config's
+    /// 'logs' section.
+    Logs {
+        #[command(subcommand)]
+        command: Option<LogsCommand>,
+        /// Show what qemu itself printed, e.g. why the VM failed to start,
+        /// instead of the serial console's output.
+        #[clap(long)]
+        qemu: bool,
+        /// Keep printing what is added to the log until interrupted.
+        #[clap(long, short = 'f')]
+        follow: bool,
+        /// How many of the last lines to show, or 0 for the whole log.
+        #[clap(long, short = 'n', default_value_t = 50)]
+        lines: usize,
+    },
+    /// Shows the CPU usage, resident memory, disk space and storage I/O of
+    /// each running VM, or only of those matching -i/--image. CPU usage is
+    /// that of the qemu process, measured over a second, where 100% is one
+    /// host CPU.
+    Stats {
+        /// Keep refreshing the table until interrupted.
+        #[clap(long, short = 'w')]
+        watch: bool,
+        /// Seconds between refreshes with --watch.
+        #[clap(
+            long,
+            value_name = "SECONDS",
+            default_value_t = 2,
+            value_parser = clap::value_parser!(u64).range(1..)
+        )]
+        interval: u64,
+    },
+    /// Must specify at least -i/--image. Copies files between the host and
+    /// the guest of a running VM using scp over its forwarded SSH port. Guest
+    /// paths start with ':', e.g.
+    ///     vm-manager copy -i dev ./build.tar.gz :/tmp/
+    ///     vm-manager copy -i dev :/var/log/syslog .
+    #[clap(verbatim_doc_comment)]
+    Copy {
+        /// Copy directories recursively.
+        #[clap(long, short = 'r')]
+        recursive: bool,
+        /// Source path(s) followed by the destination path.
+        #[clap(required = true, num_args = 2..)]
+        paths: Vec<String>,
+    },
+    /// Must specify at least -i/--image. Runs a command in the guest of a
+    /// running VM through its guest agent, printing its output, e.g.
+    ///     vm-manager exec -i dev -- systemctl is-system-running
+    /// The guest must run 'qemu-guest-agent', but needs no network or SSH.
+    #[clap(verbatim_doc_comment)]
+    Exec {
+        /// The program to run, followed by its arguments.
+        #[clap(required = true, last = true)]
+        command: Vec<String>,
+    },
+    /// Reports the IP addresses of the guest of each running VM, or only of
+    /// the one matching -i/--image. They are asked of the guest agent, or
+    /// else looked up by the guest's MAC address in the host's ARP table
+    /// and DHCP leases, which finds those of bridged guests.
+    Ip,
+    /// Prints an SSH client config entry for each running VM with a
+    /// forwarded SSH port, named after the VM, so that e.g. 'ssh deb12'
+    /// logs into it. If the config file sets 'ssh_config_file', vm-manager
+    /// keeps that file up to date as VMs start and stop, for '~/.ssh/config'
+    /// to 'Include'.
+    SshConfig {
+        /// Write the entries to 'ssh_config_file' instead of printing them.
+        #[clap(long)]
+        write: bool,
+    },
+    /// Must specify at least -i/--image. Lists every port forwarded from the
+    /// host to the guest of a running VM, with its protocol and the host
+    /// address it's bound to, in the format given with -o/--output, e.g.
+    /// 'vm-manager -o csv ports -i deb12'.
+    Ports,
+    /// Must specify at least -i/--image. Attaches the terminal to the serial
+    /// console of a running VM. Press Ctrl-] to detach. Only available for
+    /// VMs run in the background.
+    Console,
+    /// Must specify at least -i/--image. Opens an interactive session with the
+    /// qemu monitor of a running VM, taking human monitor (HMP) commands such
+    /// as 'info status', or QMP commands such as
+    /// '{"execute": "query-status"}'. Press Ctrl-D to exit.
+    Monitor,
+    /// Must specify at least -i/--image. Prints the VNC or SPICE URL of a
+    /// running VM's display, or opens it in a viewer with --launch.
+    Display {
+        /// Open the display with 'remote-viewer' instead of printing its URL.
+        #[clap(long)]
+        launch: bool,
+    },
+    /// Must specify at least -i/--image. Saves what a running VM's display
+    /// shows as a PNG image, e.g. to see the console of a headless VM.
+    Screenshot {
+        /// Where to save the screenshot. Ends in '.ppm' to keep the PPM image
+        /// qemu takes. Defaults to '<image>-<date>.png'.
+        #[clap(long, short = 'o')]
+        output: Option<String>,
+    },
+    /// Must specify at least -i/--image. Presses key combinations on a
+    /// running VM's keyboard, one after another, e.g.
+    ///     vm-manager sendkey -i dev ctrl-alt-f2
+    /// Keys are named as in qemu's 'sendkey' monitor command.
+    #[clap(verbatim_doc_comment)]
+    Sendkey {
+        /// Key combinations, with the keys pressed together joined by '-'.
+        #[clap(required = true)]
+        keys: Vec<String>,
+    },
+    /// Must specify at least -i/--image. Types text on a running VM's
+    /// keyboard, as on a US keyboard layout, e.g.
+    ///     vm-manager type -i dev --enter 'root'
+    #[clap(verbatim_doc_comment)]
+    Type {
+        /// Text to type.
+        text: String,
+        /// Press Enter after typing the text.
+        #[clap(long)]
+        enter: bool,
+    },
+    /// Must specify at least -i/--image. Copies the image into the backups
+    /// directory under a timestamped name.
+    Backup {
+        /// Pause a running VM via QMP while it is backed up, instead of
+        /// refusing to back it up.
+        #[clap(long)]
+        pause: bool,
+        /// Don't freeze the filesystems of a running VM's guest through its
+        /// guest agent while it is backed up.
+        #[clap(long)]
+        no_freeze: bool,
+        /// Only store what changed since the previous backup. The image is
+        /// moved into the backups directory, and replaced by a qcow2 overlay
+        /// backed by it.
+        #[clap(long, conflicts_with = "compress")]
+        incremental: bool,
+        /// Compress the backup. Defaults to 'zstd' if no method is given.
+        #[clap(long, value_enum, num_args = 0..=1, default_missing_value = "zstd")]
+        compress: Option<Compression>,
+    },
+    /// Must specify at least -i/--image. Replaces the image with a backup,
+    /// merging in any earlier backups an incremental backup depends on, or
+    /// decompressing a compressed backup. The VM must not be running.
+    Restore {
+        /// Name of the backup to restore, as shown by -b/--list-backup-images.
+        /// Defaults to the most recent backup of the image.
+        backup: Option<String>,
+    },
+    /// Deletes the backups not kept by the `backups` retention policy in the
+    /// config file. Prunes the backups of every image unless -i/--image is
+    /// given.
+    PruneBackups {
+        /// Only list the backups which would be deleted.
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Manage the disk images in the images directory.
+    Image {
+        #[command(subcommand)]
+        command: ImageCommand,
+    },
+    /// Export VMs for other tools.
+    Export {
+        #[command(subcommand)]
+        command: ExportCommand,
+    },
+    /// Import VMs from other tools, creating their disk image and their
+    /// entry in the config file.
+    Import {
+        #[command(subcommand)]
+        command: ImportCommand,
+    },
+    /// Manage snapshots of an image, e.g.
+    ///     vm-manager snapshot -i dev create --name pre-upgrade
+    #[clap(verbatim_doc_comment)]
+    Snapshot {
+        #[command(subcommand)]
+        command: SnapshotCommand,
+    },
+    /// Must specify at least -i/--image. Reports how much memory the guest of
+    /// a running VM has, or shrinks or grows it with --target through the
+    /// guest's memory balloon, e.g.
+    ///     vm-manager balloon -i dev --target 4G
+    #[clap(verbatim_doc_comment)]
+    Balloon {
+        /// Memory the guest should have, e.g. '4G'. At most the memory it was
+        /// started with.
+        #[clap(long)]
+        target: Option<String>,
+    },
+    /// Must specify at least -i/--image. Adds vCPUs or memory to the guest
+    /// of a running VM, which must have been started with 'max_cpus' or
+    /// 'max_memory' in its VM config, e.g.
+    ///     vm-manager scale -i dev --cpus +2 --memory +4G
+    /// The guest may need to bring the new vCPUs and memory online itself.
+    #[clap(verbatim_doc_comment)]
+    Scale {
+        /// vCPUs to add, e.g. '+2', or the number the guest should have.
+        #[clap(long, allow_hyphen_values = true)]
+        cpus: Option<String>,
+        /// Memory to add, e.g. '+4G', or the memory the guest should have.
+        #[clap(long)]
+        memory: Option<String>,
+    },
+    /// Must specify at least -i/--image. Attaches disk images to, or detaches
+    /// them from, a running VM without stopping it, e.g.
+    ///     vm-manager disk -i dev attach --path extra.qcow2
+    #[clap(verbatim_doc_comment)]
+    Disk {
+        #[command(subcommand)]
+        command: DiskCommand,
+    },
+    /// Must specify at least -i/--image. Passes host USB devices through to a
+    /// running VM, or hands them back to the host, e.g.
+    ///     vm-manager usb -i dev attach 1050:0407
+    #[clap(verbatim_doc_comment)]
+    Usb {
+        #[command(subcommand)]
+        command: UsbCommand,
+    },
+    /// Checks that the programs and firmware vm-manager relies on are
+    /// installed, and reports where they were found.
+    Doctor,
+    /// Manage the config file.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Moves the config file, with its 'conf.d' and 'vms.d' directories, and
+    /// the images directory of an older installation from '~/.vm-manager' to
+    /// '$XDG_CONFIG_HOME/vm-manager' and '$XDG_DATA_HOME/vm-manager'. VMs must
+    /// be stopped first. vm-manager's other files stay in '~/.vm-manager'.
+    MigratePaths {
+        /// Only print what would be moved.
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Manage the instances of an image, which are started with
+    /// 'vm-manager start -i <image> --instance <name>'.
+    Instance {
+        #[command(subcommand)]
+        command: InstanceCommand,
+    },
+    /// Watches the VMs running in the background whose 'restart_policy' is
+    /// 'always' or 'on-failure', and starts them again when they exit
+    /// without having been stopped through vm-manager, waiting longer after
+    /// each exit in a row. Runs until interrupted, e.g. as a systemd user
+    /// service.
+    Supervise,
+    /// Shows a full-screen dashboard of every image and running VM, with
+    /// their CPU and memory usage, which refreshes every second. Keys start,
+    /// stop, pause and resume the selected VM, or log into it with 'ssh' or
+    /// its serial console; 'q' quits.
+    Tui,
+    /// Runs a daemon controlling VMs on behalf of other tools, and of
+    /// 'vm-manager --remote', through a REST API. It listens on the socket
+    /// '~/.vm-manager/run/daemon.sock', which only the current user can
+    /// connect to. Endpoints:
+    ///     GET  /vms               lists the running VMs
+    ///     GET  /vms/<name>        shows the status of a running VM
+    ///     POST /vms/<name>/start  starts a VM; the body holds start options
+    ///     POST /vms/<name>/stop   stops a VM; the body may hold 'force',
+    ///                             'wait' and 'timeout'
+    /// where <name> is an image name pattern, followed by '@<instance>' for
+    /// instances. Runs until interrupted.
+    #[clap(verbatim_doc_comment)]
+    Daemon {
+        /// Also listen on this TCP address, e.g. '127.0.0.1:7878'. Requests
+        /// over TCP must carry the token in '~/.vm-manager/daemon-token' as
+        /// 'Authorization: Bearer <token>'; it is created if missing.
+        #[clap(long, value_name = "ADDRESS:PORT")]
+        listen: Option<String>,
+    },
+    /// Manage systemd user services running VMs, which start them at login
+    /// and restart them if they fail.
+    Systemd {
+        #[command(subcommand)]
+        command: SystemdCommand,
+    },
+}
+
+/// Options of 'vm-manager start', which are also what 'vm-manager --remote
+/// start' sends to the daemon.
+#[derive(Args, Debug, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct StartOptions {
+    /// Discard every change to the disk when the VM shuts down. Writes go
+    /// to a temporary overlay instead of the image (qemu's -snapshot).
+    #[clap(long)]
+    pub ephemeral: bool,
+    /// Memory given to the guest, e.g. '8G' or '512M'. Overrides the VM
+    /// config. Defaults to '8G' for images without one.
+    #[clap(long)]
+    pub memory: Option<String>,
+    /// Number of virtual CPUs given to the guest. Overrides the VM config.
+    /// Defaults to 4 for images without one.
+    #[clap(long)]
+    pub cpus: Option<usize>,
+    /// Machine type emulated for the guest, e.g. 'q35'. Overrides the VM
+    /// config. Defaults to qemu's default machine.
+    #[clap(long)]
+    pub machine: Option<String>,
+    /// Attach an installer ISO as a CD-ROM, and boot from it once. Later
+    /// reboots boot from the disk, so installers can finish. Overrides the VM
+    /// config.
+    #[clap(long, conflicts_with = "netboot")]
+    pub cdrom: Option<String>,
+    /// Boot from the network (PXE) once. Uses the VM config's 'netboot'
+    /// settings, which --tftp and --bootfile override.
+    #[clap(long)]
+    pub netboot: bool,
+    /// Directory served to the guest over TFTP by qemu's user-mode
+    /// networking when network booting.
+    #[clap(long, requires = "netboot")]
+    pub tftp: Option<String>,
+    /// File offered to the guest's PXE firmware when network booting,
+    /// relative to the TFTP directory, or a URL for iPXE.
+    #[clap(long, requires = "netboot")]
+    pub bootfile: Option<String>,
+    /// Forward a host port to the guest, as '[address:]host:guest[/tcp|udp]',
+    /// e.g. '8080:80' or '127.0.0.1:8080:80'. May be repeated. Replaces any
+    /// port mapping of the VM config using the same host or guest port.
+    #[clap(long, value_name = "[ADDRESS:]HOST:GUEST[/PROTO]", value_parser = PortMapping::parse)]
+    pub publish: Vec<PortMapping>,
+    /// Only return once the guest's SSH server answers on its forwarded SSH
+    /// port, failing after the given number of seconds (300 by default).
+    /// Only available for VMs run in the background.
+    #[clap(long, value_name = "SECONDS", num_args = 0..=1, default_missing_value = "300")]
+    pub wait_for_ssh: Option<u64>,
+    /// Start the VM even if a running VM already uses its disk image. Two
+    /// VMs writing to the same image corrupt it.
+    #[clap(long)]
+    pub force: bool,
+    /// Print the full qemu command the VM would be started with, including
+    /// the options merged from the config and the ports picked, without
+    /// running anything.
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+/// Options of 'vm-manager create'.
+#[derive(Args, Debug)]
+pub struct CreateOptions {
+    /// Name of the new VM, and of its image.
+    pub name: Option<String>,
+    /// Size of the disk, e.g. '40G'. A cloud image is grown to it. Default
+    /// is 20G.
+    #[clap(long)]
+    pub size: Option<String>,
+    /// Installer ISO the VM boots from until it's removed from the VM's
+    /// config.
+    #[clap(long, conflicts_with = "cloud_image")]
+    pub iso: Option<String>,
+    /// Cloud image the disk starts out as, by its name in the image
+    /// catalog, e.g. 'ubuntu-24.04'. See 'vm-manager image pull'.
+    #[clap(long, value_name = "NAME")]
+    pub cloud_image: Option<String>,
+    /// Memory of the VM, e.g. '8G'. Default is the global options'.
+    #[clap(long)]
+    pub memory: Option<String>,
+    /// Number of CPUs of the VM. Default is the global options'.
+    #[clap(long)]
+    pub cpus: Option<usize>,
+    /// Don't check the signature of the cloud image's checksums.
+    #[clap(long)]
+    pub no_signature: bool,
+    /// Start the VM once it's created.
+    #[clap(long)]
+    pub start: bool,
+    /// Don't ask anything, using the defaults for whatever isn't given.
+    #[clap(long, short = 'y')]
+    pub yes: bool,
+}
+
+/// Formats in which listings can be printed.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// Human-readable table.
+    #[default]
+    Table,
+    /// YAML list of records.
+    Yaml,
+    /// CSV with a header row.
+    Csv,
+}
+
+/// Columns of the running VM table, in the order '--wide' shows them.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunningVmColumn {
+    /// Host port forwarded to the guest's port 22.
+    SshPort,
+    /// Host port forwarded to the guest's port 443.
+    HttpsPort,
+    /// Name of the VM.
+    Name,
+    /// PID of the qemu process.
+    Pid,
+    /// Run state reported by qemu, e.g. 'running' or 'paused'.
+    State,
+    /// Time since the VM was started.
+    Uptime,
+    /// Host memory the qemu process is using.
+    Memory,
+    /// Forwarded ports other than those to 22 and 443.
+    Ports,
+    /// Times 'vm-manager supervise' started the VM again after it exited.
+    Crashes,
+    /// Tags of the VM's config.
+    Tags,
+}
+
+/// Disk image formats which images can be created in.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ImageFormat {
+    /// qcow2, which grows as it is written and supports snapshots.
+    #[default]
+    Qcow2,
+    /// A plain raw disk image.
+    Raw,
+}
+
+/// Subcommands of 'vm-manager export', which export VMs for other tools.
+#[derive(Subcommand, Debug)]
+pub enum ExportCommand {
+    /// Must specify at least -i/--image. Prints the libvirt domain XML of a
+    /// VM, with its memory, CPUs, disks, NICs and port forwards as it runs
+    /// here, for use with virsh or virt-manager. Settings libvirt has no
+    /// equivalent for are listed on stderr. E.g.
+    ///     vm-manager export libvirt -i web > web.xml && virsh define web.xml
+    #[clap(verbatim_doc_comment)]
+    Libvirt,
+}
+
+/// Subcommands of 'vm-manager import', which import VMs from other tools.
+#[derive(Subcommand, Debug)]
+pub enum ImportCommand {
+    /// Imports a Vagrant box for the libvirt or qemu provider, converting its
+    /// disk to a qcow2 image and adding a VM for it which logs in as the
+    /// 'vagrant' user, e.g.
+    ///     vm-manager import vagrant generic/debian12
+    ///     vm-manager import vagrant ~/Downloads/alma9.box --name alma
+    #[clap(verbatim_doc_comment)]
+    Vagrant {
+        /// Box file, URL of a box file, or name of a box added to Vagrant
+        /// with 'vagrant box add'.
+        #[clap(name = "box")]
+        box_name: String,
+        /// Name of the new image and VM. Defaults to the box's name, e.g.
+        /// 'debian12' for 'generic/debian12'.
+        #[clap(long)]
+        name: Option<String>,
+    },
+    /// Imports a libvirt domain, copying its disks to qcow2 images and
+    /// adding a VM with its memory, CPUs, firmware, NIC and shares. Devices
+    /// with no equivalent here are listed on stderr. E.g.
+    ///     virsh dumpxml web | vm-manager import libvirt -
+    ///     vm-manager import libvirt web.xml --name web --link
+    #[clap(verbatim_doc_comment)]
+    Libvirt {
+        /// Domain XML file, as 'virsh dumpxml' prints it, or '-' to read it
+        /// from stdin.
+        domain_xml: String,
+        /// Name of the new image and VM. Defaults to the domain's name.
+        #[clap(long)]
+        name: Option<String>,
+        /// Use the domain's disks where they are instead of copying them,
+        /// through the VM's 'image_path'. They then mustn't be used by
+        /// libvirt at the same time.
+        #[clap(long)]
+        link: bool,
+    },
+}
+
+/// Subcommands of 'vm-manager image', which manage the disk images in the
+/// images directory.
+#[derive(Subcommand, Debug)]
+pub enum ImageCommand {
+    /// Creates a new blank image in the images directory, e.g.
+    ///     vm-manager image create --name dev --size 40G
+    #[clap(verbatim_doc_comment)]
+    Create {
+        /// Name of the new image, without extension.
+        #[clap(long)]
+        name: String,
+        /// Size of the new image, e.g. '40G' or '512M'.
+        #[clap(long)]
+        size: String,
+        /// Format of the new image.
+        #[clap(long, value_enum, default_value_t = ImageFormat::Qcow2)]
+        format: ImageFormat,
+    },
+    /// Must specify at least -i/--image. Resizes an image whose VM is not
+    /// running, e.g.
+    ///     vm-manager image resize -i dev --size +20G
+    /// The guest's partitions and filesystems must be grown (or, before
+    /// shrinking, shrunk) separately.
+    #[clap(verbatim_doc_comment)]
+    Resize {
+        /// New size, either absolute ('60G') or relative ('+20G', '-5G').
+        #[clap(long, allow_hyphen_values = true)]
+        size: String,
+        /// Allow shrinking the image, discarding any data past its new end.
+        #[clap(long)]
+        force: bool,
+    },
+    /// Imports a disk image from another tool (e.g. a VirtualBox VDI or a
+    /// VMware VMDK) into the images directory, e.g.
+    ///     vm-manager image convert --from ~/Downloads/appliance.vmdk --name appliance
+    #[clap(verbatim_doc_comment)]
+    Convert {
+        /// Disk image to import. Its format is detected automatically.
+        #[clap(long)]
+        from: String,
+        /// Name of the new image, without extension.
+        #[clap(long)]
+        name: String,
+        /// Format of the new image.
+        #[clap(long, value_enum, default_value_t = ImageFormat::Qcow2)]
+        format: ImageFormat,
+    },
+    /// Downloads an official cloud image, verifies it against the checksums
+    /// (and their signature) published with it, and converts it to a qcow2
+    /// image in the images directory, e.g.
+    ///     vm-manager image pull ubuntu-24.04 --add-config
+    /// Without a name, lists the images which can be pulled. More can be
+    /// added in the config file's 'image_catalog'.
+    #[clap(verbatim_doc_comment)]
+    Pull {
+        /// Name of the image in the catalog.
+        catalog_name: Option<String>,
+        /// Name of the new image, without extension. Defaults to the name in
+        /// the catalog.
+        #[clap(long)]
+        name: Option<String>,
+        /// Add a VM for the new image to the config file.
+        #[clap(long)]
+        add_config: bool,
+        /// Don't check the signature of the checksums, e.g. when gpg doesn't
+        /// have the distribution's signing key.
+        #[clap(long)]
+        no_signature: bool,
+    },
+    /// Must specify at least -i/--image. Copies an image whose VM is not
+    /// running to a new image, e.g.
+    ///     vm-manager image clone -i base --name feature-x --linked --add-config
+    #[clap(verbatim_doc_comment)]
+    Clone {
+        /// Name of the new image, without extension.
+        #[clap(long)]
+        name: String,
+        /// Create a qcow2 overlay backed by the original image instead of a
+        /// full copy. The original must then be left unchanged.
+        #[clap(long)]
+        linked: bool,
+        /// Add a VM for the clone to the config file, copied from the
+        /// original's VM if it has one.
+        #[clap(long)]
+        add_config: bool,
+    },
+    /// Must specify at least -i/--image. Renames an image whose VM is not
+    /// running, updating any VM using it in the config file.
+    Rename {
+        /// New name of the image, without extension.
+        #[clap(long)]
+        name: String,
+    },
+    /// Must specify at least -i/--image. Deletes an image whose VM is not
+    /// running, after asking for confirmation. By default the image is moved
+    /// to '$HOME/.vm-manager/trash' rather than deleted outright.
+    Delete {
+        /// Don't ask for confirmation.
+        #[clap(long, short = 'y')]
+        yes: bool,
+        /// Delete the image permanently instead of moving it to the trash.
+        #[clap(long)]
+        permanent: bool,
+    },
+}
+
+/// Subcommands of 'vm-manager snapshot', which manage the internal snapshots
+/// of a qcow2 image. All of them must specify at least -i/--image. Snapshots
+/// of a running VM are taken live and include its RAM, so that it can be
+/// reverted to without rebooting.
+#[derive(Subcommand, Debug)]
+pub enum DiskCommand {
+    /// Attaches a disk image to a running VM as a virtio disk. The disk is
+    /// detached again when the VM stops.
+    Attach {
+        /// Path of the disk image, in any format qemu-img can read.
+        #[clap(long)]
+        path: String,
+        /// Attach the disk read-only.
+        #[clap(long)]
+        read_only: bool,
+    },
+    /// Detaches a disk image attached with 'disk attach' from a running VM,
+    /// once the guest has released it.
+    Detach {
+        /// Path of the disk image.
+        #[clap(long)]
+        path: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum UsbCommand {
+    /// Passes a host USB device through to a running VM. The device is
+    /// handed back to the host when the VM stops.
+    Attach {
+        /// The device's 'vendor:product' ID, as shown by 'lsusb'.
+        device: String,
+    },
+    /// Hands a USB device passed through with 'usb attach' back to the host.
+    Detach {
+        /// The device's 'vendor:product' ID, as shown by 'lsusb'.
+        device: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SnapshotCommand {
+    /// Takes a snapshot of an image.
+    Create {
+        /// Name of the new snapshot.
+        #[clap(long)]
+        name: String,
+    },
+    /// Lists the snapshots of an image.
+    List,
+    /// Reverts an image to a snapshot, discarding every change made since. A
+    /// running VM can only be reverted to a snapshot which includes its RAM.
+    Revert {
+        /// Name or ID of the snapshot.
+        #[clap(long)]
+        name: String,
+    },
+    /// Deletes a snapshot of an image.
+    Delete {
+        /// Name or ID of the snapshot.
+        #[clap(long)]
+        name: String,
+    },
+}
+
+/// Subcommands of 'vm-manager instance', which manage the instances of an
+/// image.
+#[derive(Subcommand, Debug)]
+pub enum InstanceCommand {
+    /// Lists the instances of an image, and whether each one is running.
+    List,
+    /// Deletes an instance's overlay disk, discarding every change made to
+    /// its disk. The instance must not be running.
+    Delete {
+        /// Name of the instance.
+        #[clap(long)]
+        name: String,
+        /// Don't ask for confirmation.
+        #[clap(long, short = 'y')]
+        yes: bool,
+    },
+}
+
+/// Subcommands of 'vm-manager config'.
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Creates the config directory and the disk-images and backups
+    /// directories, and writes a commented config file to start from, to
+    /// '$XDG_CONFIG_HOME/vm-manager/config.yml' or -c/--config-file. A config
+    /// file ending in '.toml' is written in TOML.
+    Init {
+        /// A directory of existing '.img' or '.qcow2' images to use as the
+        /// images directory, with a VM added to the config for each image.
+        #[clap(long, value_name = "DIRECTORY")]
+        scan: Option<String>,
+        /// Replace an existing config file, keeping it as '<file>.bak'.
+        #[clap(long)]
+        force: bool,
+    },
+    /// Writes the config file to OUTPUT, in TOML if its extension is '.toml'
+    /// and in YAML otherwise, e.g. to switch 'config.yml' to 'config.toml'.
+    /// Comments are not carried over.
+    Convert {
+        output: String,
+        /// Replace OUTPUT if it exists.
+        #[clap(long)]
+        force: bool,
+    },
+    /// Prints the config file, or with --effective, the config each VM (or
+    /// only that of -i/--image) is actually run with.
+    Show {
+        /// Show each VM's config with the global options and port mappings
+        /// merged into its options, and inherited and default settings
+        /// filled in.
+        #[clap(long)]
+        effective: bool,
+        /// Show the exact qemu command line each VM is started with instead,
+        /// one per line.
+        #[clap(long, conflicts_with = "effective")]
+        argv: bool,
+    },
+    /// Adds a VM running the image NAME to the config file, using the
+    /// global options, with any changes given applied.
+    ///
+    /// Comments in the rest of the config file are kept, and the previous
+    /// file is saved as '<file>.bak'. Example:
+    ///     vm-manager config add-vm deb12 memory=16G cpus=4 --add-port 2222:22
+    #[clap(verbatim_doc_comment)]
+    AddVm {
+        /// The image the VM runs, as named in its 'image_name'.
+        name: String,
+        /// Start from a copy of the entry of the VM running this image instead.
+        #[clap(long, value_name = "NAME")]
+        from: Option<String>,
+        #[command(flatten)]
+        changes: VmConfigChanges,
+    },
+    /// Changes the entry of the VM called NAME in the config file.
+    ///
+    /// Comments outside of the VM's entry are kept, and the previous file is
+    /// saved as '<file>.bak'. Examples:
+    ///     vm-manager config set deb12 memory=16G restart_policy=on-failure
+    ///     vm-manager config set deb12 ssh.user=admin --unset cpus
+    ///     vm-manager config set deb12 --remove-option -vnc --add-option '-vnc :1'
+    #[clap(verbatim_doc_comment)]
+    Set {
+        /// The VM, as named in its 'name', or else its 'image_name'.
+        name: String,
+        #[command(flatten)]
+        changes: VmConfigChanges,
+    },
+}
+
+/// Changes to a VM's entry in the config file, made by 'vm-manager config
+/// add-vm' and 'vm-manager config set'.
+#[derive(Args, Debug)]
+pub struct VmConfigChanges {
+    /// Settings to change, as KEY=VALUE. KEY is a VM setting, with nested
+    /// settings joined by '.', e.g. 'ssh.user'. VALUE is YAML, e.g.
+    /// 'memory=16G', 'cpus=4' or 'network={mode: bridge, bridge: br0}'.
+    #[clap(value_name = "KEY=VALUE")]
+    pub settings: Vec<String>,
+    /// Remove a setting, so that the global setting or default applies.
+    #[clap(long, value_name = "KEY")]
+    pub unset: Vec<String>,
+    /// Forward a host port to a guest port.
+    #[clap(long = "add-port", value_name = "[ADDRESS:]HOST:GUEST[/PROTO]", value_parser = PortMapping::parse)]
+    pub add_ports: Vec<PortMapping>,
+    /// Stop forwarding a host port.
+    #[clap(long = "remove-port", value_name = "HOST")]
+    pub remove_ports: Vec<String>,
+    /// Add a qemu option, e.g. '-smp 4'.
+    #[clap(long = "add-option", value_name = "OPTION", allow_hyphen_values = true)]
+    pub add_options: Vec<String>,
+    /// Remove the qemu options which are OPTION, or whose flag is, e.g.
+    /// '-vnc'.
+    #[clap(
+        long = "remove-option",
+        value_name = "OPTION",
+        allow_hyphen_values = true
+    )]
+    pub remove_options: Vec<String>,
+}
+
+/// Subcommands of 'vm-manager logs'.
+#[derive(Subcommand, Debug)]
+pub enum LogsCommand {
+    /// Rotates the logs of every VM, or only of -i/--image, which have grown
+    /// past the config's 'logs: max_size', and deletes the rotated logs it
+    /// no longer keeps. A VM's logs are also rotated when it starts, and
+    /// periodically while 'vm-manager supervise' runs; otherwise, run this
+    /// e.g. daily from cron to keep the logs of long-running VMs in check.
+    Prune,
+}
+
+/// Subcommands of 'vm-manager systemd'.
+#[derive(Subcommand, Debug)]
+pub enum SystemdCommand {
+    /// Writes a systemd user service running the VM of -i/--image (and
+    /// --instance) in the foreground, and stopping it gracefully, to
+    /// '~/.config/systemd/user'. Run 'loginctl enable-linger' to have it
+    /// started at boot rather than at login.
+    Generate {
+        /// Also reload systemd and enable the service, so that it starts at
+        /// the next login.
+        #[clap(long)]
+        enable: bool,
+    },
+}
+
+/// Ways in which a backup can be compressed.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// Stream the image through 'zstd', to '<backup>.zst'.
+    Zstd,
+    /// Stream the image through 'gzip', to '<backup>.gz'.
+    Gzip,
+    /// Write a compressed qcow2 image with 'qemu-img convert -c', which qemu
+    /// can run directly.
+    Qcow2,
 }
 
 /// Manage your qemu VMs.
 /// ---------------------------------------------------------------------------
 /// Installation process
 ///     1. Ensure qemu is installed.
-///     2. Create a directory '$HOME/.vm-manager/disk-images'.
-///     3. Create another directory '$HOME/.vm-manager/disk-images/backups'.
-///     4. Put .img files for active vms in '$HOME/.vm-manager/disk-images'.
-///     5. Put backup .img files in '$HOME/.vm-manager/disk-images/backups'.
-///     6. Copy sample configuration into '$HOME/.vm-manager/config.yml', then configure as needed.
-///        You can copy to another place as well if you choose, and can pass that file to the
-///        program with the -c / --config-file flag.
+///     2. Run 'vm-manager config init', which creates '$HOME/.vm-manager/disk-images', its
+///        'backups' directory and '$HOME/.vm-manager/config.yml'. Pass --scan <DIRECTORY> to
+///        use a directory of existing images instead, with a VM configured for each.
+///     3. Put .img files for active vms in '$HOME/.vm-manager/disk-images'.
+///     4. Put backup .img files in '$HOME/.vm-manager/disk-images/backups'.
+///     5. Configure '$HOME/.vm-manager/config.yml' as needed, see the sample configuration.
+///        You can put the config in another place as well if you choose, and can pass that
+///        file to the program with the -c / --config-file flag.
 #[derive(Debug, Parser)]
 #[clap(name = "vm-manager", arg_required_else_help = true, verbatim_doc_comment)]
 pub struct Arguments {
@@ -30,18 +944,46 @@ pub struct Arguments {
     #[clap(long, short = 'b')]
     pub list_backup_images: bool,
 
-    /// Run in foreground. Default is to daemonize.
+    /// Run in foreground. Default is to daemonize, or the VM config's
+    /// 'daemonize'.
     #[clap(long, short = 'f')]
     pub foreground: bool,
 
-    /// Specify the image file with which to start the container.
-    #[clap(long, short = 'i')]
-    pub image: Option<String>,
+    /// Specify the image file with which to start the container. 'start'
+    /// takes it more than once to start several VMs at once.
+    #[clap(long = "image", short = 'i', global = true, value_name = "IMAGE")]
+    pub images: Vec<String>,
+
+    /// Specify the instance of the image to start, or of the running VM to
+    /// act on. Without it, commands act on VMs running on the image itself.
+    #[clap(long, global = true)]
+    pub instance: Option<String>,
+
+    /// Only match images and VMs named exactly as given with -i, instead of
+    /// every name containing it.
+    #[clap(long, global = true, conflicts_with_all = ["glob", "regex"])]
+    pub exact: bool,
+
+    /// Match the name given with -i as a shell glob against whole image and
+    /// VM names, e.g. 'deb1?' or 'web-*'.
+    #[clap(long, global = true, conflicts_with = "regex")]
+    pub glob: bool,
+
+    /// Match the name given with -i as a regular expression against image
+    /// and VM names, e.g. '^debian1[12]$'.
+    #[clap(long, global = true)]
+    pub regex: bool,
 
     /// List images
     #[clap(long, short = 'l')]
     pub list_images: bool,
 
+    /// Only list, or stop, VMs matching the filter, e.g. 'tag=k8s' for VMs
+    /// tagged 'k8s' in the config file. May be given more than once, and
+    /// VMs must then match every filter.
+    #[clap(long, global = true, value_name = "FILTER", value_parser = VmFilter::parse)]
+    pub filter: Vec<VmFilter>,
+
     /// Specify host port to forward to container's port 22. If this is not
     /// specified, the program will find the next available port >= the default
     /// port.
@@ -58,10 +1000,71 @@ pub struct Arguments {
     #[clap(long, short = 'r')]
     pub list_running_vms: bool,
 
-    /// Config file to use. Default is '$HOME/.vm-manager/config.yml'.
+    /// Format of the running VM listing and of 'ports'. 'yaml' and 'csv' are
+    /// meant for scripts, and omit any headings.
+    #[clap(long, short = 'o', value_enum, default_value_t = OutputFormat::Table)]
+    pub output: OutputFormat,
+
+    /// Show every column of the running VM table, adding the PID, run
+    /// state, uptime, memory use and other forwarded ports of each VM.
+    #[clap(long)]
+    pub wide: bool,
+
+    /// Columns of the running VM table to show, in order, e.g.
+    /// '--columns name,state,uptime'.
+    #[clap(long, value_enum, value_delimiter = ',', conflicts_with = "wide")]
+    pub columns: Vec<RunningVmColumn>,
+
+    /// Config file to use, in TOML if its extension is '.toml' and YAML
+    /// otherwise. Default is '$XDG_CONFIG_HOME/vm-manager/config.yml' (or
+    /// '~/.config/vm-manager/config.yml'), or 'config.toml' there if only
+    /// that exists. An older installation's '~/.vm-manager/config.yml' or
+    /// 'config.toml' is used instead while it exists.
     #[clap(long, short = 'c')]
     pub config_file: Option<String>,
 
+    /// Have a running 'vm-manager daemon' list, start, stop or show the
+    /// status of VMs, instead of doing so directly. Without an address, the
+    /// daemon's socket is used; with one, e.g. '--remote=127.0.0.1:7878', its
+    /// TCP listener, authenticating with the token in $VM_MANAGER_TOKEN or
+    /// '~/.vm-manager/daemon-token'.
+    #[clap(
+        long,
+        global = true,
+        value_name = "ADDRESS:PORT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = ""
+    )]
+    pub remote: Option<String>,
+
+    /// Run vm-manager on another host over ssh, e.g. '--host admin@lab1',
+    /// using its config, images and VMs. Everything else on the command line
+    /// is passed along, so paths such as '--cdrom' are those on that host.
+    /// Set $VM_MANAGER_REMOTE_COMMAND if vm-manager isn't on its PATH.
+    #[clap(long, global = true, value_name = "[USER@]HOST")]
+    pub host: Option<String>,
+
     #[command(subcommand)]
     pub command: Option<Command>,
 }
+
+impl Arguments {
+    pub fn image(&self) -> Option<String> {
+        //! Returns the image given with -i, or the first one if 'start' was
+        //! given several.
+        self.images.first().cloned()
+    }
+
+    pub fn running_vm_columns(&self) -> Option<Vec<RunningVmColumn>> {
+        //! Returns the columns of the running VM table asked for with
+        //! --columns or --wide, or `None` for the default ones.
+        if self.wide {
+            Some(RunningVmColumn::value_variants().to_vec())
+        } else if !self.columns.is_empty() {
+            Some(self.columns.clone())
+        } else {
+            None
+        }
+    }
+}
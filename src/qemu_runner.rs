@@ -1,9 +1,123 @@
-use crate::config::{Config, VMConfig};
-use crate::utils::{get_file_from_image_name, run_shell_command, find_open_port, is_port_in_use};
-use crate::{DEFAULT_HTTPS_PORT, DEFAULT_SSH_PORT};
+use crate::affinity::{get_affinity_prefix, pin_vcpus};
+use crate::config::{
+    Config, CpuAffinity, NetbootConfig, NetworkConfig, PortMapping, PrivateNetwork, QemuRunOption,
+    Share, ShareDriver, VMConfig, WebhookEvent,
+};
+use crate::display::{get_display_url, is_display_option, DisplayType};
+use crate::firmware::prepare_firmware;
+use crate::guest_agent::guest_agent_qemu_args;
+use crate::host::quote_shell_arg;
+use crate::hostnames;
+use crate::hotplug::{cpu_hotplug_arg, memory_hotplug_arg};
+use crate::hugepages::prepare_hugepages;
+use crate::image::throttling_drive_properties;
+use crate::instance::{get_instance_vm_name, get_overlay_path, split_vm_name};
+use crate::logs::{open_qemu_log, read_startup_errors, rotate_vm_logs, serial_console_args};
+use crate::matching;
+use crate::netboot::{add_nic_properties, user_net_properties};
+use crate::network::{
+    create_network_device, delete_network_device, get_launch_prefix, get_mac_address, network_nic,
+    private_network_nics, publish_ports, set_nic_mac_address, update_user_nics,
+    user_nic_properties,
+};
+use crate::qmp::QmpClient;
+use crate::shares::{share_qemu_args, smb_nic_property, start_virtiofsd, stop_virtiofsd};
+use crate::ssh::update_config_file as update_ssh_config_file;
+use crate::state::{
+    mark_stopped, read_pidfile, remove_state_file, take_stop_marker, ForwardedPort, VmState,
+};
+use crate::stats::read_process_sample;
+use crate::tpm::{start_swtpm, stop_swtpm, tpm_qemu_args, uses_tpm};
+use crate::utils::{
+    find_open_port, get_file_from_image_name, get_pidfile_path, get_qmp_socket_path,
+    get_serial_socket_path, is_port_in_use, run_shell_command, wait_for_process_exit,
+};
+use crate::vfio::prepare_pci_passthrough;
+use crate::webhooks::{get_exit_event, notify};
+use crate::{DEFAULT_CPUS, DEFAULT_HTTPS_PORT, DEFAULT_MEMORY, DEFAULT_SSH_PORT};
 use anyhow::Result;
+use serde_json::json;
+use std::fs::{File, TryLockError};
 use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::Duration;
 
+/// The memory balloon device given to guests. The guest may deflate the
+/// balloon when it runs out of memory, rather than invoking its OOM killer.
+const BALLOON_DEVICE: &str = "virtio-balloon-pci,id=balloon0,deflate-on-oom=on";
+/// How long to wait for a stopped VM to exit before giving up on a restart.
+const RESTART_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to wait for a killed VM to exit before stopping the processes
+/// backing its devices.
+const HELPER_KILL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How a VM stopped with `QemuRunner::stop_and_wait` came to exit, from the
+/// gentlest way to the most forceful.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum StopOutcome {
+    /// The guest powered down when asked to via ACPI.
+    PoweredDown,
+    /// qemu exited when sent `SIGTERM`.
+    Terminated,
+    /// qemu only exited when sent `SIGKILL`.
+    Killed,
+    /// qemu was still running even after being sent `SIGKILL`.
+    StillRunning,
+}
+
+impl StopOutcome {
+    pub fn exit_code(&self) -> i32 {
+        //! Returns the exit code `vm-manager stop --wait` reports this
+        //! outcome with, so that scripts can tell them apart. 1 is left for
+        //! errors.
+        match self {
+            Self::PoweredDown => 0,
+            Self::Terminated => 2,
+            Self::Killed => 3,
+            Self::StillRunning => 4,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::PoweredDown => "powered down",
+            Self::Terminated => "terminated with SIGTERM",
+            Self::Killed => "killed with SIGKILL",
+            Self::StillRunning => "still running after SIGKILL",
+        }
+    }
+}
+
+fn notify_stopped(vm_name: &str, outcome: StopOutcome, config: &Config) {
+    notify(
+        config.get_webhooks(),
+        WebhookEvent::Stopped,
+        vm_name,
+        json!({ "outcome": outcome.description() }),
+    );
+    update_ssh_config_file(config);
+}
+
+pub fn format_command(prefix: &[String], args: &[&str], incoming: Option<&str>) -> String {
+    //! Writes the command a dry run prints, quoted for a shell: qemu's
+    //! `args` after the `prefix` it's launched with, and restoring the guest
+    //! from `incoming` if given.
+    prefix
+        .iter()
+        .map(|arg| arg.as_str())
+        .chain(args.iter().copied())
+        .chain(
+            incoming
+                .map(|incoming| ["-incoming", incoming])
+                .into_iter()
+                .flatten(),
+        )
+        .map(quote_shell_arg)
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[derive(Clone)]
 pub struct QemuRunner {
     daemonize: bool,
     ssh_port: usize,
@@ -11,8 +125,51 @@ pub struct QemuRunner {
     specified_ssh_port: bool,
     specified_https_port: bool,
     image: PathBuf,
+    /// The name of the image, for images whose file is named differently,
+    /// such as those of a VM config's `image_path`. If `None`, the name is
+    /// taken from the image file.
+    image_name: Option<String>,
     pid: Option<usize>,
     vm_config: Option<VMConfig>,
+    /// Whether writes to the disk are discarded when the VM shuts down.
+    ephemeral: bool,
+    /// The instance of the image this VM runs as, if any. Instances run on an
+    /// overlay disk backed by the image, instead of the image itself.
+    instance: Option<String>,
+    /// Memory given to the guest, overriding the VM config.
+    memory: Option<String>,
+    /// Number of virtual CPUs given to the guest, overriding the VM config.
+    cpus: Option<usize>,
+    /// Machine type emulated for the guest, overriding the VM config.
+    machine: Option<String>,
+    /// Installer ISO attached to the guest, overriding the VM config.
+    cdrom: Option<String>,
+    /// Network boot settings, overriding those in the VM config. If `None`,
+    /// the VM isn't network booted.
+    netboot: Option<NetbootConfig>,
+    /// Host directories shared with a running VM, as recorded in its state
+    /// file. VMs which have not been started yet use their VM config's.
+    shares: Vec<Share>,
+    /// Ports forwarded from the host with `--publish`, in addition to those
+    /// of the VM config.
+    published_ports: Vec<PortMapping>,
+    /// How a running VM is connected to the network, as recorded in its
+    /// state file. VMs which have not been started yet use their VM config's.
+    network: Option<NetworkConfig>,
+    /// The host CPUs a running VM runs on, as recorded in its state file.
+    /// VMs which have not been started yet use their VM config's.
+    cpu_affinity: Option<CpuAffinity>,
+    /// The full qemu command line of a running VM, as recorded in its state
+    /// file. Empty for VMs which have not been started yet.
+    command_line: Vec<String>,
+    /// Ports forwarded from the host to a running VM.
+    forwarded_ports: Vec<ForwardedPort>,
+    /// How many times a running VM has been started again by `vm-manager
+    /// supervise` after it exited.
+    crashes: usize,
+    /// Whether starting the VM only prints the command qemu would be run
+    /// with, instead of running anything.
+    dry_run: bool,
 }
 
 impl Default for QemuRunner {
@@ -24,33 +181,59 @@ impl Default for QemuRunner {
             specified_ssh_port: false,
             specified_https_port: false,
             image: PathBuf::from(""),
+            image_name: None,
             pid: None,
             vm_config: None,
+            ephemeral: false,
+            instance: None,
+            memory: None,
+            cpus: None,
+            machine: None,
+            cdrom: None,
+            netboot: None,
+            shares: vec![],
+            published_ports: vec![],
+            network: None,
+            cpu_affinity: None,
+            command_line: vec![],
+            forwarded_ports: vec![],
+            crashes: 0,
+            dry_run: false,
         }
     }
 }
 
 impl QemuRunner {
-    pub fn new(
-        ssh_port: usize,
-        https_port: usize,
-        image_name: &str,
-        pid: Option<usize>,
-        config: &Config,
-    ) -> Self {
+    pub fn from_state(state: VmState) -> Self {
+        //! Creates a runner representing the already running VM described by
+        //! `state`.
         Self {
-            daemonize: true,
-            ssh_port,
-            https_port,
+            daemonize: state.args.iter().any(|arg| arg == "-daemonize"),
+            ssh_port: 0,
+            https_port: 0,
             specified_ssh_port: false,
             specified_https_port: false,
-            image: if let Some(image) = get_file_from_image_name(image_name, config) {
-                image
-            } else {
-                PathBuf::from("")
-            },
-            pid,
+            image: state.image_path,
+            image_name: Some(split_vm_name(&state.image_name).0.to_owned()),
+            pid: Some(state.pid),
             vm_config: None,
+            ephemeral: state.args.iter().any(|arg| arg == "-snapshot"),
+            instance: split_vm_name(&state.image_name)
+                .1
+                .map(|instance| instance.to_owned()),
+            memory: None,
+            cpus: None,
+            machine: None,
+            cdrom: None,
+            netboot: None,
+            shares: state.shares,
+            published_ports: vec![],
+            network: state.network,
+            cpu_affinity: state.cpu_affinity,
+            command_line: state.args,
+            forwarded_ports: state.ports,
+            crashes: state.crashes,
+            dry_run: false,
         }
     }
     pub fn set_ssh_port(&mut self, port: usize) {
@@ -64,38 +247,351 @@ impl QemuRunner {
     pub fn set_image_file(&mut self, image_file: PathBuf) {
         self.image = image_file;
     }
+    pub fn set_image_name(&mut self, image_name: &str) {
+        self.image_name = Some(image_name.to_owned());
+    }
     pub fn set_daemonization_option(&mut self, should_daemonize: bool) {
         self.daemonize = should_daemonize;
     }
-    pub fn ssh_port(&self) -> usize {
-        self.ssh_port
+    pub fn set_ephemeral(&mut self, ephemeral: bool) {
+        self.ephemeral = ephemeral;
+    }
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+    pub fn is_ephemeral(&self) -> bool {
+        self.ephemeral
+    }
+    pub fn set_instance(&mut self, instance: &str) {
+        self.instance = Some(instance.to_owned());
+    }
+    pub fn instance(&self) -> Option<&str> {
+        self.instance.as_deref()
+    }
+    pub fn set_memory(&mut self, memory: &str) {
+        self.memory = Some(memory.to_owned());
+    }
+    pub fn set_cpus(&mut self, cpus: usize) {
+        self.cpus = Some(cpus);
+    }
+    pub fn set_machine(&mut self, machine: &str) {
+        self.machine = Some(machine.to_owned());
+    }
+    pub fn set_cdrom(&mut self, cdrom: &str) {
+        self.cdrom = Some(cdrom.to_owned());
     }
-    pub fn https_port(&self) -> usize {
-        self.https_port
+    pub fn set_netboot(&mut self, netboot: NetbootConfig) {
+        self.netboot = Some(netboot);
+    }
+    pub fn set_published_ports(&mut self, published_ports: &[PortMapping]) {
+        self.published_ports = published_ports.to_vec();
+    }
+    fn published_port_rules(&self) -> Result<Vec<String>, String> {
+        //! Returns the `hostfwd=` rules of the ports forwarded with
+        //! `--publish`, which must be free on the host.
+        let mut rules: Vec<String> = vec![];
+        for port_mapping in &self.published_ports {
+            let rule: String = port_mapping.clone().format_nic();
+            if let Some(port) = rule
+                .strip_prefix("hostfwd=")
+                .and_then(ForwardedPort::parse)
+                .filter(|port| is_port_in_use(port.host_port))
+            {
+                return Err(format!(
+                    "You published host port '{}', which is in use. Choose a different port.",
+                    port.host_port
+                ));
+            }
+            rules.push(rule);
+        }
+        Ok(rules)
+    }
+    fn netboot(&self) -> Option<NetbootConfig> {
+        //! Returns the network boot settings if the VM is network booted,
+        //! with those given on the command line overriding the VM config
+        //! field by field.
+        let netboot: &NetbootConfig = self.netboot.as_ref()?;
+        let vm_netboot: NetbootConfig = self
+            .vm_config
+            .as_ref()
+            .and_then(|vm_config| vm_config.netboot().cloned())
+            .unwrap_or_default();
+        Some(NetbootConfig {
+            tftp: netboot.tftp.clone().or(vm_netboot.tftp),
+            bootfile: netboot.bootfile.clone().or(vm_netboot.bootfile),
+        })
+    }
+    fn memory(&self) -> Option<String> {
+        //! Returns the memory given to the guest on the command line, or else
+        //! in its VM config.
+        self.memory.clone().or_else(|| {
+            self.vm_config
+                .as_ref()
+                .and_then(|vm_config| vm_config.memory().map(|memory| memory.to_owned()))
+        })
+    }
+    fn cpus(&self) -> Option<usize> {
+        //! Returns the number of CPUs given to the guest on the command line,
+        //! or else in its VM config.
+        self.cpus.or_else(|| {
+            self.vm_config
+                .as_ref()
+                .and_then(|vm_config| vm_config.cpus())
+        })
+    }
+    fn machine_args(&self) -> Option<Vec<String>> {
+        //! Returns the `-machine` option selecting the machine type given on
+        //! the command line, or else in the VM config. Secure Boot needs SMM,
+        //! which is only available on q35, so that is its default.
+        let secure_boot: bool = self
+            .vm_config
+            .as_ref()
+            .is_some_and(|vm_config| vm_config.secure_boot());
+        let machine: Option<String> = self.machine.clone().or_else(|| {
+            self.vm_config
+                .as_ref()
+                .and_then(|vm_config| vm_config.machine().map(|machine| machine.to_owned()))
+        });
+        match machine {
+            Some(machine) if secure_boot => Some(vec![
+                "-machine".to_owned(),
+                format!("type={machine},smm=on"),
+            ]),
+            None if secure_boot => Some(vec!["-machine".to_owned(), "type=q35,smm=on".to_owned()]),
+            Some(machine) => Some(vec!["-machine".to_owned(), format!("type={machine}")]),
+            None => None,
+        }
+    }
+    fn cdrom_args(&self) -> Result<Option<Vec<String>>, String> {
+        //! Returns the options attaching the installer ISO given on the
+        //! command line, or else in the VM config. The guest boots from it
+        //! only once, so that it boots the installed system after the
+        //! installer reboots it. Network booting takes precedence over booting
+        //! from the CD-ROM.
+        let cdrom: String = match self.cdrom.clone().or_else(|| {
+            self.vm_config
+                .as_ref()
+                .and_then(|vm_config| vm_config.cdrom().map(|cdrom| cdrom.to_owned()))
+        }) {
+            Some(cdrom) => cdrom,
+            None => return Ok(None),
+        };
+        let path: PathBuf = PathBuf::from(shellexpand::tilde(&cdrom).to_string());
+        if !path.is_file() {
+            return Err(format!("CD-ROM image '{}' does not exist.", path.display()));
+        }
+        let mut args: Vec<String> = vec!["-cdrom".to_owned(), path.display().to_string()];
+        if self.netboot.is_none() {
+            args.push("-boot".to_owned());
+            args.push("once=d".to_owned());
+        }
+        Ok(Some(args))
+    }
+    fn shares(&self) -> &[Share] {
+        match &self.vm_config {
+            Some(vm_config) => vm_config.shares(),
+            None => &self.shares,
+        }
+    }
+    fn network(&self) -> Option<&NetworkConfig> {
+        match &self.vm_config {
+            Some(vm_config) => vm_config.network(),
+            None => self.network.as_ref(),
+        }
+    }
+    fn cpu_affinity(&self) -> Option<&CpuAffinity> {
+        match &self.vm_config {
+            Some(vm_config) => vm_config.cpu_affinity(),
+            None => self.cpu_affinity.as_ref(),
+        }
+    }
+    pub fn pid(&self) -> Option<usize> {
+        self.pid
+    }
+    pub fn image_path(&self) -> &PathBuf {
+        &self.image
+    }
+    pub fn disk_path(&self) -> PathBuf {
+        //! Returns the path of the disk qemu runs on, which is the overlay of
+        //! an instance, or else the image itself.
+        match &self.instance {
+            Some(instance) => get_overlay_path(&self.base_image_name(), instance),
+            None => self.image.clone(),
+        }
+    }
+    pub fn lock_disk(&self) -> Result<Vec<File>, String> {
+        //! Takes an exclusive `flock` on this VM's disk, and a shared one on
+        //! the image an instance's overlay is backed by, until the returned
+        //! files are dropped. Held from `check_disk_not_in_use` until qemu has
+        //! opened the disk, they stop VMs started at the same time on the
+        //! same disk from both passing the check.
+        let lock = |path: PathBuf, exclusive: bool| -> Result<File, String> {
+            let path: PathBuf = std::fs::canonicalize(&path).unwrap_or(path);
+            let file: File = File::open(&path)
+                .map_err(|e| format!("Unable to open image '{}'. {e}", path.display()))?;
+            let locked: Result<(), TryLockError> = if exclusive {
+                file.try_lock()
+            } else {
+                file.try_lock_shared()
+            };
+            match locked {
+                Ok(()) => Ok(file),
+                Err(TryLockError::WouldBlock) => Err(format!(
+                    "Image '{}' is being started by another VM. Starting another VM on it can corrupt it; pass --force to start it anyway.",
+                    path.display()
+                )),
+                Err(TryLockError::Error(e)) => {
+                    Err(format!("Unable to lock image '{}'. {e}", path.display()))
+                }
+            }
+        };
+        let mut locks: Vec<File> = vec![lock(self.disk_path(), true)?];
+        if self.instance.is_some() {
+            locks.push(lock(self.image.clone(), false)?);
+        }
+        Ok(locks)
+    }
+    pub fn check_not_running(&self, running_vms: &[QemuRunner]) -> Result<(), String> {
+        //! Refuses to start this VM while one of `running_vms` has its name,
+        //! as the two would share the state file, sockets and pidfile the VM
+        //! is managed through.
+        match running_vms
+            .iter()
+            .find(|vm| vm.image_name() == self.image_name())
+        {
+            Some(vm) => Err(format!("VM '{}' is already running.", vm.image_name())),
+            None => Ok(()),
+        }
+    }
+    pub fn check_disk_not_in_use(&self, running_vms: &[QemuRunner]) -> Result<(), String> {
+        //! Refuses to start this VM while one of `running_vms` uses its disk,
+        //! as a disk written to by one qemu process while another has it open
+        //! is corrupted. That's the case if they run on the same disk, or if
+        //! either runs on the image the other's instance overlay is backed by.
+        let canonicalize = |path: PathBuf| std::fs::canonicalize(&path).unwrap_or(path);
+        let disk: PathBuf = canonicalize(self.disk_path());
+        let image: PathBuf = canonicalize(self.image.clone());
+        for vm in running_vms {
+            let other_disk: PathBuf = canonicalize(vm.disk_path());
+            if disk == other_disk || disk == canonicalize(vm.image.clone()) || image == other_disk {
+                return Err(format!(
+                    "Image '{}' is in use by running VM '{}'. Starting another VM on it can corrupt it; pass --force to start it anyway.",
+                    self.image.display(),
+                    vm.image_name()
+                ));
+            }
+        }
+        Ok(())
+    }
+    pub fn matches(&self, pattern: &str, instance: Option<&str>) -> bool {
+        //! Returns `true` if this VM runs on an image whose name matches
+        //! `pattern`, as the given instance of it. If `instance` is `None`,
+        //! only a VM running on the image itself matches.
+        matching::matches(pattern, &self.base_image_name()) && self.instance() == instance
+    }
+    pub fn command_line(&self) -> &[String] {
+        &self.command_line
+    }
+    pub fn is_daemonized(&self) -> bool {
+        self.daemonize
+    }
+    pub fn crashes(&self) -> usize {
+        self.crashes
+    }
+    pub fn set_crashes(&mut self, crashes: usize) {
+        self.crashes = crashes;
+    }
+    pub fn forwarded_ports(&self) -> &[ForwardedPort] {
+        &self.forwarded_ports
+    }
+    pub fn forwarded_host_port(&self, guest_port: usize) -> Option<usize> {
+        //! Returns the host port forwarded to the given guest TCP port, if
+        //! any.
+        self.forwarded_ports
+            .iter()
+            .find(|port| port.guest_port == guest_port && port.protocol == "tcp")
+            .map(|port| port.host_port)
+    }
+    pub fn display_url(&self) -> Option<String> {
+        //! Returns the URL of this running VM's VNC or SPICE display, if any.
+        get_display_url(&self.command_line)
+    }
+    pub fn uptime(&self) -> Option<String> {
+        //! Returns the elapsed time since the VM's process was started, as
+        //! reported by `ps` (`[[dd-]hh:]mm:ss`).
+        let pid: usize = self.pid?;
+        let output = run_shell_command(&["ps", "-o", "etime=", "-p", &format!("{pid}")]).ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        String::from_utf8(output.stdout)
+            .ok()
+            .map(|etime| etime.trim().to_owned())
+    }
+    pub fn resident_memory(&self) -> Option<u64> {
+        //! Returns how much of the host's memory the VM's process is using,
+        //! in bytes.
+        read_process_sample(self.pid?).map(|sample| sample.resident_memory)
+    }
+    pub fn run_state(&self) -> Option<String> {
+        //! Returns the run state reported by qemu, e.g. `running` or
+        //! `paused`, or `None` if its QMP socket can't be reached.
+        self.qmp_client()
+            .and_then(|mut client| client.query_status())
+            .ok()
+    }
+    fn daemonizes(&self) -> bool {
+        //! Returns whether qemu forks into the background, which VMs with a
+        //! VM config do if it says to, unless run in the foreground.
+        self.daemonize
+            && self
+                .vm_config
+                .as_ref()
+                .is_none_or(|vm_config| vm_config.daemonize())
     }
     pub fn add_vm_config(&mut self, config: &VMConfig) {
         self.vm_config = Some(config.clone());
     }
     pub fn image_name(&self) -> String {
-        if let Some(fstem) = self.image.file_stem() {
+        //! Returns the name identifying this VM: the image name, followed by
+        //! the instance name for instances (e.g. `deb12@web1`).
+        match &self.instance {
+            Some(instance) => get_instance_vm_name(&self.base_image_name(), instance),
+            None => self.base_image_name(),
+        }
+    }
+    pub fn base_image_name(&self) -> String {
+        if let Some(image_name) = &self.image_name {
+            image_name.clone()
+        } else if let Some(fstem) = self.image.file_stem() {
             fstem.to_os_string().to_str().unwrap().to_owned()
         } else {
             String::from("Can't get image name")
         }
     }
-    fn start_with_vm_config(&self, config: &Config) -> Result<(), String> {
+    pub fn get_command_line(&self, config: &Config) -> Result<Vec<String>, String> {
+        //! Returns the qemu command line the VM is started with from its VM
+        //! config, after the global options, port mappings and settings such
+        //! as `memory` and `network` have been applied. Firmware and other
+        //! files the VM needs are prepared, and the host is checked for what
+        //! the VM relies on, e.g. hugepages and devices passed through.
         if let Some(vm_config) = &self.vm_config {
 
             let drive_args: String = if let Some(image_path) = get_file_from_image_name(vm_config.image_name(), config) {
-format!("file={}", image_path.display())
+format!("file={}", if self.instance.is_some() { self.disk_path() } else { image_path }.display())
             } else {
                 return Err(format!("Unable to find image with name containing '{}' in directory '{}'", vm_config.image_name(), config.get_images_directory()));
             };
+            let throttling_properties: Vec<String> = match vm_config.disk_throttling() {
+                Some(throttling) => throttling_drive_properties(throttling)?,
+                None => vec![],
+            };
+            let drive_args: String = [vec![drive_args], throttling_properties].concat().join(",");
 
             let mut args: Vec<&str> = vec![
                 // TODO: Make this configurable via config file?
                 "qemu-system-x86_64",
-                if vm_config.daemonize() {
+                if self.daemonizes() {
                     "-daemonize"
                 } else {
                     "-nographic"
@@ -104,29 +600,216 @@ format!("file={}", image_path.display())
                 &drive_args,
             ];
 
-            // if we are daemonizing, we want it to run under nohup
-            if vm_config.daemonize() {
-                args.insert(0, "nohup");
+            let display_args: Vec<String> = vm_config
+                .display()
+                .map_or(vec![], |display| display.qemu_args());
+            args.extend(display_args.iter().map(|arg| arg.as_str()));
+
+            let memory: Option<String> = self.memory();
+            let memory_arg: Option<String> = match vm_config.max_memory() {
+                Some(max_memory) => Some(memory_hotplug_arg(
+                    memory.as_deref(),
+                    max_memory,
+                    vm_config.memory_slots(),
+                )?),
+                None => memory.clone(),
+            };
+            let cpus: Option<String> = match vm_config.max_cpus() {
+                Some(max_cpus) => Some(cpu_hotplug_arg(self.cpus(), max_cpus)?),
+                None => self.cpus().map(|cpus| cpus.to_string()),
+            };
+            if let Some(memory_arg) = &memory_arg {
+                args.extend(["-m", memory_arg.as_str()]);
+            }
+            if let Some(cpus) = &cpus {
+                args.extend(["-smp", cpus.as_str()]);
+            }
+            let machine_args: Option<Vec<String>> = self.machine_args();
+            if let Some(machine_args) = &machine_args {
+                args.extend(machine_args.iter().map(|arg| arg.as_str()));
+            }
+            let has_balloon: bool = vm_config
+                .options()
+                .iter()
+                .any(|option| option.arguments().starts_with("virtio-balloon"));
+            if vm_config.balloon() && !has_balloon {
+                args.extend(["-device", BALLOON_DEVICE]);
+            }
+            let tpm_args: Vec<String> = if vm_config.tpm() {
+                tpm_qemu_args(&self.image_name())
+            } else {
+                vec![]
+            };
+            args.extend(tpm_args.iter().map(|arg| arg.as_str()));
+            let firmware_args: Vec<String> = if vm_config.uefi() {
+                prepare_firmware(&self.image_name(), vm_config.secure_boot())?
+            } else {
+                vec![]
+            };
+            args.extend(firmware_args.iter().map(|arg| arg.as_str()));
+            let share_memory: Option<String> = memory.clone().or_else(|| {
+                vm_config
+                    .options()
+                    .iter()
+                    .find(|option| option.flag() == "-m")
+                    .map(|option| option.arguments().to_owned())
+            });
+            let share_args: Vec<String> = share_qemu_args(
+                &self.image_name(),
+                vm_config.shares(),
+                share_memory.as_deref(),
+                vm_config.hugepages(),
+            )?;
+            args.extend(share_args.iter().map(|arg| arg.as_str()));
+            let hugepages_args: Vec<String> = if vm_config.hugepages() {
+                prepare_hugepages(
+                    share_memory.as_deref(),
+                    vm_config
+                        .shares()
+                        .iter()
+                        .any(|share| share.driver == ShareDriver::Virtiofs),
+                )?
+            } else {
+                vec![]
+            };
+            args.extend(hugepages_args.iter().map(|arg| arg.as_str()));
+            let passthrough_args: Vec<String> =
+                prepare_pci_passthrough(vm_config.pci_passthrough())?;
+            args.extend(passthrough_args.iter().map(|arg| arg.as_str()));
+            let cdrom_args: Option<Vec<String>> = self.cdrom_args()?;
+            if let Some(cdrom_args) = &cdrom_args {
+                args.extend(cdrom_args.iter().map(|arg| arg.as_str()));
+            }
+
+            // a `network` other than user-mode networking replaces the VM's
+            // `-nic` options, along with the port mappings merged into them.
+            let mut options: Vec<QemuRunOption> = vm_config.options().clone();
+            let published_port_rules: Vec<String> = self.published_port_rules()?;
+            let mac_address: String = get_mac_address(&self.image_name(), vm_config.network())?;
+            if let Some(nic) = network_nic(
+                &self.image_name(),
+                vm_config.network(),
+                &options,
+                &mac_address,
+            )? {
+                if !published_port_rules.is_empty() {
+                    return Err("--publish requires user-mode networking.".to_owned());
+                }
+                options.retain(|option| option.flag() != "-nic");
+                options.push(QemuRunOption::new(&format!("-nic {nic}")));
             }
 
-            for option in vm_config.options() {
+            // user-mode networking settings, the SMB share, ports published on
+            // the command line and TFTP settings for network booting are added to the
+            // VM's user-mode networking, which is added if it has no network.
+            let user_properties: Vec<String> = user_nic_properties(vm_config.network())?;
+            if !user_properties.is_empty() {
+                update_user_nics(
+                    &mut options,
+                    |nic| add_nic_properties(nic, &user_properties),
+                    "The addressing settings of 'network'",
+                )?;
+            }
+            if let Some(smb_share) = vm_config.smb_share() {
+                let smb_property: String = smb_nic_property(smb_share)?;
+                update_user_nics(
+                    &mut options,
+                    |nic| add_nic_properties(nic, std::slice::from_ref(&smb_property)),
+                    "'smb_share'",
+                )?;
+            }
+            if !published_port_rules.is_empty() {
+                update_user_nics(
+                    &mut options,
+                    |nic| publish_ports(nic, &published_port_rules),
+                    "--publish",
+                )?;
+            }
+            let netboot_properties: Option<Vec<String>> = match self.netboot() {
+                Some(netboot) => Some(user_net_properties(&netboot)?),
+                None => None,
+            };
+            if let Some(properties) = &netboot_properties {
+                args.extend(["-boot", "once=n"]);
+                if !properties.is_empty() {
+                    update_user_nics(
+                        &mut options,
+                        |nic| add_nic_properties(nic, properties),
+                        "'tftp' and 'bootfile'",
+                    )?;
+                }
+            }
+            // private networks are joined through additional NICs, so a VM
+            // relying on qemu's default NIC gets an explicit one first.
+            let private_networks: Vec<&PrivateNetwork> = vm_config
+                .private_networks()
+                .iter()
+                .map(|name| {
+                    config.get_private_network(name).ok_or(format!(
+                        "There is no network named '{name}' in the config's 'networks' section."
+                    ))
+                })
+                .collect::<Result<Vec<&PrivateNetwork>, String>>()?;
+            if !private_networks.is_empty() {
+                let nics: Vec<String> =
+                    private_network_nics(&self.image_name(), &private_networks, &options)?;
+                if !options.iter().any(|option| option.flag() == "-nic") {
+                    options.push(QemuRunOption::new("-nic user"));
+                }
+                for nic in nics {
+                    options.push(QemuRunOption::new(&format!("-nic {nic}")));
+                }
+            }
+            set_nic_mac_address(&mut options, &mac_address);
+
+            // a VM in the foreground keeps its serial console on the terminal,
+            // and users may route it elsewhere themselves.
+            let serial_console: bool = self.daemonizes()
+                && !vm_config
+                    .options()
+                    .iter()
+                    .any(|option| option.as_str().starts_with("-serial"));
+            let runtime_args: Vec<String> =
+                self.runtime_args(serial_console, vm_config.guest_agent())?;
+            args.extend(runtime_args.iter().map(|arg| arg.as_str()));
+
+            for option in &options {
                 // because we have the specific `daemonize` option,
-                // we don't want to duplicate flags if possible.
-                if !option.as_str().starts_with("-daemonize")
-                    && !option.as_str().starts_with("-nographic")
-                {
+                // we don't want to duplicate flags if possible. `-name` is
+                // always set by us, as it's how running VMs are identified.
+                // likewise, an explicit `display`, `memory`, `cpus`,
+                // `machine` or `cdrom` replaces the matching options.
+                let is_managed: bool = option.as_str().starts_with("-daemonize")
+                    || option.as_str().starts_with("-nographic")
+                    || option.as_str().starts_with("-name")
+                    || (vm_config.display().is_some() && is_display_option(option.as_str()))
+                    || (memory.is_some() && option.flag() == "-m")
+                    || (cpus.is_some() && option.flag() == "-smp")
+                    || (machine_args.is_some() && ["-machine", "-M"].contains(&option.flag()))
+                    || (cdrom_args.is_some() && ["-cdrom", "-boot"].contains(&option.flag()))
+                    || (netboot_properties.is_some() && option.flag() == "-boot");
+                if !is_managed {
                     args.append(&mut option.get_opt_list().clone());
                 }
             }
 
-            run_shell_command(&args)?;
-
-            Ok(())
+            Ok(args.iter().map(|arg| arg.to_string()).collect())
         } else {
             Err("No VM config provided!".to_string())
         }
     }
+    fn start_with_vm_config(&self, config: &Config) -> Result<(), String> {
+        let command_line: Vec<String> = self.get_command_line(config)?;
+        let args: Vec<&str> = command_line.iter().map(|arg| arg.as_str()).collect();
+        self.launch(&args, None, config)
+    }
     pub fn start(&self, config: &Config) -> Result<(), String> {
+        // logs only grow while VMs run, so they're rotated as VMs start.
+        if !self.dry_run {
+            if let Err(e) = rotate_vm_logs(&self.image_name(), &config.get_log_rotation()) {
+                eprintln!("{e}");
+            }
+        }
         if self.vm_config.is_some() {
             self.start_with_vm_config(config)
         } else {
@@ -153,12 +836,30 @@ format!("file={}", image_path.display())
             } else {
                 "-nographic"
             };
-            let nic_args: String = format!(
-                "user,model=virtio,hostfwd=tcp::{}-:22,hostfwd=tcp::{}-:443",
-                self.ssh_port, self.https_port
+            let netboot_properties: Option<Vec<String>> = match self.netboot() {
+                Some(netboot) => Some(user_net_properties(&netboot)?),
+                None => None,
+            };
+            let nic_args: String = add_nic_properties(
+                &publish_ports(
+                    &format!(
+                        "user,model=virtio,mac={},hostfwd=tcp::{}-:22,hostfwd=tcp::{}-:443",
+                        get_mac_address(&self.image_name(), None)?,
+                        self.ssh_port,
+                        self.https_port
+                    ),
+                    &self.published_port_rules()?,
+                ),
+                netboot_properties.as_deref().unwrap_or_default(),
             );
 
-            let drive_args: String = format!("file={}", (*self.image).display());
+            let drive_args: String = format!("file={}", self.disk_path().display());
+            let memory: String = self.memory().unwrap_or(DEFAULT_MEMORY.to_owned());
+            let cpus: String = self.cpus().unwrap_or(DEFAULT_CPUS).to_string();
+            let machine_args: Option<Vec<String>> = self.machine_args();
+            let cdrom_args: Option<Vec<String>> = self.cdrom_args()?;
+            let display_args: Vec<String> = DisplayType::None.qemu_args();
+            let runtime_args: Vec<String> = self.runtime_args(self.daemonize, true)?;
 
             let mut args: Vec<&str> = vec![
                 "qemu-system-x86_64",
@@ -166,36 +867,535 @@ format!("file={}", image_path.display())
                 "-drive",
                 &drive_args,
                 "-m",
-                "8G",
+                &memory,
                 "-smp",
-                "4",
+                &cpus,
                 "-accel",
                 "kvm",
                 "-accel",
                 "tcg",
                 "-cpu",
                 "host",
-                "-vnc",
-                "none",
                 "-nic",
                 &nic_args,
+                "-device",
+                BALLOON_DEVICE,
             ];
+            if let Some(machine_args) = &machine_args {
+                args.extend(machine_args.iter().map(|arg| arg.as_str()));
+            }
+            if let Some(cdrom_args) = &cdrom_args {
+                args.extend(cdrom_args.iter().map(|arg| arg.as_str()));
+            }
+            if netboot_properties.is_some() {
+                args.extend(["-boot", "once=n"]);
+            }
+            args.extend(display_args.iter().map(|arg| arg.as_str()));
+            args.extend(runtime_args.iter().map(|arg| arg.as_str()));
+
+            self.launch(&args, None, config)
+        }
+    }
+
+    fn launch(&self, args: &[&str], incoming: Option<&str>, config: &Config) -> Result<(), String> {
+        //! Runs qemu with the given command line, and records the VM's state
+        //! for as long as it runs. With `incoming`, the guest is restored from
+        //! that migration stream, which isn't recorded.
+        //!
+        //! Daemonized VMs are run under `nohup`, with their output appended to
+        //! the VM's qemu log, and their state is written once qemu has forked
+        //! into the background. VMs run in the foreground
+        //! are waited upon, and their state is removed when they exit.
+        //!
+        //! The processes backing the VM's devices are started first, and its
+        //! vCPUs are pinned once it runs, if its `cpu_affinity` asks to.
+        let affinity_prefix: Vec<String> = get_affinity_prefix(self.cpu_affinity())?;
+        if self.dry_run {
+            // a macvtap device, which the prefix opens, is only created as
+            // the VM starts, so the prefix is left out without one.
+            let prefix: Vec<String> =
+                get_launch_prefix(&self.image_name(), self.network()).unwrap_or_default();
+            let prefix: Vec<String> = affinity_prefix.into_iter().chain(prefix).collect();
+            println!("{}", format_command(&prefix, args, incoming));
+            return Ok(());
+        }
+        take_stop_marker(&self.image_name());
+        self.start_helper_processes(args, config)?;
+        let prefix: Vec<String> = get_launch_prefix(&self.image_name(), self.network())
+            .inspect_err(|_| self.stop_helper_processes(config))?;
+        let mut command: Vec<&str> = affinity_prefix
+            .iter()
+            .chain(prefix.iter())
+            .map(|arg| arg.as_str())
+            .collect();
+        command.extend_from_slice(args);
+        if let Some(incoming) = incoming {
+            command.extend(["-incoming", incoming]);
+        }
 
-            if self.daemonize {
-                args.insert(0, "nohup");
+        if args.contains(&"-daemonize") {
+            let (log, log_start): (File, u64) = open_qemu_log(&self.image_name())
+                .inspect_err(|_| self.stop_helper_processes(config))?;
+            let output_log: File = log.try_clone().map_err(|e| e.to_string())?;
+            let status: ExitStatus = Command::new("nohup")
+                .args(&command)
+                .stdin(Stdio::null())
+                .stdout(output_log)
+                .stderr(log)
+                .status()
+                .map_err(|e| {
+                    self.stop_helper_processes(config);
+                    format!("Unable to run qemu. {e}")
+                })?;
+            if !status.success() {
+                self.stop_helper_processes(config);
+                return Err(format!(
+                    "Failed to start VM '{}'. {}",
+                    self.image_name(),
+                    read_startup_errors(&self.image_name(), log_start)
+                ));
             }
 
-            run_shell_command(&args)?;
+            let Some(pid) = read_pidfile(&get_pidfile_path(&self.image_name())) else {
+                self.stop_helper_processes(config);
+                return Err(format!(
+                    "Started VM '{}', but could not read its PID file, so it can't be managed.",
+                    self.image_name()
+                ));
+            };
+            let recorded: Result<(), String> = VmState {
+                crashes: self.crashes,
+                ..VmState::new(
+                    &self.image_name(),
+                    self.image.clone(),
+                    pid,
+                    args,
+                    self.shares(),
+                    self.network(),
+                    self.cpu_affinity(),
+                )
+            }
+            .write();
+            if let Err(e) = recorded {
+                // a VM which isn't recorded can't be managed, so it's stopped.
+                let _ = run_shell_command(&["kill", &pid.to_string()]);
+                wait_for_process_exit(pid, HELPER_KILL_TIMEOUT);
+                self.stop_helper_processes(config);
+                return Err(e);
+            }
+            self.pin_vcpus();
+            notify(
+                config.get_webhooks(),
+                WebhookEvent::Started,
+                &self.image_name(),
+                json!({ "pid": pid }),
+            );
+            update_ssh_config_file(config);
+            hostnames::publish(&self.image_name(), config.get_hostnames());
             Ok(())
+        } else {
+            let mut child: Child = Command::new(command[0])
+                .args(&command[1..])
+                .spawn()
+                .map_err(|e| {
+                    self.stop_helper_processes(config);
+                    e.to_string()
+                })?;
+
+            let state: VmState = VmState::new(
+                &self.image_name(),
+                self.image.clone(),
+                child.id() as usize,
+                args,
+                self.shares(),
+                self.network(),
+                self.cpu_affinity(),
+            );
+            if let Err(e) = state.write() {
+                let _ = child.kill();
+                let _ = child.wait();
+                self.stop_helper_processes(config);
+                return Err(e);
+            }
+            self.pin_vcpus();
+            notify(
+                config.get_webhooks(),
+                WebhookEvent::Started,
+                &self.image_name(),
+                json!({ "pid": child.id() }),
+            );
+            update_ssh_config_file(config);
+            hostnames::publish(&self.image_name(), config.get_hostnames());
+            let status: Result<ExitStatus, String> = child.wait().map_err(|e| e.to_string());
+            state.remove();
+            update_ssh_config_file(config);
+            self.stop_helper_processes(config);
+
+            let status: ExitStatus = status?;
+            let stopped: bool = take_stop_marker(&self.image_name());
+            if let Some(event) = get_exit_event(stopped, !status.success()) {
+                notify(
+                    config.get_webhooks(),
+                    event,
+                    &self.image_name(),
+                    json!({ "status": status.to_string() }),
+                );
+            }
+            if status.success() {
+                Ok(())
+            } else {
+                Err(format!("VM '{}' exited with {status}.", self.image_name()))
+            }
+        }
+    }
+
+    fn pin_vcpus(&self) {
+        //! Pins each of the VM's vCPUs to a host CPU, if its `cpu_affinity`
+        //! asks to. The VM is left running unpinned if that fails.
+        if let Some(affinity) = self.cpu_affinity().filter(|affinity| affinity.pin_vcpus) {
+            if let Err(e) = pin_vcpus(&self.image_name(), affinity) {
+                eprintln!("Unable to pin the vCPUs of VM '{}'. {e}", self.image_name());
+            }
+        }
+    }
+
+    fn start_helper_processes(&self, args: &[&str], config: &Config) -> Result<(), String> {
+        //! Starts the processes backing the VM's devices: `swtpm` for an
+        //! emulated TPM, and a `virtiofsd` for each virtiofs share. The VM's
+        //! tap or macvtap device is created as well.
+        if uses_tpm(args) {
+            start_swtpm(&self.image_name())?;
+        }
+        if let Some(network) = self.network() {
+            let mac_address: String = get_mac_address(&self.image_name(), Some(network))?;
+            create_network_device(&self.image_name(), network, &mac_address)
+                .inspect_err(|_| self.stop_helper_processes(config))?;
+        }
+        for share in self
+            .shares()
+            .iter()
+            .filter(|share| share.driver == ShareDriver::Virtiofs)
+        {
+            start_virtiofsd(&self.image_name(), share)
+                .inspect_err(|_| self.stop_helper_processes(config))?;
+        }
+        Ok(())
+    }
+
+    fn stop_helper_processes(&self, config: &Config) {
+        //! Stops the processes backing the VM's devices, if any are still
+        //! running, withdraws its published name and deletes its tap or
+        //! macvtap device.
+        stop_swtpm(&self.image_name());
+        stop_virtiofsd(&self.image_name());
+        hostnames::unpublish(&self.image_name(), config.get_hostnames());
+        if let Some(network) = self.network() {
+            delete_network_device(&self.image_name(), network);
+        }
+    }
+
+    fn runtime_args(&self, serial_console: bool, guest_agent: bool) -> Result<Vec<String>, String> {
+        //! Returns the arguments which tag the qemu process with the image
+        //! name, expose this VM's QMP server on a unix socket and have qemu
+        //! write its PID to a file, creating the runtime directory holding the
+        //! latter two if needed. If `serial_console` is set, the guest's serial
+        //! console is exposed on a unix socket as well, for `vm-manager
+        //! console`, and logged, and if `guest_agent` is set, so is the channel to its
+        //! guest agent. Ephemeral VMs also get `-snapshot`.
+        let socket_path: PathBuf = get_qmp_socket_path(&self.image_name());
+        let pidfile_path: PathBuf = get_pidfile_path(&self.image_name());
+        if let Some(directory) = socket_path.parent() {
+            std::fs::create_dir_all(directory).map_err(|e| {
+                format!(
+                    "Unable to create runtime directory '{}'. {e}",
+                    directory.display()
+                )
+            })?;
+        }
+        let mut args: Vec<String> = vec![
+            "-name".to_owned(),
+            format!("guest={}", self.image_name()),
+            "-qmp".to_owned(),
+            format!("unix:{},server,nowait", socket_path.display()),
+            "-pidfile".to_owned(),
+            pidfile_path.display().to_string(),
+        ];
+        if self.ephemeral {
+            args.push("-snapshot".to_owned());
+        }
+        if serial_console {
+            args.extend(serial_console_args(
+                &self.image_name(),
+                &get_serial_socket_path(&self.image_name()),
+            ));
+        }
+        if guest_agent {
+            args.extend(guest_agent_qemu_args(&self.image_name()));
         }
+        Ok(args)
     }
 
-    pub fn stop(&self) -> Result<(), String> {
+    pub fn qmp_client(&self) -> Result<QmpClient, String> {
+        //! Connects to the QMP server of this VM.
+        QmpClient::connect_to_vm(&self.image_name())
+    }
+
+    pub fn stop(
+        &self,
+        force: bool,
+        shutdown_timeout: Duration,
+        config: &Config,
+    ) -> Result<(), String> {
+        //! Stops the VM. Unless `force` is set, the guest is first asked to
+        //! power down via ACPI (`system_powerdown`), and is only killed if it
+        //! hasn't exited after `shutdown_timeout`.
         if let Some(pid) = self.pid {
-            run_shell_command(&["kill", &format!("{}", pid)])?;
+            mark_stopped(&self.image_name());
+            if !force && self.power_down(pid, shutdown_timeout) {
+                remove_state_file(&self.image_name());
+                self.stop_helper_processes(config);
+                notify_stopped(&self.image_name(), StopOutcome::PoweredDown, config);
+                return Ok(());
+            }
+            run_shell_command(&["kill", &format!("{}", pid)]).inspect_err(|_| {
+                take_stop_marker(&self.image_name());
+            })?;
+            remove_state_file(&self.image_name());
+            // a killed qemu may not have exited yet, and so may still hold
+            // its connections to `swtpm` and `virtiofsd`.
+            wait_for_process_exit(pid, HELPER_KILL_TIMEOUT);
+            self.stop_helper_processes(config);
+            notify_stopped(&self.image_name(), StopOutcome::Terminated, config);
             Ok(())
         } else {
             Err("No PID provided; cannot stop VM!".to_string())
         }
     }
+
+    fn power_down(&self, pid: usize, timeout: Duration) -> bool {
+        //! Asks the guest to power down via ACPI (`system_powerdown`), and
+        //! returns whether qemu exited within `timeout`.
+        match self
+            .qmp_client()
+            .and_then(|mut client| client.system_powerdown())
+        {
+            Ok(()) => {
+                if wait_for_process_exit(pid, timeout) {
+                    return true;
+                }
+                eprintln!(
+                    "VM '{}' did not shut down within {} seconds; killing it.",
+                    self.image_name(),
+                    timeout.as_secs()
+                );
+            }
+            Err(e) => eprintln!("{e} Falling back to killing the VM."),
+        }
+        false
+    }
+
+    pub fn stop_and_wait(
+        &self,
+        force: bool,
+        timeout: Duration,
+        config: &Config,
+    ) -> Result<StopOutcome, String> {
+        //! Stops the VM like `stop`, but only returns once the qemu process
+        //! has actually exited, escalating each time it hasn't exited within
+        //! `timeout`: from asking the guest to power down via ACPI, to
+        //! `SIGTERM`, to `SIGKILL`. `force` skips asking the guest. Returns
+        //! how the VM came to exit.
+        let pid: usize = self.pid.ok_or("No PID provided; cannot stop VM!")?;
+        mark_stopped(&self.image_name());
+        let outcome: StopOutcome = match self.wait_for_stop(pid, force, timeout) {
+            Ok(StopOutcome::StillRunning) => {
+                // the VM is still running, so its state is kept
+                take_stop_marker(&self.image_name());
+                return Ok(StopOutcome::StillRunning);
+            }
+            Ok(outcome) => outcome,
+            Err(e) => {
+                take_stop_marker(&self.image_name());
+                return Err(e);
+            }
+        };
+        remove_state_file(&self.image_name());
+        self.stop_helper_processes(config);
+        notify_stopped(&self.image_name(), outcome, config);
+        Ok(outcome)
+    }
+
+    fn wait_for_stop(
+        &self,
+        pid: usize,
+        force: bool,
+        timeout: Duration,
+    ) -> Result<StopOutcome, String> {
+        //! Asks qemu to exit ever more forcefully, as `stop_and_wait` does,
+        //! until it has.
+        if !force && self.power_down(pid, timeout) {
+            return Ok(StopOutcome::PoweredDown);
+        }
+        run_shell_command(&["kill", "-TERM", &pid.to_string()])?;
+        if wait_for_process_exit(pid, timeout) {
+            return Ok(StopOutcome::Terminated);
+        }
+        eprintln!(
+            "VM '{}' did not exit within {} seconds of SIGTERM; sending it SIGKILL.",
+            self.image_name(),
+            timeout.as_secs()
+        );
+        run_shell_command(&["kill", "-KILL", &pid.to_string()])?;
+        if wait_for_process_exit(pid, timeout) {
+            Ok(StopOutcome::Killed)
+        } else {
+            Ok(StopOutcome::StillRunning)
+        }
+    }
+
+    pub fn restart(&self, shutdown_timeout: Duration, config: &Config) -> Result<(), String> {
+        //! Stops the VM, waits for the qemu process to exit, and then starts it
+        //! again using the exact command line it was previously running with,
+        //! so that the same ports and options are kept.
+        if self.command_line.is_empty() {
+            return Err(format!(
+                "Unable to determine the command line of VM '{}'; cannot restart it.",
+                self.image_name()
+            ));
+        }
+
+        self.stop(false, shutdown_timeout, config)?;
+
+        // the forwarded ports are only released once qemu has fully exited.
+        if let Some(pid) = self.pid {
+            if !wait_for_process_exit(pid, RESTART_TIMEOUT) {
+                return Err(format!(
+                    "VM '{}' (PID {pid}) did not exit within {} seconds; not restarting it.",
+                    self.image_name(),
+                    RESTART_TIMEOUT.as_secs()
+                ));
+            }
+        }
+
+        let args: Vec<&str> = self.command_line.iter().map(|arg| arg.as_str()).collect();
+        self.launch(&args, None, config)
+    }
+
+    pub fn relaunch(&self, config: &Config) -> Result<(), String> {
+        //! Starts the VM again after it exited, using the exact command line
+        //! it was running with. Processes backing its devices which outlived
+        //! it are stopped first.
+        self.stop_helper_processes(config);
+        let args: Vec<&str> = self.command_line.iter().map(|arg| arg.as_str()).collect();
+        self.launch(&args, None, config)
+    }
+
+    pub fn resume_saved(&self, incoming: &str, config: &Config) -> Result<(), String> {
+        //! Starts the VM with the command line it was saved with, restoring
+        //! its RAM and device state from the migration stream `incoming`
+        //! instead of booting the guest.
+        let args: Vec<&str> = self.command_line.iter().map(|arg| arg.as_str()).collect();
+        self.launch(&args, Some(incoming), config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_command, QemuRunner, StopOutcome};
+    use crate::state::VmState;
+    use std::path::PathBuf;
+
+    fn running_vm(name: &str, image: &str) -> QemuRunner {
+        QemuRunner::from_state(VmState::new(
+            name,
+            PathBuf::from(image),
+            1,
+            &[],
+            &[],
+            None,
+            None,
+        ))
+    }
+
+    #[test]
+    fn test_check_disk_not_in_use() {
+        let running: Vec<QemuRunner> = vec![
+            running_vm("staging", "/srv/images/deb12.img"),
+            running_vm("win11@a", "/srv/images/win11.img"),
+        ];
+        assert!(running_vm("prod-sim", "/srv/images/deb12.img")
+            .check_disk_not_in_use(&running)
+            .unwrap_err()
+            .contains("in use by running VM 'staging'"));
+        assert!(running_vm("win11", "/srv/images/win11.img")
+            .check_disk_not_in_use(&running)
+            .is_err());
+        assert!(running_vm("staging", "/srv/images/arch.img")
+            .check_disk_not_in_use(&running)
+            .is_ok());
+        assert!(running_vm("staging", "/srv/images/arch.img")
+            .check_not_running(&running)
+            .is_err());
+        assert!(running_vm("win11", "/srv/images/win11.img")
+            .check_not_running(&running)
+            .is_ok());
+        assert!(running_vm("win11@b", "/srv/images/win11.img")
+            .check_disk_not_in_use(&running)
+            .is_ok());
+        assert!(running_vm("deb12@c", "/srv/images/deb12.img")
+            .check_disk_not_in_use(&running)
+            .is_err());
+        assert!(running_vm("arch", "/srv/images/arch.img")
+            .check_disk_not_in_use(&running)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_lock_disk() {
+        let image: PathBuf =
+            std::env::temp_dir().join(format!("lock-disk-{}.img", std::process::id()));
+        std::fs::write(&image, b"").unwrap();
+        let vm: QemuRunner = running_vm("lock", image.to_str().unwrap());
+        let locks: Vec<std::fs::File> = vm.lock_disk().unwrap();
+        assert!(vm.lock_disk().is_err());
+        drop(locks);
+        assert!(vm.lock_disk().is_ok());
+        std::fs::remove_file(&image).unwrap();
+    }
+
+    #[test]
+    fn test_format_command() {
+        let args: Vec<&str> = vec!["qemu-system-x86_64", "-name", "deb12 web", "-m", "2G"];
+        assert_eq!(
+            format_command(&[], &args, None),
+            "qemu-system-x86_64 -name 'deb12 web' -m 2G"
+        );
+        assert_eq!(
+            format_command(
+                &["taskset".to_owned(), "-c".to_owned(), "0-3".to_owned()],
+                &args[..1],
+                Some("exec:cat '/tmp/deb12.save'")
+            ),
+            "taskset -c 0-3 qemu-system-x86_64 -incoming 'exec:cat '\\''/tmp/deb12.save'\\'''"
+        );
+    }
+
+    #[test]
+    fn test_stop_outcome() {
+        let outcomes: [StopOutcome; 4] = [
+            StopOutcome::Terminated,
+            StopOutcome::PoweredDown,
+            StopOutcome::Killed,
+            StopOutcome::PoweredDown,
+        ];
+        let most_forceful: StopOutcome = outcomes
+            .into_iter()
+            .fold(StopOutcome::PoweredDown, StopOutcome::max);
+        assert_eq!(most_forceful, StopOutcome::Killed);
+        assert_eq!(most_forceful.exit_code(), 3);
+        assert!(StopOutcome::StillRunning > StopOutcome::Killed);
+        assert_eq!(StopOutcome::PoweredDown.exit_code(), 0);
+        assert_eq!(StopOutcome::Terminated.exit_code(), 2);
+        assert_eq!(StopOutcome::StillRunning.exit_code(), 4);
+    }
 }
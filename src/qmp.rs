@@ -0,0 +1,456 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::image::ImageInfo;
+use crate::utils::get_qmp_socket_path;
+
+/// How long to wait for the QMP server to answer before giving up.
+const QMP_READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for a VM which was just started to accept QMP
+/// connections.
+const STARTED_VM_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the progress of a migration is checked.
+const MIGRATION_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The `version` section of the greeting sent by the QMP server.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct QmpVersion {
+    pub qemu: QmpQemuVersion,
+    pub package: String,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct QmpQemuVersion {
+    pub major: u64,
+    pub minor: u64,
+    pub micro: u64,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct QmpGreetingBody {
+    pub version: QmpVersion,
+    pub capabilities: Vec<String>,
+}
+
+/// The error object of a failed command.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct QmpError {
+    pub class: String,
+    pub desc: String,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct QmpTimestamp {
+    pub seconds: i64,
+    pub microseconds: i64,
+}
+
+/// An asynchronous event emitted by qemu, such as `SHUTDOWN` or `STOP`.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct QmpEvent {
+    pub event: String,
+    #[serde(default)]
+    pub data: Value,
+    pub timestamp: QmpTimestamp,
+}
+
+/// Any message which the QMP server may send.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum QmpMessage {
+    Greeting {
+        #[serde(rename = "QMP")]
+        qmp: QmpGreetingBody,
+    },
+    Return {
+        #[serde(rename = "return")]
+        value: Value,
+        id: Option<Value>,
+    },
+    Error {
+        error: QmpError,
+        id: Option<Value>,
+    },
+    Event(QmpEvent),
+}
+
+impl QmpMessage {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        //! Parses a single line received from the QMP server.
+        serde_json::from_str::<Self>(line)
+            .map_err(|e| format!("Unable to parse QMP message '{}'. {e}", line.trim()))
+    }
+}
+
+/// A client for the QEMU Machine Protocol, connected to the QMP socket of a
+/// single VM.
+///
+/// Commands are executed synchronously. Any events received while waiting for
+/// a command's response are kept, and can be retrieved with `take_events` or
+/// waited upon with `wait_for_event`.
+pub struct QmpClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    #[allow(unused)]
+    version: QmpVersion,
+    events: Vec<QmpEvent>,
+    next_id: u64,
+}
+
+impl QmpClient {
+    pub fn connect(socket_path: &Path) -> Result<Self, String> {
+        //! Connects to the QMP socket at `socket_path`, reads the server's
+        //! greeting and negotiates capabilities, leaving the connection in
+        //! command mode.
+        let stream: UnixStream = UnixStream::connect(socket_path).map_err(|e| {
+            format!(
+                "Unable to connect to QMP socket '{}'. {e}",
+                socket_path.display()
+            )
+        })?;
+        stream
+            .set_read_timeout(Some(QMP_READ_TIMEOUT))
+            .map_err(|e| e.to_string())?;
+        let writer: UnixStream = stream.try_clone().map_err(|e| e.to_string())?;
+
+        let mut reader: BufReader<UnixStream> = BufReader::new(stream);
+        let version: QmpVersion = match Self::read_message_from(&mut reader)? {
+            QmpMessage::Greeting { qmp } => qmp.version,
+            message => return Err(format!("Expected QMP greeting, got {message:?}.")),
+        };
+
+        let mut client: Self = Self {
+            reader,
+            writer,
+            version,
+            events: vec![],
+            next_id: 0,
+        };
+        client.execute("qmp_capabilities", None)?;
+        Ok(client)
+    }
+
+    pub fn connect_to_vm(image_name: &str) -> Result<Self, String> {
+        //! Connects to the QMP socket of the VM running on the given image.
+        let socket_path: PathBuf = get_qmp_socket_path(image_name);
+        Self::connect(&socket_path)
+    }
+
+    pub fn connect_to_started_vm(image_name: &str) -> Result<Self, String> {
+        //! Connects to the QMP socket of a VM which was just started, waiting
+        //! for qemu to accept connections.
+        let started_waiting: Instant = Instant::now();
+        loop {
+            match Self::connect_to_vm(image_name) {
+                Ok(client) => return Ok(client),
+                Err(e) if started_waiting.elapsed() > STARTED_VM_CONNECT_TIMEOUT => return Err(e),
+                Err(_) => sleep(Duration::from_millis(100)),
+            }
+        }
+    }
+
+    fn read_message_from(reader: &mut BufReader<UnixStream>) -> Result<QmpMessage, String> {
+        let mut line: String = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) => Err("QMP connection closed by qemu.".to_owned()),
+            Ok(_) => QmpMessage::parse(&line),
+            Err(e) => Err(format!("Unable to read from QMP socket. {e}")),
+        }
+    }
+
+    #[allow(unused)]
+    pub fn version(&self) -> &QmpVersion {
+        &self.version
+    }
+
+    pub fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value, String> {
+        //! Executes `command` with the given arguments, returning the contents
+        //! of the `return` member on success, or the error description on
+        //! failure.
+        let id: u64 = self.next_id;
+        self.next_id += 1;
+
+        let mut request: Value = json!({ "execute": command, "id": id });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+        self.writer
+            .write_all(format!("{request}\n").as_bytes())
+            .map_err(|e| format!("Unable to send '{command}' over QMP. {e}"))?;
+
+        loop {
+            match Self::read_message_from(&mut self.reader)? {
+                QmpMessage::Return {
+                    value,
+                    id: Some(response_id),
+                } if response_id == id => return Ok(value),
+                QmpMessage::Error {
+                    error,
+                    id: Some(response_id),
+                } if response_id == id => {
+                    return Err(format!("QMP command '{command}' failed: {}", error.desc))
+                }
+                QmpMessage::Event(event) => self.events.push(event),
+                // responses to other commands, or a stray greeting
+                _ => (),
+            }
+        }
+    }
+
+    #[allow(unused)]
+    pub fn take_events(&mut self) -> Vec<QmpEvent> {
+        //! Returns, and forgets, every event received so far.
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn wait_for_event(
+        &mut self,
+        event_name: &str,
+        timeout: Duration,
+    ) -> Result<QmpEvent, String> {
+        //! Blocks until an event named `event_name` is received, or `timeout`
+        //! elapses. Events which were already received are checked first.
+        if let Some(position) = self.events.iter().position(|e| e.event == event_name) {
+            return Ok(self.events.remove(position));
+        }
+
+        let started_waiting: Instant = Instant::now();
+        while started_waiting.elapsed() < timeout {
+            match Self::read_message_from(&mut self.reader) {
+                Ok(QmpMessage::Event(event)) if event.event == event_name => return Ok(event),
+                Ok(QmpMessage::Event(event)) => self.events.push(event),
+                Ok(_) => (),
+                // the read timed out; check the deadline and keep waiting
+                Err(_) if started_waiting.elapsed() < timeout => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Err(format!(
+            "Timed out after {} seconds waiting for QMP event '{event_name}'.",
+            timeout.as_secs()
+        ))
+    }
+
+    pub fn query_status(&mut self) -> Result<String, String> {
+        //! Returns the run state of the VM, e.g. `running` or `paused`.
+        let status: Value = self.execute("query-status", None)?;
+        status["status"]
+            .as_str()
+            .map(|s| s.to_owned())
+            .ok_or(format!("Unexpected response to 'query-status': {status}"))
+    }
+
+    pub fn system_powerdown(&mut self) -> Result<(), String> {
+        //! Asks the guest to power down via ACPI.
+        self.execute("system_powerdown", None).map(|_| ())
+    }
+
+    pub fn pause(&mut self) -> Result<(), String> {
+        //! Stops the guest's vCPUs.
+        self.execute("stop", None).map(|_| ())
+    }
+
+    pub fn resume(&mut self) -> Result<(), String> {
+        //! Resumes the guest's vCPUs after `pause`.
+        self.execute("cont", None).map(|_| ())
+    }
+
+    pub fn migrate(&mut self, uri: &str) -> Result<(), String> {
+        //! Starts migrating the VM's RAM and device state to `uri`, e.g.
+        //! `exec:cat > vm.state`.
+        self.execute("migrate", Some(json!({ "uri": uri })))
+            .map(|_| ())
+    }
+
+    pub fn wait_for_migration(&mut self) -> Result<(), String> {
+        //! Blocks until the outgoing migration started by `migrate` has
+        //! completed.
+        loop {
+            let migration: Value = self.execute("query-migrate", None)?;
+            match migration["status"].as_str() {
+                Some("completed") => return Ok(()),
+                Some("failed") | Some("cancelled") => {
+                    return Err(format!(
+                        "Migration failed. {}",
+                        migration["error-desc"].as_str().unwrap_or_default()
+                    ))
+                }
+                _ => sleep(MIGRATION_POLL_INTERVAL),
+            }
+        }
+    }
+
+    pub fn query_vcpu_threads(&mut self) -> Result<Vec<(usize, usize)>, String> {
+        //! Returns the index and host thread ID of each of the guest's vCPUs.
+        let cpus: Value = self.execute("query-cpus-fast", None)?;
+        cpus.as_array()
+            .and_then(|cpus| {
+                cpus.iter()
+                    .map(|cpu| {
+                        Some((
+                            cpu["cpu-index"].as_u64()? as usize,
+                            cpu["thread-id"].as_u64()? as usize,
+                        ))
+                    })
+                    .collect::<Option<Vec<(usize, usize)>>>()
+            })
+            .ok_or(format!("Unexpected response to 'query-cpus-fast': {cpus}"))
+    }
+
+    pub fn query_balloon(&mut self) -> Result<u64, String> {
+        //! Returns how much memory the guest currently has, in bytes, as set
+        //! by its memory balloon.
+        let balloon: Value = self.execute("query-balloon", None)?;
+        balloon["actual"]
+            .as_u64()
+            .ok_or(format!("Unexpected response to 'query-balloon': {balloon}"))
+    }
+
+    pub fn set_balloon(&mut self, bytes: u64) -> Result<(), String> {
+        //! Asks the guest to inflate or deflate its memory balloon until it
+        //! has `bytes` of memory. The guest does so gradually.
+        self.execute("balloon", Some(json!({ "value": bytes })))
+            .map(|_| ())
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Duration) -> Result<(), String> {
+        //! Changes how long to wait for the QMP server to answer, for commands
+        //! which take longer than usual.
+        self.reader
+            .get_ref()
+            .set_read_timeout(Some(timeout))
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn human_monitor_command(&mut self, command_line: &str) -> Result<String, String> {
+        //! Runs a command of the human monitor (HMP), for which QMP has no
+        //! stable equivalent, returning its output.
+        let output: Value = self.execute(
+            "human-monitor-command",
+            Some(json!({ "command-line": command_line })),
+        )?;
+        Ok(output.as_str().unwrap_or_default().to_owned())
+    }
+
+    pub fn query_image_info(&mut self, image_path: &Path) -> Result<ImageInfo, String> {
+        //! Returns the details of the given image as seen by the running VM,
+        //! including its internal snapshots.
+        let devices: Value = self.execute("query-block", None)?;
+        let image: &Value = devices
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|device| device["inserted"]["file"].as_str() == image_path.to_str())
+            .map(|device| &device["inserted"]["image"])
+            .ok_or(format!(
+                "VM has no block device backed by '{}'.",
+                image_path.display()
+            ))?;
+        serde_json::from_value::<ImageInfo>(image.clone())
+            .map_err(|e| format!("Unexpected response to 'query-block': {e}"))
+    }
+
+    pub fn find_block_device(&mut self, image_path: &Path) -> Result<String, String> {
+        //! Returns the name of the block device backed by the given image file.
+        let devices: Value = self.execute("query-block", None)?;
+        devices
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|device| device["inserted"]["file"].as_str() == image_path.to_str())
+            .and_then(|device| device["device"].as_str())
+            .map(|device| device.to_owned())
+            .ok_or(format!(
+                "VM has no block device backed by '{}'.",
+                image_path.display()
+            ))
+    }
+
+    pub fn snapshot_onto_overlay(
+        &mut self,
+        device: &str,
+        overlay_path: &Path,
+    ) -> Result<(), String> {
+        //! Switches `device` over to the existing qcow2 overlay at
+        //! `overlay_path`, whose backing file must be the device's current
+        //! image. From then on, the guest's writes only go to the overlay.
+        self.execute(
+            "blockdev-snapshot-sync",
+            Some(json!({
+                "device": device,
+                "snapshot-file": overlay_path,
+                "format": "qcow2",
+                "mode": "existing",
+            })),
+        )
+        .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QmpError, QmpMessage};
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_greeting() {
+        let message: QmpMessage = QmpMessage::parse(
+            r#"{"QMP": {"version": {"qemu": {"micro": 1, "minor": 2, "major": 8}, "package": "Debian 1:8.2.1"}, "capabilities": ["oob"]}}"#,
+        )
+        .unwrap();
+        match message {
+            QmpMessage::Greeting { qmp } => {
+                assert_eq!(qmp.version.qemu.major, 8);
+                assert_eq!(qmp.version.qemu.minor, 2);
+                assert_eq!(qmp.capabilities, vec!["oob"]);
+            }
+            other => panic!("Expected greeting, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_return_and_error() {
+        assert_eq!(
+            QmpMessage::parse(r#"{"return": {"status": "running", "running": true}, "id": 3}"#)
+                .unwrap(),
+            QmpMessage::Return {
+                value: json!({"status": "running", "running": true}),
+                id: Some(json!(3)),
+            }
+        );
+        assert_eq!(
+            QmpMessage::parse(
+                r#"{"error": {"class": "CommandNotFound", "desc": "The command foo has not been found"}, "id": 4}"#
+            )
+            .unwrap(),
+            QmpMessage::Error {
+                error: QmpError {
+                    class: "CommandNotFound".to_owned(),
+                    desc: "The command foo has not been found".to_owned(),
+                },
+                id: Some(json!(4)),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_event() {
+        match QmpMessage::parse(
+            r#"{"timestamp": {"seconds": 1700000000, "microseconds": 5}, "event": "SHUTDOWN", "data": {"guest": true, "reason": "guest-shutdown"}}"#,
+        )
+        .unwrap()
+        {
+            QmpMessage::Event(event) => {
+                assert_eq!(event.event, "SHUTDOWN");
+                assert_eq!(event.data["reason"], "guest-shutdown");
+                assert_eq!(event.timestamp.seconds, 1700000000);
+            }
+            other => panic!("Expected event, got {other:?}"),
+        }
+    }
+}
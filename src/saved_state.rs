@@ -0,0 +1,130 @@
+use std::fs::{self, read_dir};
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::qemu_runner::QemuRunner;
+use crate::qmp::QmpClient;
+use crate::state::{get_state_file_path, VmState};
+use crate::SAVED_STATE_DIRECTORY;
+
+fn get_saved_state_path(vm_name: &str, extension: &str) -> PathBuf {
+    //! Returns the path of a saved VM's file with the given extension: its
+    //! RAM and device state in `.state`, and how it was run in `.json`.
+    PathBuf::from(
+        shellexpand::tilde(&format!("{SAVED_STATE_DIRECTORY}/{vm_name}.{extension}")).to_string(),
+    )
+}
+
+pub fn is_saved(vm_name: &str) -> bool {
+    //! Returns whether the VM was saved with `vm-manager save`, and not yet
+    //! resumed.
+    get_saved_state_path(vm_name, "json").is_file()
+}
+
+pub fn check_not_saved(vm_name: &str) -> Result<(), String> {
+    //! Refuses to boot a saved VM afresh, as resuming it afterwards would
+    //! corrupt its disk.
+    if is_saved(vm_name) {
+        return Err(format!(
+            "VM '{vm_name}' was saved with 'vm-manager save'. Resume it with 'vm-manager resume-from', or discard the saved state with 'vm-manager resume-from --discard'."
+        ));
+    }
+    Ok(())
+}
+
+pub fn list_saved_vms() -> Vec<QemuRunner> {
+    //! Returns every saved VM, as it was running when saved.
+    let directory: String = shellexpand::tilde(SAVED_STATE_DIRECTORY).to_string();
+    read_dir(directory)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    path.extension()
+                        .is_some_and(|extension| extension == "json")
+                })
+                .filter_map(|path| fs::read_to_string(path).ok())
+                .filter_map(|contents| serde_json::from_str::<VmState>(&contents).ok())
+                .map(QemuRunner::from_state)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn save_vm(vm: &QemuRunner, config: &Config) -> Result<PathBuf, String> {
+    //! Saves the RAM and device state of a running VM to disk, then stops it.
+    //! The guest is paused first, so that the state is written in one pass.
+    //! If saving fails, the guest is resumed.
+    let vm_name: String = vm.image_name();
+    if !vm.command_line().iter().any(|arg| arg == "-daemonize") {
+        return Err(format!(
+            "VM '{vm_name}' runs in the foreground. Only VMs run in the background can be saved."
+        ));
+    }
+    let state_path: PathBuf = get_saved_state_path(&vm_name, "state");
+    if let Some(directory) = state_path.parent() {
+        fs::create_dir_all(directory).map_err(|e| {
+            format!(
+                "Unable to create saved states directory '{}'. {e}",
+                directory.display()
+            )
+        })?;
+    }
+    if state_path.display().to_string().contains('\'') {
+        return Err(format!(
+            "Unable to save VM '{vm_name}' to '{}', as its path contains a quote.",
+            state_path.display()
+        ));
+    }
+
+    let mut qmp: QmpClient = vm.qmp_client()?;
+    qmp.pause()?;
+    let saved: Result<(), String> = qmp
+        .migrate(&format!("exec:cat > '{}'", state_path.display()))
+        .and_then(|_| qmp.wait_for_migration())
+        .and_then(|_| {
+            fs::copy(
+                get_state_file_path(&vm_name),
+                get_saved_state_path(&vm_name, "json"),
+            )
+            .map(|_| ())
+            .map_err(|e| format!("Unable to record how VM '{vm_name}' was run. {e}"))
+        });
+    if let Err(e) = saved {
+        let _ = fs::remove_file(&state_path);
+        let _ = qmp.resume();
+        return Err(format!("Unable to save VM '{vm_name}'. {e}"));
+    }
+    vm.stop(true, Duration::ZERO, config)?;
+    Ok(state_path)
+}
+
+pub fn resume_saved_vm(vm: &QemuRunner, config: &Config) -> Result<(), String> {
+    //! Starts a saved VM again from its saved RAM and device state, and
+    //! discards the saved state once it has been restored.
+    let vm_name: String = vm.image_name();
+    let state_path: PathBuf = get_saved_state_path(&vm_name, "state");
+    vm.resume_saved(&format!("exec:cat '{}'", state_path.display()), config)?;
+
+    let mut qmp: QmpClient = QmpClient::connect_to_started_vm(&vm_name)?;
+    let mut status: String = qmp.query_status()?;
+    while status == "inmigrate" {
+        sleep(Duration::from_millis(100));
+        status = qmp.query_status()?;
+    }
+    // the guest was paused while it was saved
+    if status == "paused" {
+        qmp.resume()?;
+    }
+    discard_saved_vm(&vm_name);
+    Ok(())
+}
+
+pub fn discard_saved_vm(vm_name: &str) {
+    //! Deletes the saved state of a VM.
+    let _ = fs::remove_file(get_saved_state_path(vm_name, "state"));
+    let _ = fs::remove_file(get_saved_state_path(vm_name, "json"));
+}
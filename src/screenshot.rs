@@ -0,0 +1,197 @@
+use serde_json::json;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::qmp::QmpClient;
+use crate::utils::get_partial_path;
+
+/// The signature every PNG file starts with.
+const PNG_SIGNATURE: &[u8] = &[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+/// The most data a stored (uncompressed) deflate block can hold.
+const DEFLATE_STORED_BLOCK_SIZE: usize = 0xffff;
+
+/// An RGB image, as `screendump` writes it in PPM form.
+#[derive(Debug, PartialEq, Eq)]
+struct Image {
+    width: usize,
+    height: usize,
+    /// The red, green and blue bytes of each pixel, row by row.
+    pixels: Vec<u8>,
+}
+
+fn parse_ppm(ppm: &[u8]) -> Result<Image, String> {
+    //! Parses a binary (`P6`) PPM image with 8 bits per channel.
+    let invalid = || "Unexpected screendump output; expected a binary PPM image.".to_owned();
+    // the header is 4 fields separated by whitespace, the last of which is
+    // followed by a single whitespace character and the pixels
+    let mut fields: Vec<&str> = vec![];
+    let mut position: usize = 0;
+    while fields.len() < 4 {
+        while ppm.get(position).ok_or_else(invalid)?.is_ascii_whitespace() {
+            position += 1;
+        }
+        let start: usize = position;
+        while !ppm.get(position).ok_or_else(invalid)?.is_ascii_whitespace() {
+            position += 1;
+        }
+        fields.push(std::str::from_utf8(&ppm[start..position]).map_err(|_| invalid())?);
+    }
+    let (width, height, maximum): (usize, usize, usize) = match fields[..] {
+        ["P6", width, height, maximum] => (
+            width.parse().map_err(|_| invalid())?,
+            height.parse().map_err(|_| invalid())?,
+            maximum.parse().map_err(|_| invalid())?,
+        ),
+        _ => return Err(invalid()),
+    };
+    let pixels: &[u8] = ppm.get(position + 1..).ok_or_else(invalid)?;
+    if maximum != 255 || width == 0 || height == 0 || pixels.len() < width * height * 3 {
+        return Err(invalid());
+    }
+    Ok(Image {
+        width,
+        height,
+        pixels: pixels[..width * height * 3].to_vec(),
+    })
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    //! Returns the CRC-32 checksum PNG chunks end with.
+    let mut crc: u32 = 0xffffffff;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    //! Returns the Adler-32 checksum zlib streams end with.
+    let (mut a, mut b): (u32, u32) = (1, 0);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    //! Wraps `data` in a zlib stream without compressing it, which any PNG
+    //! reader can inflate.
+    let mut stream: Vec<u8> = vec![0x78, 0x01];
+    let mut blocks = data.chunks(DEFLATE_STORED_BLOCK_SIZE).peekable();
+    if blocks.peek().is_none() {
+        stream.extend([1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let length: u16 = block.len() as u16;
+        stream.push(if blocks.peek().is_none() { 1 } else { 0 });
+        stream.extend(length.to_le_bytes());
+        stream.extend((!length).to_le_bytes());
+        stream.extend(block);
+    }
+    stream.extend(adler32(data).to_be_bytes());
+    stream
+}
+
+fn encode_png(image: &Image) -> Vec<u8> {
+    //! Encodes an RGB image as a PNG file.
+    let chunk = |kind: &[u8], data: &[u8]| {
+        let mut chunk: Vec<u8> = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend(kind);
+        chunk.extend(data);
+        chunk.extend(crc32(&chunk[4..]).to_be_bytes());
+        chunk
+    };
+    let mut header: Vec<u8> = vec![];
+    header.extend((image.width as u32).to_be_bytes());
+    header.extend((image.height as u32).to_be_bytes());
+    // 8 bits per channel, RGB, and the standard compression, filter and
+    // (no) interlace methods
+    header.extend([8, 2, 0, 0, 0]);
+    // each row starts with the filter it uses, which is none
+    let mut rows: Vec<u8> = Vec::with_capacity((image.width * 3 + 1) * image.height);
+    for row in image.pixels.chunks(image.width * 3) {
+        rows.push(0);
+        rows.extend(row);
+    }
+
+    let mut png: Vec<u8> = PNG_SIGNATURE.to_vec();
+    png.extend(chunk(b"IHDR", &header));
+    png.extend(chunk(b"IDAT", &zlib_stored(&rows)));
+    png.extend(chunk(b"IEND", &[]));
+    png
+}
+
+pub fn take_screenshot(qmp: &mut QmpClient, output: &Path) -> Result<(), String> {
+    //! Saves what the guest's display shows to `output`, as a PNG image, or
+    //! as the PPM image qemu writes if `output` ends in `.ppm`.
+    // qemu writes the screendump itself, relative to its own working
+    // directory.
+    let output: PathBuf = env::current_dir()
+        .map_err(|e| format!("Unable to determine the current directory. {e}"))?
+        .join(output);
+    let dump_path: PathBuf = get_partial_path(&output);
+    qmp.execute("screendump", Some(json!({ "filename": dump_path })))
+        .map_err(|e| format!("Unable to take a screenshot. {e}"))?;
+    let converted: Result<(), String> = if output
+        .extension()
+        .is_some_and(|extension| extension == "ppm")
+    {
+        fs::rename(&dump_path, &output).map_err(|e| e.to_string())
+    } else {
+        fs::read(&dump_path)
+            .map_err(|e| e.to_string())
+            .and_then(|ppm| parse_ppm(&ppm))
+            .and_then(|image| fs::write(&output, encode_png(&image)).map_err(|e| e.to_string()))
+    };
+    let _ = fs::remove_file(&dump_path);
+    converted.map_err(|e| format!("Unable to write screenshot '{}'. {e}", output.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{adler32, crc32, encode_png, parse_ppm, zlib_stored, Image};
+
+    #[test]
+    fn test_parse_ppm() {
+        let mut ppm: Vec<u8> = b"P6\n2 1\n255\n".to_vec();
+        ppm.extend([255, 0, 0, 0, 0, 255]);
+        assert_eq!(
+            parse_ppm(&ppm).unwrap(),
+            Image {
+                width: 2,
+                height: 1,
+                pixels: vec![255, 0, 0, 0, 0, 255],
+            }
+        );
+        assert!(parse_ppm(b"P6\n2 1\n255\n\x00").is_err());
+        assert!(parse_ppm(b"P3\n1 1\n255\n0 0 0").is_err());
+        assert!(parse_ppm(b"").is_err());
+    }
+
+    #[test]
+    fn test_encode_png() {
+        assert_eq!(crc32(b"IEND"), 0xae426082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+        let stream: Vec<u8> = zlib_stored(&[7; 70000]);
+        assert_eq!(stream.len(), 2 + 2 * 5 + 70000 + 4);
+        assert_eq!(stream[2], 0);
+
+        let png: Vec<u8> = encode_png(&Image {
+            width: 1,
+            height: 1,
+            pixels: vec![1, 2, 3],
+        });
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xaeB`\x82");
+    }
+}
@@ -0,0 +1,320 @@
+use std::fs::{self, read_dir};
+use std::path::PathBuf;
+use std::process::Output;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::config::{Share, ShareDriver};
+use crate::state::read_pidfile;
+use crate::utils::{find_in_path, is_process_running, run_shell_command, wait_for_process_exit};
+use crate::RUNTIME_DIRECTORY;
+
+/// Where distributions install `virtiofsd` when it isn't in `$PATH`.
+const VIRTIOFSD_LOCATIONS: &[&str] = &[
+    "/usr/libexec/virtiofsd",
+    "/usr/lib/qemu/virtiofsd",
+    "/usr/lib/virtiofsd",
+];
+/// Where qemu runs the Samba server of its built-in SMB share from.
+const SMBD_LOCATIONS: &[&str] = &["/usr/sbin/smbd", "/usr/local/sbin/smbd"];
+/// How long to wait for `virtiofsd` to create its socket.
+const VIRTIOFSD_STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for `virtiofsd` to exit once asked to.
+const VIRTIOFSD_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// ID of the shared memory backend virtiofs needs the guest's RAM to be in.
+const VIRTIOFS_MEMORY_ID: &str = "virtiofs-mem";
+
+pub fn find_virtiofsd() -> Option<PathBuf> {
+    //! Returns the path of `virtiofsd`, if it is installed.
+    find_in_path("virtiofsd").or_else(|| {
+        VIRTIOFSD_LOCATIONS
+            .iter()
+            .map(PathBuf::from)
+            .find(|path| path.is_file())
+    })
+}
+
+pub fn find_smbd() -> Option<PathBuf> {
+    //! Returns the path of Samba's `smbd`, if it is installed.
+    SMBD_LOCATIONS
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.is_file())
+        .or_else(|| find_in_path("smbd"))
+}
+
+pub fn smb_nic_property(path: &str) -> Result<String, String> {
+    //! Returns the user-mode networking property serving a host directory
+    //! to the guest over qemu's built-in SMB server, which runs `smbd`.
+    let path: PathBuf = PathBuf::from(shellexpand::tilde(path).to_string());
+    if !path.is_dir() {
+        return Err(format!(
+            "SMB shared directory '{}' does not exist.",
+            path.display()
+        ));
+    }
+    if path.display().to_string().contains(',') {
+        return Err(format!(
+            "SMB shared directory '{}' may not contain ','.",
+            path.display()
+        ));
+    }
+    if find_smbd().is_none() {
+        return Err("Unable to find 'smbd', which 'smb_share' needs. Install Samba.".to_owned());
+    }
+    Ok(format!("smb={}", path.display()))
+}
+
+fn get_share_path(share: &Share) -> Result<PathBuf, String> {
+    //! Returns the host directory of a share, which must exist.
+    let path: PathBuf = PathBuf::from(shellexpand::tilde(&share.path).to_string());
+    if !path.is_dir() {
+        return Err(format!(
+            "Shared directory '{}' does not exist.",
+            path.display()
+        ));
+    }
+    Ok(path)
+}
+
+pub fn get_virtiofsd_socket_path(vm_name: &str, tag: &str) -> PathBuf {
+    //! Returns the path of the socket qemu talks to the `virtiofsd` of a
+    //! share over.
+    PathBuf::from(
+        shellexpand::tilde(&format!("{RUNTIME_DIRECTORY}/{vm_name}.virtiofsd.{tag}")).to_string(),
+    )
+}
+
+fn get_virtiofsd_pidfile_path(vm_name: &str, tag: &str) -> PathBuf {
+    //! Returns the path of the file holding the PID of a share's
+    //! `virtiofsd`.
+    PathBuf::from(
+        shellexpand::tilde(&format!(
+            "{RUNTIME_DIRECTORY}/{vm_name}.virtiofsd.{tag}.pid"
+        ))
+        .to_string(),
+    )
+}
+
+pub fn share_qemu_args(
+    vm_name: &str,
+    shares: &[Share],
+    memory: Option<&str>,
+    hugepages: bool,
+) -> Result<Vec<String>, String> {
+    //! Returns the arguments exposing each share to the guest under its tag.
+    //! virtiofs shares need the guest's RAM to be shared with `virtiofsd`,
+    //! so the size of the guest's memory must be known. Memory backed by
+    //! `hugepages` is shared by its own backend instead.
+    let mut args: Vec<String> = vec![];
+    let mut tags: Vec<&str> = vec![];
+    for (index, share) in shares.iter().enumerate() {
+        if share.tag.is_empty() || share.tag.contains([',', '/']) || tags.contains(&&*share.tag) {
+            return Err(format!(
+                "Invalid share tag '{}'. Tags must be unique, non-empty, and may not contain ',' or '/'.",
+                share.tag
+            ));
+        }
+        tags.push(&share.tag);
+        let path: PathBuf = get_share_path(share)?;
+        match share.driver {
+            ShareDriver::NineP => {
+                args.push("-virtfs".to_owned());
+                args.push(format!(
+                    "local,path={},mount_tag={},security_model=none,id=share{index}{}",
+                    path.display(),
+                    share.tag,
+                    if share.readonly { ",readonly=on" } else { "" }
+                ));
+            }
+            ShareDriver::Virtiofs => {
+                args.push("-chardev".to_owned());
+                args.push(format!(
+                    "socket,id=share{index},path={}",
+                    get_virtiofsd_socket_path(vm_name, &share.tag).display()
+                ));
+                args.push("-device".to_owned());
+                args.push(format!(
+                    "vhost-user-fs-pci,chardev=share{index},tag={}",
+                    share.tag
+                ));
+            }
+        }
+    }
+
+    if !hugepages
+        && shares
+            .iter()
+            .any(|share| share.driver == ShareDriver::Virtiofs)
+    {
+        let memory: &str = memory.ok_or(
+            "virtiofs shares need the guest's memory size. Set 'memory' in the VM config, or use 'driver: 9p'.",
+        )?;
+        args.push("-object".to_owned());
+        args.push(format!(
+            "memory-backend-memfd,id={VIRTIOFS_MEMORY_ID},size={memory},share=on"
+        ));
+        args.push("-numa".to_owned());
+        args.push(format!("node,memdev={VIRTIOFS_MEMORY_ID}"));
+    }
+    Ok(args)
+}
+
+pub fn start_virtiofsd(vm_name: &str, share: &Share) -> Result<(), String> {
+    //! Starts a `virtiofsd` serving a share in the background, and waits for
+    //! its socket to appear. `virtiofsd` exits by itself once qemu
+    //! disconnects from it.
+    let virtiofsd: PathBuf = find_virtiofsd().ok_or(
+        "Unable to find 'virtiofsd', which virtiofs shares need. Install it, or use 'driver: 9p'.",
+    )?;
+    let path: PathBuf = get_share_path(share)?;
+    let socket_path: PathBuf = get_virtiofsd_socket_path(vm_name, &share.tag);
+    let pidfile_path: PathBuf = get_virtiofsd_pidfile_path(vm_name, &share.tag);
+    let _ = fs::remove_file(&socket_path);
+
+    let virtiofsd: String = virtiofsd.display().to_string();
+    let socket_arg: String = format!("--socket-path={}", socket_path.display());
+    let shared_dir_arg: String = format!("--shared-dir={}", path.display());
+    // `virtiofsd` can't daemonize itself, so it is started in the background
+    // by a shell, which leaves it to be reaped by init rather than us.
+    let mut args: Vec<&str> = vec![
+        "sh",
+        "-c",
+        "\"$@\" </dev/null >/dev/null 2>&1 & echo $!",
+        "sh",
+        &virtiofsd,
+        &socket_arg,
+        &shared_dir_arg,
+        "--cache=auto",
+    ];
+    if share.readonly {
+        args.push("--readonly");
+    }
+    let output: Output =
+        run_shell_command(&args).map_err(|e| format!("Unable to run '{virtiofsd}'. {e}"))?;
+    let pid: String = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    fs::write(&pidfile_path, &pid)
+        .map_err(|e| format!("Unable to write PID file '{}'. {e}", pidfile_path.display()))?;
+
+    let started_waiting: Instant = Instant::now();
+    while !socket_path.exists() {
+        if started_waiting.elapsed() > VIRTIOFSD_STARTUP_TIMEOUT {
+            stop_virtiofsd(vm_name);
+            return Err(format!(
+                "'virtiofsd' for share '{}' of VM '{vm_name}' did not create its socket within {} seconds.",
+                share.tag,
+                VIRTIOFSD_STARTUP_TIMEOUT.as_secs()
+            ));
+        }
+        sleep(Duration::from_millis(100));
+    }
+    Ok(())
+}
+
+pub fn stop_virtiofsd(vm_name: &str) {
+    //! Stops every `virtiofsd` of the given VM which is still running, and
+    //! removes their runtime files.
+    let directory: String = shellexpand::tilde(RUNTIME_DIRECTORY).to_string();
+    let prefix: String = format!("{vm_name}.virtiofsd.");
+    let entries = match read_dir(&directory) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for path in entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+    {
+        let is_pidfile: bool = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(&prefix) && name.ends_with(".pid"));
+        if !is_pidfile {
+            continue;
+        }
+        if let Some(pid) = read_pidfile(&path) {
+            if is_process_running(pid) {
+                let _ = run_shell_command(&["kill", &format!("{pid}")]);
+                if !wait_for_process_exit(pid, VIRTIOFSD_SHUTDOWN_TIMEOUT) {
+                    eprintln!("'virtiofsd' (PID {pid}) of VM '{vm_name}' did not exit.");
+                }
+            }
+        }
+        let _ = fs::remove_file(path.with_extension(""));
+        let _ = fs::remove_file(&path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{share_qemu_args, smb_nic_property};
+    use crate::config::{Share, ShareDriver};
+
+    #[test]
+    fn test_share_qemu_args() {
+        let directory: String = std::env::temp_dir().display().to_string();
+        let share = |tag: &str, driver: ShareDriver| Share {
+            path: directory.clone(),
+            tag: tag.to_owned(),
+            readonly: true,
+            driver,
+        };
+
+        let args: Vec<String> =
+            share_qemu_args("dev", &[share("code", ShareDriver::NineP)], None, false).unwrap();
+        assert_eq!(args[0], "-virtfs");
+        assert_eq!(
+            args[1],
+            format!(
+                "local,path={directory},mount_tag=code,security_model=none,id=share0,readonly=on"
+            )
+        );
+
+        let args: Vec<String> = share_qemu_args(
+            "dev",
+            &[share("code", ShareDriver::Virtiofs)],
+            Some("4G"),
+            false,
+        )
+        .unwrap();
+        assert!(args[1].ends_with("/dev.virtiofsd.code"));
+        assert_eq!(args[3], "vhost-user-fs-pci,chardev=share0,tag=code");
+        assert_eq!(
+            args[5],
+            "memory-backend-memfd,id=virtiofs-mem,size=4G,share=on"
+        );
+
+        // hugepages back the memory shared with `virtiofsd` instead
+        assert_eq!(
+            share_qemu_args("dev", &[share("code", ShareDriver::Virtiofs)], None, true)
+                .unwrap()
+                .len(),
+            4
+        );
+
+        // virtiofs needs the memory size, and tags must be unique
+        assert!(
+            share_qemu_args("dev", &[share("code", ShareDriver::Virtiofs)], None, false).is_err()
+        );
+        assert!(share_qemu_args(
+            "dev",
+            &[
+                share("code", ShareDriver::NineP),
+                share("code", ShareDriver::NineP)
+            ],
+            None,
+            false
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_smb_nic_property() {
+        let missing: String = std::env::temp_dir()
+            .join("vm-manager-test-missing-smb-share")
+            .display()
+            .to_string();
+        assert!(smb_nic_property(&missing)
+            .unwrap_err()
+            .contains("does not exist"));
+    }
+}
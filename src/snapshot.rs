@@ -0,0 +1,205 @@
+use chrono::{Local, TimeZone};
+use std::cmp::max;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::image::{get_image_info, run_qemu_img, ImageInfo, ImageSnapshot};
+use crate::qmp::QmpClient;
+use crate::utils::OutputStream;
+
+/// How long to wait for qemu to save or load a live snapshot, which includes
+/// writing or reading the whole of the guest's RAM.
+const LIVE_SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(600);
+
+fn get_qcow2_info(image_path: &Path) -> Result<ImageInfo, String> {
+    //! Inspects an image, refusing images which can't hold snapshots.
+    let info: ImageInfo = get_image_info(image_path)?;
+    if info.format != "qcow2" {
+        return Err(format!(
+            "'{}' is a {} image. Only qcow2 images support snapshots; use 'vm-manager image convert' to make a qcow2 copy.",
+            image_path.display(),
+            info.format
+        ));
+    }
+    Ok(info)
+}
+
+pub fn list_snapshots(image_path: &Path) -> Result<Vec<ImageSnapshot>, String> {
+    //! Returns the internal snapshots of a qcow2 image, oldest first.
+    Ok(get_qcow2_info(image_path)?.snapshots)
+}
+
+fn find_snapshot(image_path: &Path, name: &str) -> Result<ImageSnapshot, String> {
+    //! Returns the snapshot called `name`, or with the ID `name`.
+    list_snapshots(image_path)?
+        .into_iter()
+        .find(|snapshot| snapshot.name == name || snapshot.id == name)
+        .ok_or(format!(
+            "'{}' has no snapshot named '{name}'.",
+            image_path.display()
+        ))
+}
+
+pub fn create_snapshot(image_path: &Path, name: &str) -> Result<(), String> {
+    //! Takes a snapshot of the disk of a stopped VM.
+    if list_snapshots(image_path)?
+        .iter()
+        .any(|snapshot| snapshot.name == name)
+    {
+        return Err(format!(
+            "'{}' already has a snapshot named '{name}'.",
+            image_path.display()
+        ));
+    }
+    run_qemu_img(&["snapshot", "-c", name, &image_path.display().to_string()])
+}
+
+pub fn revert_snapshot(image_path: &Path, name: &str) -> Result<(), String> {
+    //! Reverts the disk of a stopped VM to the given snapshot, discarding
+    //! every change made since.
+    let snapshot: ImageSnapshot = find_snapshot(image_path, name)?;
+    run_qemu_img(&[
+        "snapshot",
+        "-a",
+        &snapshot.id,
+        &image_path.display().to_string(),
+    ])
+}
+
+pub fn delete_snapshot(image_path: &Path, name: &str) -> Result<(), String> {
+    //! Deletes the given snapshot of a stopped VM's disk.
+    let snapshot: ImageSnapshot = find_snapshot(image_path, name)?;
+    run_qemu_img(&[
+        "snapshot",
+        "-d",
+        &snapshot.id,
+        &image_path.display().to_string(),
+    ])
+}
+
+pub fn list_live_snapshots(
+    client: &mut QmpClient,
+    image_path: &Path,
+) -> Result<Vec<ImageSnapshot>, String> {
+    //! Returns the internal snapshots of the qcow2 image of a running VM, as
+    //! seen by the VM.
+    Ok(client.query_image_info(image_path)?.snapshots)
+}
+
+fn run_live_snapshot_command(client: &mut QmpClient, command_line: &str) -> Result<(), String> {
+    //! Runs `savevm`, `loadvm` or `delvm` on a running VM. These report
+    //! failures as monitor output rather than as QMP errors.
+    client.set_read_timeout(LIVE_SNAPSHOT_TIMEOUT)?;
+    let output: String = client.human_monitor_command(command_line)?;
+    if output.trim().is_empty() {
+        Ok(())
+    } else {
+        Err(format!("'{command_line}' failed: {}", output.trim()))
+    }
+}
+
+pub fn create_live_snapshot(
+    client: &mut QmpClient,
+    image_path: &Path,
+    name: &str,
+) -> Result<(), String> {
+    //! Takes a snapshot of a running VM, including its RAM and device state,
+    //! so that it can be reverted to without rebooting. The guest is paused
+    //! while the snapshot is written.
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(format!("'{name}' is not a valid snapshot name."));
+    }
+    if list_live_snapshots(client, image_path)?
+        .iter()
+        .any(|snapshot| snapshot.name == name)
+    {
+        return Err(format!(
+            "'{}' already has a snapshot named '{name}'.",
+            image_path.display()
+        ));
+    }
+    run_live_snapshot_command(client, &format!("savevm {name}"))
+}
+
+fn find_live_snapshot(
+    client: &mut QmpClient,
+    image_path: &Path,
+    name: &str,
+) -> Result<ImageSnapshot, String> {
+    //! Returns the snapshot of a running VM's image called `name`, or with
+    //! the ID `name`.
+    list_live_snapshots(client, image_path)?
+        .into_iter()
+        .find(|snapshot| snapshot.name == name || snapshot.id == name)
+        .ok_or(format!(
+            "'{}' has no snapshot named '{name}'.",
+            image_path.display()
+        ))
+}
+
+pub fn revert_live_snapshot(
+    client: &mut QmpClient,
+    image_path: &Path,
+    name: &str,
+) -> Result<(), String> {
+    //! Reverts a running VM to the given snapshot. Snapshots which include
+    //! RAM resume where they were taken; disk-only snapshots can't be loaded
+    //! into a running VM.
+    let snapshot: ImageSnapshot = find_live_snapshot(client, image_path, name)?;
+    if snapshot.vm_state_size == 0 {
+        return Err(format!(
+            "Snapshot '{}' only holds the disk. Stop the VM to revert to it.",
+            snapshot.name
+        ));
+    }
+    run_live_snapshot_command(client, &format!("loadvm {}", snapshot.id))
+}
+
+pub fn delete_live_snapshot(
+    client: &mut QmpClient,
+    image_path: &Path,
+    name: &str,
+) -> Result<(), String> {
+    //! Deletes the given snapshot of a running VM's image.
+    let snapshot: ImageSnapshot = find_live_snapshot(client, image_path, name)?;
+    run_live_snapshot_command(client, &format!("delvm {}", snapshot.id))
+}
+
+fn format_snapshot_date(date: i64) -> String {
+    Local
+        .timestamp_opt(date, 0)
+        .single()
+        .map_or("-".to_owned(), |date| {
+            date.format("%Y-%m-%d %H:%M:%S").to_string()
+        })
+}
+
+pub fn print_snapshot_table(snapshots: &[ImageSnapshot], output_buffer: &mut OutputStream) {
+    let name_width: usize = snapshots
+        .iter()
+        .map(|snapshot| snapshot.name.len())
+        .fold("Name".len(), max)
+        + 2;
+    output_buffer.addln(&format!(
+        "{:4} | {:name_width$} | {:19} | {}",
+        "ID", "Name", "Date", "RAM"
+    ));
+    output_buffer.addln(&format!(
+        "{:-<4}-+-{:-<name_width$}-+-{:-<19}-+-{:-<4}",
+        "", "", "", ""
+    ));
+    for snapshot in snapshots {
+        output_buffer.addln(&format!(
+            "{:4} | {:name_width$} | {:19} | {}",
+            snapshot.id,
+            snapshot.name,
+            format_snapshot_date(snapshot.date),
+            // snapshots taken of a running VM also hold its RAM
+            if snapshot.vm_state_size > 0 {
+                "yes"
+            } else {
+                "no"
+            }
+        ));
+    }
+}
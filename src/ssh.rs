@@ -0,0 +1,344 @@
+use std::fs;
+use std::io::Read;
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, SshCredentials};
+use crate::instance::INSTANCE_SEPARATOR;
+use crate::utils::get_list_of_running_vms;
+
+/// Address forwarded SSH ports are reached on.
+const SSH_HOST: &str = "127.0.0.1";
+/// How long a single attempt to reach the guest's SSH server may take.
+const SSH_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long to wait between attempts to reach the guest's SSH server.
+const SSH_PROBE_INTERVAL: Duration = Duration::from_secs(1);
+/// The first line of SSH client config files written by vm-manager.
+const SSH_CONFIG_HEADER: &str =
+    "# Written by vm-manager for its running VMs, and rewritten as they start and stop.";
+
+fn is_ssh_server_ready(port: usize) -> bool {
+    //! Returns whether an SSH server answers on the forwarded port. Accepting
+    //! the connection isn't enough, as qemu's user-mode networking accepts
+    //! it before the guest does, so the server's greeting is awaited.
+    let address: SocketAddr = match format!("{SSH_HOST}:{port}").parse() {
+        Ok(address) => address,
+        Err(_) => return false,
+    };
+    let mut stream: TcpStream = match TcpStream::connect_timeout(&address, SSH_PROBE_TIMEOUT) {
+        Ok(stream) => stream,
+        Err(_) => return false,
+    };
+    let mut greeting: [u8; 4] = [0; 4];
+    stream.set_read_timeout(Some(SSH_PROBE_TIMEOUT)).is_ok()
+        && stream.read_exact(&mut greeting).is_ok()
+        && &greeting == b"SSH-"
+}
+
+pub fn wait_for_ssh(port: usize, timeout: Duration) -> Result<Duration, String> {
+    //! Waits for the guest's SSH server to answer on the forwarded port,
+    //! returning how long that took.
+    let started_waiting: Instant = Instant::now();
+    while !is_ssh_server_ready(port) {
+        if started_waiting.elapsed() > timeout {
+            return Err(format!(
+                "The guest's SSH server didn't answer on port {port} within {} seconds.",
+                timeout.as_secs()
+            ));
+        }
+        sleep(SSH_PROBE_INTERVAL);
+    }
+    Ok(started_waiting.elapsed())
+}
+
+pub fn update_config_file(config: &Config) {
+    //! Rewrites the config's `ssh_config_file`, if it sets one, after a VM
+    //! started or stopped. Failing to is only reported, as the VM itself did.
+    if config.get_ssh_config_file().is_some() {
+        if let Err(e) = write_config_file(config) {
+            eprintln!("{e}");
+        }
+    }
+}
+
+pub fn write_config_file(config: &Config) -> Result<(), String> {
+    //! Writes an SSH client config entry for each running VM to the
+    //! config's `ssh_config_file`.
+    let path: String = shellexpand::tilde(config.get_ssh_config_file().ok_or(
+        "Set 'ssh_config_file' in the config file to write SSH client config entries to.",
+    )?)
+    .into_owned();
+    if let Some(directory) = Path::new(&path).parent() {
+        fs::create_dir_all(directory)
+            .map_err(|e| format!("Unable to create directory '{}'. {e}", directory.display()))?;
+    }
+    fs::write(&path, get_config_entries(config))
+        .map_err(|e| format!("Unable to write SSH client config to '{path}'. {e}"))
+}
+
+pub fn get_config_entries(config: &Config) -> String {
+    //! Returns an SSH client config entry for each running VM which
+    //! forwards a host port to the guest's port 22.
+    let targets: Vec<(String, SshTarget)> = get_list_of_running_vms()
+        .into_iter()
+        .filter_map(|vm| {
+            let port: usize = vm.forwarded_host_port(22)?;
+            Some((
+                vm.image_name(),
+                SshTarget::new(port, config.get_ssh_credentials(&vm.base_image_name())),
+            ))
+        })
+        .collect();
+    render_config_entries(&targets)
+}
+
+pub fn get_host_alias(vm_name: &str) -> String {
+    //! Returns the name `ssh` reaches a running VM by. Instances are named
+    //! `image.instance`, as `ssh deb12@web` would log into `web` as
+    //! `deb12`.
+    vm_name.replace(INSTANCE_SEPARATOR, ".")
+}
+
+fn quote_config_value(value: &str) -> String {
+    //! Quotes a value of an SSH client config option if it contains
+    //! whitespace.
+    if value.contains(char::is_whitespace) {
+        format!("\"{value}\"")
+    } else {
+        value.to_owned()
+    }
+}
+
+fn render_config_entries(targets: &[(String, SshTarget)]) -> String {
+    //! Renders a `Host` entry for each named target. Host keys are
+    //! remembered under the VM's name rather than the forwarded port, which
+    //! other VMs may use later on.
+    let mut config: String = format!("{SSH_CONFIG_HEADER}\n");
+    for (vm_name, target) in targets {
+        let alias: String = get_host_alias(vm_name);
+        config.push_str(&format!(
+            "\nHost {alias}\n    HostName {SSH_HOST}\n    Port {}\n    HostKeyAlias {alias}.vm-manager\n",
+            target.port
+        ));
+        if let Some(user) = &target.credentials.user {
+            config.push_str(&format!("    User {}\n", quote_config_value(user)));
+        }
+        if let Some(identity_file) = &target.credentials.identity_file {
+            config.push_str(&format!(
+                "    IdentityFile {}\n",
+                quote_config_value(identity_file)
+            ));
+        }
+    }
+    config
+}
+
+/// Everything needed to reach a running VM's guest over SSH.
+///
+/// # Attributes:
+/// * port - The host port forwarded to the guest's port 22.
+/// * credentials - The credentials to log in with.
+pub struct SshTarget {
+    pub port: usize,
+    pub credentials: SshCredentials,
+}
+
+impl SshTarget {
+    pub fn new(port: usize, credentials: SshCredentials) -> Self {
+        Self { port, credentials }
+    }
+
+    pub fn destination(&self) -> String {
+        //! Returns `user@host`, or just the host if no user is configured.
+        match &self.credentials.user {
+            Some(user) => format!("{user}@{SSH_HOST}"),
+            None => SSH_HOST.to_owned(),
+        }
+    }
+
+    fn identity_args(&self) -> Vec<String> {
+        match &self.credentials.identity_file {
+            Some(identity_file) => vec![
+                "-i".to_owned(),
+                shellexpand::tilde(identity_file).to_string(),
+            ],
+            None => vec![],
+        }
+    }
+
+    pub fn ssh_args(&self) -> Vec<String> {
+        //! Builds the `ssh` command line logging into the guest.
+        let mut args: Vec<String> = vec!["ssh".to_owned(), "-p".to_owned(), self.port.to_string()];
+        args.extend(self.identity_args());
+        args.push(self.destination());
+        args
+    }
+
+    pub fn scp_args(&self, paths: &[String], recursive: bool) -> Result<Vec<String>, String> {
+        //! Builds the `scp` command line copying between the host and the
+        //! guest. Every path except the last is a source, and the last is the
+        //! destination. Guest paths are written with a leading `:`, e.g.
+        //! `:/tmp/`, and either all sources or the destination must be guest
+        //! paths, but not both.
+        //!
+        //! Example:
+        //!
+        //! ```
+        //! let target = SshTarget::new(5555, SshCredentials::default());
+        //! assert_eq!(
+        //!     target.scp_args(&["a.txt".to_owned(), ":/tmp/".to_owned()], false)?,
+        //!     vec!["scp", "-P", "5555", "a.txt", "127.0.0.1:/tmp/"]
+        //! );
+        //! ```
+        let (destination, sources) = match paths.split_last() {
+            Some((destination, sources)) if !sources.is_empty() => (destination, sources),
+            _ => return Err("Must provide at least one source and a destination.".to_owned()),
+        };
+
+        let is_guest_path = |path: &String| path.starts_with(':');
+        let to_remote = is_guest_path(destination);
+        if to_remote && sources.iter().any(is_guest_path) {
+            return Err("Cannot copy from the guest to the guest.".to_owned());
+        }
+        if !to_remote && !sources.iter().all(is_guest_path) {
+            return Err(
+                "Either the destination or every source must be a guest path, starting with ':'."
+                    .to_owned(),
+            );
+        }
+
+        let mut args: Vec<String> = vec!["scp".to_owned(), "-P".to_owned(), self.port.to_string()];
+        if recursive {
+            args.push("-r".to_owned());
+        }
+        args.extend(self.identity_args());
+        args.extend(paths.iter().map(|path| {
+            if is_guest_path(path) {
+                format!("{}{path}", self.destination())
+            } else {
+                path.to_owned()
+            }
+        }));
+        Ok(args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_ssh_server_ready, render_config_entries, SshTarget};
+    use crate::config::SshCredentials;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn to_strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_scp_args_to_guest() {
+        let target: SshTarget = SshTarget::new(
+            5555,
+            SshCredentials {
+                user: Some("dev".to_owned()),
+                identity_file: Some("/keys/id_ed25519".to_owned()),
+            },
+        );
+        assert_eq!(
+            target.scp_args(&to_strings(&["./build.tar.gz", "b.txt", ":/tmp/"]), true),
+            Ok(to_strings(&[
+                "scp",
+                "-P",
+                "5555",
+                "-r",
+                "-i",
+                "/keys/id_ed25519",
+                "./build.tar.gz",
+                "b.txt",
+                "dev@127.0.0.1:/tmp/"
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_scp_args_from_guest() {
+        let target: SshTarget = SshTarget::new(2222, SshCredentials::default());
+        assert_eq!(
+            target.scp_args(&to_strings(&[":/var/log/syslog", "."]), false),
+            Ok(to_strings(&[
+                "scp",
+                "-P",
+                "2222",
+                "127.0.0.1:/var/log/syslog",
+                "."
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_scp_args_invalid() {
+        let target: SshTarget = SshTarget::new(2222, SshCredentials::default());
+        assert!(target.scp_args(&to_strings(&["a.txt"]), false).is_err());
+        assert!(target
+            .scp_args(&to_strings(&["a.txt", "b.txt"]), false)
+            .is_err());
+        assert!(target
+            .scp_args(&to_strings(&[":/a", ":/b"]), false)
+            .is_err());
+        assert!(target
+            .scp_args(&to_strings(&[":/a", "b", "c"]), false)
+            .is_err());
+    }
+
+    #[test]
+    fn test_is_ssh_server_ready() {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port: usize = listener.local_addr().unwrap().port() as usize;
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"SSH-2.0-OpenSSH_9.6\r\n").unwrap();
+        });
+        assert!(is_ssh_server_ready(port));
+        server.join().unwrap();
+        // nothing listens on the port any more
+        assert!(!is_ssh_server_ready(port));
+    }
+
+    #[test]
+    fn test_render_config_entries() {
+        let targets: Vec<(String, SshTarget)> = vec![
+            (
+                "deb12".to_owned(),
+                SshTarget::new(
+                    5555,
+                    SshCredentials {
+                        user: Some("dev".to_owned()),
+                        identity_file: Some("~/My Keys/id_ed25519".to_owned()),
+                    },
+                ),
+            ),
+            (
+                "deb12@web".to_owned(),
+                SshTarget::new(5556, SshCredentials::default()),
+            ),
+        ];
+        assert_eq!(
+            render_config_entries(&targets),
+            "# Written by vm-manager for its running VMs, and rewritten as they start and stop.\n\
+             \n\
+             Host deb12\n    \
+             HostName 127.0.0.1\n    \
+             Port 5555\n    \
+             HostKeyAlias deb12.vm-manager\n    \
+             User dev\n    \
+             IdentityFile \"~/My Keys/id_ed25519\"\n\
+             \n\
+             Host deb12.web\n    \
+             HostName 127.0.0.1\n    \
+             Port 5556\n    \
+             HostKeyAlias deb12.web.vm-manager\n"
+        );
+    }
+}
@@ -0,0 +1,375 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, read_dir};
+use std::path::{Path, PathBuf};
+
+use crate::config::{CpuAffinity, NetworkConfig, Share};
+use crate::utils::{
+    get_pidfile_path, get_process_command_line, get_stop_marker_path, is_process_running,
+};
+use crate::STATE_DIRECTORY;
+
+/// A single host-to-guest port forward of a running VM, as found in a
+/// `hostfwd=` rule on its command line.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct ForwardedPort {
+    /// `tcp` or `udp`.
+    pub protocol: String,
+    /// Host address the forward is bound to. Empty means all addresses.
+    pub host_address: String,
+    pub host_port: usize,
+    pub guest_port: usize,
+}
+
+impl ForwardedPort {
+    pub fn parse(rule: &str) -> Option<Self> {
+        //! Parses the value of a `hostfwd=` rule, which looks like
+        //! `[tcp|udp]:[hostaddr]:hostport-[guestaddr]:guestport`.
+        //!
+        //! Example:
+        //!
+        //! ```
+        //! let port: ForwardedPort = ForwardedPort::parse("tcp::5555-:22").unwrap();
+        //! assert_eq!(port.host_port, 5555);
+        //! assert_eq!(port.guest_port, 22);
+        //! ```
+        let (host, guest) = rule.split_once('-')?;
+        let (protocol, host) = host.split_once(':')?;
+        let (host_address, host_port) = host.rsplit_once(':')?;
+        let host_port: usize = host_port.parse().ok()?;
+        let guest_port: usize = guest.rsplit_once(':')?.1.parse().ok()?;
+        // IPv6 addresses are written in brackets, e.g. `[::1]`.
+        let host_address: &str = host_address
+            .strip_prefix('[')
+            .and_then(|address| address.strip_suffix(']'))
+            .unwrap_or(host_address);
+        Some(Self {
+            protocol: if protocol.is_empty() { "tcp" } else { protocol }.to_owned(),
+            host_address: host_address.to_owned(),
+            host_port,
+            guest_port,
+        })
+    }
+
+    pub fn from_command_line(args: &[String]) -> Vec<Self> {
+        //! Returns every port forward found in the `hostfwd=` rules of a qemu
+        //! command line.
+        args.iter()
+            .flat_map(|arg| arg.split(','))
+            .filter_map(|part| part.strip_prefix("hostfwd="))
+            .filter_map(Self::parse)
+            .collect()
+    }
+}
+
+impl fmt::Display for ForwardedPort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host_address.is_empty() {
+            write!(
+                f,
+                "{} -> {}/{}",
+                self.host_port, self.guest_port, self.protocol
+            )
+        } else if self.host_address.contains(':') {
+            write!(
+                f,
+                "[{}]:{} -> {}/{}",
+                self.host_address, self.host_port, self.guest_port, self.protocol
+            )
+        } else {
+            write!(
+                f,
+                "{}:{} -> {}/{}",
+                self.host_address, self.host_port, self.guest_port, self.protocol
+            )
+        }
+    }
+}
+
+/// The recorded state of a VM started by this program, stored as
+/// `~/.vm-manager/state/<image name>.json` for as long as the VM runs.
+///
+/// # Attributes:
+/// * image_name - The name of the image the VM runs on.
+/// * image_path - The full path of the image file.
+/// * pid - The PID of the qemu process, as read from its PID file.
+/// * ports - Every port forwarded from the host to the guest.
+/// * args - The full qemu command line the VM was launched with.
+/// * shares - The host directories shared with the guest, which are needed to
+///   serve virtiofs shares again when the VM is restarted.
+/// * network - How the guest is connected to the network, which is needed to
+///   delete its network device when the VM stops, and to create it again
+///   when the VM is restarted.
+/// * cpu_affinity - The host CPUs the VM runs on, which are needed to pin its
+///   vCPUs again when the VM is restarted.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct VmState {
+    pub image_name: String,
+    pub image_path: PathBuf,
+    pub pid: usize,
+    pub ports: Vec<ForwardedPort>,
+    pub args: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shares: Vec<Share>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<NetworkConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_affinity: Option<CpuAffinity>,
+    /// How many times `vm-manager supervise` has started the VM again after
+    /// it exited.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub crashes: usize,
+}
+
+fn is_zero(count: &usize) -> bool {
+    *count == 0
+}
+
+impl VmState {
+    pub fn new(
+        image_name: &str,
+        image_path: PathBuf,
+        pid: usize,
+        args: &[&str],
+        shares: &[Share],
+        network: Option<&NetworkConfig>,
+        cpu_affinity: Option<&CpuAffinity>,
+    ) -> Self {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        Self {
+            image_name: image_name.to_owned(),
+            image_path,
+            pid,
+            ports: ForwardedPort::from_command_line(&args),
+            args,
+            shares: shares.to_vec(),
+            network: network.cloned(),
+            cpu_affinity: cpu_affinity.cloned(),
+            crashes: 0,
+        }
+    }
+
+    pub fn write(&self) -> Result<(), String> {
+        //! Writes the state file of this VM, creating the state directory if
+        //! needed.
+        let path: PathBuf = get_state_file_path(&self.image_name);
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory).map_err(|e| {
+                format!(
+                    "Unable to create state directory '{}'. {e}",
+                    directory.display()
+                )
+            })?;
+        }
+        let contents: String = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        fs::write(&path, contents)
+            .map_err(|e| format!("Unable to write state file '{}'. {e}", path.display()))
+    }
+
+    pub fn remove(&self) {
+        //! Removes the state file of this VM, if there is one.
+        remove_state_file(&self.image_name);
+    }
+
+    pub fn is_alive(&self) -> bool {
+        //! Returns `true` if the recorded PID is still a qemu process tagged
+        //! with this VM's name. This guards against the PID having been reused
+        //! by an unrelated process after the VM exited.
+        is_process_running(self.pid)
+            && get_process_command_line(self.pid)
+                .is_some_and(|args| get_guest_name(&args) == Some(self.image_name.as_str()))
+    }
+}
+
+pub fn get_guest_name(args: &[String]) -> Option<&str> {
+    //! Returns the guest name given to qemu via `-name`, which may either be
+    //! `-name <name>` or `-name guest=<name>[,...]`.
+    let value: &str = args
+        .iter()
+        .position(|arg| arg == "-name")
+        .and_then(|position| args.get(position + 1))?;
+    value
+        .split(',')
+        .find_map(|part| part.strip_prefix("guest="))
+        .or_else(|| value.split(',').next().filter(|name| !name.contains('=')))
+}
+
+pub fn get_state_file_path(image_name: &str) -> PathBuf {
+    //! Returns the path of the state file for the VM running on the given
+    //! image.
+    PathBuf::from(shellexpand::tilde(&format!("{STATE_DIRECTORY}/{image_name}.json")).to_string())
+}
+
+pub fn remove_state_file(image_name: &str) {
+    //! Removes the state file for the VM running on the given image, if there
+    //! is one.
+    let _ = fs::remove_file(get_state_file_path(image_name));
+}
+
+pub fn mark_stopped(image_name: &str) {
+    //! Marks the VM running on the given image as stopped through
+    //! vm-manager, so that `vm-manager supervise` doesn't start it again. It's
+    //! marked before qemu is asked to exit, as whatever waits on qemu may see
+    //! it exit before the stop returns.
+    let _ = fs::write(get_stop_marker_path(image_name), "");
+}
+
+pub fn take_stop_marker(image_name: &str) -> bool {
+    //! Returns whether the VM running on the given image was stopped through
+    //! vm-manager since it was started, clearing the mark.
+    fs::remove_file(get_stop_marker_path(image_name)).is_ok()
+}
+
+pub fn read_pidfile(path: &Path) -> Option<usize> {
+    //! Reads the PID written by qemu to the given `-pidfile`.
+    fs::read_to_string(path).ok()?.trim().parse::<usize>().ok()
+}
+
+pub fn load_running_vm_states() -> Vec<VmState> {
+    //! Reads every state file, returning the states of VMs which are still
+    //! running. State files left behind by VMs which have since exited are
+    //! removed.
+    let directory: String = shellexpand::tilde(STATE_DIRECTORY).to_string();
+    let entries = match read_dir(&directory) {
+        Ok(entries) => entries,
+        // no VM has been started yet
+        Err(_) => return vec![],
+    };
+
+    let mut states: Vec<VmState> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "json")
+        })
+        .filter_map(|path| match fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str::<VmState>(&contents) {
+                Ok(state) => Some(state),
+                Err(e) => {
+                    eprintln!("Ignoring malformed state file '{}'. {e}", path.display());
+                    None
+                }
+            },
+            Err(e) => {
+                eprintln!("Unable to read state file '{}'. {e}", path.display());
+                None
+            }
+        })
+        .filter_map(|mut state| {
+            // qemu removes its PID file when it exits, but a VM in the
+            // foreground is recorded before qemu has written it, so without
+            // one the VM is only gone if the recorded process is.
+            if let Some(pid) = read_pidfile(&get_pidfile_path(&state.image_name)) {
+                state.pid = pid;
+            }
+            if state.is_alive() {
+                Some(state)
+            } else {
+                state.remove();
+                None
+            }
+        })
+        .collect();
+
+    states.sort_by(|a, b| a.image_name.cmp(&b.image_name));
+    states
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_guest_name, read_pidfile, ForwardedPort};
+
+    #[test]
+    fn test_forwarded_port_parse() {
+        assert_eq!(
+            ForwardedPort::parse("tcp::5555-:22"),
+            Some(ForwardedPort {
+                protocol: "tcp".to_owned(),
+                host_address: "".to_owned(),
+                host_port: 5555,
+                guest_port: 22,
+            })
+        );
+        assert_eq!(
+            ForwardedPort::parse("udp:127.0.0.1:5353-10.0.2.15:53"),
+            Some(ForwardedPort {
+                protocol: "udp".to_owned(),
+                host_address: "127.0.0.1".to_owned(),
+                host_port: 5353,
+                guest_port: 53,
+            })
+        );
+        assert_eq!(
+            ForwardedPort::parse("tcp:[::1]:8080-:80"),
+            Some(ForwardedPort {
+                protocol: "tcp".to_owned(),
+                host_address: "::1".to_owned(),
+                host_port: 8080,
+                guest_port: 80,
+            })
+        );
+        assert_eq!(ForwardedPort::parse("::8081-:443").unwrap().protocol, "tcp");
+        assert_eq!(ForwardedPort::parse("tcp::notaport-:22"), None);
+    }
+
+    #[test]
+    fn test_forwarded_ports_from_command_line() {
+        let args: Vec<String> = vec![
+            "qemu-system-x86_64".to_owned(),
+            "-nic".to_owned(),
+            "user,model=virtio,hostfwd=tcp::5555-:22,hostfwd=tcp::8081-:443".to_owned(),
+        ];
+        let ports: Vec<ForwardedPort> = ForwardedPort::from_command_line(&args);
+        assert_eq!(ports.len(), 2);
+        assert_eq!(ports[0].to_string(), "5555 -> 22/tcp");
+        assert_eq!(ports[1].to_string(), "8081 -> 443/tcp");
+    }
+
+    #[test]
+    fn test_read_pidfile() {
+        let path = std::env::temp_dir().join(format!("vm-manager-test-{}.pid", std::process::id()));
+        std::fs::write(&path, "12345\n").unwrap();
+        assert_eq!(read_pidfile(&path), Some(12345));
+        std::fs::write(&path, "garbage").unwrap();
+        assert_eq!(read_pidfile(&path), None);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read_pidfile(&path), None);
+    }
+
+    #[test]
+    fn test_get_guest_name() {
+        let to_args =
+            |args: &[&str]| -> Vec<String> { args.iter().map(|a| a.to_string()).collect() };
+        assert_eq!(
+            get_guest_name(&to_args(&[
+                "qemu-system-x86_64",
+                "-name",
+                "guest=deb12",
+                "-m",
+                "8G"
+            ])),
+            Some("deb12")
+        );
+        assert_eq!(
+            get_guest_name(&to_args(&[
+                "qemu-system-x86_64",
+                "-name",
+                "deb12,debug-threads=on"
+            ])),
+            Some("deb12")
+        );
+        assert_eq!(
+            get_guest_name(&to_args(&[
+                "qemu-system-x86_64",
+                "-name",
+                "process=x,guest=deb12"
+            ])),
+            Some("deb12")
+        );
+        assert_eq!(
+            get_guest_name(&to_args(&["qemu-system-x86_64", "-m", "8G"])),
+            None
+        );
+    }
+}
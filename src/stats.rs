@@ -0,0 +1,227 @@
+use std::cmp::max;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::qemu_runner::QemuRunner;
+use crate::utils::{run_shell_command, OutputStream};
+
+/// How long CPU usage is measured over for a one-shot `vm-manager stats`.
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+/// Clock ticks per second `/proc/<pid>/stat` counts CPU time in, on nearly
+/// every Linux system.
+const DEFAULT_CLOCK_TICKS: u64 = 100;
+
+/// Resource usage counters of a qemu process at one point in time.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessSample {
+    /// User and system CPU time used so far, in clock ticks.
+    pub cpu_ticks: u64,
+    /// Resident memory, in bytes.
+    pub resident_memory: u64,
+    /// Bytes read from and written to storage so far.
+    pub read_bytes: u64,
+    pub written_bytes: u64,
+}
+
+/// The resource usage of a running VM shown by `vm-manager stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmStats {
+    pub image_name: String,
+    pub pid: usize,
+    pub cpu_percent: f64,
+    pub resident_memory: u64,
+    /// Space taken on the host by the disk the VM runs on.
+    pub disk_size: Option<u64>,
+    pub read_bytes: u64,
+    pub written_bytes: u64,
+}
+
+fn parse_stat_cpu_ticks(stat: &str) -> Option<u64> {
+    //! Returns the user plus system CPU time in the contents of
+    //! `/proc/<pid>/stat`. Fields are counted from after the command name,
+    //! which may itself contain spaces and parentheses.
+    let fields: Vec<&str> = stat
+        .get(stat.rfind(')')? + 1..)?
+        .split_whitespace()
+        .collect();
+    // utime and stime are fields 14 and 15, the first after the name being 3.
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    Some(utime + stime)
+}
+
+fn parse_field(contents: &str, name: &str) -> Option<u64> {
+    //! Returns the first number of a `Name: value` line, as found in
+    //! `/proc/<pid>/status` and `/proc/<pid>/io`.
+    contents
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+pub fn read_process_sample(pid: usize) -> Option<ProcessSample> {
+    //! Reads the resource usage counters of a process from `/proc`. I/O
+    //! counters are left at zero if the kernel doesn't account for them.
+    let stat: String = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    let status: String = fs::read_to_string(format!("/proc/{pid}/status")).ok()?;
+    let io: String = fs::read_to_string(format!("/proc/{pid}/io")).unwrap_or_default();
+    Some(ProcessSample {
+        cpu_ticks: parse_stat_cpu_ticks(&stat)?,
+        resident_memory: parse_field(&status, "VmRSS").unwrap_or(0) * 1024,
+        read_bytes: parse_field(&io, "read_bytes").unwrap_or(0),
+        written_bytes: parse_field(&io, "write_bytes").unwrap_or(0),
+    })
+}
+
+pub fn get_clock_ticks() -> u64 {
+    run_shell_command(&["getconf", "CLK_TCK"])
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .and_then(|ticks| ticks.trim().parse().ok())
+        .filter(|ticks| *ticks > 0)
+        .unwrap_or(DEFAULT_CLOCK_TICKS)
+}
+
+pub fn get_cpu_percent(cpu_ticks: u64, elapsed: Duration, clock_ticks: u64) -> f64 {
+    //! Returns the CPU time used over `elapsed` as a percentage of one host
+    //! CPU, so a VM keeping two CPUs busy uses 200%.
+    if elapsed.is_zero() {
+        return 0.0;
+    }
+    cpu_ticks as f64 / clock_ticks as f64 / elapsed.as_secs_f64() * 100.0
+}
+
+fn get_disk_size(vm: &QemuRunner) -> Option<u64> {
+    //! Returns the space allocated on the host for the VM's disk, which for
+    //! sparse and qcow2 images is less than the size the guest sees.
+    fs::metadata(vm.disk_path())
+        .ok()
+        .map(|metadata| metadata.blocks() * 512)
+}
+
+pub fn collect_stats(vms: &[QemuRunner], interval: Duration) -> Vec<VmStats> {
+    //! Measures the resource usage of the given running VMs, sampling their
+    //! CPU time `interval` apart. VMs whose process can't be read, e.g. as
+    //! they stopped meanwhile, are left out.
+    let clock_ticks: u64 = get_clock_ticks();
+    let first_samples: Vec<Option<ProcessSample>> = vms
+        .iter()
+        .map(|vm| vm.pid().and_then(read_process_sample))
+        .collect();
+    let started: Instant = Instant::now();
+    sleep(interval);
+    let elapsed: Duration = started.elapsed();
+
+    vms.iter()
+        .zip(first_samples)
+        .filter_map(|(vm, first_sample)| {
+            let pid: usize = vm.pid()?;
+            let sample: ProcessSample = read_process_sample(pid)?;
+            let cpu_ticks: u64 = sample.cpu_ticks.saturating_sub(first_sample?.cpu_ticks);
+            Some(VmStats {
+                image_name: vm.image_name(),
+                pid,
+                cpu_percent: get_cpu_percent(cpu_ticks, elapsed, clock_ticks),
+                resident_memory: sample.resident_memory,
+                disk_size: get_disk_size(vm),
+                read_bytes: sample.read_bytes,
+                written_bytes: sample.written_bytes,
+            })
+        })
+        .collect()
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    //! Formats a number of bytes with a binary unit, e.g. `1.5 GiB`.
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value: f64 = bytes as f64;
+    let mut unit: usize = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+pub fn print_stats_table(stats: &[VmStats], output_buffer: &mut OutputStream) {
+    //! Renders the resource usage of running VMs as a table.
+    let image_name_width: usize = stats
+        .iter()
+        .map(|vm| vm.image_name.len())
+        .fold("image name".len(), max)
+        + 2;
+    output_buffer.addln(&format!(
+        "{:width$} | {:>8} | {:>6} | {:>10} | {:>10} | {:>10} | {:>10}",
+        "Image Name",
+        "PID",
+        "CPU %",
+        "Memory",
+        "Disk",
+        "Read",
+        "Written",
+        width = image_name_width
+    ));
+    output_buffer.addln(&format!(
+        "{:-<width$}-+-{:-<8}-+-{:-<6}-+-{:-<10}-+-{:-<10}-+-{:-<10}-+-{:-<10}",
+        "",
+        "",
+        "",
+        "",
+        "",
+        "",
+        "",
+        width = image_name_width
+    ));
+    for vm in stats {
+        output_buffer.addln(&format!(
+            "{:width$} | {:>8} | {:>6.1} | {:>10} | {:>10} | {:>10} | {:>10}",
+            vm.image_name,
+            vm.pid,
+            vm.cpu_percent,
+            format_bytes(vm.resident_memory),
+            vm.disk_size.map_or("-".to_owned(), format_bytes),
+            format_bytes(vm.read_bytes),
+            format_bytes(vm.written_bytes),
+            width = image_name_width
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_bytes, get_cpu_percent, parse_field, parse_stat_cpu_ticks};
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_proc_files() {
+        let stat: &str = "4242 (qemu-system-x86) S 1 4241 4241 0 -1 4194624 52711 0 0 0 1500 250 0 0 20 0 7 0 123456 4300000000 262144 18446744073709551615";
+        assert_eq!(parse_stat_cpu_ticks(stat), Some(1750));
+        assert_eq!(
+            parse_stat_cpu_ticks("7 (a) b) c) R 1 2 3 4 5 6 7 8 9 10 11 12 13"),
+            Some(23)
+        );
+        assert_eq!(parse_stat_cpu_ticks("7 (truncated"), None);
+
+        let io: &str =
+            "rchar: 100\nread_bytes: 4096\nwrite_bytes: 8192\ncancelled_write_bytes: 0\n";
+        assert_eq!(parse_field(io, "read_bytes"), Some(4096));
+        assert_eq!(parse_field(io, "write_bytes"), Some(8192));
+        assert_eq!(parse_field("VmRSS:\t  204800 kB\n", "VmRSS"), Some(204800));
+        assert_eq!(parse_field(io, "VmRSS"), None);
+
+        assert_eq!(get_cpu_percent(150, Duration::from_secs(1), 100), 150.0);
+        assert_eq!(get_cpu_percent(150, Duration::ZERO, 100), 0.0);
+
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(3 << 29), "1.5 GiB");
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+use crate::config::{Config, RestartPolicy};
+use crate::logs::rotate_all_logs;
+use crate::qemu_runner::QemuRunner;
+use crate::state::{read_pidfile, take_stop_marker};
+use crate::utils::{get_list_of_running_vms, get_pidfile_path};
+use crate::webhooks::{get_exit_event, notify};
+
+/// How often running VMs are checked for having exited.
+const SUPERVISE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// How long to wait before starting a VM again after it first exits. The
+/// wait doubles with each exit in a row, up to `MAXIMUM_RESTART_BACKOFF`.
+const INITIAL_RESTART_BACKOFF: Duration = Duration::from_secs(1);
+const MAXIMUM_RESTART_BACKOFF: Duration = Duration::from_secs(300);
+/// How long a VM has to keep running after being started again for its
+/// exits to no longer count as being in a row.
+const STABLE_UPTIME: Duration = Duration::from_secs(600);
+/// How often the logs of VMs are rotated while supervising them.
+const LOG_ROTATION_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// A running VM watched by the supervisor.
+struct WatchedVm {
+    vm: QemuRunner,
+    policy: RestartPolicy,
+    /// When the supervisor last started the VM again, if ever.
+    restarted_at: Option<Instant>,
+    /// How many times the VM has exited in a row without running stably in
+    /// between.
+    exits_in_a_row: u32,
+    /// When to next try starting the VM, once it has exited.
+    restart_at: Option<Instant>,
+}
+
+fn get_restart_backoff(exits_in_a_row: u32) -> Duration {
+    //! Returns how long to wait before starting a VM again after it exited
+    //! the given number of times in a row.
+    INITIAL_RESTART_BACKOFF
+        .saturating_mul(2u32.saturating_pow(exits_in_a_row.saturating_sub(1)))
+        .min(MAXIMUM_RESTART_BACKOFF)
+}
+
+fn should_restart(policy: RestartPolicy, crashed: bool) -> bool {
+    match policy {
+        RestartPolicy::Always => true,
+        RestartPolicy::OnFailure => crashed,
+        RestartPolicy::Never => false,
+    }
+}
+
+fn get_restart_policy(vm: &QemuRunner, config: &Config) -> RestartPolicy {
+    //! Returns the restart policy of a running VM, which instances share with
+    //! their image.
+    config
+        .get_vm_config_with_name(&vm.base_image_name())
+        .map_or(RestartPolicy::Never, |vm_config| vm_config.restart_policy())
+}
+
+fn handle_exit(watched: &mut WatchedVm, vm_name: &str, config: &Config) -> bool {
+    //! Decides what to do about a watched VM which is no longer running.
+    //! Returns whether to keep watching it, to start it again.
+    let stopped: bool = take_stop_marker(vm_name);
+    // qemu removes its PID file when it exits by itself, but not when it
+    // crashes or is killed.
+    let crashed: bool = read_pidfile(&get_pidfile_path(vm_name)).is_some();
+    if let Some(event) = get_exit_event(stopped, crashed) {
+        notify(
+            config.get_webhooks(),
+            event,
+            vm_name,
+            json!({ "restart_policy": watched.policy }),
+        );
+    }
+    if stopped {
+        println!("VM '{vm_name}' was stopped; no longer watching it.");
+        return false;
+    }
+    let how: &str = if crashed { "crashed" } else { "exited" };
+    if !should_restart(watched.policy, crashed) {
+        println!(
+            "VM '{vm_name}' {how}; not starting it again, as its restart policy is {}.",
+            watched.policy
+        );
+        return false;
+    }
+    if watched
+        .restarted_at
+        .is_some_and(|restarted_at| restarted_at.elapsed() >= STABLE_UPTIME)
+    {
+        watched.exits_in_a_row = 0;
+    }
+    watched.exits_in_a_row += 1;
+    let backoff: Duration = get_restart_backoff(watched.exits_in_a_row);
+    println!(
+        "VM '{vm_name}' {how}; starting it again in {} seconds.",
+        backoff.as_secs()
+    );
+    watched.restart_at = Some(Instant::now() + backoff);
+    true
+}
+
+fn restart(watched: &mut WatchedVm, vm_name: &str, config: &Config) {
+    //! Starts a VM which exited again, counting the crash, or tries again
+    //! after a longer wait if that fails.
+    let crashes: usize = watched.vm.crashes();
+    watched.vm.set_crashes(crashes + 1);
+    match watched.vm.relaunch(config) {
+        Ok(()) => {
+            println!(
+                "Started VM '{vm_name}' again; it has exited {} time(s).",
+                watched.vm.crashes()
+            );
+            watched.restarted_at = Some(Instant::now());
+            watched.restart_at = None;
+        }
+        Err(e) => {
+            watched.vm.set_crashes(crashes);
+            watched.exits_in_a_row += 1;
+            let backoff: Duration = get_restart_backoff(watched.exits_in_a_row);
+            eprintln!(
+                "Unable to start VM '{vm_name}' again; retrying in {} seconds. {e}",
+                backoff.as_secs()
+            );
+            watched.restart_at = Some(Instant::now() + backoff);
+        }
+    }
+}
+
+pub fn supervise(config: &Config) -> Result<(), String> {
+    //! Watches the VMs running in the background whose `restart_policy`
+    //! isn't `never`, including ones started later, and starts them again
+    //! when they exit without having been stopped through vm-manager. Runs
+    //! until interrupted. The logs of VMs are rotated along the way.
+    let mut watched_vms: HashMap<String, WatchedVm> = HashMap::new();
+    let mut logs_rotated_at: Option<Instant> = None;
+    println!("Supervising VMs with a restart policy. Press Ctrl-C to stop.");
+    loop {
+        if logs_rotated_at.is_none_or(|rotated_at| rotated_at.elapsed() >= LOG_ROTATION_INTERVAL) {
+            match rotate_all_logs(&config.get_log_rotation()) {
+                Ok(done) => done.iter().for_each(|line| println!("{line}")),
+                Err(e) => eprintln!("{e}"),
+            }
+            logs_rotated_at = Some(Instant::now());
+        }
+
+        let running_vms: Vec<QemuRunner> = get_list_of_running_vms();
+        let running_names: Vec<String> = running_vms.iter().map(|vm| vm.image_name()).collect();
+        for vm in running_vms {
+            let policy: RestartPolicy = get_restart_policy(&vm, config);
+            // VMs in the foreground are supervised by whoever runs them.
+            if policy == RestartPolicy::Never || !vm.is_daemonized() {
+                continue;
+            }
+            let vm_name: String = vm.image_name();
+            match watched_vms.get_mut(&vm_name) {
+                Some(watched) => {
+                    watched.vm = vm;
+                    watched.policy = policy;
+                    watched.restart_at = None;
+                }
+                None => {
+                    println!("Watching VM '{vm_name}' (restart policy {policy}).");
+                    watched_vms.insert(
+                        vm_name,
+                        WatchedVm {
+                            vm,
+                            policy,
+                            restarted_at: None,
+                            exits_in_a_row: 0,
+                            restart_at: None,
+                        },
+                    );
+                }
+            }
+        }
+
+        watched_vms.retain(|vm_name, watched| {
+            if running_names.contains(vm_name) {
+                return true;
+            }
+            match watched.restart_at {
+                None => handle_exit(watched, vm_name, config),
+                Some(restart_at) => {
+                    if Instant::now() >= restart_at {
+                        restart(watched, vm_name, config);
+                    }
+                    true
+                }
+            }
+        });
+        sleep(SUPERVISE_POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_restart_backoff, should_restart};
+    use crate::config::RestartPolicy;
+    use std::time::Duration;
+
+    #[test]
+    fn test_restart_policy() {
+        assert_eq!(get_restart_backoff(1), Duration::from_secs(1));
+        assert_eq!(get_restart_backoff(4), Duration::from_secs(8));
+        assert_eq!(get_restart_backoff(40), Duration::from_secs(300));
+
+        assert!(should_restart(RestartPolicy::Always, false));
+        assert!(should_restart(RestartPolicy::OnFailure, true));
+        assert!(!should_restart(RestartPolicy::OnFailure, false));
+        assert!(!should_restart(RestartPolicy::Never, true));
+    }
+}
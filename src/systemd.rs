@@ -0,0 +1,160 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use std::time::Duration;
+
+use crate::utils::run_shell_command;
+use crate::SYSTEMD_USER_UNIT_DIRECTORY;
+
+/// How long systemd waits for `vm-manager stop --wait` on top of its own
+/// timeouts, before killing the service itself.
+const STOP_TIMEOUT_MARGIN: Duration = Duration::from_secs(30);
+
+/// What a generated unit runs, and how long stopping it may take.
+pub struct ServiceOptions<'a> {
+    pub executable: &'a Path,
+    pub config_file: &'a Path,
+    pub image_name: &'a str,
+    pub instance: Option<&'a str>,
+    pub shutdown_timeout: Duration,
+}
+
+pub fn get_unit_name(vm_name: &str) -> String {
+    //! Returns the name of the user unit running a VM. The `@` of instance
+    //! names is replaced, as systemd reads it as the start of a template
+    //! instance name.
+    format!("vm-manager-{}.service", vm_name.replace('@', "-"))
+}
+
+pub fn get_unit_path(vm_name: &str) -> PathBuf {
+    //! Returns the path the user unit running a VM is written to.
+    PathBuf::from(shellexpand::tilde(SYSTEMD_USER_UNIT_DIRECTORY).to_string())
+        .join(get_unit_name(vm_name))
+}
+
+fn escape_exec_arg(arg: &str) -> String {
+    //! Escapes an argument of an `ExecStart=` or `ExecStop=` line, which
+    //! systemd splits on whitespace and expands `%` specifiers and `$`
+    //! variables in.
+    let escaped: String = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    if escaped.is_empty() || escaped.contains(char::is_whitespace) || escaped != arg {
+        format!("\"{escaped}\"")
+    } else {
+        escaped
+    }
+}
+
+fn exec_line(options: &ServiceOptions, args: &[&str]) -> String {
+    //! Returns a command line running vm-manager on the unit's VM with the
+    //! given arguments.
+    let mut command: Vec<String> = vec![
+        options.executable.display().to_string(),
+        "--config-file".to_owned(),
+        options.config_file.display().to_string(),
+    ];
+    command.extend(args.iter().map(|arg| arg.to_string()));
+    command.extend(["-i".to_owned(), options.image_name.to_owned()]);
+    if let Some(instance) = options.instance {
+        command.extend(["--instance".to_owned(), instance.to_owned()]);
+    }
+    command
+        .iter()
+        .map(|arg| escape_exec_arg(arg))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+pub fn generate_unit(vm_name: &str, options: &ServiceOptions) -> String {
+    //! Returns a systemd user service running the VM in the foreground, so
+    //! that systemd supervises it, and stopping it gracefully. Stopping may
+    //! take a shutdown timeout for each of ACPI, `SIGTERM` and `SIGKILL`.
+    let stop_timeout: Duration = options.shutdown_timeout * 3 + STOP_TIMEOUT_MARGIN;
+    format!(
+        "[Unit]
+Description=vm-manager VM '{vm_name}'
+After=network.target
+
+[Service]
+Type=simple
+ExecStart={}
+ExecStop={}
+TimeoutStopSec={}
+Restart=on-failure
+
+[Install]
+WantedBy=default.target
+",
+        exec_line(options, &["--foreground", "start"]),
+        exec_line(options, &["stop", "--wait"]),
+        stop_timeout.as_secs()
+    )
+}
+
+fn run_systemctl(args: &[&str]) -> Result<(), String> {
+    let mut command: Vec<&str> = vec!["systemctl", "--user"];
+    command.extend_from_slice(args);
+    let output: Output = run_shell_command(&command)?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "'{}' failed. {}",
+            command.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+pub fn write_unit(
+    vm_name: &str,
+    options: &ServiceOptions,
+    enable: bool,
+) -> Result<PathBuf, String> {
+    //! Writes the user unit running a VM, and with `enable`, has systemd
+    //! start it at login from now on. Returns the path of the unit.
+    let path: PathBuf = get_unit_path(vm_name);
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)
+            .map_err(|e| format!("Unable to create directory '{}'. {e}", directory.display()))?;
+    }
+    fs::write(&path, generate_unit(vm_name, options))
+        .map_err(|e| format!("Unable to write unit '{}'. {e}", path.display()))?;
+    if enable {
+        run_systemctl(&["daemon-reload"])?;
+        run_systemctl(&["enable", &get_unit_name(vm_name)])?;
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape_exec_arg, generate_unit, get_unit_name, ServiceOptions};
+    use std::path::Path;
+    use std::time::Duration;
+
+    #[test]
+    fn test_generate_unit() {
+        assert_eq!(get_unit_name("deb12@web1"), "vm-manager-deb12-web1.service");
+        assert_eq!(escape_exec_arg("deb12"), "deb12");
+        assert_eq!(escape_exec_arg("my vms/a%b"), "\"my vms/a%%b\"");
+
+        let unit: String = generate_unit(
+            "deb12@web1",
+            &ServiceOptions {
+                executable: Path::new("/usr/bin/vm-manager"),
+                config_file: Path::new("/home/dev/.vm-manager/config.yml"),
+                image_name: "deb12",
+                instance: Some("web1"),
+                shutdown_timeout: Duration::from_secs(30),
+            },
+        );
+        assert!(unit.contains("\nExecStart=/usr/bin/vm-manager --config-file /home/dev/.vm-manager/config.yml --foreground start -i deb12 --instance web1\n"));
+        assert!(unit.contains("\nExecStop=/usr/bin/vm-manager --config-file /home/dev/.vm-manager/config.yml stop --wait -i deb12 --instance web1\n"));
+        assert!(unit.contains("\nTimeoutStopSec=120\n"));
+        assert!(unit.ends_with("WantedBy=default.target\n"));
+    }
+}
@@ -0,0 +1,141 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::Output;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::state::read_pidfile;
+use crate::utils::{is_process_running, run_shell_command, wait_for_process_exit};
+use crate::{RUNTIME_DIRECTORY, TPM_DIRECTORY};
+
+/// How long to wait for `swtpm` to create its control socket.
+const SWTPM_STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for `swtpm` to exit once asked to.
+const SWTPM_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// ID of the qemu TPM backend, which identifies VMs using an emulated TPM.
+const TPM_DEVICE_ID: &str = "tpm0";
+
+pub fn get_tpm_state_directory(vm_name: &str) -> PathBuf {
+    //! Returns the directory holding the persistent state of a VM's emulated
+    //! TPM, such as its keys.
+    PathBuf::from(shellexpand::tilde(&format!("{TPM_DIRECTORY}/{vm_name}")).to_string())
+}
+
+pub fn get_swtpm_socket_path(vm_name: &str) -> PathBuf {
+    //! Returns the path of the socket qemu talks to a VM's `swtpm` over.
+    PathBuf::from(shellexpand::tilde(&format!("{RUNTIME_DIRECTORY}/{vm_name}.swtpm")).to_string())
+}
+
+fn get_swtpm_pidfile_path(vm_name: &str) -> PathBuf {
+    //! Returns the path of the file `swtpm` writes its PID to.
+    PathBuf::from(
+        shellexpand::tilde(&format!("{RUNTIME_DIRECTORY}/{vm_name}.swtpm.pid")).to_string(),
+    )
+}
+
+pub fn tpm_qemu_args(vm_name: &str) -> Vec<String> {
+    //! Returns the arguments attaching a TPM 2.0 device backed by the VM's
+    //! `swtpm` to the guest.
+    vec![
+        "-chardev".to_owned(),
+        format!(
+            "socket,id=chr{TPM_DEVICE_ID},path={}",
+            get_swtpm_socket_path(vm_name).display()
+        ),
+        "-tpmdev".to_owned(),
+        format!("emulator,id={TPM_DEVICE_ID},chardev=chr{TPM_DEVICE_ID}"),
+        "-device".to_owned(),
+        format!("tpm-tis,tpmdev={TPM_DEVICE_ID}"),
+    ]
+}
+
+pub fn uses_tpm(args: &[&str]) -> bool {
+    //! Returns `true` if a qemu command line attaches an emulated TPM.
+    args.iter()
+        .any(|arg| arg.starts_with(&format!("emulator,id={TPM_DEVICE_ID},")))
+}
+
+pub fn start_swtpm(vm_name: &str) -> Result<(), String> {
+    //! Starts a `swtpm` process in the background for the given VM, keeping
+    //! its state across restarts of the VM, and waits for its socket to
+    //! appear. `swtpm` exits by itself once qemu disconnects from it.
+    let state_directory: PathBuf = get_tpm_state_directory(vm_name);
+    fs::create_dir_all(&state_directory).map_err(|e| {
+        format!(
+            "Unable to create TPM state directory '{}'. {e}",
+            state_directory.display()
+        )
+    })?;
+    // a swtpm left behind by a VM which was killed would hold the socket.
+    stop_swtpm(vm_name);
+
+    let socket_path: PathBuf = get_swtpm_socket_path(vm_name);
+    let output: Output = run_shell_command(&[
+        "swtpm",
+        "socket",
+        "--tpm2",
+        "--tpmstate",
+        &format!("dir={}", state_directory.display()),
+        "--ctrl",
+        &format!("type=unixio,path={}", socket_path.display()),
+        "--pid",
+        &format!("file={}", get_swtpm_pidfile_path(vm_name).display()),
+        "--log",
+        &format!("file={}", state_directory.join("swtpm.log").display()),
+        "--terminate",
+        "--daemon",
+    ])
+    .map_err(|e| format!("Unable to run 'swtpm'. Is it installed? {e}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Failed to start 'swtpm' for VM '{vm_name}'. {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let started_waiting: Instant = Instant::now();
+    while !socket_path.exists() {
+        if started_waiting.elapsed() > SWTPM_STARTUP_TIMEOUT {
+            stop_swtpm(vm_name);
+            return Err(format!(
+                "'swtpm' for VM '{vm_name}' did not create its socket within {} seconds.",
+                SWTPM_STARTUP_TIMEOUT.as_secs()
+            ));
+        }
+        sleep(Duration::from_millis(100));
+    }
+    Ok(())
+}
+
+pub fn stop_swtpm(vm_name: &str) {
+    //! Stops the `swtpm` process of the given VM, if it is still running, and
+    //! removes its runtime files.
+    let pidfile_path: PathBuf = get_swtpm_pidfile_path(vm_name);
+    if let Some(pid) = read_pidfile(&pidfile_path) {
+        if is_process_running(pid) {
+            let _ = run_shell_command(&["kill", &format!("{pid}")]);
+            if !wait_for_process_exit(pid, SWTPM_SHUTDOWN_TIMEOUT) {
+                eprintln!("'swtpm' (PID {pid}) of VM '{vm_name}' did not exit.");
+            }
+        }
+    }
+    let _ = fs::remove_file(pidfile_path);
+    let _ = fs::remove_file(get_swtpm_socket_path(vm_name));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{tpm_qemu_args, uses_tpm};
+
+    #[test]
+    fn test_uses_tpm() {
+        let args: Vec<String> = tpm_qemu_args("win11");
+        assert_eq!(args.len(), 6);
+        assert!(args[1].starts_with("socket,id=chrtpm0,path="));
+        assert!(args[1].ends_with("/win11.swtpm"));
+        assert!(uses_tpm(
+            &args.iter().map(|arg| arg.as_str()).collect::<Vec<&str>>()
+        ));
+        assert!(!uses_tpm(&["-m", "8G"]));
+    }
+}
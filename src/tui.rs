@@ -0,0 +1,552 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::widgets::{Block, List, ListItem, Paragraph, Row, Sparkline, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::config::Config;
+use crate::console::attach_console;
+use crate::instance::split_vm_name;
+use crate::parse_args::{Arguments, StartOptions};
+use crate::qemu_runner::QemuRunner;
+use crate::ssh::SshTarget;
+use crate::stats::{
+    format_bytes, get_clock_ticks, get_cpu_percent, read_process_sample, ProcessSample,
+};
+use crate::utils::{
+    get_list_of_images, get_list_of_running_vms, get_serial_socket_path, run_interactive_command,
+};
+use crate::{run_command_start, ImageLocation};
+
+/// How often the list of VMs and their resource usage is refreshed.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+/// How many refreshes the resource graphs keep.
+const HISTORY_LENGTH: usize = 300;
+/// How many lines the log panel keeps.
+const LOG_LENGTH: usize = 200;
+const KEY_HELP: &str =
+    " q quit | j/k select | s start | x stop | X force stop | p pause/resume | S ssh | c console";
+
+/// A VM listed by the dashboard, which lists every image and every running
+/// instance.
+struct VmEntry {
+    name: String,
+    vm: Option<QemuRunner>,
+    /// The run state reported by qemu, e.g. `running` or `paused`, or
+    /// `stopped`.
+    status: String,
+    uptime: Option<String>,
+}
+
+/// The CPU and memory usage of a running VM over recent refreshes.
+#[derive(Default)]
+struct UsageHistory {
+    last_sample: Option<(Instant, ProcessSample)>,
+    cpu_percent: VecDeque<u64>,
+    resident_memory: VecDeque<u64>,
+}
+
+impl UsageHistory {
+    fn record(&mut self, sample: ProcessSample, clock_ticks: u64) {
+        let now: Instant = Instant::now();
+        if let Some((then, last_sample)) = self.last_sample {
+            let cpu_ticks: u64 = sample.cpu_ticks.saturating_sub(last_sample.cpu_ticks);
+            let cpu_percent: f64 = get_cpu_percent(cpu_ticks, now - then, clock_ticks);
+            push_bounded(&mut self.cpu_percent, cpu_percent.round() as u64);
+        }
+        push_bounded(&mut self.resident_memory, sample.resident_memory);
+        self.last_sample = Some((now, sample));
+    }
+}
+
+fn push_bounded<T>(values: &mut VecDeque<T>, value: T) {
+    if values.len() == HISTORY_LENGTH {
+        values.pop_front();
+    }
+    values.push_back(value);
+}
+
+fn get_vm_names(image_names: &[String], running_names: &[String]) -> Vec<String> {
+    //! Returns the sorted names of every image and every running VM, which
+    //! includes instances and VMs whose image has since been moved.
+    let mut names: Vec<String> = image_names.iter().chain(running_names).cloned().collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+fn get_latest<T: Copy>(values: &VecDeque<T>, width: u16) -> Vec<T> {
+    //! Returns as many of the latest values as fit in a graph `width` wide.
+    values
+        .iter()
+        .skip(values.len().saturating_sub(width as usize))
+        .copied()
+        .collect()
+}
+
+struct Dashboard {
+    config: Arc<Config>,
+    entries: Vec<VmEntry>,
+    usage: HashMap<String, UsageHistory>,
+    table_state: TableState,
+    log: VecDeque<String>,
+    /// Carries the outcomes of starting and stopping VMs, which is done in
+    /// the background, to the log.
+    log_sender: Sender<String>,
+    log_receiver: Receiver<String>,
+    clock_ticks: u64,
+}
+
+impl Dashboard {
+    fn new(config: &Config) -> Self {
+        let (log_sender, log_receiver) = channel();
+        Self {
+            config: Arc::new(config.clone()),
+            entries: Vec::new(),
+            usage: HashMap::new(),
+            table_state: TableState::default().with_selected(Some(0)),
+            log: VecDeque::new(),
+            log_sender,
+            log_receiver,
+            clock_ticks: get_clock_ticks(),
+        }
+    }
+
+    fn add_log(&mut self, message: String) {
+        if self.log.len() == LOG_LENGTH {
+            self.log.pop_front();
+        }
+        self.log.push_back(format!(
+            "{}  {message}",
+            chrono::Local::now().format("%H:%M:%S")
+        ));
+    }
+
+    fn selected(&self) -> Option<&VmEntry> {
+        self.entries.get(self.table_state.selected()?)
+    }
+
+    fn selected_vm(&mut self) -> Option<QemuRunner> {
+        //! Returns the selected VM if it is running, or else logs that it
+        //! isn't.
+        let entry: &VmEntry = self.selected()?;
+        if entry.vm.is_none() {
+            let message: String = format!("VM '{}' isn't running.", entry.name);
+            self.add_log(message);
+        }
+        self.selected()?.vm.clone()
+    }
+
+    fn refresh(&mut self) {
+        //! Lists the images and running VMs again, and samples the resource
+        //! usage of the running ones. The same VM stays selected.
+        let selected_name: Option<String> = self.selected().map(|entry| entry.name.clone());
+        let running_vms: Vec<QemuRunner> = get_list_of_running_vms();
+        let running_names: Vec<String> = running_vms.iter().map(|vm| vm.image_name()).collect();
+        let image_names: Vec<String> =
+            get_list_of_images(ImageLocation::WorkingImages, &self.config);
+
+        self.usage.retain(|name, _| running_names.contains(name));
+        self.entries = get_vm_names(&image_names, &running_names)
+            .into_iter()
+            .map(|name| {
+                let vm: Option<QemuRunner> = running_vms
+                    .iter()
+                    .find(|vm| vm.image_name() == name)
+                    .cloned();
+                let status: String = match &vm {
+                    Some(vm) => vm
+                        .qmp_client()
+                        .and_then(|mut client| client.query_status())
+                        .unwrap_or("running".to_owned()),
+                    None => "stopped".to_owned(),
+                };
+                if let Some(sample) = vm
+                    .as_ref()
+                    .and_then(|vm| vm.pid())
+                    .and_then(read_process_sample)
+                {
+                    self.usage
+                        .entry(name.clone())
+                        .or_default()
+                        .record(sample, self.clock_ticks);
+                }
+                VmEntry {
+                    uptime: vm.as_ref().and_then(|vm| vm.uptime()),
+                    name,
+                    vm,
+                    status,
+                }
+            })
+            .collect();
+
+        let selected: usize = selected_name
+            .and_then(|name| self.entries.iter().position(|entry| entry.name == name))
+            .or(self.table_state.selected())
+            .unwrap_or(0);
+        self.table_state
+            .select(Some(selected.min(self.entries.len().saturating_sub(1))));
+    }
+
+    fn start_selected(&mut self) {
+        //! Starts the selected VM in the background, as `vm-manager start`
+        //! does without options.
+        let Some(entry) = self.selected() else {
+            return;
+        };
+        let name: String = entry.name.clone();
+        if entry.vm.is_some() {
+            self.add_log(format!("VM '{name}' is already running."));
+            return;
+        }
+        self.add_log(format!("Starting VM '{name}'..."));
+        let config: Arc<Config> = Arc::clone(&self.config);
+        let log_sender: Sender<String> = self.log_sender.clone();
+        thread::spawn(move || {
+            let (image_name, instance) = split_vm_name(&name);
+            let result: Result<(), String> =
+                Arguments::try_parse_from(["vm-manager", "-i", image_name])
+                    .map_err(|e| e.to_string())
+                    .and_then(|mut args| {
+                        args.instance = instance.map(|instance| instance.to_owned());
+                        run_command_start(&args, &StartOptions::default(), &config)
+                    });
+            let _ = log_sender.send(match result {
+                Ok(()) => format!("Started VM '{name}'."),
+                Err(e) => format!("Unable to start VM '{name}'. {e}"),
+            });
+        });
+    }
+
+    fn stop_selected(&mut self, force: bool) {
+        //! Stops the selected VM in the background, as `vm-manager stop`
+        //! does.
+        let Some(vm) = self.selected_vm() else {
+            return;
+        };
+        let name: String = vm.image_name();
+        self.add_log(format!("Stopping VM '{name}'..."));
+        let config: Arc<Config> = Arc::clone(&self.config);
+        let log_sender: Sender<String> = self.log_sender.clone();
+        thread::spawn(move || {
+            let _ = log_sender.send(
+                match vm.stop(force, config.get_shutdown_timeout(), &config) {
+                    Ok(()) => format!("Stopped VM '{name}'."),
+                    Err(e) => format!("Unable to stop VM '{name}'. {e}"),
+                },
+            );
+        });
+    }
+
+    fn toggle_pause_selected(&mut self) {
+        let Some(vm) = self.selected_vm() else {
+            return;
+        };
+        let result: Result<String, String> = vm.qmp_client().and_then(|mut client| {
+            if client.query_status()? == "paused" {
+                client.resume().map(|()| "Resumed")
+            } else {
+                client.pause().map(|()| "Paused")
+            }
+            .map(|done| format!("{done} VM '{}'.", vm.image_name()))
+        });
+        self.add_log(result.unwrap_or_else(|e| e));
+    }
+
+    fn ssh_into_selected(&mut self) -> Result<(), String> {
+        let Some(vm) = self.selected_vm() else {
+            return Ok(());
+        };
+        let port: usize = vm.forwarded_host_port(22).ok_or(format!(
+            "VM '{}' does not forward any host port to guest port 22.",
+            vm.image_name()
+        ))?;
+        let target: SshTarget =
+            SshTarget::new(port, self.config.get_ssh_credentials(&vm.base_image_name()));
+        let args: Vec<String> = target.ssh_args();
+        run_interactive_command(&args.iter().map(|arg| arg.as_str()).collect::<Vec<&str>>())
+    }
+
+    fn attach_to_selected(&mut self) -> Result<(), String> {
+        let Some(vm) = self.selected_vm() else {
+            return Ok(());
+        };
+        let socket_path: PathBuf = get_serial_socket_path(&vm.image_name());
+        if !socket_path.exists() {
+            return Err(format!(
+                "VM '{}' has no serial console socket.",
+                vm.image_name()
+            ));
+        }
+        attach_console(&vm.image_name(), &socket_path)
+    }
+
+    fn leave_terminal(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        run: fn(&mut Self) -> Result<(), String>,
+    ) -> Result<(), String> {
+        //! Hands the terminal over to an interactive session, such as `ssh`,
+        //! and takes it back once it ends.
+        ratatui::restore();
+        let result: Result<(), String> = run(self);
+        *terminal =
+            ratatui::try_init().map_err(|e| format!("Unable to set up the terminal. {e}"))?;
+        if let Err(e) = result {
+            self.add_log(e);
+        }
+        Ok(())
+    }
+
+    fn handle_key(
+        &mut self,
+        key: KeyEvent,
+        terminal: &mut DefaultTerminal,
+    ) -> Result<bool, String> {
+        //! Acts on a key press. Returns whether to quit.
+        if key.modifiers.contains(KeyModifiers::CONTROL) {
+            return Ok(key.code == KeyCode::Char('c'));
+        }
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(true),
+            KeyCode::Down | KeyCode::Char('j') => self.table_state.select_next(),
+            KeyCode::Up | KeyCode::Char('k') => self.table_state.select_previous(),
+            KeyCode::Char('s') => self.start_selected(),
+            KeyCode::Char('x') => self.stop_selected(false),
+            KeyCode::Char('X') => self.stop_selected(true),
+            KeyCode::Char('p') => self.toggle_pause_selected(),
+            KeyCode::Char('S') => self.leave_terminal(terminal, Self::ssh_into_selected)?,
+            KeyCode::Char('c') => self.leave_terminal(terminal, Self::attach_to_selected)?,
+            _ => {}
+        }
+        Ok(false)
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<(), String> {
+        let mut next_refresh: Instant = Instant::now();
+        loop {
+            // a VM started or stopped in the background shows up at once,
+            // and anything starting or stopping it printed is drawn over
+            while let Ok(message) = self.log_receiver.try_recv() {
+                self.add_log(message);
+                next_refresh = Instant::now();
+                terminal.clear().map_err(|e| e.to_string())?;
+            }
+            if Instant::now() >= next_refresh {
+                self.refresh();
+                next_refresh = Instant::now() + REFRESH_INTERVAL;
+            }
+            terminal
+                .draw(|frame| self.draw(frame))
+                .map_err(|e| format!("Unable to draw the dashboard. {e}"))?;
+
+            let timeout: Duration = next_refresh.saturating_duration_since(Instant::now());
+            if !event::poll(timeout).map_err(|e| e.to_string())? {
+                continue;
+            }
+            match event::read().map_err(|e| e.to_string())? {
+                Event::Key(key) if key.kind == KeyEventKind::Press => {
+                    if self.handle_key(key, terminal)? {
+                        return Ok(());
+                    }
+                    next_refresh = Instant::now();
+                }
+                Event::Resize(_, _) => terminal.clear().map_err(|e| e.to_string())?,
+                _ => {}
+            }
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main_area, log_area, help_area] = Layout::vertical([
+            Constraint::Min(8),
+            Constraint::Length(8),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [list_area, details_area] =
+            Layout::horizontal([Constraint::Percentage(55), Constraint::Percentage(45)])
+                .areas(main_area);
+
+        self.draw_vm_table(frame, list_area);
+        self.draw_details(frame, details_area);
+
+        let log_height: usize = log_area.height.saturating_sub(2) as usize;
+        let log_lines: Vec<ListItem> = self
+            .log
+            .iter()
+            .skip(self.log.len().saturating_sub(log_height))
+            .map(|line| ListItem::new(line.as_str()))
+            .collect();
+        frame.render_widget(
+            List::new(log_lines).block(Block::bordered().title(" Log ")),
+            log_area,
+        );
+        frame.render_widget(
+            Paragraph::new(KEY_HELP).style(Style::default().add_modifier(Modifier::REVERSED)),
+            help_area,
+        );
+    }
+
+    fn draw_vm_table(&mut self, frame: &mut Frame, area: Rect) {
+        let rows: Vec<Row> = self
+            .entries
+            .iter()
+            .map(|entry| {
+                let usage: Option<&UsageHistory> = self.usage.get(&entry.name);
+                let status_color: Color = match entry.status.as_str() {
+                    "running" => Color::Green,
+                    "stopped" => Color::DarkGray,
+                    _ => Color::Yellow,
+                };
+                Row::new(vec![
+                    entry.name.clone(),
+                    entry.status.clone(),
+                    entry
+                        .vm
+                        .as_ref()
+                        .and_then(|vm| vm.pid())
+                        .map_or(String::new(), |pid| pid.to_string()),
+                    usage
+                        .and_then(|usage| usage.cpu_percent.back())
+                        .map_or(String::new(), |cpu_percent| format!("{cpu_percent}%")),
+                    usage
+                        .and_then(|usage| usage.resident_memory.back())
+                        .map_or(String::new(), |memory| format_bytes(*memory)),
+                ])
+                .style(Style::default().fg(status_color))
+            })
+            .collect();
+        let table: Table = Table::new(
+            rows,
+            [
+                Constraint::Fill(1),
+                Constraint::Length(10),
+                Constraint::Length(8),
+                Constraint::Length(6),
+                Constraint::Length(10),
+            ],
+        )
+        .header(
+            Row::new(["Name", "Status", "PID", "CPU", "Memory"])
+                .style(Style::default().add_modifier(Modifier::BOLD)),
+        )
+        .row_highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+        .block(Block::bordered().title(" VMs "));
+        frame.render_stateful_widget(table, area, &mut self.table_state);
+    }
+
+    fn draw_details(&self, frame: &mut Frame, area: Rect) {
+        //! Draws the details and resource graphs of the selected VM.
+        let Some(entry) = self.selected() else {
+            frame.render_widget(Block::bordered().title(" No VMs "), area);
+            return;
+        };
+        let block: Block = Block::bordered().title(format!(" {} ", entry.name));
+        let inner: Rect = block.inner(area);
+        frame.render_widget(block, area);
+
+        let [info_area, cpu_area, memory_area] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Fill(1),
+            Constraint::Fill(1),
+        ])
+        .areas(inner);
+        let ports: String = entry.vm.as_ref().map_or(String::new(), |vm| {
+            vm.forwarded_ports()
+                .iter()
+                .map(|port| port.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        });
+        frame.render_widget(
+            Paragraph::new(format!(
+                "Status: {}\nUptime: {}\nPorts:  {}",
+                entry.status,
+                entry.uptime.as_deref().unwrap_or("-"),
+                if ports.is_empty() { "-" } else { &ports }
+            )),
+            info_area,
+        );
+
+        let Some(usage) = self.usage.get(&entry.name) else {
+            return;
+        };
+        let cpu_percent: Vec<u64> =
+            get_latest(&usage.cpu_percent, cpu_area.width.saturating_sub(2));
+        frame.render_widget(
+            Sparkline::default()
+                .block(Block::bordered().title(format!(
+                    " CPU {}% ",
+                    usage.cpu_percent.back().copied().unwrap_or(0)
+                )))
+                // 100% is one host CPU, which VMs with more may exceed
+                .max(cpu_percent.iter().copied().max().unwrap_or(0).max(100))
+                .style(Style::default().fg(Color::Cyan))
+                .data(cpu_percent),
+            cpu_area,
+        );
+        frame.render_widget(
+            Sparkline::default()
+                .block(Block::bordered().title(format!(
+                    " Memory {} ",
+                    format_bytes(usage.resident_memory.back().copied().unwrap_or(0))
+                )))
+                .style(Style::default().fg(Color::Magenta))
+                .data(get_latest(
+                    &usage.resident_memory,
+                    memory_area.width.saturating_sub(2),
+                )),
+            memory_area,
+        );
+    }
+}
+
+pub fn run_dashboard(config: &Config) -> Result<(), String> {
+    //! Shows a full-screen dashboard of every image and running VM until the
+    //! user quits, with keys to start, stop, pause, and log into them.
+    let mut dashboard: Dashboard = Dashboard::new(config);
+    let mut terminal: DefaultTerminal =
+        ratatui::try_init().map_err(|e| format!("Unable to set up the terminal. {e}"))?;
+    let result: Result<(), String> = dashboard.run(&mut terminal);
+    ratatui::restore();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_latest, get_vm_names, push_bounded, HISTORY_LENGTH};
+    use std::collections::VecDeque;
+
+    #[test]
+    fn test_dashboard_helpers() {
+        let to_strings =
+            |names: &[&str]| -> Vec<String> { names.iter().map(|name| name.to_string()).collect() };
+        assert_eq!(
+            get_vm_names(
+                &to_strings(&["deb12", "alpine"]),
+                &to_strings(&["deb12@web1", "deb12", "old"])
+            ),
+            to_strings(&["alpine", "deb12", "deb12@web1", "old"])
+        );
+
+        let mut values: VecDeque<usize> = VecDeque::new();
+        for value in 0..HISTORY_LENGTH + 5 {
+            push_bounded(&mut values, value);
+        }
+        assert_eq!(values.len(), HISTORY_LENGTH);
+        assert_eq!(values.front(), Some(&5));
+        assert_eq!(
+            get_latest(&values, 2),
+            vec![HISTORY_LENGTH + 3, HISTORY_LENGTH + 4]
+        );
+    }
+}
@@ -0,0 +1,129 @@
+use serde_json::{json, Value};
+use std::fs::{self, read_dir, OpenOptions};
+use std::path::PathBuf;
+
+use crate::hotplug::wait_for_device_deleted;
+use crate::qmp::QmpClient;
+
+/// Where the kernel lists the host's USB devices.
+const SYSFS_USB_DEVICES: &str = "/sys/bus/usb/devices";
+/// Where the device nodes of the host's USB devices are, by bus and device
+/// number.
+const DEV_BUS_USB: &str = "/dev/bus/usb";
+/// ID of the USB controller added to guests which don't have one yet.
+const USB_CONTROLLER_ID: &str = "usb-hotplug";
+
+pub fn parse_usb_id(id: &str) -> Result<(u16, u16), String> {
+    //! Parses a USB device's `vendor:product` ID, as shown by `lsusb`, e.g.
+    //! `1050:0407`.
+    let is_hex = |part: &str| part.len() == 4 && part.chars().all(|c| c.is_ascii_hexdigit());
+    match id.trim().split_once(':') {
+        Some((vendor, product)) if is_hex(vendor) && is_hex(product) => Ok((
+            u16::from_str_radix(vendor, 16).unwrap_or_default(),
+            u16::from_str_radix(product, 16).unwrap_or_default(),
+        )),
+        _ => Err(format!(
+            "Invalid USB device '{id}'. Use the 'vendor:product' ID shown by 'lsusb', e.g. '1050:0407'."
+        )),
+    }
+}
+
+fn get_usb_device_id(vendor: u16, product: u16) -> String {
+    //! Returns the ID of the guest device passing through a host USB device.
+    format!("usb-{vendor:04x}-{product:04x}")
+}
+
+fn find_usb_device(vendor: u16, product: u16) -> Option<PathBuf> {
+    //! Returns the device node of the first host USB device with the given
+    //! vendor and product IDs.
+    let read_attribute = |device: &PathBuf, name: &str| {
+        fs::read_to_string(device.join(name))
+            .ok()
+            .map(|value| value.trim().to_owned())
+    };
+    read_dir(SYSFS_USB_DEVICES)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .find_map(|device| {
+            let matches: bool = read_attribute(&device, "idVendor")? == format!("{vendor:04x}")
+                && read_attribute(&device, "idProduct")? == format!("{product:04x}");
+            if !matches {
+                return None;
+            }
+            let bus: u32 = read_attribute(&device, "busnum")?.parse().ok()?;
+            let number: u32 = read_attribute(&device, "devnum")?.parse().ok()?;
+            Some(PathBuf::from(format!("{DEV_BUS_USB}/{bus:03}/{number:03}")))
+        })
+}
+
+fn has_usb_controller(qmp: &mut QmpClient) -> Result<bool, String> {
+    //! Returns whether the USB controller this program adds is already in the
+    //! guest.
+    let devices: Value = qmp.execute("qom-list", Some(json!({ "path": "/machine/peripheral" })))?;
+    Ok(devices
+        .as_array()
+        .into_iter()
+        .flatten()
+        .any(|device| device["name"].as_str() == Some(USB_CONTROLLER_ID)))
+}
+
+pub fn attach_usb_device(qmp: &mut QmpClient, id: &str) -> Result<(), String> {
+    //! Passes the host USB device with the `vendor:product` ID `id` through
+    //! to a running guest, first adding a USB controller to the guest if
+    //! needed.
+    let (vendor, product): (u16, u16) = parse_usb_id(id)?;
+    let device_node: PathBuf = find_usb_device(vendor, product)
+        .ok_or(format!("No USB device '{id}' is plugged into the host."))?;
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&device_node)
+        .map_err(|e| {
+            format!(
+                "Unable to access USB device '{id}' at '{}'. {e}. Give your user access to it, e.g. with a udev rule.",
+                device_node.display()
+            )
+        })?;
+    if !has_usb_controller(qmp)? {
+        qmp.execute(
+            "device_add",
+            Some(json!({ "driver": "qemu-xhci", "id": USB_CONTROLLER_ID })),
+        )?;
+    }
+    qmp.execute(
+        "device_add",
+        Some(json!({
+            "driver": "usb-host",
+            "id": get_usb_device_id(vendor, product),
+            "bus": format!("{USB_CONTROLLER_ID}.0"),
+            "vendorid": vendor,
+            "productid": product,
+        })),
+    )
+    .map(|_| ())
+}
+
+pub fn detach_usb_device(qmp: &mut QmpClient, id: &str) -> Result<(), String> {
+    //! Unplugs a USB device passed through with `attach_usb_device` from a
+    //! running guest, handing it back to the host.
+    let (vendor, product): (u16, u16) = parse_usb_id(id)?;
+    let device_id: String = get_usb_device_id(vendor, product);
+    qmp.execute("device_del", Some(json!({ "id": device_id })))
+        .map_err(|e| format!("USB device '{id}' is not attached to the VM. {e}"))?;
+    wait_for_device_deleted(qmp, &device_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_usb_device_id, parse_usb_id};
+
+    #[test]
+    fn test_parse_usb_id() {
+        assert_eq!(parse_usb_id("1050:0407").unwrap(), (0x1050, 0x0407));
+        assert_eq!(parse_usb_id("046D:C52B\n").unwrap(), (0x046d, 0xc52b));
+        assert_eq!(get_usb_device_id(0x046d, 0xc52b), "usb-046d-c52b");
+        assert!(parse_usb_id("1050").is_err());
+        assert!(parse_usb_id("1050:04070").is_err());
+        assert!(parse_usb_id("zzzz:0407").is_err());
+    }
+}
@@ -1,12 +1,38 @@
 use crate::config::Config;
+use crate::parse_args::{OutputFormat, RunningVmColumn};
 use crate::qemu_runner::QemuRunner;
-use crate::{ImageLocation, IMAGES_DIRECTORY};
+use crate::state::{load_running_vm_states, ForwardedPort};
+use crate::stats::format_bytes;
+use crate::{image, matching, ImageLocation, RUNTIME_DIRECTORY};
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
 use std::cmp::max;
 use std::fs::read_dir;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Output};
-use std::str::Chars;
+use std::sync::{Mutex, PoisonError};
+use std::thread::{self, sleep, ThreadId};
+use std::time::{Duration, Instant};
+
+/// Ports `find_open_port` picked, with the thread each was picked on, so that
+/// VMs started at once on several threads aren't given the same port.
+static CLAIMED_PORTS: Mutex<Vec<(usize, ThreadId)>> = Mutex::new(Vec::new());
+
+/// Releases the ports the current thread claimed with `find_open_port` when
+/// dropped, by which time the VM they were picked for has bound them, or
+/// failed to start.
+pub struct PortClaims;
+
+impl Drop for PortClaims {
+    fn drop(&mut self) {
+        let thread: ThreadId = thread::current().id();
+        CLAIMED_PORTS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|(_, owner)| *owner != thread);
+    }
+}
 
 pub enum OutputStreamTarget {
     Stdout,
@@ -67,22 +93,34 @@ pub fn run_shell_command(command: &[&str]) -> Result<Output, String> {
     }
 }
 
-pub fn get_list_of_images(image_location: ImageLocation, config: &Config) -> Vec<String> {
-    //! Returns a vector of image names found in the given location.
-    //!
-    //! If the provided location is ImageLocation::WorkingImages, then
-    //! it will search the path ~/.vm-manager/disk-images
-    //!
-    //! If the provided location is ImageLocation::BackupImages, then
-    //! it will search the path ~/.vm-manager/disk-images/backups
-    let images_directory: String = match image_location {
-        ImageLocation::WorkingImages => config.get_images_directory(),
-        ImageLocation::BackupImages => config.get_backup_images_directory(),
-    };
+pub fn run_interactive_command(command: &[&str]) -> Result<(), String> {
+    //! Runs an arbitrary command attached to the current terminal, so that
+    //! the user can interact with it (e.g. to enter a password), returning an
+    //! error if it could not be run or exited unsuccessfully.
+    match Command::new(command[0]).args(&command[1..]).status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("'{}' exited with {status}.", command[0])),
+        Err(e) => Err(format!("Unable to run '{}'. {e}", command[0])),
+    }
+}
 
-    match read_dir(shellexpand::tilde(&images_directory).to_string()) {
+pub fn confirm(prompt: &str) -> bool {
+    //! Asks the user a yes/no question on the terminal, returning `true` only
+    //! if they answer yes.
+    print!("{prompt} [y/N] ");
+    let _ = std::io::stdout().flush();
+    let mut answer: String = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+fn get_images_in_directory(images_directory: &str) -> Vec<String> {
+    //! Returns the names of the images in a single directory.
+    match read_dir(shellexpand::tilde(images_directory).to_string()) {
         Err(e) => {
-            eprintln!("{e}");
+            eprintln!("Unable to read images directory '{images_directory}'. {e}");
             vec![]
         }
         Ok(iter) => iter
@@ -115,129 +153,462 @@ pub fn get_list_of_images(image_location: ImageLocation, config: &Config) -> Vec
     }
 }
 
-pub fn get_list_of_running_vms(config: &Config) -> Vec<QemuRunner> {
-    let output: String = match run_shell_command(&["ps", "ax"]) {
-        Ok(output) => match String::from_utf8(output.stdout) {
-            Ok(stdout) => stdout,
-            Err(e) => {
-                println!("from_utf ERROR: {e:#?}");
-                return vec![];
-            }
-        },
-        Err(e) => {
-            println!("run_shell_command ERROR: {e:#?}");
-            return vec![];
-        }
+pub fn get_list_of_images(image_location: ImageLocation, config: &Config) -> Vec<String> {
+    //! Returns a vector of image names found in the given location.
+    //!
+    //! If the provided location is ImageLocation::WorkingImages, then
+    //! it will search each of the config's images directories in order,
+    //! listing an image found in several of them only once, followed by the
+    //! images of VMs with an `image_path` and the names of VMs running an
+    //! image called differently, as long as their image exists
+    //!
+    //! If the provided location is ImageLocation::BackupImages, then
+    //! it will search the `backups` directory inside the primary one
+    let images_directories: Vec<String> = match image_location {
+        ImageLocation::WorkingImages => config.get_image_directories(),
+        ImageLocation::BackupImages => vec![config.get_backup_images_directory()],
     };
 
-    let mut result: Vec<QemuRunner> = vec![];
-
-    for line in output
-        .split('\n')
-        .filter(|l| l.contains("qemu-system-x86_64"))
-        .collect::<Vec<&str>>()
-    {
-        let strings: Vec<&str> = line.split_ascii_whitespace().collect();
-        let mut filename: String = String::new();
-        if let Some(fname) = strings[7].split('=').nth(1) {
-            if let Some(fstem) = Path::new(fname)
-                .file_stem()
-                .unwrap()
-                .to_os_string()
-                .to_str()
-            {
-                filename = fstem.to_owned();
+    let mut images: Vec<String> = vec![];
+    for images_directory in images_directories {
+        for image in get_images_in_directory(&images_directory) {
+            if !images.contains(&image) {
+                images.push(image);
             }
-        } else {
-            continue;
-        }
-
-        let pid: usize = strings[0]
-            .parse::<usize>()
-            .unwrap_or_else(|_| panic!("Unable to parse '{}' as usize.", strings[0]));
-        let port_data: Vec<&str> = strings[21].split(',').collect();
-        let ssh_port_data: Vec<&str> = port_data[2].split(':').collect();
-        let https_port_data: Vec<&str> = port_data[3].split(':').collect();
-        let mut ssh_port: Chars = ssh_port_data[2].chars();
-        let mut https_port: Chars = https_port_data[2].chars();
-
-        // remove trailing '-' character
-        ssh_port.next_back();
-        https_port.next_back();
-
-        let running_vm_entry: QemuRunner = QemuRunner::new(
-            ssh_port.as_str().parse::<usize>().unwrap(),
-            https_port.as_str().parse::<usize>().unwrap(),
-            &filename,
-            Some(pid),
-            config,
-        );
-        result.push(running_vm_entry);
+        }
     }
+    if let ImageLocation::WorkingImages = image_location {
+        for vm in config.get_vm_configs() {
+            if !image::get_image_path(vm.name(), config).is_file() {
+                continue;
+            }
+            for name in [vm.image_name(), vm.name()] {
+                if !images.iter().any(|image| image == name) {
+                    images.push(name.to_owned());
+                }
+            }
+        }
+    }
+    images
+}
 
-    result
+pub fn get_list_of_running_vms() -> Vec<QemuRunner> {
+    //! Returns a runner for each VM which is currently running, as recorded
+    //! in the state directory.
+    load_running_vm_states()
+        .into_iter()
+        .map(QemuRunner::from_state)
+        .collect()
 }
 
-pub fn print_running_vm_table(running_vms: &[QemuRunner], output_buffer: &mut OutputStream) {
-    let image_name_header_len = "image name".len();
-    let image_name_width: usize = if let Some(max_elem) =
-        running_vms.iter().reduce(|last_max, elem| {
-            if last_max.image_name().len() > elem.image_name().len() {
-                last_max
-            } else {
-                elem
-            }
-        }) {
-        max(max_elem.image_name().len(), image_name_header_len)
-    } else {
-        image_name_header_len
-    } + 2;
+/// A structured, renderer-agnostic view of a single running VM, used to
+/// build every running-VM listing.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct RunningVmRecord {
+    pub image_name: String,
+    pub pid: Option<usize>,
+    pub ssh_port: Option<usize>,
+    pub https_port: Option<usize>,
+    /// Every forwarded port, e.g. `5555 -> 22/tcp`.
+    pub ports: Vec<String>,
+    /// How many times `vm-manager supervise` has started the VM again after
+    /// it exited.
+    pub crashes: usize,
+    /// The tags of the VM's config.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// The run state reported by qemu, e.g. `running` or `paused`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+    /// The elapsed time since the VM was started, as reported by `ps`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uptime: Option<String>,
+    /// The host memory used by the qemu process, in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resident_memory: Option<u64>,
+}
+
+impl RunningVmRecord {
+    pub fn from_runner(vm: &QemuRunner, config: &Config) -> Self {
+        Self {
+            image_name: vm.image_name(),
+            pid: vm.pid(),
+            ssh_port: vm.forwarded_host_port(22),
+            https_port: vm.forwarded_host_port(443),
+            ports: vm
+                .forwarded_ports()
+                .iter()
+                .map(|port| port.to_string())
+                .collect(),
+            crashes: vm.crashes(),
+            tags: config.get_tags(&vm.base_image_name()),
+            state: vm.run_state(),
+            uptime: vm.uptime(),
+            resident_memory: vm.resident_memory(),
+        }
+    }
+}
+
+/// The details of a single running VM shown by `vm-manager status`.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct VmStatus {
+    pub image_name: String,
+    /// The run state reported by qemu, e.g. `running` or `paused`.
+    pub status: String,
+    pub pid: Option<usize>,
+    pub uptime: Option<String>,
+    pub ephemeral: bool,
+    /// Every forwarded port, e.g. `5555 -> 22/tcp`.
+    pub ports: Vec<String>,
+    pub command_line: Vec<String>,
+}
+
+impl VmStatus {
+    pub fn from_runner(vm: &QemuRunner) -> Self {
+        Self {
+            image_name: vm.image_name(),
+            status: vm.run_state().unwrap_or("running".to_owned()),
+            pid: vm.pid(),
+            uptime: vm.uptime(),
+            ephemeral: vm.is_ephemeral(),
+            ports: vm
+                .forwarded_ports()
+                .iter()
+                .map(|port| port.to_string())
+                .collect(),
+            command_line: vm.command_line().to_vec(),
+        }
+    }
+}
+
+pub fn print_vm_status(status: &VmStatus, output_buffer: &mut OutputStream) {
+    output_buffer.add_spacer();
     output_buffer.addln(&format!(
-        "{:8} | {:10} | {:width$}",
-        "SSH Port",
-        "HTTPS Port",
-        "Image Name",
-        width = image_name_width
+        "--------------------\n{}\n--------------------",
+        status.image_name
     ));
+    output_buffer.addln(&format!("Status:       {}", status.status));
     output_buffer.addln(&format!(
-        "{:-<8}-+-{:-<10}-+-{:-<width$}",
-        "",
-        "",
-        "",
-        width = image_name_width
+        "PID:          {}",
+        status
+            .pid
+            .map_or("unknown".to_owned(), |pid| pid.to_string())
     ));
-    for vm in running_vms {
-        output_buffer.addln(&format!(
-            "{:-8} | {:-10} | {:-width$}",
-            vm.ssh_port(),
-            vm.https_port(),
-            vm.image_name(),
-            width = image_name_width
+    output_buffer.addln(&format!(
+        "Uptime:       {}",
+        status.uptime.as_deref().unwrap_or("unknown")
+    ));
+    output_buffer.addln(&format!(
+        "Ephemeral:    {}",
+        if status.ephemeral { "yes" } else { "no" }
+    ));
+    output_buffer.addln(&format!(
+        "Ports:        {}",
+        if status.ports.is_empty() {
+            "none".to_owned()
+        } else {
+            status.ports.join(", ")
+        }
+    ));
+    output_buffer.addln(&format!("Command line: {}", status.command_line.join(" ")));
+}
+
+pub fn print_running_vms(
+    running_vms: &[QemuRunner],
+    config: &Config,
+    output_format: OutputFormat,
+    columns: Option<&[RunningVmColumn]>,
+    output_buffer: &mut OutputStream,
+) {
+    //! Renders the list of running VMs into `output_buffer` in the requested
+    //! format, with `columns` in a table.
+    let records: Vec<RunningVmRecord> = running_vms
+        .iter()
+        .map(|vm| RunningVmRecord::from_runner(vm, config))
+        .collect();
+    print_running_vm_records(&records, output_format, columns, output_buffer);
+}
+
+pub fn print_running_vm_records(
+    records: &[RunningVmRecord],
+    output_format: OutputFormat,
+    columns: Option<&[RunningVmColumn]>,
+    output_buffer: &mut OutputStream,
+) {
+    //! Renders records of running VMs, such as those listed by a `vm-manager
+    //! daemon`, into `output_buffer` in the requested format.
+    match output_format {
+        OutputFormat::Table => print_running_vm_table(records, columns, output_buffer),
+        OutputFormat::Yaml => match serde_yaml::to_string(&records) {
+            Ok(yaml) => output_buffer.addln(yaml.trim_end()),
+            Err(e) => eprintln!("Unable to render running VMs as YAML. {e}"),
+        },
+        OutputFormat::Csv => output_buffer.addln(&render_running_vms_csv(records)),
+    }
+}
+
+fn render_running_vms_csv(records: &[RunningVmRecord]) -> String {
+    //! Renders the records as CSV with a header row. Multiple forwarded ports
+    //! and tags are separated by `;` within the `ports` and `tags` columns.
+    let mut lines: Vec<String> = vec![
+        "image_name,pid,ssh_port,https_port,ports,crashes,tags,state,uptime,resident_memory"
+            .to_owned(),
+    ];
+    for record in records {
+        lines.push(
+            [
+                escape_csv_field(&record.image_name),
+                record.pid.map_or(String::new(), |pid| pid.to_string()),
+                record
+                    .ssh_port
+                    .map_or(String::new(), |port| port.to_string()),
+                record
+                    .https_port
+                    .map_or(String::new(), |port| port.to_string()),
+                escape_csv_field(&record.ports.join(";")),
+                record.crashes.to_string(),
+                escape_csv_field(&record.tags.join(";")),
+                record.state.clone().unwrap_or_default(),
+                escape_csv_field(record.uptime.as_deref().unwrap_or_default()),
+                record
+                    .resident_memory
+                    .map_or(String::new(), |memory| memory.to_string()),
+            ]
+            .join(","),
+        );
+    }
+    lines.join("\n")
+}
+
+pub fn print_forwarded_ports(
+    ports: &[ForwardedPort],
+    output_format: OutputFormat,
+    output_buffer: &mut OutputStream,
+) {
+    //! Renders the ports forwarded to a VM into `output_buffer` in the
+    //! requested format.
+    match output_format {
+        OutputFormat::Table => output_buffer.addln(&render_forwarded_ports_table(ports)),
+        OutputFormat::Yaml => match serde_yaml::to_string(&ports) {
+            Ok(yaml) => output_buffer.addln(yaml.trim_end()),
+            Err(e) => eprintln!("Unable to render forwarded ports as YAML. {e}"),
+        },
+        OutputFormat::Csv => {
+            let mut lines: Vec<String> =
+                vec!["protocol,host_address,host_port,guest_port".to_owned()];
+            lines.extend(ports.iter().map(|port| {
+                format!(
+                    "{},{},{},{}",
+                    port.protocol,
+                    escape_csv_field(&port.host_address),
+                    port.host_port,
+                    port.guest_port
+                )
+            }));
+            output_buffer.addln(&lines.join("\n"));
+        }
+    }
+}
+
+fn render_forwarded_ports_table(ports: &[ForwardedPort]) -> String {
+    //! Renders the forwarded ports as a table. Ports bound to every host
+    //! address show `*` as their address.
+    let address_width: usize = ports
+        .iter()
+        .map(|port| port.host_address.len())
+        .fold("Host Address".len(), max);
+    let mut lines: Vec<String> = vec![
+        format!(
+            "Protocol | {:address_width$} | Host Port | Guest Port",
+            "Host Address"
+        ),
+        format!(
+            "{:-<8}-+-{:-<address_width$}-+-{:-<9}-+-{:-<10}",
+            "", "", "", ""
+        ),
+    ];
+    for port in ports {
+        lines.push(format!(
+            "{:8} | {:address_width$} | {:<9} | {}",
+            port.protocol,
+            if port.host_address.is_empty() {
+                "*"
+            } else {
+                &port.host_address
+            },
+            port.host_port,
+            port.guest_port
         ));
     }
+    lines.join("\n")
 }
 
-pub fn get_file_from_image_name(image_name: &str, config: &Config) -> Option<PathBuf> {
-    let mut num_found = 0;
-    let mut real_image_name = String::new();
-    for full_image_name in get_list_of_images(ImageLocation::WorkingImages, config) {
-        if full_image_name.contains(image_name) {
-            real_image_name = full_image_name;
-            num_found += 1;
+fn escape_csv_field(field: &str) -> String {
+    //! Quotes a CSV field if it contains a delimiter, quote or newline.
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+pub fn print_running_vm_table(
+    running_vms: &[RunningVmRecord],
+    columns: Option<&[RunningVmColumn]>,
+    output_buffer: &mut OutputStream,
+) {
+    output_buffer.addln(&render_running_vm_table(running_vms, columns));
+}
+
+fn render_running_vm_table(
+    running_vms: &[RunningVmRecord],
+    columns: Option<&[RunningVmColumn]>,
+) -> String {
+    //! Renders the running VMs as a table with the given columns. By
+    //! default, it has their ports and names, with a column of crash counts
+    //! if any of them has been started again after exiting, and a column of
+    //! tags if any of them has tags.
+    let columns: Vec<RunningVmColumn> = match columns {
+        Some(columns) => columns.to_vec(),
+        None => {
+            let mut columns: Vec<RunningVmColumn> = vec![
+                RunningVmColumn::SshPort,
+                RunningVmColumn::HttpsPort,
+                RunningVmColumn::Name,
+            ];
+            if running_vms.iter().any(|vm| vm.crashes > 0) {
+                columns.push(RunningVmColumn::Crashes);
+            }
+            if running_vms.iter().any(|vm| !vm.tags.is_empty()) {
+                columns.push(RunningVmColumn::Tags);
+            }
+            columns
         }
+    };
+    let rows: Vec<Vec<String>> = running_vms
+        .iter()
+        .map(|vm| {
+            columns
+                .iter()
+                .map(|column| get_running_vm_cell(vm, *column))
+                .collect()
+        })
+        .collect();
+    let widths: Vec<usize> = columns
+        .iter()
+        .enumerate()
+        .map(|(index, column)| {
+            rows.iter()
+                .map(|row| row[index].len())
+                .fold(get_running_vm_header(*column).len(), max)
+        })
+        .collect();
+    let render_row = |cells: Vec<String>| {
+        cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<String>>()
+            .join(" | ")
+            .trim_end()
+            .to_owned()
+    };
+    let mut lines: Vec<String> = vec![
+        render_row(
+            columns
+                .iter()
+                .map(|column| get_running_vm_header(*column).to_owned())
+                .collect(),
+        ),
+        widths
+            .iter()
+            .map(|width| "-".repeat(*width))
+            .collect::<Vec<String>>()
+            .join("-+-"),
+    ];
+    lines.extend(rows.into_iter().map(render_row));
+    lines.join("\n")
+}
+
+fn get_running_vm_header(column: RunningVmColumn) -> &'static str {
+    match column {
+        RunningVmColumn::SshPort => "SSH Port",
+        RunningVmColumn::HttpsPort => "HTTPS Port",
+        RunningVmColumn::Name => "Image Name",
+        RunningVmColumn::Pid => "PID",
+        RunningVmColumn::State => "State",
+        RunningVmColumn::Uptime => "Uptime",
+        RunningVmColumn::Memory => "Memory",
+        RunningVmColumn::Ports => "Other Ports",
+        RunningVmColumn::Crashes => "Crashes",
+        RunningVmColumn::Tags => "Tags",
     }
+}
 
-    if real_image_name.is_empty() || num_found > 1 {
-        return None;
+fn get_running_vm_cell(vm: &RunningVmRecord, column: RunningVmColumn) -> String {
+    //! Returns what the table shows for `vm` in `column`, which is `-` for
+    //! anything unknown.
+    match column {
+        RunningVmColumn::SshPort => format_optional_port(vm.ssh_port),
+        RunningVmColumn::HttpsPort => format_optional_port(vm.https_port),
+        RunningVmColumn::Name => vm.image_name.clone(),
+        RunningVmColumn::Pid => vm.pid.map_or("-".to_owned(), |pid| pid.to_string()),
+        RunningVmColumn::State => vm.state.clone().unwrap_or("-".to_owned()),
+        RunningVmColumn::Uptime => vm.uptime.clone().unwrap_or("-".to_owned()),
+        RunningVmColumn::Memory => vm.resident_memory.map_or("-".to_owned(), format_bytes),
+        RunningVmColumn::Ports => {
+            // The ports forwarded to 22 and 443 have columns of their own.
+            let default_ports: Vec<String> = [(vm.ssh_port, 22), (vm.https_port, 443)]
+                .iter()
+                .filter_map(|(host_port, guest_port)| {
+                    host_port.map(|host_port| format!("{host_port} -> {guest_port}/tcp"))
+                })
+                .collect();
+            let other_ports: Vec<&str> = vm
+                .ports
+                .iter()
+                .filter(|port| !default_ports.contains(port))
+                .map(|port| port.as_str())
+                .collect();
+            if other_ports.is_empty() {
+                "-".to_owned()
+            } else {
+                other_ports.join(", ")
+            }
+        }
+        RunningVmColumn::Crashes => vm.crashes.to_string(),
+        RunningVmColumn::Tags if vm.tags.is_empty() => "-".to_owned(),
+        RunningVmColumn::Tags => vm.tags.join(", "),
     }
+}
+
+fn format_optional_port(port: Option<usize>) -> String {
+    port.map_or("-".to_owned(), |port| port.to_string())
+}
+
+pub fn get_file_from_image_name(image_name: &str, config: &Config) -> Option<PathBuf> {
+    find_image(image_name, config).ok().map(|(_, path)| path)
+}
+pub fn find_image(image_name: &str, config: &Config) -> Result<(String, PathBuf), String> {
+    //! Returns the full name and the path of the image called `image_name`,
+    //! or else of the only image whose name matches it.
+    let images: Vec<String> = get_list_of_images(ImageLocation::WorkingImages, config);
+    // internal lookups pass full image names, which mustn't be taken for a
+    // glob or regular expression matching others as well.
+    let real_image_name: String = if images.iter().any(|image| image == image_name) {
+        image_name.to_owned()
+    } else {
+        matching::select(image_name, images, |image| image.clone(), "image")?
+    };
 
-    let proposed_path: PathBuf = PathBuf::from(
-        shellexpand::tilde(&format!("{IMAGES_DIRECTORY}/{real_image_name}.img")).to_string(),
-    );
+    let proposed_path: PathBuf = image::get_image_path(&real_image_name, config);
     if !proposed_path.is_file() {
-        None
+        Err(format!(
+            "Image file '{}' does not exist.",
+            proposed_path.display()
+        ))
     } else {
-        Some(proposed_path.to_owned())
+        Ok((real_image_name, proposed_path))
     }
 }
 pub fn is_port_in_use(port: usize) -> bool {
@@ -246,10 +617,209 @@ pub fn is_port_in_use(port: usize) -> bool {
         Err(_) => true,
     }
 }
+pub fn find_in_path(program: &str) -> Option<PathBuf> {
+    //! Returns the path of the given program in `$PATH`, if it is installed.
+    std::env::var_os("PATH").and_then(|path| {
+        std::env::split_paths(&path)
+            .map(|directory| directory.join(program))
+            .find(|candidate| candidate.is_file())
+    })
+}
+pub fn is_process_running(pid: usize) -> bool {
+    //! Returns `true` if a process with the given PID currently exists.
+    Path::new(&format!("/proc/{pid}")).exists()
+}
+pub fn get_process_command_line(pid: usize) -> Option<Vec<String>> {
+    //! Returns the command line of the process with the given PID, as listed
+    //! in `/proc/<pid>/cmdline`.
+    let contents: Vec<u8> = std::fs::read(format!("/proc/{pid}/cmdline")).ok()?;
+    Some(
+        contents
+            .split(|byte| *byte == 0)
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect(),
+    )
+}
+pub fn wait_for_process_exit(pid: usize, timeout: Duration) -> bool {
+    //! Polls until the process with the given PID has exited. Returns `false`
+    //! if it is still running after `timeout`.
+    let started_waiting: Instant = Instant::now();
+    while is_process_running(pid) {
+        if started_waiting.elapsed() > timeout {
+            return false;
+        }
+        sleep(Duration::from_millis(100));
+    }
+    true
+}
+pub fn get_qmp_socket_path(image_name: &str) -> PathBuf {
+    //! Returns the path of the QMP socket for the VM running on the given
+    //! image.
+    PathBuf::from(shellexpand::tilde(&format!("{RUNTIME_DIRECTORY}/{image_name}.qmp")).to_string())
+}
+pub fn get_serial_socket_path(image_name: &str) -> PathBuf {
+    //! Returns the path of the serial console socket for the VM running on
+    //! the given image.
+    PathBuf::from(
+        shellexpand::tilde(&format!("{RUNTIME_DIRECTORY}/{image_name}.serial")).to_string(),
+    )
+}
+pub fn get_guest_agent_socket_path(image_name: &str) -> PathBuf {
+    //! Returns the path of the socket the guest agent of the VM running on
+    //! the given image is reached through.
+    PathBuf::from(shellexpand::tilde(&format!("{RUNTIME_DIRECTORY}/{image_name}.qga")).to_string())
+}
+pub fn get_pidfile_path(image_name: &str) -> PathBuf {
+    //! Returns the path of the file qemu writes its PID to for the VM running
+    //! on the given image.
+    PathBuf::from(shellexpand::tilde(&format!("{RUNTIME_DIRECTORY}/{image_name}.pid")).to_string())
+}
+pub fn get_daemon_socket_path() -> PathBuf {
+    //! Returns the path of the socket `vm-manager daemon` listens on.
+    PathBuf::from(shellexpand::tilde(&format!("{RUNTIME_DIRECTORY}/daemon.sock")).to_string())
+}
+pub fn get_stop_marker_path(image_name: &str) -> PathBuf {
+    //! Returns the path of the file marking the VM running on the given image
+    //! as stopped through vm-manager, rather than having exited by itself.
+    PathBuf::from(
+        shellexpand::tilde(&format!("{RUNTIME_DIRECTORY}/{image_name}.stopped")).to_string(),
+    )
+}
+pub fn get_partial_path(path: &Path) -> PathBuf {
+    //! Returns the temporary path a file is written to before being moved to
+    //! `path`, so that an interrupted write never looks complete.
+    let file_name: String = path
+        .file_name()
+        .map_or(String::new(), |name| name.to_string_lossy().to_string());
+    path.with_file_name(format!(".{file_name}.partial"))
+}
 pub fn find_open_port(starting_port: usize) -> usize {
+    //! Returns the first port from `starting_port` on which is neither in use
+    //! nor already picked by another thread, which may be starting a VM on
+    //! it that hasn't bound it yet.
+    let claimed_ports = || CLAIMED_PORTS.lock().unwrap_or_else(PoisonError::into_inner);
+    let thread: ThreadId = thread::current().id();
     let mut selected_port: usize = starting_port;
-    while is_port_in_use(selected_port) {
+    loop {
+        {
+            let mut claimed_ports = claimed_ports();
+            if claimed_ports
+                .iter()
+                .any(|(port, owner)| *port == selected_port && *owner != thread)
+            {
+                selected_port += 1;
+                continue;
+            }
+            claimed_ports.push((selected_port, thread));
+        }
+        // the port is only probed once claimed, so that threads picking
+        // ports probe them at the same time without picking the same one.
+        if !is_port_in_use(selected_port) {
+            return selected_port;
+        }
+        let mut claimed_ports = claimed_ports();
+        if let Some(index) = claimed_ports
+            .iter()
+            .rposition(|claim| *claim == (selected_port, thread))
+        {
+            claimed_ports.remove(index);
+        }
         selected_port += 1;
     }
-    selected_port
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        escape_csv_field, render_forwarded_ports_table, render_running_vm_table,
+        render_running_vms_csv, RunningVmRecord,
+    };
+    use crate::parse_args::RunningVmColumn;
+    use crate::state::ForwardedPort;
+
+    #[test]
+    fn test_escape_csv_field() {
+        assert_eq!(escape_csv_field("deb12"), "deb12");
+        assert_eq!(escape_csv_field("a,b"), "\"a,b\"");
+        assert_eq!(escape_csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn test_render_running_vms_csv() {
+        let records: Vec<RunningVmRecord> = vec![
+            RunningVmRecord {
+                image_name: "deb12".to_owned(),
+                pid: Some(1234),
+                ssh_port: Some(5555),
+                https_port: None,
+                ports: vec!["5555 -> 22/tcp".to_owned(), "5353 -> 53/udp".to_owned()],
+                crashes: 2,
+                tags: vec!["k8s".to_owned(), "ci".to_owned()],
+                state: Some("paused".to_owned()),
+                uptime: Some("1-02:03:04".to_owned()),
+                resident_memory: Some(1073741824),
+            },
+            RunningVmRecord {
+                image_name: "alpine".to_owned(),
+                pid: None,
+                ssh_port: None,
+                https_port: None,
+                ports: vec![],
+                crashes: 0,
+                tags: vec![],
+                state: None,
+                uptime: None,
+                resident_memory: None,
+            },
+        ];
+        assert_eq!(
+            render_running_vms_csv(&records),
+            "image_name,pid,ssh_port,https_port,ports,crashes,tags,state,uptime,resident_memory\n\
+             deb12,1234,5555,,5555 -> 22/tcp;5353 -> 53/udp,2,k8s;ci,paused,1-02:03:04,1073741824\n\
+             alpine,,,,,0,,,,"
+        );
+        assert_eq!(
+            render_running_vm_table(&records, None),
+            "SSH Port | HTTPS Port | Image Name | Crashes | Tags\n\
+             ---------+------------+------------+---------+--------\n\
+             5555     | -          | deb12      | 2       | k8s, ci\n\
+             -        | -          | alpine     | 0       | -"
+        );
+        assert_eq!(
+            render_running_vm_table(
+                &records,
+                Some(&[
+                    RunningVmColumn::Name,
+                    RunningVmColumn::State,
+                    RunningVmColumn::Memory,
+                    RunningVmColumn::Ports
+                ])
+            ),
+            "Image Name | State  | Memory  | Other Ports\n\
+             -----------+--------+---------+---------------\n\
+             deb12      | paused | 1.0 GiB | 5353 -> 53/udp\n\
+             alpine     | -      | -       | -"
+        );
+    }
+
+    #[test]
+    fn test_render_forwarded_ports_table() {
+        let ports: Vec<ForwardedPort> = [
+            "tcp::5555-:22",
+            "udp:127.0.0.1:5353-:53",
+            "tcp:[::1]:8080-:80",
+        ]
+        .iter()
+        .filter_map(|rule| ForwardedPort::parse(rule))
+        .collect();
+        assert_eq!(
+            render_forwarded_ports_table(&ports),
+            "Protocol | Host Address | Host Port | Guest Port\n\
+             ---------+--------------+-----------+-----------\n\
+             tcp      | *            | 5555      | 22\n\
+             udp      | 127.0.0.1    | 5353      | 53\n\
+             tcp      | ::1          | 8080      | 80"
+        );
+    }
 }
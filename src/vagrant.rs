@@ -0,0 +1,259 @@
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::image::convert_image;
+use crate::parse_args::ImageFormat;
+use crate::utils::{get_partial_path, run_interactive_command, run_shell_command};
+
+/// Where Vagrant keeps the boxes it has added, e.g. with `vagrant box add`.
+const VAGRANT_BOXES_DIRECTORY: &str = "~/.vagrant.d/boxes";
+/// Private keys Vagrant boxes accept for the `vagrant` user until Vagrant
+/// replaces them on first boot, newest first.
+const VAGRANT_INSECURE_KEYS: [&str; 2] = [
+    "~/.vagrant.d/insecure_private_keys/vagrant.key.ed25519",
+    "~/.vagrant.d/insecure_private_key",
+];
+/// The user Vagrant boxes are logged into as.
+pub const VAGRANT_USER: &str = "vagrant";
+/// Providers whose boxes hold qemu disk images.
+const QEMU_PROVIDERS: [&str; 2] = ["libvirt", "qemu"];
+
+/// The parts of a box's `metadata.json` this program cares about.
+#[derive(Debug, Deserialize, Eq, PartialEq)]
+struct BoxMetadata {
+    provider: String,
+    /// The box's disks, in boxes of format version 2. Older boxes have a
+    /// single `box.img`.
+    #[serde(default)]
+    disks: Vec<BoxDisk>,
+}
+
+#[derive(Debug, Deserialize, Eq, PartialEq)]
+struct BoxDisk {
+    path: String,
+}
+
+pub fn get_default_name(box_name: &str) -> String {
+    //! Returns the name an imported box's image gets unless told otherwise,
+    //! e.g. `debian12` for `generic/debian12` or `./debian12.box`.
+    let name: &str = box_name
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or(box_name);
+    name.strip_suffix(".box").unwrap_or(name).to_owned()
+}
+
+fn compare_versions(a: &str, b: &str) -> std::cmp::Ordering {
+    //! Compares box versions such as `4.3.12` part by part, numerically
+    //! where both parts are numbers.
+    let parts = |version: &str| -> Vec<(u64, String)> {
+        version
+            .split('.')
+            .map(|part| (part.parse::<u64>().unwrap_or(0), part.to_owned()))
+            .collect()
+    };
+    parts(a).cmp(&parts(b))
+}
+
+fn find_provider_directory(directory: &Path) -> Option<PathBuf> {
+    //! Returns the directory under `directory` holding an added box's files
+    //! for a qemu provider. Newer Vagrant versions put it in a directory per
+    //! architecture.
+    for provider in QEMU_PROVIDERS {
+        if directory.join(provider).join("metadata.json").is_file() {
+            return Some(directory.join(provider));
+        }
+    }
+    fs::read_dir(directory)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_dir())
+        .find_map(|path| {
+            QEMU_PROVIDERS
+                .iter()
+                .map(|provider| path.join(provider))
+                .find(|path| path.join("metadata.json").is_file())
+        })
+}
+
+fn find_added_box(box_name: &str) -> Result<PathBuf, String> {
+    //! Returns the directory of the newest version of a box added to
+    //! Vagrant, such as `generic/debian12`.
+    let directory: PathBuf = PathBuf::from(
+        shellexpand::tilde(&format!(
+            "{VAGRANT_BOXES_DIRECTORY}/{}",
+            box_name.replace('/', "-VAGRANTSLASH-")
+        ))
+        .to_string(),
+    );
+    let mut versions: Vec<String> = fs::read_dir(&directory)
+        .map_err(|_| {
+            format!(
+                "'{box_name}' is neither a box file, a URL, nor a box added to Vagrant. Add it with 'vagrant box add {box_name} --provider libvirt'."
+            )
+        })?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .collect();
+    versions.sort_by(|a, b| compare_versions(a, b));
+    versions
+        .iter()
+        .rev()
+        .find_map(|version| find_provider_directory(&directory.join(version)))
+        .ok_or(format!(
+            "Box '{box_name}' has no version for the libvirt or qemu provider. Add one with 'vagrant box add {box_name} --provider libvirt'."
+        ))
+}
+
+fn get_disk_paths(box_directory: &Path) -> Result<Vec<PathBuf>, String> {
+    //! Reads the metadata of an extracted box, returning the paths of its
+    //! disks.
+    let metadata_path: PathBuf = box_directory.join("metadata.json");
+    let metadata: String = fs::read_to_string(&metadata_path)
+        .map_err(|e| format!("The box has no readable 'metadata.json'. {e}"))?;
+    let metadata: BoxMetadata = serde_json::from_str(&metadata)
+        .map_err(|e| format!("The box's 'metadata.json' is invalid. {e}"))?;
+    if !QEMU_PROVIDERS.contains(&metadata.provider.as_str()) {
+        return Err(format!(
+            "The box is for the '{}' provider. Only boxes for libvirt or qemu can be imported.",
+            metadata.provider
+        ));
+    }
+    if metadata.disks.is_empty() {
+        return Ok(vec![box_directory.join("box.img")]);
+    }
+    Ok(metadata
+        .disks
+        .iter()
+        .map(|disk| box_directory.join(&disk.path))
+        .collect())
+}
+
+fn extract_box(box_file: &Path, directory: &Path) -> Result<(), String> {
+    //! Extracts a box file, a tar archive which may be compressed.
+    fs::create_dir_all(directory)
+        .map_err(|e| format!("Unable to create '{}'. {e}", directory.display()))?;
+    let output = run_shell_command(&[
+        "tar",
+        "-xf",
+        &box_file.display().to_string(),
+        "-C",
+        &directory.display().to_string(),
+    ])?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "Unable to extract box '{}'. {}",
+            box_file.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+fn get_box_directory(box_name: &str, scratch_directory: &Path) -> Result<PathBuf, String> {
+    //! Returns the directory holding the files of the box `box_name`,
+    //! downloading and extracting it into `scratch_directory` unless it was
+    //! added to Vagrant.
+    let box_file: PathBuf = PathBuf::from(shellexpand::tilde(box_name).to_string());
+    if box_name.starts_with("https://") || box_name.starts_with("http://") {
+        fs::create_dir_all(scratch_directory)
+            .map_err(|e| format!("Unable to create '{}'. {e}", scratch_directory.display()))?;
+        let download_path: PathBuf = scratch_directory.join("download.box");
+        run_interactive_command(&[
+            "curl",
+            "--fail",
+            "--location",
+            "--progress-bar",
+            "--output",
+            &download_path.display().to_string(),
+            box_name,
+        ])
+        .map_err(|e| format!("Unable to download '{box_name}'. {e}"))?;
+        let box_directory: PathBuf = scratch_directory.join("box");
+        extract_box(&download_path, &box_directory)?;
+        Ok(box_directory)
+    } else if box_file.is_file() {
+        extract_box(&box_file, scratch_directory)?;
+        Ok(scratch_directory.to_path_buf())
+    } else {
+        find_added_box(box_name)
+    }
+}
+
+fn convert_box_disk(box_directory: &Path, path: &Path) -> Result<(), String> {
+    let disks: Vec<PathBuf> = get_disk_paths(box_directory)?;
+    if disks.len() > 1 {
+        eprintln!(
+            "The box has {} disks, only the first of which is imported.",
+            disks.len()
+        );
+    }
+    convert_image(&disks[0], path, ImageFormat::Qcow2)
+}
+
+pub fn import_box(box_name: &str, path: &Path) -> Result<(), String> {
+    //! Imports the first disk of a Vagrant box as a qcow2 image at `path`.
+    //! The box is a box file, a URL to download one from, or the name of a
+    //! box added to Vagrant, such as `generic/debian12`.
+    let scratch_directory: PathBuf = get_partial_path(&path.with_extension("box"));
+    let imported: Result<(), String> = get_box_directory(box_name, &scratch_directory)
+        .and_then(|box_directory| convert_box_disk(&box_directory, path));
+    let _ = fs::remove_dir_all(&scratch_directory);
+    imported
+}
+
+pub fn find_insecure_key() -> Option<String> {
+    //! Returns the insecure private key Vagrant boxes accept, if Vagrant is
+    //! installed.
+    VAGRANT_INSECURE_KEYS
+        .iter()
+        .find(|key| Path::new(&*shellexpand::tilde(key)).is_file())
+        .map(|key| key.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compare_versions, get_default_name, get_disk_paths};
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_vagrant_box() {
+        assert_eq!(get_default_name("generic/debian12"), "debian12");
+        assert_eq!(get_default_name("~/Downloads/alma9.box"), "alma9");
+        let mut versions: Vec<&str> = vec!["4.3.9", "4.3.12", "202401.1", "4.3.10"];
+        versions.sort_by(|a, b| compare_versions(a, b));
+        assert_eq!(versions, ["4.3.9", "4.3.10", "4.3.12", "202401.1"]);
+
+        let directory: PathBuf =
+            std::env::temp_dir().join(format!("vm-manager-test-vagrant-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(
+            directory.join("metadata.json"),
+            r#"{"provider": "libvirt", "format": "qcow2", "virtual_size": 128}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            get_disk_paths(&directory),
+            Ok(vec![directory.join("box.img")])
+        );
+        fs::write(
+            directory.join("metadata.json"),
+            r#"{"provider": "libvirt", "format_version": 2, "disks": [{"path": "box_1.img", "format": "qcow2"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            get_disk_paths(&directory),
+            Ok(vec![directory.join("box_1.img")])
+        );
+        fs::write(
+            directory.join("metadata.json"),
+            r#"{"provider": "virtualbox"}"#,
+        )
+        .unwrap();
+        assert!(get_disk_paths(&directory).is_err());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
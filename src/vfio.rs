@@ -0,0 +1,231 @@
+use std::fs::{self, read_dir, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use crate::config::PciDevice;
+
+/// Where the kernel lists the host's PCI devices.
+const SYSFS_PCI_DEVICES: &str = "/sys/bus/pci/devices";
+/// Where the kernel lists IOMMU groups, which is empty if the IOMMU is off.
+pub const SYSFS_IOMMU_GROUPS: &str = "/sys/kernel/iommu_groups";
+/// Where the `vfio-pci` driver appears once its module is loaded.
+pub const SYSFS_VFIO_PCI_DRIVER: &str = "/sys/bus/pci/drivers/vfio-pci";
+/// Where VFIO exposes a device node for each IOMMU group bound to it.
+const DEV_VFIO: &str = "/dev/vfio";
+/// The driver passed through devices must be bound to.
+const VFIO_DRIVER: &str = "vfio-pci";
+/// Drivers which may hold the other devices of a passed through device's
+/// IOMMU group, as they don't give the host access to the devices.
+const VFIO_COMPATIBLE_DRIVERS: &[&str] = &[VFIO_DRIVER, "pci-stub", "pcieport"];
+
+pub fn normalize_pci_address(address: &str) -> Result<String, String> {
+    //! Returns a PCI address in the full `domain:bus:slot.function` form
+    //! sysfs uses, e.g. `0000:01:00.0`. The domain may be omitted.
+    let address: String = address.trim().to_lowercase();
+    let address: String = if address.matches(':').count() == 1 {
+        format!("0000:{address}")
+    } else {
+        address
+    };
+    let is_hex = |part: &str, length: usize| {
+        part.len() == length && part.chars().all(|c| c.is_ascii_hexdigit())
+    };
+    let valid: bool = match address.split(':').collect::<Vec<&str>>()[..] {
+        [domain, bus, device] => match device.split_once('.') {
+            Some((slot, function)) => {
+                is_hex(domain, 4)
+                    && is_hex(bus, 2)
+                    && is_hex(slot, 2)
+                    && function.len() == 1
+                    && ('0'..='7').contains(&function.chars().next().unwrap_or('8'))
+            }
+            None => false,
+        },
+        _ => false,
+    };
+    if !valid {
+        return Err(format!(
+            "Invalid PCI address '{address}'. Use the form shown by 'lspci -D', e.g. '0000:01:00.0'."
+        ));
+    }
+    Ok(address)
+}
+
+fn read_link_name(path: &Path) -> Option<String> {
+    //! Returns the last component of the target of a sysfs symlink, such as
+    //! the name of a device's driver.
+    fs::read_link(path)
+        .ok()
+        .and_then(|target| target.file_name()?.to_str().map(|name| name.to_owned()))
+}
+
+fn check_iommu_group(sysfs_devices: &Path, address: &str) -> Result<String, String> {
+    //! Checks that a PCI device can be passed through: it must exist, be in
+    //! an IOMMU group, and be bound to `vfio-pci`, as must every other
+    //! device in its group. Returns the device's IOMMU group.
+    let device: PathBuf = sysfs_devices.join(address);
+    if !device.exists() {
+        return Err(format!(
+            "There is no PCI device '{address}' on this host. List the host's devices with 'lspci -D'."
+        ));
+    }
+    let group: String = read_link_name(&device.join("iommu_group")).ok_or(format!(
+        "PCI device '{address}' is not in an IOMMU group. Enable the IOMMU in the firmware settings and add 'intel_iommu=on' or 'amd_iommu=on' to the kernel command line."
+    ))?;
+
+    match read_link_name(&device.join("driver")) {
+        Some(driver) if driver == VFIO_DRIVER => {}
+        driver => {
+            return Err(format!(
+                "PCI device '{address}' is {}, not '{VFIO_DRIVER}'. Bind it with e.g. 'driverctl set-override {address} {VFIO_DRIVER}', or add its vendor:device ID (see 'lspci -nn') to the 'vfio-pci.ids=' kernel parameter.",
+                driver.map_or("not bound to a driver".to_owned(), |driver| format!(
+                    "bound to '{driver}'"
+                ))
+            ));
+        }
+    }
+
+    let members = read_dir(device.join("iommu_group").join("devices"))
+        .map_err(|e| format!("Unable to list IOMMU group {group} of '{address}'. {e}"))?;
+    for member in members.filter_map(|entry| entry.ok()) {
+        let member_address: String = member.file_name().to_string_lossy().to_string();
+        if member_address == address {
+            continue;
+        }
+        if let Some(driver) = read_link_name(&member.path().join("driver")) {
+            if !VFIO_COMPATIBLE_DRIVERS.contains(&driver.as_str()) {
+                return Err(format!(
+                    "PCI device '{member_address}' shares IOMMU group {group} with '{address}', but is bound to '{driver}'. Every device in the group must be bound to '{VFIO_DRIVER}'; pass it through as well, or bind it with e.g. 'driverctl set-override {member_address} {VFIO_DRIVER}'."
+                ));
+            }
+        }
+    }
+    Ok(group)
+}
+
+fn check_device_node(group: &str) -> Result<(), String> {
+    //! Checks that qemu will be able to open the VFIO device node of an
+    //! IOMMU group.
+    let node: PathBuf = Path::new(DEV_VFIO).join(group);
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&node)
+        .map(|_| ())
+        .map_err(|e| {
+            format!(
+                "Unable to open '{}'. {e}. Your user needs read and write access to it, e.g. via a udev rule or the 'kvm' group.",
+                node.display()
+            )
+        })
+}
+
+pub fn vfio_qemu_args(devices: &[PciDevice]) -> Result<Vec<String>, String> {
+    //! Returns the arguments passing each device through to the guest.
+    let mut args: Vec<String> = vec![];
+    for device in devices {
+        let mut properties: String =
+            format!("vfio-pci,host={}", normalize_pci_address(&device.address)?);
+        if let Some(romfile) = &device.romfile {
+            properties.push_str(&format!(",romfile={}", shellexpand::tilde(romfile)));
+        }
+        args.push("-device".to_owned());
+        args.push(properties);
+    }
+    Ok(args)
+}
+
+pub fn prepare_pci_passthrough(devices: &[PciDevice]) -> Result<Vec<String>, String> {
+    //! Checks that the host is set up to pass each device through, and
+    //! returns the qemu arguments doing so. qemu's own errors when it isn't
+    //! are rather cryptic, so this checks beforehand.
+    for device in devices {
+        let address: String = normalize_pci_address(&device.address)?;
+        let group: String = check_iommu_group(Path::new(SYSFS_PCI_DEVICES), &address)?;
+        check_device_node(&group)?;
+        if let Some(romfile) = &device.romfile {
+            let romfile: String = shellexpand::tilde(romfile).to_string();
+            if !Path::new(&romfile).is_file() {
+                return Err(format!(
+                    "ROM file '{romfile}' of PCI device '{address}' does not exist."
+                ));
+            }
+        }
+    }
+    vfio_qemu_args(devices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_iommu_group, normalize_pci_address, vfio_qemu_args};
+    use crate::config::PciDevice;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_normalize_pci_address() {
+        assert_eq!(normalize_pci_address("01:00.0").unwrap(), "0000:01:00.0");
+        assert_eq!(
+            normalize_pci_address("0000:0A:00.1").unwrap(),
+            "0000:0a:00.1"
+        );
+        assert!(normalize_pci_address("01:00").is_err());
+        assert!(normalize_pci_address("01:00.8").is_err());
+        assert!(normalize_pci_address("gpu").is_err());
+
+        let devices: Vec<PciDevice> = vec![PciDevice {
+            address: "01:00.0".to_owned(),
+            romfile: Some("/roms/gpu.rom".to_owned()),
+        }];
+        assert_eq!(
+            vfio_qemu_args(&devices).unwrap(),
+            vec![
+                "-device",
+                "vfio-pci,host=0000:01:00.0,romfile=/roms/gpu.rom"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_iommu_group() {
+        // a fake sysfs with a GPU and its audio function in IOMMU group 13
+        let root: PathBuf =
+            std::env::temp_dir().join(format!("vm-manager-test-vfio-{}", std::process::id()));
+        let devices: PathBuf = root.join("devices");
+        let group: PathBuf = root.join("iommu_groups/13");
+        fs::create_dir_all(group.join("devices")).unwrap();
+        for driver in ["vfio-pci", "snd_hda_intel"] {
+            fs::create_dir_all(root.join("drivers").join(driver)).unwrap();
+        }
+        let add_device = |address: &str, driver: Option<&str>| {
+            let device: PathBuf = devices.join(address);
+            fs::create_dir_all(&device).unwrap();
+            symlink(&group, device.join("iommu_group")).unwrap();
+            symlink(&device, group.join("devices").join(address)).unwrap();
+            if let Some(driver) = driver {
+                symlink(root.join("drivers").join(driver), device.join("driver")).unwrap();
+            }
+        };
+        add_device("0000:01:00.0", Some("vfio-pci"));
+        add_device("0000:01:00.1", Some("snd_hda_intel"));
+        add_device("0000:02:00.0", None);
+
+        let error: String = check_iommu_group(&devices, "0000:01:00.0").unwrap_err();
+        assert!(error.contains("'0000:01:00.1' shares IOMMU group 13"));
+        assert!(check_iommu_group(&devices, "0000:01:00.1")
+            .unwrap_err()
+            .contains("bound to 'snd_hda_intel'"));
+        assert!(check_iommu_group(&devices, "0000:03:00.0")
+            .unwrap_err()
+            .contains("no PCI device"));
+
+        fs::remove_file(devices.join("0000:01:00.1/driver")).unwrap();
+        symlink(
+            root.join("drivers/vfio-pci"),
+            devices.join("0000:01:00.1/driver"),
+        )
+        .unwrap();
+        assert_eq!(check_iommu_group(&devices, "0000:01:00.0").unwrap(), "13");
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
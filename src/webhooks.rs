@@ -0,0 +1,223 @@
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::config::{Webhook, WebhookEvent};
+
+/// How long a webhook may take to accept an event before it's given up on.
+const WEBHOOK_TIMEOUT_SECONDS: u64 = 10;
+/// The header carrying the signature of a post to a webhook with a secret.
+const SIGNATURE_HEADER: &str = "X-VM-Manager-Signature";
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    //! Computes the HMAC-SHA256 of `message`, as described in RFC 2104.
+    const BLOCK_SIZE: usize = 64;
+    let mut block: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let inner = Sha256::new()
+        .chain_update(block.map(|byte| byte ^ 0x36))
+        .chain_update(message)
+        .finalize();
+    Sha256::new()
+        .chain_update(block.map(|byte| byte ^ 0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn quote_curl_config(value: &str) -> String {
+    //! Quotes a value for a curl config file.
+    let mut quoted: String = String::from('"');
+    for c in value.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn get_curl_config(webhook: &Webhook, event: WebhookEvent, body: &str) -> String {
+    //! Returns the curl config posting `body` to the webhook. It's passed to
+    //! curl on its stdin, so the URL and secret aren't visible in the
+    //! process list.
+    let mut lines: Vec<String> = vec![
+        format!("url = {}", quote_curl_config(&webhook.url)),
+        "request = \"POST\"".to_owned(),
+        "silent".to_owned(),
+        "show-error".to_owned(),
+        "fail".to_owned(),
+        format!("max-time = {WEBHOOK_TIMEOUT_SECONDS}"),
+        "header = \"Content-Type: application/json\"".to_owned(),
+        format!("header = \"X-VM-Manager-Event: {event}\""),
+    ];
+    if let Some(secret) = &webhook.secret {
+        let signature: String = to_hex(&hmac_sha256(secret.as_bytes(), body.as_bytes()));
+        lines.push(format!(
+            "header = \"{SIGNATURE_HEADER}: sha256={signature}\""
+        ));
+    }
+    lines.push(format!("data-binary = {}", quote_curl_config(body)));
+    lines.join("\n") + "\n"
+}
+
+fn get_host_name() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_owned())
+        .unwrap_or_else(|_| "unknown".to_owned())
+}
+
+fn get_event_body(event: WebhookEvent, vm_name: &str, details: Value) -> String {
+    //! Returns the JSON posted for an event. Besides the event's own
+    //! details, it has a readable `text`, which chat services such as Slack
+    //! show as the message.
+    let host: String = get_host_name();
+    let text: String = match event {
+        WebhookEvent::Started => format!("VM '{vm_name}' started on {host}."),
+        WebhookEvent::Stopped => format!("VM '{vm_name}' stopped on {host}."),
+        WebhookEvent::Crashed => format!("VM '{vm_name}' crashed on {host}."),
+        WebhookEvent::BackupCompleted => {
+            format!("Backup of VM '{vm_name}' completed on {host}.")
+        }
+    };
+    let mut body: Map<String, Value> = Map::new();
+    body.insert("event".to_owned(), Value::from(event.to_string()));
+    body.insert("vm".to_owned(), Value::from(vm_name));
+    body.insert("host".to_owned(), Value::from(host));
+    body.insert(
+        "timestamp".to_owned(),
+        Value::from(chrono::Local::now().to_rfc3339()),
+    );
+    body.insert("text".to_owned(), Value::from(text));
+    if let Value::Object(details) = details {
+        body.extend(details);
+    }
+    Value::Object(body).to_string()
+}
+
+fn post(webhook: &Webhook, event: WebhookEvent, body: &str) -> Result<(), String> {
+    let mut curl = Command::new("curl")
+        .args(["--config", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Unable to run 'curl'. {e}"))?;
+    if let Some(mut stdin) = curl.stdin.take() {
+        stdin
+            .write_all(get_curl_config(webhook, event, body).as_bytes())
+            .map_err(|e| format!("Unable to pass the request to curl. {e}"))?;
+    }
+    let output: Output = curl
+        .wait_with_output()
+        .map_err(|e| format!("Unable to run 'curl'. {e}"))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_owned())
+    }
+}
+
+pub fn get_exit_event(stopped: bool, crashed: bool) -> Option<WebhookEvent> {
+    //! Returns the event a VM whose qemu exited is reported with by what
+    //! waited on it: the launcher of a VM in the foreground, or `vm-manager
+    //! supervise`. A VM `stopped` through vm-manager isn't, as the stop
+    //! reports it, so that each exit is only reported once.
+    match (stopped, crashed) {
+        (true, _) => None,
+        (false, true) => Some(WebhookEvent::Crashed),
+        (false, false) => Some(WebhookEvent::Stopped),
+    }
+}
+
+pub fn notify(webhooks: &[Webhook], event: WebhookEvent, vm_name: &str, details: Value) {
+    //! Posts an event to every one of `webhooks` subscribed to it. A webhook
+    //! which can't be reached is only reported, as the event itself
+    //! succeeded.
+    let webhooks: Vec<&Webhook> = webhooks
+        .iter()
+        .filter(|webhook| webhook.is_subscribed(event))
+        .collect();
+    if webhooks.is_empty() {
+        return;
+    }
+    let body: String = get_event_body(event, vm_name, details);
+    for webhook in webhooks {
+        if let Err(e) = post(webhook, event, &body) {
+            eprintln!(
+                "Unable to post event '{event}' of VM '{vm_name}' to webhook '{}'. {e}",
+                webhook.url
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_curl_config, get_exit_event, hmac_sha256, to_hex};
+    use crate::config::{Webhook, WebhookEvent};
+
+    #[test]
+    fn test_signed_curl_config() {
+        // test cases 2 and 6 of RFC 4231
+        assert_eq!(
+            to_hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            to_hex(&hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+
+        let webhook = Webhook {
+            url: "https://automation.lab/vm-events".to_owned(),
+            events: vec![WebhookEvent::Crashed],
+            secret: Some("Jefe".to_owned()),
+        };
+        assert!(webhook.is_subscribed(WebhookEvent::Crashed));
+        assert!(!webhook.is_subscribed(WebhookEvent::Started));
+        let config: String = get_curl_config(
+            &webhook,
+            WebhookEvent::Crashed,
+            "what do ya want for nothing?",
+        );
+        assert!(config.starts_with("url = \"https://automation.lab/vm-events\"\n"));
+        assert!(config.contains("header = \"X-VM-Manager-Event: crashed\"\n"));
+        assert!(config.contains(
+            "header = \"X-VM-Manager-Signature: sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843\"\n"
+        ));
+        assert!(
+            get_curl_config(&webhook, WebhookEvent::Crashed, "{\"text\":\"a\\\\b\"}")
+                .ends_with("data-binary = \"{\\\"text\\\":\\\"a\\\\\\\\b\\\"}\"\n")
+        );
+    }
+
+    #[test]
+    fn test_get_exit_event() {
+        // a VM stopped through vm-manager was already reported by the stop,
+        // even if it had to be killed.
+        assert_eq!(get_exit_event(true, false), None);
+        assert_eq!(get_exit_event(true, true), None);
+        assert_eq!(get_exit_event(false, false), Some(WebhookEvent::Stopped));
+        assert_eq!(get_exit_event(false, true), Some(WebhookEvent::Crashed));
+    }
+}
@@ -0,0 +1,135 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+use crate::{CONFIG_FILE, IMAGES_DIRECTORY, TOML_CONFIG_FILE};
+
+/// Name of vm-manager's directory in each XDG base directory.
+const APP_DIRECTORY: &str = "vm-manager";
+/// Names of the config files in the config directory, by preference.
+const CONFIG_FILE_NAMES: [&str; 2] = ["config.yml", "config.toml"];
+/// Directories next to the config file which hold parts of the config.
+const CONFIG_SUBDIRECTORIES: [&str; 2] = ["conf.d", "vms.d"];
+/// Name of the images directory in the data directory.
+const IMAGES_DIRECTORY_NAME: &str = "disk-images";
+
+fn get_base_directory(value: Option<String>, default: &str) -> PathBuf {
+    //! Returns an XDG base directory, given the value of its environment
+    //! variable. As the specification asks, the variable is ignored unless
+    //! it holds an absolute path, and `default` under the home directory is
+    //! used instead.
+    value
+        .map(PathBuf::from)
+        .filter(|directory| directory.is_absolute())
+        .unwrap_or_else(|| PathBuf::from(shellexpand::tilde(default).as_ref()))
+}
+
+pub fn get_config_directory() -> PathBuf {
+    //! Returns `$XDG_CONFIG_HOME/vm-manager`.
+    get_base_directory(env::var("XDG_CONFIG_HOME").ok(), "~/.config").join(APP_DIRECTORY)
+}
+
+pub fn get_data_directory() -> PathBuf {
+    //! Returns `$XDG_DATA_HOME/vm-manager`.
+    get_base_directory(env::var("XDG_DATA_HOME").ok(), "~/.local/share").join(APP_DIRECTORY)
+}
+
+fn expand(path: &str) -> PathBuf {
+    PathBuf::from(shellexpand::tilde(path).as_ref())
+}
+
+fn get_legacy_config_file() -> Option<PathBuf> {
+    //! Returns the config file of an installation from before vm-manager
+    //! followed the XDG base directory specification, if there is one.
+    [CONFIG_FILE, TOML_CONFIG_FILE]
+        .into_iter()
+        .map(expand)
+        .find(|path| path.is_file())
+}
+
+pub fn get_default_config_file() -> String {
+    //! Returns the config file used without -c/--config-file:
+    //! `~/.vm-manager/config.yml` or `config.toml` if either exists, and
+    //! otherwise `config.yml` in the config directory, or `config.toml` if
+    //! only that exists.
+    let config_directory: PathBuf = get_config_directory();
+    let path: PathBuf = get_legacy_config_file()
+        .or_else(|| {
+            CONFIG_FILE_NAMES
+                .iter()
+                .map(|name| config_directory.join(name))
+                .find(|path| path.is_file())
+        })
+        .unwrap_or_else(|| config_directory.join(CONFIG_FILE_NAMES[0]));
+    path.display().to_string()
+}
+
+pub fn get_default_images_directory() -> String {
+    //! Returns the images directory used when the config doesn't set one:
+    //! `~/.vm-manager/disk-images` if it exists, and otherwise
+    //! `disk-images` in the data directory.
+    if expand(IMAGES_DIRECTORY).is_dir() {
+        IMAGES_DIRECTORY.to_owned()
+    } else {
+        get_data_directory()
+            .join(IMAGES_DIRECTORY_NAME)
+            .display()
+            .to_string()
+    }
+}
+
+pub fn get_legacy_moves() -> Vec<(PathBuf, PathBuf)> {
+    //! Returns the files and directories of an installation from before
+    //! vm-manager followed the XDG base directory specification which
+    //! `migrate-paths` moves, each with where it's moved to: the config file
+    //! with its backup and drop-in directories, and the images directory.
+    let mut moves: Vec<(PathBuf, PathBuf)> = vec![];
+    if let Some(config_file) = get_legacy_config_file() {
+        let config_directory: PathBuf = get_config_directory();
+        let legacy_directory: &Path = config_file.parent().unwrap_or(Path::new("/"));
+        let mut names: Vec<String> = vec![];
+        if let Some(name) = config_file.file_name() {
+            let name: String = name.to_string_lossy().into_owned();
+            names.push(format!("{name}.bak"));
+            names.insert(0, name);
+        }
+        names.extend(CONFIG_SUBDIRECTORIES.iter().map(|name| name.to_string()));
+        moves.extend(
+            names
+                .iter()
+                .map(|name| (legacy_directory.join(name), config_directory.join(name)))
+                .filter(|(source, _)| source.exists()),
+        );
+    }
+    let images_directory: PathBuf = expand(IMAGES_DIRECTORY);
+    if images_directory.is_dir() {
+        moves.push((
+            images_directory,
+            get_data_directory().join(IMAGES_DIRECTORY_NAME),
+        ));
+    }
+    moves
+}
+
+#[cfg(test)]
+mod tests {
+    use super::get_base_directory;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_get_base_directory() {
+        assert_eq!(
+            get_base_directory(Some("/srv/config".to_owned()), "~/.config"),
+            PathBuf::from("/srv/config")
+        );
+        let default: PathBuf = PathBuf::from(shellexpand::tilde("~/.config").as_ref());
+        assert_eq!(
+            get_base_directory(Some("relative/config".to_owned()), "~/.config"),
+            default
+        );
+        assert_eq!(
+            get_base_directory(Some(String::new()), "~/.config"),
+            default
+        );
+        assert_eq!(get_base_directory(None, "~/.config"), default);
+    }
+}